@@ -6,10 +6,43 @@
 
 use std::mem;
 use std::slice;
+use crate::hpet::{HPET_BASE, HPET_NUM_TIMERS};
 use crate::memory::GuestMemory;
+use crate::layout::{LAPIC_BASE, RSDP_START};
 
+/// PM1a event block (status + enable) and control block I/O ports
+pub const PM1A_EVT_BLK: u16 = 0x600;
+pub const PM1A_CNT_BLK: u16 = 0x604;
+const PM1_EVT_LEN: u8 = 4;
+const PM1_CNT_LEN: u8 = 2;
 
-pub const RSDP_START: usize = 0xE0000;
+// PM1 control bits; SCI_EN reads as set so the guest sees ACPI mode already on
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+/// SLP_TYPa the DSDT's `_S5_` package tells the guest to write for soft-off
+const SLP_TYP_S5: u8 = 5;
+
+// The legacy FPU error line; the other ISA IRQs all belong to devices
+pub const SCI_IRQ: u16 = 13;
+
+// FADT flags: WBINVD works, power and sleep buttons are not fixed features
+const FADT_F_WBINVD: u32 = 1 << 0;
+const FADT_F_PWR_BUTTON: u32 = 1 << 4;
+const FADT_F_SLP_BUTTON: u32 = 1 << 5;
+
+const FACS_ALIGN: usize = 64;
+
+// AML opcodes used by the DSDT
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_STRING_PREFIX: u8 = 0x0D;
+const AML_SCOPE_OP: u8 = 0x10;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ROOT_CHAR: u8 = b'\\';
+const AML_DEVICE_OP: [u8; 2] = [0x5B, 0x82];
 
 #[repr(C, packed)]
 #[derive(Default, Clone, Copy)]
@@ -57,47 +90,114 @@ struct MadtLocalApic {
     flags: u32,
 }
 
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy)]
+struct GenericAddress {
+    space_id: u8,
+    bit_width: u8,
+    bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy)]
+struct HpetTable {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    min_tick: u16,
+    page_protection: u8,
+}
+
+/// ACPI 1.0 FADT: enough for the guest to find the DSDT and the PM1a
+/// registers it writes to power off.
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy)]
+struct Fadt {
+    header: SdtHeader,
+    firmware_ctrl: u32,
+    dsdt: u32,
+    int_model: u8,
+    reserved1: u8,
+    sci_int: u16,
+    smi_cmd: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_req: u8,
+    reserved2: u8,
+    pm1a_evt_blk: u32,
+    pm1b_evt_blk: u32,
+    pm1a_cnt_blk: u32,
+    pm1b_cnt_blk: u32,
+    pm2_cnt_blk: u32,
+    pm_tmr_blk: u32,
+    gpe0_blk: u32,
+    gpe1_blk: u32,
+    pm1_evt_len: u8,
+    pm1_cnt_len: u8,
+    pm2_cnt_len: u8,
+    pm_tmr_len: u8,
+    gpe0_blk_len: u8,
+    gpe1_blk_len: u8,
+    gpe1_base: u8,
+    reserved3: u8,
+    p_lvl2_lat: u16,
+    p_lvl3_lat: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alrm: u8,
+    mon_alrm: u8,
+    century: u8,
+    reserved4: [u8; 3],
+    flags: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Clone, Copy)]
+struct Facs {
+    signature: [u8; 4],
+    length: u32,
+    hardware_signature: u32,
+    firmware_waking_vector: u32,
+    global_lock: u32,
+    flags: u32,
+    reserved: [u32; 10],
+}
+
 fn calculate_checksum(data: &[u8]) -> u8 {
     0u8.wrapping_sub(data.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)))
 }
 
 
-pub fn setup_acpi(mem: &mut GuestMemory, vcpu_count: u8) -> Result<(), String> {
-    let rsdt_addr = RSDP_START + mem::size_of::<Rsdp>();
-    let madt_addr = rsdt_addr + mem::size_of::<SdtHeader>() + 4;
+/// Write the RSDP, RSDT, MADT, HPET, FADT, FACS and DSDT. CPUs from
+/// `online_count` on are listed with the MADT enabled bit clear: present but
+/// offline. The tables are laid out back to back from the RSDP; returns
+/// their total length so the region can be read back.
+pub fn setup_acpi(mem: &mut GuestMemory, vcpu_count: u8, online_count: u8) -> Result<usize, String> {
+    let rsdp_addr = mem.base() + RSDP_START;
+    let rsdt_addr = rsdp_addr + mem::size_of::<Rsdp>();
+    let madt_addr = rsdt_addr + mem::size_of::<SdtHeader>() + 12;
 
-    
-    let madt_len = mem::size_of::<Madt>() + (mem::size_of::<MadtLocalApic>() * vcpu_count as usize);
-    let mut madt_data = vec![0u8; madt_len];
+    let madt_data = madt_table(vcpu_count, online_count);
+    let hpet_addr = madt_addr + madt_data.len();
+    mem.write_slice(madt_addr, &madt_data)?;
 
-    unsafe {
-        let madt = &mut *(madt_data.as_mut_ptr() as *mut Madt);
-        madt.header.signature = *b"APIC";
-        madt.header.length = madt_len as u32;
-        madt.header.revision = 1;
-        madt.header.oem_id = *b"AXVM  ";
-        madt.header.oem_table_id = *b"AXVMCPU ";
-        madt.header.oem_revision = 1;
-        madt.header.creator_id = 0x4D5641; 
-        madt.header.creator_revision = 1;
-        madt.local_apic_addr = 0xFEE00000;
-        madt.flags = 1; 
+    let hpet_data = hpet_table();
+    mem.write_slice(hpet_addr, &hpet_data)?;
 
-        let entries_ptr = madt_data.as_mut_ptr().add(mem::size_of::<Madt>());
-        for i in 0..vcpu_count {
-            let entry = &mut *(entries_ptr.add(i as usize * mem::size_of::<MadtLocalApic>()) as *mut MadtLocalApic);
-            entry.type_ = 0; 
-            entry.length = 8;
-            entry.acpi_processor_id = i;
-            entry.apic_id = i;
-            entry.flags = 1; 
-        }
-        madt.header.checksum = calculate_checksum(&madt_data);
-    }
-    mem.write_slice(madt_addr, &madt_data)?;
+    let fadt_addr = hpet_addr + hpet_data.len();
+    let facs_addr = (fadt_addr + mem::size_of::<Fadt>()).next_multiple_of(FACS_ALIGN);
+    let dsdt_addr = facs_addr + mem::size_of::<Facs>();
+    let dsdt_data = dsdt_table(vcpu_count);
+    mem.write_slice(dsdt_addr, &dsdt_data)?;
+    mem.write_slice(facs_addr, &facs_table())?;
+    mem.write_slice(fadt_addr, &fadt_table(facs_addr as u32, dsdt_addr as u32))?;
 
-    
-    let rsdt_len = mem::size_of::<SdtHeader>() + 4;
+    let rsdt_len = mem::size_of::<SdtHeader>() + 12;
     let mut rsdt_data = vec![0u8; rsdt_len];
     unsafe {
         let rsdt = &mut *(rsdt_data.as_mut_ptr() as *mut SdtHeader);
@@ -112,17 +212,21 @@ pub fn setup_acpi(mem: &mut GuestMemory, vcpu_count: u8) -> Result<(), String> {
         
         let ptr_loc = rsdt_data.as_mut_ptr().add(mem::size_of::<SdtHeader>()) as *mut u32;
         *ptr_loc = madt_addr as u32;
+        *ptr_loc.add(1) = hpet_addr as u32;
+        *ptr_loc.add(2) = fadt_addr as u32;
         rsdt.checksum = calculate_checksum(&rsdt_data);
     }
     mem.write_slice(rsdt_addr, &rsdt_data)?;
 
     
-    let mut rsdp = Rsdp::default();
-    rsdp.signature = *b"RSD PTR ";
-    rsdp.rsdt_addr = rsdt_addr as u32;
-    rsdp.length = mem::size_of::<Rsdp>() as u32;
-    rsdp.revision = 0;
-    rsdp.oem_id = *b"AXVM  ";
+    let rsdp = Rsdp {
+        signature: *b"RSD PTR ",
+        rsdt_addr: rsdt_addr as u32,
+        length: mem::size_of::<Rsdp>() as u32,
+        revision: 0,
+        oem_id: *b"AXVM  ",
+        ..Default::default()
+    };
 
     unsafe {
         let rsdp_slice = slice::from_raw_parts(
@@ -135,9 +239,345 @@ pub fn setup_acpi(mem: &mut GuestMemory, vcpu_count: u8) -> Result<(), String> {
         let mut rsdp_vec = rsdp_slice.to_vec();
         rsdp_vec[8] = checksum; 
         
-        mem.write_slice(RSDP_START, &rsdp_vec)?;
+        mem.write_slice(rsdp_addr, &rsdp_vec)?;
     }
 
-    println!(">>> [ACPI] SMP Tables generated for {} CPUs at {:#x}", vcpu_count, RSDP_START);
-    Ok(())
+    status!("ACPI", "SMP Tables generated for {} CPUs ({} online) at {:#x}", vcpu_count, online_count, rsdp_addr);
+    status!("ACPI", "HPET table at {:#x} -> MMIO {:#x}", hpet_addr, HPET_BASE);
+    status!("ACPI", "FADT at {:#x}, DSDT at {:#x} ({} bytes AML), PM1a control at port {:#x}",
+        fadt_addr, dsdt_addr, dsdt_data.len(), PM1A_CNT_BLK);
+    Ok(dsdt_addr + dsdt_data.len() - rsdp_addr)
+}
+
+fn sdt_header(signature: [u8; 4], length: usize, revision: u8, oem_table_id: [u8; 8]) -> SdtHeader {
+    SdtHeader {
+        signature,
+        length: length as u32,
+        revision,
+        oem_id: *b"AXVM  ",
+        oem_table_id,
+        oem_revision: 1,
+        creator_id: 0x4D5641,
+        creator_revision: 1,
+        ..Default::default()
+    }
+}
+
+fn table_bytes<T: Copy>(table: &T) -> Vec<u8> {
+    unsafe { slice::from_raw_parts(table as *const T as *const u8, mem::size_of::<T>()).to_vec() }
+}
+
+fn fadt_table(facs_addr: u32, dsdt_addr: u32) -> Vec<u8> {
+    let fadt = Fadt {
+        header: sdt_header(*b"FACP", mem::size_of::<Fadt>(), 1, *b"AXVMFACP"),
+        firmware_ctrl: facs_addr,
+        dsdt: dsdt_addr,
+        sci_int: SCI_IRQ,
+        pm1a_evt_blk: PM1A_EVT_BLK as u32,
+        pm1a_cnt_blk: PM1A_CNT_BLK as u32,
+        pm1_evt_len: PM1_EVT_LEN,
+        pm1_cnt_len: PM1_CNT_LEN,
+        // Above 100 and 1000: C2 and C3 not supported
+        p_lvl2_lat: 101,
+        p_lvl3_lat: 1001,
+        century: crate::rtc::REG_CENTURY,
+        flags: FADT_F_WBINVD | FADT_F_PWR_BUTTON | FADT_F_SLP_BUTTON,
+        ..Default::default()
+    };
+    let mut data = table_bytes(&fadt);
+    data[9] = calculate_checksum(&data);
+    data
+}
+
+fn facs_table() -> Vec<u8> {
+    table_bytes(&Facs {
+        signature: *b"FACS",
+        length: mem::size_of::<Facs>() as u32,
+        ..Default::default()
+    })
+}
+
+/// PkgLength: the encoded length counts its own bytes too
+fn aml_pkg_length(body_len: usize) -> Vec<u8> {
+    if body_len + 1 < 0x40 {
+        return vec![(body_len + 1) as u8];
+    }
+    let (extra, total) = if body_len + 2 < 0x1000 { (1, body_len + 2) } else { (2, body_len + 3) };
+    let mut bytes = vec![((extra as u8) << 6) | (total & 0xF) as u8];
+    for i in 0..extra {
+        bytes.push((total >> (4 + 8 * i)) as u8);
+    }
+    bytes
+}
+
+fn aml_block(op: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut out = op.to_vec();
+    out.extend(aml_pkg_length(body.len()));
+    out.extend_from_slice(body);
+    out
+}
+
+fn aml_name(name: &[u8; 4], value: &[u8]) -> Vec<u8> {
+    let mut out = vec![AML_NAME_OP];
+    out.extend_from_slice(name);
+    out.extend_from_slice(value);
+    out
+}
+
+fn aml_byte(value: u8) -> Vec<u8> {
+    match value {
+        0 => vec![AML_ZERO_OP],
+        1 => vec![AML_ONE_OP],
+        v => vec![AML_BYTE_PREFIX, v],
+    }
+}
+
+fn aml_string(s: &str) -> Vec<u8> {
+    let mut out = vec![AML_STRING_PREFIX];
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+    out
+}
+
+fn aml_device(name: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut named = name.to_vec();
+    named.extend_from_slice(body);
+    aml_block(&AML_DEVICE_OP, &named)
+}
+
+/// AML for `\_S5_` (SLP_TYPa = SLP_TYPb = 5) and, under `\_SB_`, a
+/// processor container holding one ACPI0007 device per CPU, with `_UID`
+/// matching the MADT processor ID.
+fn dsdt_aml(vcpu_count: u8) -> Vec<u8> {
+    let mut cpus = aml_name(b"_HID", &aml_string("ACPI0010"));
+    for i in 0..vcpu_count {
+        let name = format!("C{:03X}", i);
+        let mut body = aml_name(b"_HID", &aml_string("ACPI0007"));
+        body.extend(aml_name(b"_UID", &aml_byte(i)));
+        cpus.extend(aml_device(name.as_bytes().try_into().unwrap(), &body));
+    }
+    let mut sb = vec![AML_ROOT_CHAR];
+    sb.extend_from_slice(b"_SB_");
+    sb.extend(aml_device(b"CPUS", &cpus));
+
+    let mut s5 = vec![4];
+    for value in [SLP_TYP_S5, SLP_TYP_S5, 0, 0] {
+        s5.extend(aml_byte(value));
+    }
+    // The DSDT's names start out in the root scope
+    let mut aml = aml_name(b"_S5_", &aml_block(&[AML_PACKAGE_OP], &s5));
+    aml.extend(aml_block(&[AML_SCOPE_OP], &sb));
+    aml
+}
+
+fn dsdt_table(vcpu_count: u8) -> Vec<u8> {
+    let aml = dsdt_aml(vcpu_count);
+    let header = sdt_header(*b"DSDT", mem::size_of::<SdtHeader>() + aml.len(), 2, *b"AXVMDSDT");
+    let mut data = table_bytes(&header);
+    data.extend(aml);
+    data[9] = calculate_checksum(&data);
+    data
+}
+
+/// Whether `port` is one of the PM1a registers.
+pub fn pm_handles(port: u16) -> bool {
+    (PM1A_EVT_BLK..PM1A_EVT_BLK + PM1_EVT_LEN as u16).contains(&port)
+        || (PM1A_CNT_BLK..PM1A_CNT_BLK + PM1_CNT_LEN as u16).contains(&port)
+}
+
+/// PM1 status and enable read as zero, control with only SCI_EN set.
+pub fn pm_read(port: u16, data: &mut [u8]) {
+    data.fill(0);
+    if port == PM1A_CNT_BLK {
+        if let Some(low) = data.first_mut() {
+            *low = SCI_EN as u8;
+        }
+    }
+}
+
+/// Handle a PM1a write; `true` if it asks for S5 (soft off).
+pub fn pm_write(port: u16, data: &[u8]) -> bool {
+    if port != PM1A_CNT_BLK || data.len() < 2 {
+        return false;
+    }
+    let value = u16::from_le_bytes([data[0], data[1]]);
+    value & SLP_EN != 0 && (value >> SLP_TYP_SHIFT) & 0x7 == SLP_TYP_S5 as u16
+}
+
+fn madt_table(vcpu_count: u8, online_count: u8) -> Vec<u8> {
+    let madt_len = mem::size_of::<Madt>() + (mem::size_of::<MadtLocalApic>() * vcpu_count as usize);
+    let mut madt_data = vec![0u8; madt_len];
+
+    unsafe {
+        let madt = &mut *(madt_data.as_mut_ptr() as *mut Madt);
+        madt.header.signature = *b"APIC";
+        madt.header.length = madt_len as u32;
+        madt.header.revision = 1;
+        madt.header.oem_id = *b"AXVM  ";
+        madt.header.oem_table_id = *b"AXVMCPU ";
+        madt.header.oem_revision = 1;
+        madt.header.creator_id = 0x4D5641; 
+        madt.header.creator_revision = 1;
+        madt.local_apic_addr = LAPIC_BASE as u32;
+        madt.flags = 1; 
+
+        let entries_ptr = madt_data.as_mut_ptr().add(mem::size_of::<Madt>());
+        for i in 0..vcpu_count {
+            let entry = &mut *(entries_ptr.add(i as usize * mem::size_of::<MadtLocalApic>()) as *mut MadtLocalApic);
+            entry.type_ = 0; 
+            entry.length = 8;
+            entry.acpi_processor_id = i;
+            entry.apic_id = i;
+            entry.flags = (i < online_count) as u32;
+        }
+        madt.header.checksum = calculate_checksum(&madt_data);
+    }
+    madt_data
+}
+
+fn hpet_table() -> Vec<u8> {
+    let mut table = HpetTable {
+        header: SdtHeader {
+            signature: *b"HPET",
+            length: mem::size_of::<HpetTable>() as u32,
+            revision: 1,
+            oem_id: *b"AXVM  ",
+            oem_table_id: *b"AXVMHPET",
+            oem_revision: 1,
+            creator_id: 0x4D5641,
+            creator_revision: 1,
+            ..Default::default()
+        },
+        // Vendor 0x8086, 64-bit counter, N-1 comparators, revision 1
+        event_timer_block_id: (0x8086 << 16) | (1 << 13) | (((HPET_NUM_TIMERS - 1) as u32) << 8) | 1,
+        base_address: GenericAddress {
+            space_id: 0,
+            bit_width: 64,
+            address: HPET_BASE,
+            ..Default::default()
+        },
+        hpet_number: 0,
+        min_tick: 0x80,
+        page_protection: 0,
+    };
+
+    let mut data = unsafe {
+        slice::from_raw_parts(&table as *const _ as *const u8, mem::size_of::<HpetTable>()).to_vec()
+    };
+    table.header.checksum = calculate_checksum(&data);
+    data[9] = table.header.checksum;
+    data
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hpet_table() {
+        let table = hpet_table();
+        assert_eq!(table.len(), 56);
+        assert_eq!(&table[0..4], b"HPET");
+        assert_eq!(u32::from_le_bytes(table[4..8].try_into().unwrap()), 56);
+        assert_eq!(u64::from_le_bytes(table[44..52].try_into().unwrap()), HPET_BASE);
+        assert_eq!(table.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)), 0);
+    }
+
+    #[test]
+    fn test_madt_offline_cpus() {
+        let table = madt_table(4, 2);
+        let entries = &table[mem::size_of::<Madt>()..];
+        let flags: Vec<u32> = entries.chunks_exact(8)
+            .map(|e| u32::from_le_bytes(e[4..8].try_into().unwrap()))
+            .collect();
+        assert_eq!(flags, [1, 1, 0, 0]);
+        assert_eq!(table.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)), 0);
+    }
+
+    #[test]
+    fn test_setup_acpi_region() {
+        let mut mem = GuestMemory::new(0x100000).unwrap();
+        let len = setup_acpi(&mut mem, 2, 2).unwrap();
+        let region = mem.read_slice(RSDP_START, len).unwrap();
+
+        // RSDP, RSDT, MADT and HPET, with the HPET table ending the region
+        let rsdt_len = mem::size_of::<SdtHeader>() + 12;
+        let rsdt = &region[mem::size_of::<Rsdp>()..][..rsdt_len];
+        assert_eq!(&region[..8], b"RSD PTR ");
+        assert_eq!(&rsdt[..4], b"RSDT");
+        assert_eq!(rsdt.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)), 0);
+
+        // Every RSDT entry points at a checksummed table inside the region
+        let table_at = |addr: u32| -> &[u8] {
+            let start = addr as usize - RSDP_START;
+            let len = u32::from_le_bytes(region[start + 4..start + 8].try_into().unwrap()) as usize;
+            &region[start..start + len]
+        };
+        let entries: Vec<u32> = rsdt[mem::size_of::<SdtHeader>()..].chunks_exact(4)
+            .map(|e| u32::from_le_bytes(e.try_into().unwrap()))
+            .collect();
+        let signatures: Vec<&[u8]> = entries.iter().map(|&e| &table_at(e)[..4]).collect();
+        assert_eq!(signatures, [b"APIC", b"HPET", b"FACP"]);
+        for &entry in &entries {
+            assert_eq!(table_at(entry).iter().fold(0u8, |acc, &b| acc.wrapping_add(b)), 0);
+        }
+
+        // The FADT leads to the FACS and to the DSDT that ends the region
+        let fadt = table_at(entries[2]);
+        let facs = u32::from_le_bytes(fadt[36..40].try_into().unwrap());
+        let dsdt = u32::from_le_bytes(fadt[40..44].try_into().unwrap());
+        assert_eq!(facs as usize % FACS_ALIGN, 0);
+        assert_eq!(&region[facs as usize - RSDP_START..][..4], b"FACS");
+        let dsdt = table_at(dsdt);
+        assert_eq!(&dsdt[..4], b"DSDT");
+        assert_eq!(dsdt.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)), 0);
+        assert_eq!(dsdt.as_ptr_range().end, region.as_ptr_range().end);
+    }
+
+    #[test]
+    fn test_fadt_layout() {
+        assert_eq!(mem::size_of::<Fadt>(), 116);
+        assert_eq!(mem::size_of::<Facs>(), 64);
+        let fadt = fadt_table(0x1000, 0x2000);
+        assert_eq!(u32::from_le_bytes(fadt[40..44].try_into().unwrap()), 0x2000);
+        assert_eq!(u16::from_le_bytes(fadt[46..48].try_into().unwrap()), SCI_IRQ);
+        assert_eq!(u32::from_le_bytes(fadt[64..68].try_into().unwrap()), PM1A_CNT_BLK as u32);
+        assert_eq!(fadt[89], PM1_CNT_LEN);
+    }
+
+    #[test]
+    fn test_dsdt_s5_package() {
+        let aml = dsdt_aml(2);
+        // Name (_S5_, Package (4) { 5, 5, Zero, Zero })
+        assert_eq!(aml[..14], [0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 5, 0x0A, 5, 0, 0]);
+        // Scope (\_SB_) follows
+        assert_eq!(aml[14], AML_SCOPE_OP);
+        assert!(aml.windows(5).any(|w| w == b"\\_SB_"));
+        assert!(aml.windows(4).any(|w| w == b"C001"));
+        assert!(!aml.windows(4).any(|w| w == b"C002"));
+
+        assert_eq!(aml_pkg_length(0x3E), [0x3F]);
+        assert_eq!(aml_pkg_length(0x3F), [0x41, 0x04]);
+        assert_eq!(aml_pkg_length(0x1000), [0x83, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_pm1a_soft_off() {
+        let write = |value: u16| pm_write(PM1A_CNT_BLK, &value.to_le_bytes());
+        assert!(write(SLP_EN | (SLP_TYP_S5 as u16) << SLP_TYP_SHIFT));
+        // SLP_TYP is written on its own first; S1 is not power off
+        assert!(!write((SLP_TYP_S5 as u16) << SLP_TYP_SHIFT));
+        assert!(!write(SLP_EN | 1 << SLP_TYP_SHIFT));
+        assert!(!pm_write(PM1A_EVT_BLK, &(SLP_EN | 5 << SLP_TYP_SHIFT).to_le_bytes()));
+
+        let mut data = [0xFF; 2];
+        pm_read(PM1A_CNT_BLK, &mut data);
+        assert_eq!(u16::from_le_bytes(data), SCI_EN);
+        assert!(pm_handles(PM1A_EVT_BLK + 3) && pm_handles(PM1A_CNT_BLK + 1));
+        assert!(!pm_handles(PM1A_CNT_BLK + 2));
+    }
 }
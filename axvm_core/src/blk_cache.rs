@@ -0,0 +1,125 @@
+// src/blk_cache.rs
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+
+/// In-memory write-back cache of whole disk sectors.
+///
+/// Writes land here keyed by sector number and reach the backing file only
+/// on `flush`. Reads must be passed through `overlay` after hitting the file
+/// so the guest always sees its own most recent writes.
+pub struct WriteCache {
+    sector_size: usize,
+    limit_bytes: usize,
+    sectors: BTreeMap<u64, Vec<u8>>,
+}
+
+impl WriteCache {
+    pub fn new(sector_size: usize, limit_bytes: usize) -> Self {
+        Self { sector_size, limit_bytes, sectors: BTreeMap::new() }
+    }
+
+    /// Buffer `data` starting at `sector`. `data` must be a whole number of sectors.
+    pub fn insert(&mut self, sector: u64, data: &[u8]) {
+        debug_assert!(data.len().is_multiple_of(self.sector_size));
+        for (i, chunk) in data.chunks_exact(self.sector_size).enumerate() {
+            self.sectors.insert(sector + i as u64, chunk.to_vec());
+        }
+    }
+
+    /// Replace the parts of `buf` (read from disk at `sector`) that have newer cached data.
+    pub fn overlay(&self, sector: u64, buf: &mut [u8]) {
+        let count = buf.len().div_ceil(self.sector_size) as u64;
+        for (&s, data) in self.sectors.range(sector..sector + count) {
+            let start = (s - sector) as usize * self.sector_size;
+            let end = (start + self.sector_size).min(buf.len());
+            buf[start..end].copy_from_slice(&data[..end - start]);
+        }
+    }
+
+    /// Drop cached sectors in `sector..sector + count`, whose contents the
+    /// guest has thrown away or zeroed on disk.
+    pub fn discard(&mut self, sector: u64, count: u64) {
+        let stale: Vec<u64> = self.sectors.range(sector..sector.saturating_add(count)).map(|(&s, _)| s).collect();
+        for s in stale {
+            self.sectors.remove(&s);
+        }
+    }
+
+    pub fn len_bytes(&self) -> usize {
+        self.sectors.len() * self.sector_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sectors.is_empty()
+    }
+
+    pub fn over_limit(&self) -> bool {
+        self.len_bytes() > self.limit_bytes
+    }
+
+    /// Write every cached sector to `file`, coalescing adjacent sectors into
+    /// one write, then sync. The cache is only emptied if all writes succeed.
+    pub fn flush(&mut self, file: &mut File) -> Result<(), String> {
+        let mut iter = self.sectors.iter().peekable();
+        while let Some((&start, data)) = iter.next() {
+            let mut run = data.clone();
+            let mut next = start + 1;
+            while let Some((_, data)) = iter.next_if(|(&s, _)| s == next) {
+                run.extend_from_slice(data);
+                next += 1;
+            }
+
+            file.seek(SeekFrom::Start(start * self.sector_size as u64))
+                .map_err(|e| format!("seek failed: {}", e))?;
+            file.write_all(&run).map_err(|e| format!("write failed: {}", e))?;
+        }
+        file.sync_data().map_err(|e| format!("sync failed: {}", e))?;
+
+        tracing::debug!(sectors = self.sectors.len(), "Write-back cache flushed");
+        self.sectors.clear();
+        Ok(())
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_overlay_and_flush() {
+        let path = std::env::temp_dir().join(format!("axvm-test-cache-{}.img", std::process::id()));
+        std::fs::write(&path, vec![0u8; 8 * 4]).unwrap();
+        let mut file = std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+
+        let mut cache = WriteCache::new(4, 12);
+        cache.insert(1, &[1; 8]);
+        cache.insert(5, &[5; 4]);
+        // A later write to the same sector wins
+        cache.insert(2, &[2; 4]);
+        assert_eq!(cache.len_bytes(), 12);
+        assert!(!cache.over_limit());
+
+        // Read of sectors 0..3 ending mid-sector
+        let mut buf = [0u8; 10];
+        cache.overlay(0, &mut buf);
+        assert_eq!(buf, [0, 0, 0, 0, 1, 1, 1, 1, 2, 2]);
+
+        cache.insert(7, &[7; 4]);
+        assert!(cache.over_limit());
+        cache.flush(&mut file).unwrap();
+        assert!(cache.is_empty());
+
+        let mut disk = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut disk).unwrap();
+        let expected: Vec<u8> = [0, 1, 2, 0, 0, 5, 0, 7].iter().flat_map(|&b| [b; 4]).collect();
+        assert_eq!(disk, expected);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,117 @@
+// src/coalesced.rs
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{fence, Ordering};
+
+use kvm_bindings::{kvm_coalesced_mmio, kvm_coalesced_mmio_ring, KVM_COALESCED_MMIO_PAGE_OFFSET};
+use kvm_ioctls::VcpuFd;
+
+/// The VM's coalesced MMIO ring: writes to registered zones that KVM
+/// buffered instead of exiting.
+///
+/// There is one ring per VM, reachable through any vCPU fd. `VcpuFd` has
+/// its own accessor, but it needs the vCPU mutably, which the `VcpuExit`
+/// being handled still borrows; mapping the ring separately lets a vCPU
+/// drain it before handling that exit, and lets all vCPUs share one copy
+/// behind a lock.
+pub struct CoalescedRing {
+    ring: NonNull<kvm_coalesced_mmio_ring>,
+    page_size: usize,
+}
+
+// The mapping is only touched through `&mut self`
+unsafe impl Send for CoalescedRing {}
+
+impl CoalescedRing {
+    pub fn map(vcpu: &VcpuFd) -> Result<Self, String> {
+        let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            -1 => return Err(format!("sysconf(_SC_PAGESIZE) failed: {}", std::io::Error::last_os_error())),
+            size => size as usize,
+        };
+        let offset = KVM_COALESCED_MMIO_PAGE_OFFSET as libc::off_t * page_size as libc::off_t;
+        let addr = unsafe {
+            libc::mmap(ptr::null_mut(), page_size, libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED, vcpu.as_raw_fd(), offset)
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(format!("mmap of the coalesced MMIO ring failed: {}", std::io::Error::last_os_error()));
+        }
+        let ring = NonNull::new(addr.cast()).ok_or("mmap of the coalesced MMIO ring returned NULL")?;
+        Ok(Self { ring, page_size })
+    }
+
+    fn capacity(&self) -> u32 {
+        ((self.page_size - size_of::<kvm_coalesced_mmio_ring>()) / size_of::<kvm_coalesced_mmio>()) as u32
+    }
+
+    /// Take the oldest buffered write, if any.
+    pub fn pop(&mut self) -> Option<kvm_coalesced_mmio> {
+        let ring = self.ring.as_ptr();
+        unsafe {
+            let first = ptr::read_volatile(ptr::addr_of!((*ring).first));
+            let last = ptr::read_volatile(ptr::addr_of!((*ring).last));
+            if first == last {
+                return None;
+            }
+            // The kernel fills the entry before it advances `last`
+            fence(Ordering::Acquire);
+            let entry = ptr::read_volatile((*ring).coalesced_mmio.as_ptr().add(first as usize));
+            fence(Ordering::Release);
+            ptr::write_volatile(ptr::addr_of_mut!((*ring).first), (first + 1) % self.capacity());
+            Some(entry)
+        }
+    }
+}
+
+impl Drop for CoalescedRing {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ring.as_ptr().cast(), self.page_size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::GuestMemory;
+    use crate::vcpu;
+    use kvm_ioctls::{IoEventAddress, Kvm, VcpuExit};
+
+    #[test]
+    fn test_pop_buffered_writes_in_order() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mut mem = GuestMemory::new(0x10000).unwrap();
+        // Real mode at 0x1000: two dword stores to 0xF0000, beyond RAM, then hlt
+        mem.write_slice(0x1000, &[
+            0xB8, 0x00, 0xF0,                                     // mov ax, 0xF000
+            0x8E, 0xD8,                                           // mov ds, ax
+            0x66, 0xC7, 0x06, 0x00, 0x00, 0x78, 0x56, 0x34, 0x12, // mov dword [0], 0x12345678
+            0x66, 0xC7, 0x06, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov dword [0], 2
+            0xF4,                                                 // hlt
+        ]).unwrap();
+        let region = kvm_bindings::kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0,
+            memory_size: mem.size() as u64,
+            userspace_addr: mem.as_ptr() as u64,
+            flags: 0,
+        };
+        unsafe { vm.set_user_memory_region(region).unwrap() };
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        vcpu::setup_real_mode(&mut vcpu, 0, 0x1000).unwrap();
+
+        let mut ring = CoalescedRing::map(&vcpu).unwrap();
+        assert!(ring.pop().is_none());
+        vm.register_coalesced_mmio(IoEventAddress::Mmio(0xF0000), 4).unwrap();
+
+        // Neither store exits to userspace
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::Hlt));
+        let writes: Vec<_> = std::iter::from_fn(|| ring.pop())
+            .map(|e| (e.phys_addr, e.len, u32::from_le_bytes(e.data[..4].try_into().unwrap())))
+            .collect();
+        assert_eq!(writes, [(0xF0000, 4, 0x12345678), (0xF0000, 4, 2)]);
+    }
+}
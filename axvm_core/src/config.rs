@@ -1,30 +1,154 @@
-use clap::Parser;
-use std::path::PathBuf;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Once;
+use std::time::Duration;
+
+use crate::layout::RAM_BASE_ALIGN;
+use crate::linux::{HDRS_MAGIC, HDRS_MAGIC_OFFSET};
+use crate::sha256;
+use crate::virtio::QUEUE_NUM_MAX;
+
+/// Maximum number of virtio-blk devices (vda..vdg)
+pub const MAX_DISKS: usize = 7;
+
+/// Size of each virtio-mmio register window
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+pub const DEFAULT_VIRTIO_BLK_BASE: u64 = 0xFEB00000;
+pub const DEFAULT_VIRTIO_NET_BASE: u64 = 0xFEB10000;
+
+// Highest address reachable from real mode (without the A20 wraparound)
+const REAL_MODE_LIMIT: u64 = 0x100000;
+
+// IOAPIC (0xFEC00000), HPET (0xFED00000) and LAPIC (0xFEE00000) windows
+const PLATFORM_MMIO_START: u64 = 0xFEC00000;
+const PLATFORM_MMIO_END: u64 = 0xFF000000;
+
+// Guest RAM must end below the fixed vsock and extra-disk windows and the
+// platform range above them
+const FIXED_MMIO_START: u64 = crate::VIRTIO_VSOCK_MMIO_BASE;
+
+/// Base kernel command line; device tokens are generated from the MMIO bus.
+///
+/// The loader passes an RNG seed through setup_data, so on boot protocol
+/// 2.09+ kernels `nokaslr` can be dropped to test with KASLR enabled.
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0 earlyprintk=serial reboot=k panic=1 nokaslr noapic root=/dev/vda rw";
 
 #[derive(Parser, Debug, Clone)]
 #[command(name = "AxVM")]
 #[command(version = "0.7.0")]
 #[command(about = "Lightweight KVM-based hypervisor", long_about = None)]
 pub struct VmConfig {
+    /// Load settings from a TOML file (command-line flags take precedence)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    
     /// Memory size in MB (must be multiple of 2MB for HugePages)
     #[arg(short, long, default_value = "1024")]
     pub memory: usize,
     
+    /// Guest physical address guest RAM starts at, leaving the range below unmapped (2 MB aligned)
+    #[arg(long, default_value = "0", value_parser = parse_addr)]
+    pub ram_base: u64,
+    
+    /// Back guest RAM with this file (MAP_SHARED), created and sized as needed; contents persist
+    #[arg(long)]
+    pub mem_file: Option<PathBuf>,
+    
+    /// Lock guest RAM in host memory (mlock) so it is never swapped out; needs a large enough RLIMIT_MEMLOCK
+    #[arg(long)]
+    pub mlock: bool,
+    
     /// Number of vCPUs
     #[arg(short = 'c', long, default_value = "1")]
     pub vcpus: u8,
     
+    /// vCPUs marked enabled in the MADT; the rest are present but offline (default: all)
+    #[arg(long)]
+    pub online_cpus: Option<u8>,
+    
+    /// Only this vCPU polls virtio-net RX/TX (default: whichever vCPU gets there first)
+    #[arg(long)]
+    pub device_cpu: Option<u8>,
+    
     /// Path to kernel image
     #[arg(short, long, default_value = "bzImage")]
     pub kernel: PathBuf,
     
-    /// Path to disk image (optional)
-    #[arg(short, long)]
-    pub disk: Option<PathBuf>,
+    /// Boot a flat binary instead of a bzImage (no boot_params or ACPI)
+    #[arg(long)]
+    pub raw: Option<PathBuf>,
+    
+    /// Guest physical address the --raw payload is copied to (hex or decimal)
+    #[arg(long, default_value = "0x100000", value_parser = parse_addr)]
+    pub load_addr: u64,
+    
+    /// Guest address execution of the --raw payload starts at; defaults to --load-addr
+    #[arg(long, value_parser = parse_addr)]
+    pub entry: Option<u64>,
     
-    /// Kernel command line arguments
-    #[arg(long, default_value = "console=ttyS0 earlyprintk=serial reboot=k panic=1 nokaslr noapic virtio_mmio.device=4K@0xFEB00000:5 virtio_mmio.device=4K@0xFEB10000:6 root=/dev/vda rw")]
-    pub cmdline: String,
+    /// CPU mode the --raw payload starts in (real: 16-bit, e.g. boot sectors at 0x7C00)
+    #[arg(long, value_enum, default_value = "long")]
+    pub raw_mode: RawMode,
+    
+    /// Refuse to boot unless the kernel (or --raw payload) has this digest: sha256:<64 hex digits>
+    #[arg(long)]
+    pub verify_checksum: Option<String>,
+    
+    /// Path to disk image(s); the first is /dev/vda, then /dev/vdb, ...
+    #[arg(short, long, num_args = 1..)]
+    pub disk: Vec<PathBuf>,
+    
+    /// Replace the whole kernel command line, including the virtio_mmio.device= tokens
+    #[arg(long)]
+    pub cmdline: Option<String>,
+    
+    /// Read the --cmdline override from a file (trailing newline trimmed)
+    #[arg(long, conflicts_with = "cmdline")]
+    pub cmdline_file: Option<PathBuf>,
+    
+    /// Extra kernel command line tokens, added after the default line and device tokens
+    #[arg(long)]
+    pub append: Vec<String>,
+    
+    /// Pack this directory into a cpio archive at startup and boot it as the initramfs
+    #[arg(long)]
+    pub initrd_dir: Option<PathBuf>,
+    
+    /// Flattened device tree blob: passed to Linux as setup_data, or its address in RDI for --raw payloads
+    #[arg(long)]
+    pub dtb: Option<PathBuf>,
+    
+    /// Extra E820 memory map entry for the kernel: ADDR,SIZE,TYPE (TYPE: reserved, acpi, nvs, unusable, pmem or a number)
+    #[arg(long)]
+    pub e820: Vec<E820Region>,
+    
+    /// SMBIOS system product name reported to the guest (dmidecode -s system-product-name)
+    #[arg(long, default_value = "AxVM Virtual Machine")]
+    pub smbios_product: String,
+    
+    /// SMBIOS system serial number reported to the guest
+    #[arg(long)]
+    pub smbios_serial: Option<String>,
+    
+    /// MAC address of the virtio-net device (e.g. 52:54:00:12:34:56)
+    #[arg(long, default_value = "52:54:00:12:34:56")]
+    pub mac: String,
+    
+    /// Network backend of the virtio-net device
+    #[arg(long, value_enum, default_value = "tap")]
+    pub net: NetMode,
+    
+    /// With --net user, forward a host TCP port to the guest: tcp:[HOSTADDR]:PORT-[GUESTADDR]:PORT
+    #[arg(long)]
+    pub hostfwd: Vec<HostFwd>,
     
     /// Increase verbosity (-v: info, -vv: debug, -vvv: trace)
     #[arg(short, long, action = clap::ArgAction::Count)]
@@ -33,13 +157,511 @@ pub struct VmConfig {
     /// Disable metrics collection
     #[arg(long)]
     pub no_metrics: bool,
+    
+    /// Enable a virtio-vsock device with this guest CID (>= 3)
+    #[arg(long)]
+    pub vsock_cid: Option<u64>,
+    
+    /// Emulate a VGA text console at 0xB8000 and render it on the host terminal
+    #[arg(long)]
+    pub vga: bool,
+    
+    /// Translate host stdin into PS/2 keyboard scancodes (i8042)
+    #[arg(long)]
+    pub stdin_keyboard: bool,
+    
+    /// Single-step the guest to count executed instructions (very slow)
+    #[arg(long)]
+    pub count_instructions: bool,
+    
+    /// Log every vm-exit with its port or address, size and guest RIP (rate-limited per vCPU)
+    #[arg(long)]
+    pub trace_exits: bool,
+    
+    /// Capture every virtio-net frame to this pcap file
+    #[arg(long)]
+    pub pcap: Option<PathBuf>,
+    
+    /// Write the generated ACPI tables (RSDP onwards) to this file for iasl or acpidump
+    #[arg(long, value_name = "PATH")]
+    pub dump_acpi: Option<PathBuf>,
+    
+    /// COM1 output: stdout, file:PATH or pty
+    #[arg(long, default_value = "stdout")]
+    pub serial: SerialTarget,
+    
+    /// Keep the last BYTES of COM1 output in memory and print them if the VM dies
+    #[arg(long, value_name = "BYTES")]
+    pub serial_capture: Option<usize>,
+    
+    /// Write COM2 (ttyS1, port 0x2F8) output to this file instead of stdout
+    #[arg(long)]
+    pub com2_log: Option<PathBuf>,
+    
+    /// Enable KVM dirty page logging and print the number of dirtied pages every second
+    #[arg(long)]
+    pub dirty_stats: bool,
+    
+    /// Load the kernel and build the guest layout, then exit without touching /dev/kvm
+    #[arg(long)]
+    pub dry_run: bool,
+    
+    /// Print the capabilities the host KVM reports and exit without creating a VM
+    #[arg(long)]
+    pub print_caps: bool,
+    
+    /// Seconds to wait for vCPU threads after Ctrl+C before kicking them out of KVM_RUN
+    #[arg(long, default_value = "5")]
+    pub shutdown_timeout: u64,
+    
+    /// Guest physical base of the root virtio-blk MMIO window (hex or decimal)
+    #[arg(long, default_value = "0xFEB00000", value_parser = parse_addr)]
+    pub virtio_blk_base: u64,
+    
+    /// Guest physical base of the virtio-net MMIO window (hex or decimal)
+    #[arg(long, default_value = "0xFEB10000", value_parser = parse_addr)]
+    pub virtio_net_base: u64,
+    
+    /// Buffer virtio QUEUE_NOTIFY kicks in KVM's coalesced MMIO ring; they are handled at the next vCPU exit
+    #[arg(long)]
+    pub coalesced_mmio: bool,
+    
+    /// Largest virtqueue the virtio devices advertise (QUEUE_NUM_MAX); a power of two up to 256
+    #[arg(long, default_value = "256")]
+    pub virtio_queue_size: u16,
+    
+    /// Host caching mode for virtio-blk writes
+    #[arg(long, value_enum, default_value = "none")]
+    pub disk_cache: DiskCache,
+    
+    /// Size in MB at which the write-back cache is flushed to disk
+    #[arg(long, default_value = "64")]
+    pub disk_cache_mb: usize,
+    
+    /// Open disk images with O_DIRECT to bypass the host page cache
+    #[arg(long)]
+    pub disk_direct: bool,
+    
+    /// Dump registers, code around RIP and the faulting page walk when a vCPU crashes
+    #[arg(long)]
+    pub crash_dump: bool,
+    
+    /// Serve text commands (regs, mem, setreg, pause, resume, metrics) on this Unix socket
+    #[arg(long, value_name = "PATH")]
+    pub control_socket: Option<PathBuf>,
+    
+    /// Stop with an error if the guest produces no serial output and no VM exits for this many seconds
+    #[arg(long)]
+    pub boot_timeout: Option<u64>,
+    
+    /// Emulate an IB700 hardware watchdog on ports 0x441/0x443 (Linux: ib700wdt)
+    #[arg(long)]
+    pub watchdog: bool,
+    
+    /// What happens when the guest stops kicking --watchdog
+    #[arg(long, value_enum, default_value = "reset")]
+    pub watchdog_action: WatchdogAction,
+    
+    /// Interrupt any single KVM_RUN that lasts longer than this many milliseconds and count it as a timeout
+    #[arg(long, value_name = "MS")]
+    pub vcpu_timeout: Option<u64>,
+    
+    /// Shift the guest's wall clock (CMOS RTC) by this many seconds, negative for the past; hides kvmclock
+    #[arg(long, allow_negative_numbers = true, value_name = "SECONDS")]
+    pub clock_offset: Option<i64>,
+    
+    /// CPU vendor reported in CPUID leaf 0 (default: the host's)
+    #[arg(long, value_enum)]
+    pub cpu_vendor: Option<CpuVendor>,
+    
+    /// Hypervisor signature in CPUID leaf 0x40000000, exactly 12 bytes (default: AxVMAxVMAxVM)
+    #[arg(long, value_name = "SIGNATURE")]
+    pub hv_vendor: Option<String>,
+    
+    /// Once the VM runs, allow only the syscalls it needs from then on (seccomp-bpf); any other kills AxVM
+    #[arg(long)]
+    pub seccomp: bool,
+    
+    /// Suppress the startup banner and informational status lines
+    #[arg(short, long)]
+    pub quiet: bool,
+    
+    /// Format of log and status output on stderr/stdout
+    #[arg(long, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+}
+
+/// CPU mode a `--raw` payload is entered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RawMode {
+    /// 64-bit mode with the low 1 GiB identity-mapped
+    Long,
+    /// 16-bit real mode, as a BIOS would start a boot sector
+    Real,
+}
+
+/// How virtio-blk writes reach the backing file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskCache {
+    /// Write each request straight to the file
+    None,
+    /// Buffer writes in memory until the guest flushes or the cache fills up
+    Writeback,
+    /// Write each request to the file and sync it before completing
+    Writethrough,
+}
+
+/// Backend of the virtio-net device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NetMode {
+    /// Host TAP interface `axvm-tap0` (needs CAP_NET_ADMIN)
+    Tap,
+    /// Built-in user-mode stack: DHCP on 10.0.2.0/24, TCP/UDP through host sockets
+    User,
+    /// Every transmitted frame is received back by the guest; needs no TAP or root
+    Loopback,
+}
+
+/// `--watchdog-action`: what an expired guest watchdog does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchdogAction {
+    /// Reset the guest, taking the same path as an i8042 reset
+    Reset,
+    /// Power the VM off
+    Poweroff,
+    /// Only log and count the expiry
+    None,
+}
+
+/// CPU vendor a guest sees, for reproducing vendor-specific code paths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuVendor {
+    Intel,
+    Amd,
+}
+
+impl CpuVendor {
+    /// Vendor string as CPUID spells it
+    pub fn id(self) -> &'static [u8; 12] {
+        match self {
+            Self::Intel => b"GenuineIntel",
+            Self::Amd => b"AuthenticAMD",
+        }
+    }
+}
+
+/// Output format of the tracing subscriber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Host sink for a serial port, parsed from `stdout`, `file:PATH` or `pty`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum SerialTarget {
+    Stdout,
+    File(PathBuf),
+    Pty,
+}
+
+impl FromStr for SerialTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(Self::Stdout),
+            "pty" => Ok(Self::Pty),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(Self::File(PathBuf::from(path))),
+                _ => Err(format!("invalid serial target '{}' (expected stdout, file:PATH or pty)", s)),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for SerialTarget {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for SerialTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stdout => write!(f, "stdout"),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+            Self::Pty => write!(f, "pty"),
+        }
+    }
+}
+
+/// A `--hostfwd` rule, parsed from `tcp:[HOSTADDR]:HOSTPORT-[GUESTADDR]:GUESTPORT`.
+/// HOSTADDR defaults to 127.0.0.1 and GUESTADDR to the address the guest
+/// gets from DHCP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HostFwd {
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_addr: Ipv4Addr,
+    pub guest_port: u16,
+}
+
+impl FromStr for HostFwd {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid hostfwd rule '{}' (expected tcp:[HOSTADDR]:PORT-[GUESTADDR]:PORT)", s);
+        let endpoint = |part: &str, default: Ipv4Addr| -> Option<(Ipv4Addr, u16)> {
+            let (addr, port) = part.split_once(':')?;
+            let addr = if addr.is_empty() { default } else { addr.parse().ok()? };
+            Some((addr, port.parse().ok().filter(|&p| p != 0)?))
+        };
+        let rule = s.strip_prefix("tcp:").ok_or_else(invalid)?;
+        let (host, guest) = rule.split_once('-').ok_or_else(invalid)?;
+        let (host_addr, host_port) = endpoint(host, Ipv4Addr::LOCALHOST).ok_or_else(invalid)?;
+        let (guest_addr, guest_port) = endpoint(guest, crate::usernet::GUEST_ADDR).ok_or_else(invalid)?;
+        Ok(Self { host_addr, host_port, guest_addr, guest_port })
+    }
+}
+
+impl TryFrom<String> for HostFwd {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for HostFwd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tcp:{}:{}-{}:{}", self.host_addr, self.host_port, self.guest_addr, self.guest_port)
+    }
+}
+
+/// An `--e820` entry, parsed from `ADDR,SIZE,TYPE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct E820Region {
+    pub addr: u64,
+    pub size: u64,
+    pub type_: u32,
+}
+
+const E820_TYPE_NAMES: [(&str, u32); 6] = [
+    ("ram", 1), ("reserved", 2), ("acpi", 3), ("nvs", 4), ("unusable", 5), ("pmem", 7),
+];
+
+impl FromStr for E820Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').collect();
+        let [addr, size, type_] = parts[..] else {
+            return Err(format!("invalid E820 entry '{}' (expected ADDR,SIZE,TYPE)", s));
+        };
+        let addr = parse_addr(addr)?;
+        let size = parse_addr(size).map_err(|_| format!("invalid E820 size '{}'", size))?;
+        if size == 0 || addr.checked_add(size).is_none() {
+            return Err(format!("invalid E820 entry '{}': empty or past the end of the address space", s));
+        }
+        let type_ = match E820_TYPE_NAMES.iter().find(|(name, _)| *name == type_) {
+            Some(&(_, value)) => value,
+            None => type_.parse().map_err(|_| format!("invalid E820 type '{}'", type_))?,
+        };
+        Ok(Self { addr, size, type_ })
+    }
+}
+
+impl TryFrom<String> for E820Region {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for E820Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x},{:#x},", self.addr, self.size)?;
+        match E820_TYPE_NAMES.iter().find(|&&(_, value)| value == self.type_) {
+            Some((name, _)) => write!(f, "{}", name),
+            None => write!(f, "{}", self.type_),
+        }
+    }
+}
+
+/// Settings accepted in a `--config` file; every field is optional.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    memory: Option<usize>,
+    ram_base: Option<u64>,
+    mem_file: Option<PathBuf>,
+    mlock: Option<bool>,
+    vcpus: Option<u8>,
+    online_cpus: Option<u8>,
+    device_cpu: Option<u8>,
+    kernel: Option<PathBuf>,
+    raw: Option<PathBuf>,
+    verify_checksum: Option<String>,
+    load_addr: Option<u64>,
+    entry: Option<u64>,
+    raw_mode: Option<RawMode>,
+    disk: Option<Vec<PathBuf>>,
+    cmdline: Option<String>,
+    cmdline_file: Option<PathBuf>,
+    append: Option<Vec<String>>,
+    initrd_dir: Option<PathBuf>,
+    dtb: Option<PathBuf>,
+    e820: Option<Vec<E820Region>>,
+    smbios_product: Option<String>,
+    smbios_serial: Option<String>,
+    mac: Option<String>,
+    net: Option<NetMode>,
+    hostfwd: Option<Vec<HostFwd>>,
+    verbose: Option<u8>,
+    no_metrics: Option<bool>,
+    vsock_cid: Option<u64>,
+    vga: Option<bool>,
+    stdin_keyboard: Option<bool>,
+    count_instructions: Option<bool>,
+    trace_exits: Option<bool>,
+    pcap: Option<PathBuf>,
+    dump_acpi: Option<PathBuf>,
+    serial: Option<SerialTarget>,
+    serial_capture: Option<usize>,
+    com2_log: Option<PathBuf>,
+    dirty_stats: Option<bool>,
+    dry_run: Option<bool>,
+    print_caps: Option<bool>,
+    shutdown_timeout: Option<u64>,
+    virtio_blk_base: Option<u64>,
+    virtio_net_base: Option<u64>,
+    coalesced_mmio: Option<bool>,
+    virtio_queue_size: Option<u16>,
+    disk_cache: Option<DiskCache>,
+    disk_cache_mb: Option<usize>,
+    disk_direct: Option<bool>,
+    crash_dump: Option<bool>,
+    control_socket: Option<PathBuf>,
+    boot_timeout: Option<u64>,
+    watchdog: Option<bool>,
+    watchdog_action: Option<WatchdogAction>,
+    vcpu_timeout: Option<u64>,
+    clock_offset: Option<i64>,
+    cpu_vendor: Option<CpuVendor>,
+    hv_vendor: Option<String>,
+    seccomp: Option<bool>,
+    quiet: Option<bool>,
+    log_format: Option<LogFormat>,
+}
+
+impl FileConfig {
+    /// Settings from `AXVM_MEMORY`, `AXVM_VCPUS`, `AXVM_KERNEL`, `AXVM_DISK`
+    /// (comma-separated) and `AXVM_CMDLINE`; empty variables count as unset.
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        fn parse<T: FromStr>(name: &str, value: Option<String>) -> Result<Option<T>, String>
+        where
+            T::Err: fmt::Display,
+        {
+            value.map(|v| v.parse().map_err(|e| format!("Invalid {}={}: {}", name, v, e))).transpose()
+        }
+        let var = |name: &str| var(name).filter(|v| !v.is_empty());
+        Ok(Self {
+            memory: parse("AXVM_MEMORY", var("AXVM_MEMORY"))?,
+            vcpus: parse("AXVM_VCPUS", var("AXVM_VCPUS"))?,
+            kernel: var("AXVM_KERNEL").map(PathBuf::from),
+            disk: var("AXVM_DISK").map(|v| v.split(',').map(PathBuf::from).collect()),
+            cmdline: var("AXVM_CMDLINE"),
+            ..Default::default()
+        })
+    }
+
+    fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        toml::from_str(&text)
+            .map_err(|e| format!("Failed to parse config file {}: {}", path.display(), e))
+    }
 }
 
 impl VmConfig {
+    /// Parse the command line, then fill in what it left unset from the
+    /// `AXVM_*` environment variables and the `--config` file, in that order
+    /// of precedence.
+    ///
+    /// The result is not validated; call `validate` afterwards.
+    pub fn from_env_and_args() -> Result<Self, String> {
+        Self::from_matches(&Self::command().get_matches(), |name| std::env::var(name).ok())
+    }
+    
+    fn from_matches(matches: &ArgMatches, env: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut config = Self::from_arg_matches(matches).map_err(|e| e.to_string())?;
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        if let Some(path) = config.config.clone() {
+            let mut file = FileConfig::load(&path)?;
+            // A --cmdline-file flag replaces the file's cmdline, as it does AXVM_CMDLINE
+            if config.cmdline_file.is_some() {
+                file.cmdline = None;
+            }
+            config.merge_file(file, from_cli);
+        }
+        let mut env = FileConfig::from_env(env)?;
+        if config.cmdline_file.is_some() {
+            env.cmdline = None;
+        }
+        config.merge_file(env, from_cli);
+        if let Some(ref path) = config.cmdline_file {
+            if config.cmdline.is_some() {
+                return Err("--cmdline and --cmdline-file are mutually exclusive".to_string());
+            }
+            let cmdline = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read cmdline file {}: {}", path.display(), e))?;
+            config.cmdline = Some(cmdline.trim_end_matches(['\n', '\r']).to_string());
+        }
+        Ok(config)
+    }
+    
+    /// Overwrite fields with file values unless `from_cli(id)` says the flag was given explicitly.
+    fn merge_file(&mut self, file: FileConfig, from_cli: impl Fn(&str) -> bool) {
+        macro_rules! merge {
+            ($($field:ident),*) => {
+                $(if let Some(v) = file.$field {
+                    if !from_cli(stringify!($field)) {
+                        self.$field = v;
+                    }
+                })*
+            };
+        }
+        merge!(memory, ram_base, mlock, vcpus, kernel, load_addr, raw_mode, disk, append, e820, smbios_product, mac, net, hostfwd, verbose, no_metrics, vga, stdin_keyboard, count_instructions, trace_exits, serial, dirty_stats, dry_run, print_caps, shutdown_timeout, virtio_blk_base, virtio_net_base, coalesced_mmio, virtio_queue_size, disk_cache, disk_cache_mb, disk_direct, crash_dump, seccomp, watchdog, watchdog_action, quiet, log_format);
+        
+        macro_rules! merge_optional {
+            ($($field:ident),*) => {
+                $(if let Some(v) = file.$field {
+                    if !from_cli(stringify!($field)) {
+                        self.$field = Some(v);
+                    }
+                })*
+            };
+        }
+        merge_optional!(mem_file, serial_capture, initrd_dir, dtb, vcpu_timeout, clock_offset, cpu_vendor, hv_vendor, vsock_cid, online_cpus, device_cpu, smbios_serial, pcap, dump_acpi, com2_log, control_socket, boot_timeout, raw, entry, cmdline, cmdline_file, verify_checksum);
+    }
+    
     /// Validate configuration parameters
     pub fn validate(&self) -> Result<(), String> {
         // Validate memory alignment (must be multiple of 2MB for HugePages)
-        if self.memory % 2 != 0 {
+        if !self.memory.is_multiple_of(2) {
             return Err(format!(
                 "Memory size must be a multiple of 2MB for HugePages optimization. Got: {} MB",
                 self.memory
@@ -76,27 +698,254 @@ impl VmConfig {
             ));
         }
         
-        // Validate kernel file exists
-        if !self.kernel.exists() {
+        if let Some(online) = self.online_cpus {
+            if online == 0 || online > self.vcpus {
+                return Err(format!(
+                    "Invalid online CPU count: {}. Must be between 1 and --vcpus ({})",
+                    online, self.vcpus
+                ));
+            }
+        }
+        
+        if let Some(cpu) = self.device_cpu {
+            if cpu >= self.online_cpus() {
+                return Err(format!(
+                    "Invalid device CPU: {}. Must be an online vCPU (0..{})",
+                    cpu, self.online_cpus()
+                ));
+            }
+        }
+        
+        if let Some(ref raw) = self.raw {
+            if !raw.exists() {
+                return Err(format!("Raw payload not found: {}", raw.display()));
+            }
+            if self.raw_mode == RawMode::Real && self.raw_entry() >= REAL_MODE_LIMIT {
+                return Err(format!(
+                    "Real-mode entry point {:#x} must lie below {:#x}",
+                    self.raw_entry(), REAL_MODE_LIMIT
+                ));
+            }
+            if self.raw_mode == RawMode::Real && self.ram_base != 0 {
+                return Err("Real-mode payloads need guest RAM at 0 (no --ram-base)".to_string());
+            }
+        } else if !self.kernel.exists() {
             return Err(format!(
                 "Kernel image not found: {}",
                 self.kernel.display()
             ));
+        } else {
+            check_bzimage(&self.kernel)?;
+        }
+        
+        if let Some(ref checksum) = self.verify_checksum {
+            verify_checksum(self.raw.as_deref().unwrap_or(&self.kernel), checksum)?;
+        }
+        
+        if let Some(ref signature) = self.hv_vendor {
+            if signature.len() != 12 {
+                return Err(format!(
+                    "Invalid hypervisor signature '{}': must be exactly 12 bytes, got {}",
+                    signature, signature.len()
+                ));
+            }
+        }
+        
+        // Validate vsock CID (0-2 are reserved, u32::MAX is VMADDR_CID_ANY)
+        if let Some(cid) = self.vsock_cid {
+            if !(3..u32::MAX as u64).contains(&cid) {
+                return Err(format!(
+                    "Invalid vsock CID: {}. Must be between 3 and {}",
+                    cid, u32::MAX - 1
+                ));
+            }
+        }
+        
+        if !(2..=QUEUE_NUM_MAX).contains(&self.virtio_queue_size) || !self.virtio_queue_size.is_power_of_two() {
+            return Err(format!(
+                "Invalid virtio queue size: {}. Must be a power of two between 2 and {}",
+                self.virtio_queue_size, QUEUE_NUM_MAX
+            ));
+        }
+        
+        if self.disk_cache == DiskCache::Writeback && self.disk_cache_mb == 0 {
+            return Err("Invalid disk cache size: must be at least 1 MB".to_string());
+        }
+        
+        if self.disk_direct && self.disk_cache == DiskCache::Writeback {
+            return Err("--disk-direct cannot be combined with --disk-cache writeback".to_string());
+        }
+        
+        if self.boot_timeout == Some(0) {
+            return Err("Invalid boot timeout: must be at least 1 second".to_string());
+        }
+        
+        if self.vcpu_timeout == Some(0) {
+            return Err("Invalid vCPU timeout: must be at least 1 millisecond".to_string());
+        }
+        
+        for (name, value) in [("product", Some(&self.smbios_product)), ("serial", self.smbios_serial.as_ref())] {
+            if let Some(value) = value {
+                if value.contains('\0') || value.len() > 64 {
+                    return Err(format!("Invalid SMBIOS {}: must be at most 64 bytes without NUL", name));
+                }
+            }
+        }
+        
+        parse_mac(&self.mac).map_err(|e| format!("Invalid MAC address: {}", e))?;
+        
+        if self.serial_capture == Some(0) {
+            return Err("Invalid serial capture size: must be at least 1 byte".to_string());
+        }
+        
+        if !self.e820.is_empty() && self.raw.is_some() {
+            return Err("--e820 needs a kernel: --raw payloads get no memory map".to_string());
+        }
+        
+        if self.dump_acpi.is_some() && self.raw.is_some() {
+            return Err("--dump-acpi needs a kernel: --raw payloads get no ACPI tables".to_string());
+        }
+        
+        if let Some(ref dir) = self.initrd_dir {
+            if self.raw.is_some() {
+                return Err("--initrd-dir needs a kernel: --raw payloads get no initrd".to_string());
+            }
+            if !dir.is_dir() {
+                return Err(format!("Initrd directory not found: {}", dir.display()));
+            }
+        }
+        
+        if let Some(ref dtb) = self.dtb {
+            if !dtb.exists() {
+                return Err(format!("Device tree blob not found: {}", dtb.display()));
+            }
+        }
+        
+        if !self.hostfwd.is_empty() && self.net != NetMode::User {
+            return Err("--hostfwd requires --net user".to_string());
+        }
+        
+        self.validate_ram_base()?;
+        self.validate_mmio_bases()?;
+        
+        // validate runs from both main and Vm::new; say it once
+        static CMDLINE_WARNING: Once = Once::new();
+        let missing = self.cmdline_missing_devices();
+        if !missing.is_empty() {
+            CMDLINE_WARNING.call_once(|| {
+                tracing::warn!(missing = ?missing,
+                    "--cmdline overrides the virtio_mmio.device= tokens but does not declare {}; the kernel will not find those devices",
+                    missing.join(", "));
+            });
+        }
+        
+        // Validate disk files exist (if specified)
+        if self.disk.len() > MAX_DISKS {
+            return Err(format!(
+                "Too many disk images: {}. Maximum: {}",
+                self.disk.len(), MAX_DISKS
+            ));
         }
         
-        // Validate disk file exists (if specified)
-        if let Some(ref disk) = self.disk {
+        for disk in &self.disk {
             if !disk.exists() {
                 return Err(format!(
                     "Disk image not found: {}",
                     disk.display()
                 ));
             }
+            // Block devices report a zero length here; only image files are checked
+            if disk.metadata().is_ok_and(|m| m.is_file() && m.len() == 0) {
+                return Err(format!(
+                    "Disk image is empty: {}. Create it with e.g. `truncate -s 1G {}`",
+                    disk.display(), disk.display()
+                ));
+            }
         }
         
         Ok(())
     }
     
+    /// Check that `--ram-base` is aligned and guest RAM stays below the
+    /// fixed MMIO windows and the legacy VGA buffer it would hide.
+    fn validate_ram_base(&self) -> Result<(), String> {
+        if !self.ram_base.is_multiple_of(RAM_BASE_ALIGN) {
+            return Err(format!("RAM base {:#x} is not {:#x}-aligned", self.ram_base, RAM_BASE_ALIGN));
+        }
+        if self.ram_end() > FIXED_MMIO_START {
+            return Err(format!(
+                "Guest RAM ({:#x} - {:#x}) overlaps the fixed MMIO windows from {:#x}",
+                self.ram_base, self.ram_end(), FIXED_MMIO_START
+            ));
+        }
+        if self.vga && self.ram_base != 0 {
+            return Err("--vga needs guest RAM at 0 for the text buffer at 0xB8000 (no --ram-base)".to_string());
+        }
+        Ok(())
+    }
+    
+    /// Check the configurable virtio windows against each other, guest RAM
+    /// and the fixed IOAPIC/HPET/LAPIC range.
+    fn validate_mmio_bases(&self) -> Result<(), String> {
+        let windows = [("virtio-blk", self.virtio_blk_base), ("virtio-net", self.virtio_net_base)];
+        
+        for (name, base) in windows {
+            if !base.is_multiple_of(VIRTIO_MMIO_SIZE) {
+                return Err(format!("{} base {:#x} is not {:#x}-aligned", name, base, VIRTIO_MMIO_SIZE));
+            }
+            if base < self.ram_end() && self.ram_base < base.saturating_add(VIRTIO_MMIO_SIZE) {
+                return Err(format!(
+                    "{} base {:#x} overlaps guest RAM ({:#x} - {:#x})",
+                    name, base, self.ram_base, self.ram_end()
+                ));
+            }
+            if base < PLATFORM_MMIO_END && PLATFORM_MMIO_START < base.saturating_add(VIRTIO_MMIO_SIZE) {
+                return Err(format!(
+                    "{} base {:#x} overlaps the IOAPIC/HPET/LAPIC range ({:#x} - {:#x})",
+                    name, base, PLATFORM_MMIO_START, PLATFORM_MMIO_END
+                ));
+            }
+        }
+        
+        if self.virtio_blk_base.abs_diff(self.virtio_net_base) < VIRTIO_MMIO_SIZE {
+            return Err(format!(
+                "virtio-blk base {:#x} and virtio-net base {:#x} overlap",
+                self.virtio_blk_base, self.virtio_net_base
+            ));
+        }
+        
+        Ok(())
+    }
+    
+    /// The kernel command line: `--cmdline` verbatim if given, otherwise the
+    /// default line, then `device_tokens`, then the `--append` tokens.
+    pub fn kernel_cmdline(&self, device_tokens: &[String]) -> String {
+        if let Some(ref cmdline) = self.cmdline {
+            return cmdline.clone();
+        }
+        std::iter::once(DEFAULT_CMDLINE)
+            .chain(device_tokens.iter().map(String::as_str))
+            .chain(self.append.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+    
+    /// Virtio devices whose window a `--cmdline` override does not mention.
+    fn cmdline_missing_devices(&self) -> Vec<&'static str> {
+        let Some(ref cmdline) = self.cmdline else {
+            return Vec::new();
+        };
+        let cmdline = cmdline.to_ascii_lowercase();
+        let mut devices = vec![("virtio-net", self.virtio_net_base)];
+        if !self.disk.is_empty() {
+            devices.insert(0, ("virtio-blk", self.virtio_blk_base));
+        }
+        devices.into_iter()
+            .filter(|(_, base)| !cmdline.contains(&format!("@{:#x}", base)))
+            .map(|(name, _)| name)
+            .collect()
+    }
+    
     /// Get tracing log level based on verbosity
     pub fn log_level(&self) -> &str {
         match self.verbose {
@@ -107,35 +956,442 @@ impl VmConfig {
         }
     }
     
+    /// Get the default tracing filter: `log_level` for diagnostics, plus
+    /// status lines at `info` unless `--quiet` was given, and exit traces
+    /// with `--trace-exits`
+    pub fn log_filter(&self) -> String {
+        let status = if self.quiet { "warn" } else { "info" };
+        let mut filter = format!("{},{}={}", self.log_level(), crate::STATUS_TARGET, status);
+        if self.trace_exits {
+            filter.push_str(&format!(",{}=info", crate::exit_trace::EXIT_TRACE_TARGET));
+        }
+        filter
+    }
+    
     /// Get memory size in bytes
     pub fn memory_bytes(&self) -> usize {
         self.memory * 1024 * 1024
     }
     
+    /// Guest physical address one past the end of RAM
+    pub fn ram_end(&self) -> u64 {
+        self.ram_base.saturating_add(self.memory_bytes() as u64)
+    }
+    
+    /// Number of vCPUs the guest is told are online
+    pub fn online_cpus(&self) -> u8 {
+        self.online_cpus.unwrap_or(self.vcpus)
+    }
+    
+    /// Get the entry point of the --raw payload
+    pub fn raw_entry(&self) -> u64 {
+        self.entry.unwrap_or(self.load_addr)
+    }
+    
     /// Get kernel path as string
     pub fn kernel_path(&self) -> String {
         self.kernel.to_string_lossy().to_string()
     }
     
-    /// Get disk path as optional string
-    pub fn disk_path(&self) -> Option<String> {
-        self.disk.as_ref().map(|p| p.to_string_lossy().to_string())
+    /// Get the boot watchdog window, if enabled
+    pub fn boot_timeout(&self) -> Option<Duration> {
+        self.boot_timeout.map(Duration::from_secs)
+    }
+    
+    /// Get the longest single KVM_RUN allowed, if bounded
+    pub fn vcpu_timeout(&self) -> Option<Duration> {
+        self.vcpu_timeout.map(Duration::from_millis)
+    }
+    
+    /// Get the graceful shutdown timeout
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout)
     }
+    
+    /// Signature for CPUID leaf 0x40000000, if overridden; `validate` checked its length.
+    pub fn hv_signature(&self) -> Option<[u8; 12]> {
+        self.hv_vendor.as_ref().and_then(|s| s.as_bytes().try_into().ok())
+    }
+    
+    /// Get disk paths as strings, in device order
+    pub fn disk_paths(&self) -> Vec<String> {
+        self.disk.iter().map(|p| p.to_string_lossy().to_string()).collect()
+    }
+    
+    /// Get the virtio-net MAC address as bytes
+    pub fn mac_bytes(&self) -> Result<[u8; 6], String> {
+        parse_mac(&self.mac)
+    }
+}
+
+/// Parse a guest physical address given as `0x`-prefixed hex or decimal.
+/// Fail fast on a `--kernel` that is not a bzImage, e.g. a vmlinux ELF,
+/// rather than deep in the loader.
+fn check_bzimage(path: &Path) -> Result<(), String> {
+    let mut file = File::open(path)
+        .map_err(|e| format!("Failed to open kernel image {}: {}", path.display(), e))?;
+    let mut magic = [0u8; 4];
+    let found = match file.seek(SeekFrom::Start(HDRS_MAGIC_OFFSET)).and_then(|_| file.read_exact(&mut magic)) {
+        Ok(()) => u32::from_le_bytes(magic) == HDRS_MAGIC,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(format!("Failed to read kernel image {}: {}", path.display(), e)),
+    };
+    if !found {
+        return Err(format!(
+            "Kernel image {} is not a bzImage (no \"HdrS\" header at {:#x}); use arch/x86/boot/bzImage, not vmlinux",
+            path.display(), HDRS_MAGIC_OFFSET
+        ));
+    }
+    Ok(())
+}
+
+/// Hash `path` and compare it with a `sha256:<hex>` digest.
+fn verify_checksum(path: &Path, expected: &str) -> Result<(), String> {
+    let hex = expected.strip_prefix("sha256:")
+        .filter(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+        .ok_or_else(|| format!("Invalid checksum '{}'. Expected sha256:<64 hex digits>", expected))?;
+    let digest = sha256::hash_file(path)
+        .map_err(|e| format!("Failed to hash {}: {}", path.display(), e))?;
+    let actual: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    if !actual.eq_ignore_ascii_case(hex) {
+        return Err(format!(
+            "Checksum mismatch for {}: expected sha256:{}, got sha256:{}",
+            path.display(), hex.to_ascii_lowercase(), actual
+        ));
+    }
+    Ok(())
+}
+
+pub fn parse_addr(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("invalid address '{}'", s))
+}
+
+/// Parse a colon-separated MAC address such as `52:54:00:12:34:56`.
+pub fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split(':');
+    for byte in mac.iter_mut() {
+        let part = parts.next().ok_or_else(|| format!("'{}' has fewer than 6 octets", s))?;
+        if part.len() != 2 {
+            return Err(format!("'{}' has a malformed octet '{}'", s, part));
+        }
+        *byte = u8::from_str_radix(part, 16)
+            .map_err(|_| format!("'{}' has a malformed octet '{}'", s, part))?;
+    }
+    if parts.next().is_some() {
+        return Err(format!("'{}' has more than 6 octets", s));
+    }
+    Ok(mac)
 }
 
 impl Default for VmConfig {
     fn default() -> Self {
         Self {
+            config: None,
             memory: 1024,
+            ram_base: 0,
+            mem_file: None,
+            mlock: false,
             vcpus: 1,
             kernel: PathBuf::from("bzImage"),
-            disk: None,
-            cmdline: String::from(
-                "console=ttyS0 earlyprintk=serial reboot=k panic=1 nokaslr noapic \
-                 virtio_mmio.device=4K@0xFEB00000:5 virtio_mmio.device=4K@0xFEB10000:6 root=/dev/vda rw"
-            ),
+            raw: None,
+            verify_checksum: None,
+            load_addr: 0x100000,
+            entry: None,
+            raw_mode: RawMode::Long,
+            disk: Vec::new(),
+            cmdline: None,
+            cmdline_file: None,
+            append: Vec::new(),
+            initrd_dir: None,
+            dtb: None,
+            e820: Vec::new(),
+            smbios_product: String::from("AxVM Virtual Machine"),
+            smbios_serial: None,
+            mac: String::from("52:54:00:12:34:56"),
+            net: NetMode::Tap,
+            hostfwd: Vec::new(),
             verbose: 1,
             no_metrics: false,
+            vsock_cid: None,
+            online_cpus: None,
+            device_cpu: None,
+            vga: false,
+            stdin_keyboard: false,
+            count_instructions: false,
+            trace_exits: false,
+            pcap: None,
+            dump_acpi: None,
+            serial: SerialTarget::Stdout,
+            serial_capture: None,
+            com2_log: None,
+            dirty_stats: false,
+            dry_run: false,
+            print_caps: false,
+            shutdown_timeout: 5,
+            virtio_blk_base: DEFAULT_VIRTIO_BLK_BASE,
+            virtio_net_base: DEFAULT_VIRTIO_NET_BASE,
+            coalesced_mmio: false,
+            virtio_queue_size: QUEUE_NUM_MAX,
+            disk_cache: DiskCache::None,
+            disk_cache_mb: 64,
+            disk_direct: false,
+            crash_dump: false,
+            control_socket: None,
+            boot_timeout: None,
+            watchdog: false,
+            watchdog_action: WatchdogAction::Reset,
+            vcpu_timeout: None,
+            clock_offset: None,
+            cpu_vendor: None,
+            hv_vendor: None,
+            seccomp: false,
+            quiet: false,
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_overrides_config_file() {
+        let path = std::env::temp_dir().join(format!("axvm-test-{}.toml", std::process::id()));
+        std::fs::write(&path, concat!(
+            "memory = 512\n",
+            "vcpus = 2\n",
+            "kernel = \"vmlinuz\"\n",
+            "disk = [\"root.img\", \"data.img\"]\n",
+            "mac = \"02:00:00:aa:bb:cc\"\n",
+        )).unwrap();
+
+        let matches = VmConfig::command().try_get_matches_from([
+            "axvm", "--config", path.to_str().unwrap(), "--memory", "2048",
+        ]).unwrap();
+        let config = VmConfig::from_matches(&matches, |_| None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.memory, 2048);
+        assert_eq!(config.vcpus, 2);
+        assert_eq!(config.kernel, PathBuf::from("vmlinuz"));
+        assert_eq!(config.disk, vec![PathBuf::from("root.img"), PathBuf::from("data.img")]);
+        assert_eq!(config.mac_bytes(), Ok([0x02, 0x00, 0x00, 0xAA, 0xBB, 0xCC]));
+        assert!(config.kernel_cmdline(&[]).contains("root=/dev/vda"));
+    }
+
+    #[test]
+    fn test_env_between_cli_and_defaults() {
+        let env = |name: &str| match name {
+            "AXVM_MEMORY" => Some("512".to_string()),
+            "AXVM_VCPUS" => Some("2".to_string()),
+            "AXVM_DISK" => Some("root.img,data.img".to_string()),
+            "AXVM_CMDLINE" => Some(String::new()),
+            _ => None,
+        };
+        let matches = VmConfig::command().try_get_matches_from(["axvm", "--memory", "2048"]).unwrap();
+        let config = VmConfig::from_matches(&matches, env).unwrap();
+
+        assert_eq!(config.memory, 2048);
+        assert_eq!(config.vcpus, 2);
+        assert_eq!(config.kernel, PathBuf::from("bzImage"));
+        assert_eq!(config.disk, vec![PathBuf::from("root.img"), PathBuf::from("data.img")]);
+        assert_eq!(config.cmdline, None);
+
+        let bad = |name: &str| (name == "AXVM_VCPUS").then(|| "four".to_string());
+        let err = VmConfig::from_matches(&matches, bad).unwrap_err();
+        assert!(err.starts_with("Invalid AXVM_VCPUS=four"), "{}", err);
+    }
+
+    #[test]
+    fn test_append_and_cmdline_override() {
+        let tokens = ["virtio_mmio.device=4K@0xFEB10000:6".to_string()];
+        let config = VmConfig { append: vec!["quiet".to_string(), "loglevel=3".to_string()], ..Default::default() };
+        assert_eq!(config.kernel_cmdline(&tokens),
+            format!("{} virtio_mmio.device=4K@0xFEB10000:6 quiet loglevel=3", DEFAULT_CMDLINE));
+
+        let config = VmConfig {
+            cmdline: Some("console=ttyS0 root=/dev/vda".to_string()),
+            disk: vec![PathBuf::from("root.img")],
+            ..Default::default()
+        };
+        assert_eq!(config.kernel_cmdline(&tokens), "console=ttyS0 root=/dev/vda");
+        assert_eq!(config.cmdline_missing_devices(), ["virtio-blk", "virtio-net"]);
+
+        let config = VmConfig {
+            cmdline: Some("console=ttyS0 virtio_mmio.device=4K@0xFEB10000:6".to_string()),
+            ..Default::default()
+        };
+        assert!(config.cmdline_missing_devices().is_empty());
+    }
+
+    #[test]
+    fn test_cmdline_file() {
+        let path = std::env::temp_dir().join(format!("axvm-test-cmdline-{}.txt", std::process::id()));
+        std::fs::write(&path, "console=ttyS0 root=/dev/vda quiet\n").unwrap();
+
+        let matches = VmConfig::command().try_get_matches_from([
+            "axvm", "--cmdline-file", path.to_str().unwrap(),
+        ]).unwrap();
+        let config = VmConfig::from_matches(&matches, |_| None).unwrap();
+        assert_eq!(config.kernel_cmdline(&[]), "console=ttyS0 root=/dev/vda quiet");
+
+        assert!(VmConfig::command().try_get_matches_from([
+            "axvm", "--cmdline-file", path.to_str().unwrap(), "--cmdline", "console=ttyS0",
+        ]).is_err());
+
+        // The flag also wins over a cmdline from the config file
+        let toml = std::env::temp_dir().join(format!("axvm-test-cmdline-{}.toml", std::process::id()));
+        std::fs::write(&toml, "cmdline = \"console=tty0\"\n").unwrap();
+        let matches = VmConfig::command().try_get_matches_from([
+            "axvm", "--config", toml.to_str().unwrap(), "--cmdline-file", path.to_str().unwrap(),
+        ]).unwrap();
+        let config = VmConfig::from_matches(&matches, |_| None).unwrap();
+        assert_eq!(config.kernel_cmdline(&[]), "console=ttyS0 root=/dev/vda quiet");
+        std::fs::remove_file(&toml).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_multiple_disks() {
+        let dir = std::env::temp_dir();
+        let raw = dir.join(format!("axvm-test-disks-{}.bin", std::process::id()));
+        let disks: Vec<PathBuf> = (0..=MAX_DISKS)
+            .map(|i| dir.join(format!("axvm-test-disk{}-{}.img", i, std::process::id())))
+            .collect();
+        std::fs::write(&raw, [0xF4]).unwrap();
+        for disk in &disks {
+            std::fs::write(disk, [0u8; 512]).unwrap();
         }
+
+        let matches = VmConfig::command().try_get_matches_from([
+            "axvm", "--raw", raw.to_str().unwrap(),
+            "--disk", disks[0].to_str().unwrap(), disks[1].to_str().unwrap(),
+        ]).unwrap();
+        let config = VmConfig::from_matches(&matches, |_| None).unwrap();
+        assert_eq!(config.disk, disks[..2]);
+        assert_eq!(config.disk_paths()[1], disks[1].to_string_lossy());
+        assert!(config.validate().is_ok());
+
+        let full = VmConfig { raw: Some(raw.clone()), disk: disks[..MAX_DISKS].to_vec(), ..Default::default() };
+        assert!(full.validate().is_ok());
+        let too_many = VmConfig { raw: Some(raw.clone()), disk: disks.clone(), ..Default::default() };
+        assert!(too_many.validate().unwrap_err().contains("Too many disk images: 8"));
+
+        std::fs::remove_file(&raw).unwrap();
+        for disk in &disks {
+            std::fs::remove_file(disk).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_device_cpu_must_be_online() {
+        let raw = std::env::temp_dir().join(format!("axvm-test-device-cpu-{}.bin", std::process::id()));
+        std::fs::write(&raw, [0xF4]).unwrap();
+
+        let matches = VmConfig::command().try_get_matches_from([
+            "axvm", "--raw", raw.to_str().unwrap(), "--vcpus", "2", "--device-cpu", "1",
+        ]).unwrap();
+        let config = VmConfig::from_matches(&matches, |_| None).unwrap();
+        assert_eq!(config.device_cpu, Some(1));
+        assert!(config.validate().is_ok());
+
+        let offline = VmConfig { online_cpus: Some(1), ..config.clone() };
+        assert!(offline.validate().unwrap_err().contains("Invalid device CPU: 1. Must be an online vCPU (0..1)"));
+        let missing = VmConfig { device_cpu: Some(2), ..config };
+        assert!(missing.validate().unwrap_err().contains("Invalid device CPU: 2"));
+        std::fs::remove_file(&raw).unwrap();
+    }
+
+    #[test]
+    fn test_parse_serial_target() {
+        assert_eq!("stdout".parse(), Ok(SerialTarget::Stdout));
+        assert_eq!("pty".parse(), Ok(SerialTarget::Pty));
+        assert_eq!("file:/tmp/ttyS0.log".parse(), Ok(SerialTarget::File(PathBuf::from("/tmp/ttyS0.log"))));
+        assert!("file:".parse::<SerialTarget>().is_err());
+        assert!("tcp:1234".parse::<SerialTarget>().is_err());
+    }
+
+    #[test]
+    fn test_parse_e820_region() {
+        assert_eq!("0xA0000,0x60000,reserved".parse(), Ok(E820Region { addr: 0xA0000, size: 0x60000, type_: 2 }));
+        assert_eq!("0x7f000000,4096,12".parse::<E820Region>().map(|r| r.type_), Ok(12));
+        assert_eq!("0x7f000000,0x1000,nvs".parse::<E820Region>().unwrap().to_string(), "0x7f000000,0x1000,nvs");
+        assert!("0x1000,0x1000".parse::<E820Region>().is_err());
+        assert!("0x1000,0,reserved".parse::<E820Region>().is_err());
+        assert!("0x1000,0x1000,bogus".parse::<E820Region>().is_err());
+    }
+
+    #[test]
+    fn test_parse_hostfwd() {
+        let rule: HostFwd = "tcp::2222-:22".parse().unwrap();
+        assert_eq!(rule, HostFwd {
+            host_addr: Ipv4Addr::LOCALHOST, host_port: 2222,
+            guest_addr: crate::usernet::GUEST_ADDR, guest_port: 22,
+        });
+        assert_eq!(rule.to_string(), "tcp:127.0.0.1:2222-10.0.2.15:22");
+        assert_eq!("tcp:0.0.0.0:8080-10.0.2.16:80".parse::<HostFwd>().map(|r| r.host_addr), Ok(Ipv4Addr::UNSPECIFIED));
+        assert!("udp::53-:53".parse::<HostFwd>().is_err());
+        assert!("tcp::2222".parse::<HostFwd>().is_err());
+        assert!("tcp::0-:22".parse::<HostFwd>().is_err());
+    }
+
+    #[test]
+    fn test_mmio_bases() {
+        assert_eq!(parse_addr("0xFEB00000"), Ok(0xFEB00000));
+        assert_eq!(parse_addr("4096"), Ok(4096));
+        assert!(parse_addr("0xZZ").is_err());
+
+        let config = VmConfig::default();
+        assert!(config.validate_mmio_bases().is_ok());
+
+        let overlapping = VmConfig { virtio_net_base: DEFAULT_VIRTIO_BLK_BASE, ..Default::default() };
+        assert!(overlapping.validate_mmio_bases().unwrap_err().contains("overlap"));
+
+        let in_ram = VmConfig { virtio_blk_base: 0x1000_0000, ..Default::default() };
+        assert!(in_ram.validate_mmio_bases().unwrap_err().contains("guest RAM"));
+
+        let on_ioapic = VmConfig { virtio_net_base: 0xFEC00000, ..Default::default() };
+        assert!(on_ioapic.validate_mmio_bases().unwrap_err().contains("IOAPIC"));
+
+        let unaligned = VmConfig { virtio_blk_base: 0xFEB00800, ..Default::default() };
+        assert!(unaligned.validate_mmio_bases().unwrap_err().contains("aligned"));
+    }
+
+    #[test]
+    fn test_ram_base() {
+        let moved = VmConfig { ram_base: 0x4000_0000, ..Default::default() };
+        assert!(moved.validate_ram_base().is_ok());
+        assert_eq!(moved.ram_end(), 0x8000_0000);
+
+        // Windows below a moved RAM region are fine, inside it they are not
+        let below = VmConfig { ram_base: 0x4000_0000, virtio_blk_base: 0x1000_0000, ..Default::default() };
+        assert!(below.validate_mmio_bases().is_ok());
+        let inside = VmConfig { ram_base: 0x4000_0000, virtio_blk_base: 0x5000_0000, ..Default::default() };
+        assert!(inside.validate_mmio_bases().unwrap_err().contains("guest RAM (0x40000000 - 0x80000000)"));
+
+        let unaligned = VmConfig { ram_base: 0x10_0000, ..Default::default() };
+        assert!(unaligned.validate_ram_base().unwrap_err().contains("aligned"));
+        let too_high = VmConfig { ram_base: 0xC000_0000, ..Default::default() };
+        assert!(too_high.validate_ram_base().unwrap_err().contains("fixed MMIO"));
+        let vga = VmConfig { ram_base: 0x20_0000, vga: true, ..Default::default() };
+        assert!(vga.validate_ram_base().unwrap_err().contains("--vga"));
+    }
+
+    #[test]
+    fn test_parse_mac() {
+        assert_eq!(parse_mac("52:54:00:12:34:56"), Ok([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]));
+        assert!(parse_mac("52:54:00:12:34").is_err());
+        assert!(parse_mac("52:54:00:12:34:56:78").is_err());
+        assert!(parse_mac("52:54:00:12:34:zz").is_err());
     }
 }
@@ -0,0 +1,426 @@
+// src/control.rs
+//! `--control-socket`: a line-based text protocol for poking at a running
+//! guest without a debugger.
+//!
+//! ```text
+//! regs <cpu>                     general, control and segment registers
+//! mem <addr> <len>               hexdump of guest physical memory
+//! setreg <cpu> <name> <value>    write a register; the VM must be paused
+//! pause | resume | metrics | help
+//! ```
+//!
+//! Numbers are decimal or `0x` hex. Every reply ends with a line that is
+//! either `ok` or `error: <reason>`.
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use kvm_ioctls::VcpuFd;
+
+use crate::error::LockExt;
+use crate::memory::GuestMemory;
+use crate::metrics::VmMetrics;
+use crate::pause::PauseGate;
+use crate::PauseHandle;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long a parked vCPU thread gets to pick up a queued request
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_DUMP_LEN: usize = 4096;
+const DUMP_WIDTH: usize = 16;
+
+const HELP: &[&str] = &[
+    "regs <cpu>",
+    "mem <addr> <len>",
+    "setreg <cpu> <name> <value>  (VM paused)",
+    "pause",
+    "resume",
+    "metrics",
+];
+
+type Job = Box<dyn FnOnce(&VcpuFd) + Send>;
+
+/// Requests for one vCPU. Only its thread owns the fd, so they run there,
+/// while the thread is parked by a pause.
+pub struct VcpuMailbox {
+    jobs: Mutex<Vec<Job>>,
+}
+
+impl VcpuMailbox {
+    pub fn new() -> Self {
+        Self { jobs: Mutex::new(Vec::new()) }
+    }
+
+    fn post(&self, job: Job) {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner()).push(job);
+    }
+
+    /// Called by the vCPU thread: run everything queued so far.
+    pub fn service(&self, vcpu: &VcpuFd) {
+        let jobs = std::mem::take(&mut *self.jobs.lock().unwrap_or_else(|e| e.into_inner()));
+        for job in jobs {
+            job(vcpu);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Regs(usize),
+    Mem { addr: u64, len: usize },
+    SetReg { cpu: usize, name: String, value: u64 },
+    Pause,
+    Resume,
+    Metrics,
+    Help,
+}
+
+fn parse_number(arg: Option<&str>, what: &str) -> Result<u64, String> {
+    let arg = arg.ok_or_else(|| format!("missing {}", what))?;
+    let parsed = match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    parsed.map_err(|_| format!("invalid {} '{}'", what, arg))
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let command = match words.next().unwrap_or_default() {
+        "regs" => Command::Regs(parse_number(words.next(), "cpu")? as usize),
+        "mem" => {
+            let addr = parse_number(words.next(), "address")?;
+            let len = parse_number(words.next(), "length")? as usize;
+            if len == 0 || len > MAX_DUMP_LEN {
+                return Err(format!("length must be 1 to {} bytes", MAX_DUMP_LEN));
+            }
+            Command::Mem { addr, len }
+        },
+        "setreg" => {
+            let cpu = parse_number(words.next(), "cpu")? as usize;
+            let name = words.next().ok_or("missing register name")?.to_ascii_lowercase();
+            let value = parse_number(words.next(), "value")?;
+            Command::SetReg { cpu, name, value }
+        },
+        "pause" => Command::Pause,
+        "resume" => Command::Resume,
+        "metrics" => Command::Metrics,
+        "help" => Command::Help,
+        other => return Err(format!("unknown command '{}', try 'help'", other)),
+    };
+    match words.next() {
+        Some(extra) => Err(format!("unexpected argument '{}'", extra)),
+        None => Ok(command),
+    }
+}
+
+fn read_registers(vcpu: &VcpuFd) -> Result<Vec<String>, String> {
+    let regs = vcpu.get_regs().map_err(|e| format!("KVM_GET_REGS failed: {}", e))?;
+    let sregs = vcpu.get_sregs().map_err(|e| format!("KVM_GET_SREGS failed: {}", e))?;
+    let mut lines = vec![
+        format!("RIP={:#018x} RFLAGS={:#010x}", regs.rip, regs.rflags),
+        format!("RAX={:#018x} RBX={:#018x} RCX={:#018x} RDX={:#018x}", regs.rax, regs.rbx, regs.rcx, regs.rdx),
+        format!("RSI={:#018x} RDI={:#018x} RSP={:#018x} RBP={:#018x}", regs.rsi, regs.rdi, regs.rsp, regs.rbp),
+        format!("R8 ={:#018x} R9 ={:#018x} R10={:#018x} R11={:#018x}", regs.r8, regs.r9, regs.r10, regs.r11),
+        format!("R12={:#018x} R13={:#018x} R14={:#018x} R15={:#018x}", regs.r12, regs.r13, regs.r14, regs.r15),
+        format!("CR0={:#010x} CR2={:#018x} CR3={:#018x} CR4={:#010x} CR8={:#x} EFER={:#x}",
+            sregs.cr0, sregs.cr2, sregs.cr3, sregs.cr4, sregs.cr8, sregs.efer),
+    ];
+    for (name, seg) in [("CS", sregs.cs), ("DS", sregs.ds), ("ES", sregs.es), ("FS", sregs.fs), ("GS", sregs.gs), ("SS", sregs.ss)] {
+        lines.push(format!("{}={:#06x} base={:#018x} limit={:#010x} type={:#x} l={} db={}",
+            name, seg.selector, seg.base, seg.limit, seg.type_, seg.l, seg.db));
+    }
+    lines.push(format!("GDT base={:#018x} limit={:#06x}  IDT base={:#018x} limit={:#06x}",
+        sregs.gdt.base, sregs.gdt.limit, sregs.idt.base, sregs.idt.limit));
+    Ok(lines)
+}
+
+fn write_register(vcpu: &VcpuFd, name: &str, value: u64) -> Result<(), String> {
+    let mut regs = vcpu.get_regs().map_err(|e| format!("KVM_GET_REGS failed: {}", e))?;
+    let general = match name {
+        "rax" => Some(&mut regs.rax),
+        "rbx" => Some(&mut regs.rbx),
+        "rcx" => Some(&mut regs.rcx),
+        "rdx" => Some(&mut regs.rdx),
+        "rsi" => Some(&mut regs.rsi),
+        "rdi" => Some(&mut regs.rdi),
+        "rsp" => Some(&mut regs.rsp),
+        "rbp" => Some(&mut regs.rbp),
+        "r8" => Some(&mut regs.r8),
+        "r9" => Some(&mut regs.r9),
+        "r10" => Some(&mut regs.r10),
+        "r11" => Some(&mut regs.r11),
+        "r12" => Some(&mut regs.r12),
+        "r13" => Some(&mut regs.r13),
+        "r14" => Some(&mut regs.r14),
+        "r15" => Some(&mut regs.r15),
+        "rip" => Some(&mut regs.rip),
+        "rflags" => Some(&mut regs.rflags),
+        _ => None,
+    };
+    if let Some(field) = general {
+        *field = value;
+        return vcpu.set_regs(&regs).map_err(|e| format!("KVM_SET_REGS failed: {}", e));
+    }
+
+    let mut sregs = vcpu.get_sregs().map_err(|e| format!("KVM_GET_SREGS failed: {}", e))?;
+    let field = match name {
+        "cr0" => &mut sregs.cr0,
+        "cr2" => &mut sregs.cr2,
+        "cr3" => &mut sregs.cr3,
+        "cr4" => &mut sregs.cr4,
+        "cr8" => &mut sregs.cr8,
+        "efer" => &mut sregs.efer,
+        _ => return Err(format!("unknown register '{}'", name)),
+    };
+    *field = value;
+    vcpu.set_sregs(&sregs).map_err(|e| format!("KVM_SET_SREGS failed: {}", e))
+}
+
+fn hexdump(addr: u64, bytes: &[u8]) -> Vec<String> {
+    bytes.chunks(DUMP_WIDTH).enumerate().map(|(i, chunk)| {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk.iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        format!("{:#010x}: {:<width$} |{}|", addr + (i * DUMP_WIDTH) as u64, hex.join(" "), ascii,
+            width = DUMP_WIDTH * 3 - 1)
+    }).collect()
+}
+
+/// What the control socket can reach inside a running [`Vm`](crate::Vm).
+pub struct Control {
+    // Indexed by vCPU id; parked offline vCPUs have none
+    mailboxes: Vec<Arc<VcpuMailbox>>,
+    guest_mem: Arc<Mutex<GuestMemory>>,
+    metrics: Arc<VmMetrics>,
+    pause: PauseHandle,
+    gate: Arc<PauseGate>,
+}
+
+impl Control {
+    pub fn new(
+        mailboxes: Vec<Arc<VcpuMailbox>>,
+        guest_mem: Arc<Mutex<GuestMemory>>,
+        metrics: Arc<VmMetrics>,
+        pause: PauseHandle,
+        gate: Arc<PauseGate>,
+    ) -> Self {
+        Self { mailboxes, guest_mem, metrics, pause, gate }
+    }
+
+    /// Run `request` on the thread of vCPU `cpu`, which must be parked.
+    fn on_vcpu<T: Send + 'static>(
+        &self,
+        cpu: usize,
+        request: impl FnOnce(&VcpuFd) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let mailbox = self.mailboxes.get(cpu).ok_or_else(|| format!("no online vCPU {}", cpu))?;
+        let (reply, answer) = mpsc::channel();
+        mailbox.post(Box::new(move |vcpu| {
+            let _ = reply.send(request(vcpu));
+        }));
+        self.gate.release();
+        answer.recv_timeout(REPLY_TIMEOUT)
+            .map_err(|_| format!("vCPU {} did not pick up the request within {:?}", cpu, REPLY_TIMEOUT))?
+    }
+
+    fn execute(&self, command: Command) -> Result<Vec<String>, String> {
+        match command {
+            Command::Regs(cpu) => {
+                // A running vCPU is only reachable from its own thread: pause just for the read
+                let running = !self.pause.is_paused();
+                if running {
+                    self.pause.pause().map_err(|e| e.to_string())?;
+                }
+                let lines = self.on_vcpu(cpu, read_registers);
+                if running {
+                    self.pause.resume().map_err(|e| e.to_string())?;
+                }
+                lines
+            },
+            Command::Mem { addr, len } => {
+                let mem = self.guest_mem.lock_or_err().map_err(|e| e.to_string())?;
+                let bytes = usize::try_from(addr).map_err(|e| e.to_string())
+                    .and_then(|offset| mem.read_slice(offset, len))?;
+                Ok(hexdump(addr, bytes))
+            },
+            Command::SetReg { cpu, name, value } => {
+                if !self.pause.is_paused() {
+                    return Err("setreg needs a paused VM, send 'pause' first".to_string());
+                }
+                self.on_vcpu(cpu, move |vcpu| write_register(vcpu, &name, value))?;
+                Ok(Vec::new())
+            },
+            Command::Pause => self.pause.pause().map(|_| Vec::new()).map_err(|e| e.to_string()),
+            Command::Resume => self.pause.resume().map(|_| Vec::new()).map_err(|e| e.to_string()),
+            Command::Metrics => Ok(self.metrics.to_string().lines().map(str::to_string).collect()),
+            Command::Help => Ok(HELP.iter().map(|line| line.to_string()).collect()),
+        }
+    }
+
+    /// Answer one line of input.
+    fn reply(&self, line: &str) -> Vec<String> {
+        let mut lines = match parse_command(line).and_then(|command| self.execute(command)) {
+            Ok(lines) => lines,
+            Err(e) => return vec![format!("error: {}", e)],
+        };
+        lines.push("ok".to_string());
+        lines
+    }
+
+    /// Serve one client until it disconnects or the VM stops.
+    fn serve(&self, stream: UnixStream, should_stop: &AtomicBool) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        while !should_stop.load(Ordering::Relaxed) {
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) if line.ends_with(b"\n") => {},
+                Ok(_) => continue,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => continue,
+                Err(e) => return Err(e),
+            }
+            let text = String::from_utf8_lossy(&line).trim().to_string();
+            line.clear();
+            if text.is_empty() {
+                continue;
+            }
+            tracing::debug!(command = %text, "Control command");
+            for reply in self.reply(&text) {
+                writeln!(writer, "{}", reply)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Listen on `path`, replacing a socket left behind by an earlier run but
+/// nothing else. Only the owner may connect. The socket is not removed on
+/// exit: unlinking is outside the seccomp set.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, "path exists and is not a socket"));
+        },
+        Ok(_) => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(ErrorKind::AddrInUse, "another process is serving it"));
+            }
+            std::fs::remove_file(path)?;
+        },
+        Err(_) => {},
+    }
+    // Created 0600 rather than chmodded after bind, so nobody else can
+    // connect in between
+    let old_mask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(old_mask) };
+    let listener = listener?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Serve clients of `listener` one at a time, until `should_stop`.
+pub fn spawn_control_socket(
+    listener: UnixListener,
+    control: Control,
+    should_stop: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while !should_stop.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = control.serve(stream, &should_stop) {
+                        tracing::debug!(error = %e, "Control client dropped");
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Control socket accept failed");
+                    thread::sleep(POLL_INTERVAL);
+                },
+            }
+        }
+    })
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_ioctls::Kvm;
+
+    #[test]
+    fn test_bind_is_owner_only_and_keeps_other_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("axvm-control-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let listener = bind(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(bind(&path).unwrap_err().kind(), ErrorKind::AddrInUse);
+        // A stale socket is replaced
+        drop(listener);
+        drop(bind(&path).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, b"not a socket").unwrap();
+        assert_eq!(bind(&path).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(parse_command("regs 1"), Ok(Command::Regs(1)));
+        assert_eq!(parse_command("  mem 0x1000 32 "), Ok(Command::Mem { addr: 0x1000, len: 32 }));
+        assert_eq!(parse_command("setreg 0 RIP 0x100000"),
+            Ok(Command::SetReg { cpu: 0, name: "rip".to_string(), value: 0x100000 }));
+        assert_eq!(parse_command("pause"), Ok(Command::Pause));
+
+        assert!(parse_command("mem 0x1000").is_err());
+        assert!(parse_command("mem 0 99999").is_err());
+        assert!(parse_command("regs zero").is_err());
+        assert!(parse_command("resume now").is_err());
+        assert!(parse_command("reboot").is_err());
+
+        let dump = hexdump(0x10, b"Hi\x00\xff");
+        assert_eq!(dump, vec![format!("0x00000010: {:<47} |Hi..|", "48 69 00 ff")]);
+    }
+
+    #[test]
+    fn test_mailbox_reads_and_writes_registers() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mailbox = VcpuMailbox::new();
+
+        let (reply, answer) = mpsc::channel();
+        mailbox.post(Box::new(move |vcpu| {
+            let result = write_register(vcpu, "rdi", 0x40000)
+                .and_then(|_| write_register(vcpu, "cr3", 0x9000))
+                .and_then(|_| read_registers(vcpu));
+            reply.send(result).unwrap();
+        }));
+        assert!(answer.try_recv().is_err());
+        mailbox.service(&vcpu);
+
+        let lines = answer.recv().unwrap().unwrap();
+        assert!(lines[2].contains("RDI=0x0000000000040000"));
+        assert!(lines[5].contains("CR3=0x0000000000009000"));
+        assert_eq!(write_register(&vcpu, "xmm0", 1), Err("unknown register 'xmm0'".to_string()));
+    }
+}
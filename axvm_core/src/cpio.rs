@@ -0,0 +1,146 @@
+// src/cpio.rs
+//! `--initrd-dir`: pack a host directory into a newc ("070701") cpio
+//! archive, the format the kernel unpacks into its initramfs.
+
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+const NEWC_MAGIC: &[u8] = b"070701";
+const TRAILER: &str = "TRAILER!!!";
+
+fn pad4(out: &mut Vec<u8>) {
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+fn device_numbers(dev: u64) -> (u32, u32) {
+    (libc::major(dev), libc::minor(dev))
+}
+
+struct Entry<'a> {
+    ino: u32,
+    mode: u32,
+    nlink: u32,
+    mtime: u32,
+    rdev: u64,
+    name: &'a [u8],
+    data: &'a [u8],
+}
+
+fn push_entry(out: &mut Vec<u8>, entry: &Entry) {
+    let (rdev_major, rdev_minor) = device_numbers(entry.rdev);
+    // Owned by root whoever built the tree; an initramfs runs as root
+    let fields = [
+        entry.ino, entry.mode, 0, 0, entry.nlink, entry.mtime, entry.data.len() as u32,
+        0, 0, rdev_major, rdev_minor, entry.name.len() as u32 + 1, 0,
+    ];
+    out.extend_from_slice(NEWC_MAGIC);
+    for field in fields {
+        out.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    out.extend_from_slice(entry.name);
+    out.push(0);
+    pad4(out);
+    out.extend_from_slice(entry.data);
+    pad4(out);
+}
+
+/// Archive everything below `dir` (not `dir` itself), with names relative
+/// to it, e.g. `init` and `bin/busybox`. Entries are sorted so the same
+/// tree always gives the same archive; sockets are skipped.
+pub fn archive_dir(dir: &Path) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut ino = 1;
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(current) = pending.pop() {
+        let mut children: Vec<_> = fs::read_dir(&current)
+            .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect::<Result<_, _>>())
+            .map_err(|e| format!("Failed to read {}: {}", current.display(), e))?;
+        children.sort();
+
+        for path in children {
+            let meta = fs::symlink_metadata(&path)
+                .map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+            let file_type = meta.file_type();
+            let data = if file_type.is_file() {
+                fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(&path)
+                    .map_err(|e| format!("Failed to read link {}: {}", path.display(), e))?;
+                target.as_os_str().as_bytes().to_vec()
+            } else if file_type.is_socket() {
+                continue;
+            } else {
+                Vec::new()
+            };
+            if data.len() > u32::MAX as usize {
+                return Err(format!("{} is too large for a cpio archive", path.display()));
+            }
+
+            let name = path.strip_prefix(dir).unwrap();
+            push_entry(&mut out, &Entry {
+                ino,
+                mode: meta.mode(),
+                nlink: if file_type.is_dir() { 2 } else { 1 },
+                mtime: meta.mtime().clamp(0, u32::MAX as i64) as u32,
+                rdev: meta.rdev(),
+                name: name.as_os_str().as_bytes(),
+                data: &data,
+            });
+            ino += 1;
+            if file_type.is_dir() {
+                pending.push(path);
+            }
+        }
+    }
+
+    push_entry(&mut out, &Entry { ino: 0, mode: 0, nlink: 1, mtime: 0, rdev: 0, name: TRAILER.as_bytes(), data: &[] });
+    Ok(out)
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(header: &[u8], index: usize) -> u32 {
+        let hex = std::str::from_utf8(&header[6 + index * 8..14 + index * 8]).unwrap();
+        u32::from_str_radix(hex, 16).unwrap()
+    }
+
+    #[test]
+    fn test_archive_dir() {
+        let dir = std::env::temp_dir().join(format!("axvm-test-initrd-{}", std::process::id()));
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("init"), b"#!/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink("init", dir.join("linuxrc")).unwrap();
+
+        let archive = archive_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // bin, init, linuxrc, then the trailer; every header 4-byte aligned
+        let mut names = Vec::new();
+        let mut offset = 0;
+        while offset < archive.len() {
+            let header = &archive[offset..offset + 110];
+            assert_eq!(&header[..6], NEWC_MAGIC);
+            let (size, namesize) = (field(header, 6) as usize, field(header, 11) as usize);
+            let name = &archive[offset + 110..offset + 110 + namesize - 1];
+            let data_start = (offset + 110 + namesize).next_multiple_of(4);
+            names.push((String::from_utf8(name.to_vec()).unwrap(), archive[data_start..data_start + size].to_vec()));
+            offset = (data_start + size).next_multiple_of(4);
+        }
+        assert_eq!(offset, archive.len());
+        assert_eq!(names, [
+            ("bin".to_string(), vec![]),
+            ("init".to_string(), b"#!/bin/sh\n".to_vec()),
+            ("linuxrc".to_string(), b"init".to_vec()),
+            (TRAILER.to_string(), vec![]),
+        ]);
+    }
+}
@@ -0,0 +1,313 @@
+use kvm_bindings::{CpuId, kvm_cpuid_entry2, KVM_CPUID_FLAG_SIGNIFCANT_INDEX};
+
+
+const LEAF_VENDOR: u32 = 0x0;
+const LEAF_FEATURES: u32 = 0x1;
+const LEAF_CACHE_PARAMS: u32 = 0x4;
+const LEAF_MONITOR_MWAIT: u32 = 0x5;
+const LEAF_EXT_TOPOLOGY: u32 = 0xB;
+const LEAF_EXT_TOPOLOGY_V2: u32 = 0x1F;
+const LEAF_HYPERVISOR: u32 = 0x40000000;
+const LEAF_HOST_KVM_FEATURES: u32 = 0x40000001;
+// KVM's paravirt leaves, moved one hypervisor-range slot up; Linux scans
+// 0x40000000-0x4000FF00 in 0x100 steps for the KVM signature
+const LEAF_KVM_SIGNATURE: u32 = 0x40000100;
+const LEAF_KVM_FEATURES: u32 = 0x40000101;
+// AMD repeats the vendor string here
+const LEAF_EXT_VENDOR: u32 = 0x80000000;
+
+
+const ECX_MONITOR: u32 = 1 << 3;
+const ECX_HYPERVISOR: u32 = 1 << 31;
+const EDX_HTT: u32 = 1 << 28;
+
+// Leaf 0xB level types (ECX[15:8])
+const LEVEL_TYPE_SMT: u32 = 1;
+const LEVEL_TYPE_CORE: u32 = 2;
+
+
+// kvmclock via MSR_KVM_SYSTEM_TIME_NEW / MSR_KVM_WALL_CLOCK_NEW
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+const KVM_CLOCK_FEATURES: u32 = KVM_FEATURE_CLOCKSOURCE2 | KVM_FEATURE_CLOCKSOURCE_STABLE_BIT;
+
+
+const HYPERVISOR_SIGNATURE: &[u8; 12] = b"AxVMAxVMAxVM";
+const KVM_SIGNATURE: &[u8; 12] = b"KVMKVMKVM\0\0\0";
+
+
+/// Adjust the host-supported CPUID before handing it to a vCPU.
+pub fn filter_cpuid(cpuid: &mut CpuId) -> Result<(), String> {
+    let (ebx, ecx, edx) = signature_regs(HYPERVISOR_SIGNATURE);
+    let mut has_hypervisor_leaf = false;
+    let mut has_kvm_features = false;
+
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            LEAF_FEATURES => {
+                // We are a hypervisor, and MONITOR/MWAIT is not virtualized.
+                entry.ecx |= ECX_HYPERVISOR;
+                entry.ecx &= !ECX_MONITOR;
+            },
+            LEAF_MONITOR_MWAIT => {
+                entry.eax = 0;
+                entry.ebx = 0;
+                entry.ecx = 0;
+                entry.edx = 0;
+            },
+            LEAF_HYPERVISOR => {
+                entry.eax = LEAF_HYPERVISOR;
+                entry.ebx = ebx;
+                entry.ecx = ecx;
+                entry.edx = edx;
+                has_hypervisor_leaf = true;
+            },
+            LEAF_HOST_KVM_FEATURES => {
+                // Only the paravirtual clock is offered to the guest
+                entry.function = LEAF_KVM_FEATURES;
+                entry.eax &= KVM_CLOCK_FEATURES;
+                entry.ebx = 0;
+                entry.ecx = 0;
+                entry.edx = 0;
+                has_kvm_features = true;
+            },
+            _ => {}
+        }
+    }
+
+    if !has_hypervisor_leaf {
+        cpuid.push(kvm_cpuid_entry2 {
+            function: LEAF_HYPERVISOR,
+            eax: LEAF_HYPERVISOR,
+            ebx,
+            ecx,
+            edx,
+            ..Default::default()
+        }).map_err(|e| format!("Failed to add hypervisor leaf: {:?}", e))?;
+    }
+
+    let (ebx, ecx, edx) = signature_regs(KVM_SIGNATURE);
+    cpuid.push(kvm_cpuid_entry2 {
+        function: LEAF_KVM_SIGNATURE,
+        eax: LEAF_KVM_FEATURES,
+        ebx,
+        ecx,
+        edx,
+        ..Default::default()
+    }).map_err(|e| format!("Failed to add KVM signature leaf: {:?}", e))?;
+
+    if !has_kvm_features {
+        cpuid.push(kvm_cpuid_entry2 {
+            function: LEAF_KVM_FEATURES,
+            eax: KVM_CLOCK_FEATURES,
+            ..Default::default()
+        }).map_err(|e| format!("Failed to add KVM features leaf: {:?}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Report `vendor` (e.g. "AuthenticAMD") as the CPU vendor. Leaf 0 holds it
+/// in EBX, EDX, ECX order; the feature leaves stay the host's.
+pub fn set_vendor(cpuid: &mut CpuId, vendor: &[u8; 12]) {
+    let (first, second, third) = signature_regs(vendor);
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        if entry.function == LEAF_VENDOR || entry.function == LEAF_EXT_VENDOR {
+            entry.ebx = first;
+            entry.edx = second;
+            entry.ecx = third;
+        }
+    }
+}
+
+/// Replace the hypervisor signature `filter_cpuid` put in leaf 0x40000000.
+pub fn set_hypervisor_signature(cpuid: &mut CpuId, signature: &[u8; 12]) {
+    let (ebx, ecx, edx) = signature_regs(signature);
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        if entry.function == LEAF_HYPERVISOR {
+            entry.ebx = ebx;
+            entry.ecx = ecx;
+            entry.edx = edx;
+        }
+    }
+}
+
+/// Withdraw kvmclock so the guest takes its wall time from the CMOS RTC.
+/// KVM derives the kvmclock wall clock from host time, which would undo
+/// `--clock-offset`.
+pub fn hide_kvmclock(cpuid: &mut CpuId) {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        if entry.function == LEAF_KVM_FEATURES {
+            entry.eax &= !KVM_CLOCK_FEATURES;
+        }
+    }
+}
+
+/// Present the `vcpus` vCPUs as single-threaded cores of one package, as
+/// seen by vCPU `cpu_id`. Its APIC ID is `cpu_id`: KVM's default for the
+/// vCPU index, and what the MADT lists.
+pub fn set_topology(cpuid: &mut CpuId, cpu_id: u8, vcpus: u8) -> Result<(), String> {
+    let apic_id = cpu_id as u32;
+    // APIC IDs are allocated in power-of-two blocks per level
+    let core_bits = (vcpus.max(1) as u32).next_power_of_two().trailing_zeros();
+
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            LEAF_FEATURES => {
+                entry.ebx = (entry.ebx & 0x0000_FFFF) | (apic_id << 24) | ((1 << core_bits) << 16);
+                if vcpus > 1 {
+                    entry.edx |= EDX_HTT;
+                } else {
+                    entry.edx &= !EDX_HTT;
+                }
+            },
+            LEAF_CACHE_PARAMS if entry.eax & 0x1F != 0 => {
+                // L1 and L2 belong to one core, L3 to the whole package
+                let sharing = if (entry.eax >> 5) & 0x7 >= 3 { (1 << core_bits) - 1 } else { 0 };
+                entry.eax = (entry.eax & 0x3FFF) | (sharing << 14) | (((1 << core_bits) - 1) << 26);
+            },
+            _ => {}
+        }
+    }
+
+    // Replace the host's layout; without 0x1F the guest falls back to 0xB
+    cpuid.retain(|e| e.function != LEAF_EXT_TOPOLOGY && e.function != LEAF_EXT_TOPOLOGY_V2);
+    let levels = [
+        (0, 1, LEVEL_TYPE_SMT),
+        (core_bits, vcpus as u32, LEVEL_TYPE_CORE),
+        (0, 0, 0),
+    ];
+    for (index, (shift, count, level_type)) in levels.into_iter().enumerate() {
+        cpuid.push(kvm_cpuid_entry2 {
+            function: LEAF_EXT_TOPOLOGY,
+            index: index as u32,
+            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            eax: shift,
+            ebx: count,
+            ecx: (level_type << 8) | index as u32,
+            edx: apic_id,
+            ..Default::default()
+        }).map_err(|e| format!("Failed to add topology leaf: {:?}", e))?;
+    }
+
+    Ok(())
+}
+
+fn signature_regs(sig: &[u8; 12]) -> (u32, u32, u32) {
+    (
+        u32::from_le_bytes([sig[0], sig[1], sig[2], sig[3]]),
+        u32::from_le_bytes([sig[4], sig[5], sig[6], sig[7]]),
+        u32::from_le_bytes([sig[8], sig[9], sig[10], sig[11]]),
+    )
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(cpuid: &CpuId, function: u32) -> kvm_cpuid_entry2 {
+        *cpuid.as_slice().iter().find(|e| e.function == function).unwrap()
+    }
+
+    #[test]
+    fn test_filter_cpuid() {
+        let mut cpuid = CpuId::from_entries(&[
+            kvm_cpuid_entry2 { function: LEAF_FEATURES, ecx: ECX_MONITOR, ..Default::default() },
+            kvm_cpuid_entry2 { function: LEAF_MONITOR_MWAIT, eax: 0x40, ebx: 0x40, ..Default::default() },
+            kvm_cpuid_entry2 { function: LEAF_HOST_KVM_FEATURES, eax: 0x0100_7AFF, ..Default::default() },
+        ]).unwrap();
+
+        filter_cpuid(&mut cpuid).unwrap();
+
+        let features = leaf(&cpuid, LEAF_FEATURES);
+        assert_eq!(features.ecx & ECX_MONITOR, 0);
+        assert_ne!(features.ecx & ECX_HYPERVISOR, 0);
+        assert_eq!(leaf(&cpuid, LEAF_MONITOR_MWAIT).eax, 0);
+
+        let hv = leaf(&cpuid, LEAF_HYPERVISOR);
+        let mut sig = Vec::new();
+        sig.extend_from_slice(&hv.ebx.to_le_bytes());
+        sig.extend_from_slice(&hv.ecx.to_le_bytes());
+        sig.extend_from_slice(&hv.edx.to_le_bytes());
+        assert_eq!(&sig[..], HYPERVISOR_SIGNATURE);
+        assert!(cpuid.as_slice().iter().all(|e| e.function != LEAF_HOST_KVM_FEATURES));
+
+        let kvm = leaf(&cpuid, LEAF_KVM_SIGNATURE);
+        assert_eq!(kvm.eax, LEAF_KVM_FEATURES);
+        assert_eq!(signature_regs(KVM_SIGNATURE), (kvm.ebx, kvm.ecx, kvm.edx));
+        assert_eq!(leaf(&cpuid, LEAF_KVM_FEATURES).eax, KVM_CLOCK_FEATURES);
+
+        hide_kvmclock(&mut cpuid);
+        assert_eq!(leaf(&cpuid, LEAF_KVM_FEATURES).eax, 0);
+    }
+
+    #[test]
+    fn test_kvm_leaves_added_without_host_leaf() {
+        let mut cpuid = CpuId::from_entries(&[
+            kvm_cpuid_entry2 { function: LEAF_FEATURES, ..Default::default() },
+        ]).unwrap();
+        filter_cpuid(&mut cpuid).unwrap();
+
+        assert_eq!(leaf(&cpuid, LEAF_KVM_SIGNATURE).eax, LEAF_KVM_FEATURES);
+        assert_eq!(leaf(&cpuid, LEAF_KVM_FEATURES).eax, KVM_CLOCK_FEATURES);
+        assert_eq!(cpuid.as_slice().iter().filter(|e| e.function == LEAF_KVM_FEATURES).count(), 1);
+    }
+
+    #[test]
+    fn test_vendor_and_hypervisor_signature() {
+        let mut cpuid = CpuId::from_entries(&[
+            kvm_cpuid_entry2 { function: LEAF_VENDOR, eax: 0x16, ..Default::default() },
+            kvm_cpuid_entry2 { function: LEAF_EXT_VENDOR, eax: 0x8000_0008, ..Default::default() },
+        ]).unwrap();
+        filter_cpuid(&mut cpuid).unwrap();
+
+        set_vendor(&mut cpuid, b"AuthenticAMD");
+        for function in [LEAF_VENDOR, LEAF_EXT_VENDOR] {
+            let entry = leaf(&cpuid, function);
+            let mut vendor = Vec::new();
+            for reg in [entry.ebx, entry.edx, entry.ecx] {
+                vendor.extend_from_slice(&reg.to_le_bytes());
+            }
+            assert_eq!(&vendor[..], b"AuthenticAMD");
+        }
+        // Max leaf numbers are kept
+        assert_eq!(leaf(&cpuid, LEAF_VENDOR).eax, 0x16);
+
+        set_hypervisor_signature(&mut cpuid, b"Microsoft Hv");
+        let hv = leaf(&cpuid, LEAF_HYPERVISOR);
+        assert_eq!(signature_regs(b"Microsoft Hv"), (hv.ebx, hv.ecx, hv.edx));
+        assert_eq!(hv.eax, LEAF_HYPERVISOR);
+    }
+
+    #[test]
+    fn test_topology_leaves() {
+        let mut cpuid = CpuId::from_entries(&[
+            kvm_cpuid_entry2 { function: LEAF_FEATURES, ebx: 0x0110_0800, ..Default::default() },
+            // L1d and L3 descriptors (type in EAX[4:0], level in EAX[7:5])
+            kvm_cpuid_entry2 { function: LEAF_CACHE_PARAMS, index: 0, eax: 0x1C00_4121, ..Default::default() },
+            kvm_cpuid_entry2 { function: LEAF_CACHE_PARAMS, index: 3, eax: 0x1C03_C163, ..Default::default() },
+            kvm_cpuid_entry2 { function: LEAF_EXT_TOPOLOGY, index: 0, eax: 1, ebx: 2, edx: 17, ..Default::default() },
+            kvm_cpuid_entry2 { function: LEAF_EXT_TOPOLOGY_V2, ..Default::default() },
+        ]).unwrap();
+
+        set_topology(&mut cpuid, 2, 3).unwrap();
+
+        let features = leaf(&cpuid, LEAF_FEATURES);
+        assert_eq!(features.ebx, 0x0204_0800);
+        assert_ne!(features.edx & EDX_HTT, 0);
+
+        let caches: Vec<u32> = cpuid.as_slice().iter()
+            .filter(|e| e.function == LEAF_CACHE_PARAMS).map(|e| e.eax).collect();
+        assert_eq!(caches, [0x0C00_0121, 0x0C00_C163]);
+
+        assert!(cpuid.as_slice().iter().all(|e| e.function != LEAF_EXT_TOPOLOGY_V2));
+        let topo: Vec<_> = cpuid.as_slice().iter()
+            .filter(|e| e.function == LEAF_EXT_TOPOLOGY)
+            .map(|e| (e.index, e.eax, e.ebx, e.ecx, e.edx)).collect();
+        assert_eq!(topo, [(0, 0, 1, 0x100, 2), (1, 2, 3, 0x201, 2), (2, 0, 0, 2, 2)]);
+    }
+}
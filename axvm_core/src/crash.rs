@@ -0,0 +1,160 @@
+// src/crash.rs
+use kvm_ioctls::VcpuFd;
+
+use crate::debug;
+use crate::memory::GuestMemory;
+
+const PAGE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_HUGE: u64 = 1 << 7;
+
+const CR0_PG: u64 = 1 << 31;
+const CR4_LA57: u64 = 1 << 12;
+const EFER_LMA: u64 = 1 << 10;
+
+// Bytes of guest code dumped on each side of RIP
+const CODE_CONTEXT: u64 = 32;
+
+const LEVELS: [(&str, u32); 4] = [("PML4E", 39), ("PDPTE", 30), ("PDE", 21), ("PTE", 12)];
+
+/// Result of a 4-level page table walk.
+struct Walk {
+    steps: Vec<String>,
+    phys: Option<u64>,
+}
+
+fn read_u64(mem: &GuestMemory, addr: u64) -> Option<u64> {
+    let bytes = mem.read_slice(addr as usize, 8).ok()?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Translate `virt` through the 4-level tables rooted at `cr3`, logging every entry.
+fn walk(mem: &GuestMemory, cr3: u64, virt: u64) -> Walk {
+    let mut steps = Vec::new();
+    let mut table = cr3 & PAGE_ADDR_MASK;
+
+    for (level, (name, shift)) in LEVELS.iter().enumerate() {
+        let index = (virt >> shift) & 0x1FF;
+        let entry_addr = table + index * 8;
+        let Some(entry) = read_u64(mem, entry_addr) else {
+            steps.push(format!("{}[{}] @ {:#x}: outside guest RAM", name, index, entry_addr));
+            return Walk { steps, phys: None };
+        };
+        steps.push(format!("{}[{}] @ {:#x} = {:#018x}", name, index, entry_addr, entry));
+
+        if entry & PTE_PRESENT == 0 {
+            steps.push(format!("{} not present", name));
+            return Walk { steps, phys: None };
+        }
+        // 1 GiB pages at the PDPT, 2 MiB pages at the PD
+        let last = level == LEVELS.len() - 1 || (level > 0 && entry & PTE_HUGE != 0);
+        if last {
+            let offset_mask = (1u64 << shift) - 1;
+            let phys = (entry & PAGE_ADDR_MASK & !offset_mask) | (virt & offset_mask);
+            return Walk { steps, phys: Some(phys) };
+        }
+        table = entry & PAGE_ADDR_MASK;
+    }
+    unreachable!("the PTE level always terminates the walk")
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+/// Describe the state of a crashed vCPU: registers, the code around RIP and
+/// the page walk of the faulting address (CR2, or RIP if CR2 is clear).
+pub fn crash_dump(vcpu: &VcpuFd, mem: &GuestMemory) -> Result<Vec<String>, String> {
+    let regs = vcpu.get_regs().map_err(|e| format!("KVM_GET_REGS failed: {}", e))?;
+    let sregs = vcpu.get_sregs().map_err(|e| format!("KVM_GET_SREGS failed: {}", e))?;
+
+    let mut lines = vec![
+        format!("RIP={:#018x} RSP={:#018x} RFLAGS={:#010x}", regs.rip, regs.rsp, regs.rflags),
+        format!("RAX={:#018x} RBX={:#018x} RCX={:#018x} RDX={:#018x}", regs.rax, regs.rbx, regs.rcx, regs.rdx),
+        format!("RSI={:#018x} RDI={:#018x} RBP={:#018x}", regs.rsi, regs.rdi, regs.rbp),
+        format!("CR0={:#010x} CR2={:#018x} CR3={:#018x} CR4={:#010x} EFER={:#x}",
+            sregs.cr0, sregs.cr2, sregs.cr3, sregs.cr4, sregs.efer),
+        format!("CS={:#06x} (base={:#x} l={} db={}) SS={:#06x}",
+            sregs.cs.selector, sregs.cs.base, sregs.cs.l, sregs.cs.db, sregs.ss.selector),
+    ];
+
+    let paging = sregs.cr0 & CR0_PG != 0;
+    let long_mode = paging && sregs.efer & EFER_LMA != 0 && sregs.cr4 & CR4_LA57 == 0;
+    let translate = |virt: u64| -> Option<Walk> {
+        if long_mode {
+            Some(walk(mem, sregs.cr3, virt))
+        } else if !paging {
+            Some(Walk { steps: vec!["paging disabled".to_string()], phys: Some(virt) })
+        } else {
+            None
+        }
+    };
+
+    let rip_linear = if long_mode { regs.rip } else { sregs.cs.base.wrapping_add(regs.rip) };
+    match translate(rip_linear).and_then(|w| w.phys) {
+        Some(phys) => {
+            let start = phys.saturating_sub(CODE_CONTEXT);
+            let len = (phys - start + CODE_CONTEXT) as usize;
+            match mem.read_slice(start as usize, len) {
+                Ok(bytes) => {
+                    let split = (phys - start) as usize;
+                    lines.push(format!("Code @ {:#x}: {} <{}>", start,
+                        hex_bytes(&bytes[..split]), hex_bytes(&bytes[split..])));
+                },
+                Err(_) => lines.push(format!("Code @ {:#x}: outside guest RAM", phys)),
+            }
+        },
+        None => lines.push(format!("Code: RIP {:#x} does not translate", rip_linear)),
+    }
+
+    let fault = if sregs.cr2 != 0 { sregs.cr2 } else { rip_linear };
+    lines.push(format!("Page walk for {:#018x}:", fault));
+    match translate(fault) {
+        Some(w) => {
+            lines.extend(w.steps.into_iter().map(|s| format!("  {}", s)));
+            if let Some(phys) = w.phys {
+                lines.push(format!("  -> physical {:#x}", phys));
+            }
+        },
+        // Not walked here (32-bit, PAE or 5-level paging): ask KVM instead
+        None => match debug::translate_gva(vcpu, fault) {
+            Ok(tr) => lines.push(format!("  KVM_TRANSLATE: {}", tr)),
+            Err(e) => lines.push(format!("  unsupported paging mode and {}", e)),
+        },
+    }
+
+    Ok(lines)
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk_4k_and_2m_pages() {
+        let mut mem = GuestMemory::new(4 * 1024 * 1024).unwrap();
+        let (pml4, pdpt, pd, pt) = (0x1000u64, 0x2000u64, 0x3000u64, 0x4000u64);
+        mem.write_u64(pml4 as usize, pdpt | 0x3).unwrap();
+        mem.write_u64(pdpt as usize, pd | 0x3).unwrap();
+        // PD[0] -> page table, PD[1] -> 2 MiB page at 0x200000
+        mem.write_u64(pd as usize, pt | 0x3).unwrap();
+        mem.write_u64(pd as usize + 8, 0x200000 | PTE_HUGE | 0x3).unwrap();
+        mem.write_u64(pt as usize + 5 * 8, 0x9000 | 0x3).unwrap();
+
+        let w = walk(&mem, pml4, 0x5123);
+        assert_eq!(w.phys, Some(0x9123));
+        assert_eq!(w.steps.len(), 4);
+
+        let w = walk(&mem, pml4, 0x200000 + 0x4567);
+        assert_eq!(w.phys, Some(0x204567));
+        assert_eq!(w.steps.len(), 3);
+
+        let w = walk(&mem, pml4, 0x6000);
+        assert_eq!(w.phys, None);
+        assert_eq!(w.steps.last().unwrap(), "PTE not present");
+    }
+}
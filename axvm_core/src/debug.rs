@@ -0,0 +1,60 @@
+// src/debug.rs
+use kvm_ioctls::VcpuFd;
+use std::fmt;
+
+/// Where a guest-virtual address lands, as KVM's MMU sees it right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GvaTranslation {
+    pub gva: u64,
+    pub gpa: u64,
+    pub valid: bool,
+    pub writeable: bool,
+    pub usermode: bool,
+}
+
+impl fmt::Display for GvaTranslation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.valid {
+            return write!(f, "{:#x} -> not mapped", self.gva);
+        }
+        write!(f, "{:#x} -> {:#x} ({}, {})", self.gva, self.gpa,
+            if self.writeable { "rw" } else { "ro" },
+            if self.usermode { "user" } else { "supervisor" })
+    }
+}
+
+/// Translate `gva` with `KVM_TRANSLATE`, through whatever paging mode the
+/// vCPU is in (identity when paging is off). Meant for diagnostics only:
+/// the answer is stale as soon as the guest touches its page tables.
+pub fn translate_gva(vcpu: &VcpuFd, gva: u64) -> Result<GvaTranslation, String> {
+    let tr = vcpu.translate_gva(gva).map_err(|e| format!("KVM_TRANSLATE of {:#x} failed: {}", gva, e))?;
+    Ok(GvaTranslation {
+        gva,
+        gpa: tr.physical_address,
+        valid: tr.valid != 0,
+        writeable: tr.writeable != 0,
+        usermode: tr.usermode != 0,
+    })
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_ioctls::Kvm;
+
+    #[test]
+    fn test_translate_without_paging() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        // A fresh vCPU is in real mode: linear addresses are physical
+        let tr = translate_gva(&vcpu, 0x12345).unwrap();
+        assert_eq!((tr.gpa, tr.valid, tr.writeable), (0x12345, true, true));
+        assert_eq!(tr.to_string(), "0x12345 -> 0x12345 (rw, supervisor)");
+    }
+}
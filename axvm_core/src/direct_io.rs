@@ -0,0 +1,110 @@
+// src/direct_io.rs
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+
+/// Offset, length and buffer alignment used for `O_DIRECT` I/O. 4 KiB covers
+/// both 512-byte and 4K logical block devices.
+pub const DIRECT_IO_ALIGN: usize = 4096;
+
+/// Heap buffer whose usable slice starts on a `DIRECT_IO_ALIGN` boundary.
+struct AlignedBuf {
+    storage: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let storage = vec![0u8; len + DIRECT_IO_ALIGN];
+        let offset = storage.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        Self { storage, offset, len }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.storage[self.offset..self.offset + self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.storage[self.offset..self.offset + self.len]
+    }
+}
+
+/// Widen `[offset, offset + len)` to `DIRECT_IO_ALIGN` boundaries.
+fn aligned_range(offset: u64, len: usize) -> (u64, usize) {
+    let align = DIRECT_IO_ALIGN as u64;
+    let start = offset / align * align;
+    let end = (offset + len as u64).div_ceil(align) * align;
+    (start, (end - start) as usize)
+}
+
+/// Open `path` read/write with `O_DIRECT`, bypassing the host page cache.
+///
+/// Fails if the image size is not a multiple of `DIRECT_IO_ALIGN` or the
+/// filesystem rejects direct I/O, so the caller can fall back to buffered I/O.
+pub fn open(path: &str, size: u64) -> Result<File, String> {
+    if !size.is_multiple_of(DIRECT_IO_ALIGN as u64) {
+        return Err(format!("image size {} is not a multiple of {}", size, DIRECT_IO_ALIGN));
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+        .map_err(|e| format!("open with O_DIRECT failed: {}", e))?;
+
+    // Some filesystems accept the flag but fail the first I/O with EINVAL
+    if size > 0 {
+        let mut probe = AlignedBuf::new(DIRECT_IO_ALIGN);
+        file.read_exact_at(probe.as_mut_slice(), 0)
+            .map_err(|e| format!("O_DIRECT read failed: {}", e))?;
+    }
+    Ok(file)
+}
+
+/// Read `buf.len()` bytes at `offset` through an aligned bounce buffer.
+pub fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+    let (start, len) = aligned_range(offset, buf.len());
+    let mut bounce = AlignedBuf::new(len);
+    file.read_exact_at(bounce.as_mut_slice(), start)
+        .map_err(|e| format!("read failed: {}", e))?;
+
+    let skip = (offset - start) as usize;
+    buf.copy_from_slice(&bounce.as_slice()[skip..skip + buf.len()]);
+    Ok(())
+}
+
+/// Write `data` at `offset`, reading back the surrounding blocks first when
+/// the request does not cover them completely.
+pub fn write_at(file: &File, offset: u64, data: &[u8]) -> Result<(), String> {
+    let (start, len) = aligned_range(offset, data.len());
+    let mut bounce = AlignedBuf::new(len);
+    if start != offset || len != data.len() {
+        file.read_exact_at(bounce.as_mut_slice(), start)
+            .map_err(|e| format!("read-modify-write failed: {}", e))?;
+    }
+
+    let skip = (offset - start) as usize;
+    bounce.as_mut_slice()[skip..skip + data.len()].copy_from_slice(data);
+    file.write_all_at(bounce.as_slice(), start)
+        .map_err(|e| format!("write failed: {}", e))
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aligned_range() {
+        assert_eq!(aligned_range(0, 4096), (0, 4096));
+        assert_eq!(aligned_range(512, 512), (0, 4096));
+        assert_eq!(aligned_range(3584, 1024), (0, 8192));
+
+        let buf = AlignedBuf::new(100);
+        assert!((buf.as_slice().as_ptr() as usize).is_multiple_of(DIRECT_IO_ALIGN));
+        assert_eq!(buf.as_slice().len(), 100);
+    }
+}
@@ -0,0 +1,68 @@
+// src/dirty.rs
+use kvm_ioctls::VmFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const GUEST_MEM_SLOT: u32 = 0;
+
+const PAGE_SIZE: u64 = 4096;
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Fetch (and reset) the dirty page bitmap of a memory slot.
+///
+/// The slot must have been registered with `KVM_MEM_LOG_DIRTY_PAGES`.
+/// Bit `n` set means page `n` of the slot was written since the last call.
+pub fn get_dirty_log(vm: &VmFd, slot: u32, memory_size: usize) -> Result<Vec<u64>, String> {
+    vm.get_dirty_log(slot, memory_size)
+        .map_err(|e| format!("KVM_GET_DIRTY_LOG failed for slot {}: {}", slot, e))
+}
+
+pub fn count_dirty_pages(bitmap: &[u64]) -> u64 {
+    bitmap.iter().map(|word| word.count_ones() as u64).sum()
+}
+
+/// Periodically log how many guest pages were dirtied since the previous sample.
+pub fn spawn_dirty_stats(
+    vm_fd: Arc<Mutex<VmFd>>,
+    memory_size: usize,
+    should_stop: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while !should_stop.load(Ordering::Relaxed) {
+            thread::sleep(STATS_INTERVAL);
+
+            let bitmap = match vm_fd.lock() {
+                Ok(vm) => get_dirty_log(&vm, GUEST_MEM_SLOT, memory_size),
+                Err(_) => break,
+            };
+            match bitmap {
+                Ok(bitmap) => {
+                    let pages = count_dirty_pages(&bitmap);
+                    status!("Dirty", pages = pages, "{} pages ({} KB) dirtied in last {:?}",
+                        pages, pages * PAGE_SIZE / 1024, STATS_INTERVAL);
+                },
+                Err(e) => {
+                    status!(warn, "Dirty", error = %e, "{}. Dirty stats disabled.", e);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_dirty_pages() {
+        assert_eq!(count_dirty_pages(&[]), 0);
+        assert_eq!(count_dirty_pages(&[0b1011, 0, u64::MAX]), 3 + 64);
+    }
+}
@@ -7,6 +7,7 @@
 
 use std::fmt;
 use std::io;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
 
 
@@ -124,6 +125,26 @@ impl From<io::Error> for AxvmError {
     }
 }
 
+impl<T> From<PoisonError<T>> for AxvmError {
+    fn from(err: PoisonError<T>) -> Self {
+        Self::LockPoisoned(err.to_string())
+    }
+}
+
+/// `Mutex::lock` that reports a poisoned lock as `AxvmError::LockPoisoned`
+/// instead of panicking the calling thread.
+pub trait LockExt<T> {
+    fn lock_or_err(&self) -> AxvmResult<MutexGuard<'_, T>>;
+}
+
+impl<T> LockExt<T> for Mutex<T> {
+    fn lock_or_err(&self) -> AxvmResult<MutexGuard<'_, T>> {
+        self.lock().map_err(|_| {
+            AxvmError::LockPoisoned(format!("Mutex<{}> poisoned", std::any::type_name::<T>()))
+        })
+    }
+}
+
 
 
 
@@ -230,4 +251,20 @@ mod tests {
         let axvm_err: AxvmError = io_err.into();
         assert!(matches!(axvm_err, AxvmError::IoError(_)));
     }
+
+    #[test]
+    fn test_lock_or_err_reports_poisoning() {
+        let mutex = std::sync::Arc::new(Mutex::new(0u32));
+        assert_eq!(*mutex.lock_or_err().unwrap(), 0);
+
+        let m = std::sync::Arc::clone(&mutex);
+        let _ = std::thread::spawn(move || {
+            let _guard = m.lock().unwrap();
+            panic!("poison");
+        }).join();
+
+        let err = mutex.lock_or_err().unwrap_err();
+        assert!(matches!(err, AxvmError::LockPoisoned(_)));
+        assert!(err.requires_shutdown());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,84 @@
+// src/exit_trace.rs
+use kvm_ioctls::VcpuExit;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+use crate::vcpu;
+
+/// Target of the trace events, enabled at `info` by `--trace-exits`
+/// whatever the `-v` level.
+pub const EXIT_TRACE_TARGET: &str = "axvm::exits";
+
+// Per vCPU; a busy guest exits far more often than anyone can read
+const MAX_TRACES_PER_WINDOW: u32 = 200;
+const TRACE_WINDOW: Duration = Duration::from_secs(1);
+
+/// `--trace-exits`: logs each vm-exit of one vCPU with the guest RIP,
+/// dropping (and counting) whatever exceeds the per-second budget.
+pub struct ExitTracer {
+    cpu_id: u8,
+    fd: RawFd,
+    window_start: Instant,
+    traced: u32,
+    suppressed: u64,
+}
+
+impl ExitTracer {
+    pub fn new(cpu_id: u8, fd: RawFd) -> Self {
+        Self { cpu_id, fd, window_start: Instant::now(), traced: 0, suppressed: 0 }
+    }
+
+    pub fn trace(&mut self, exit: &VcpuExit) {
+        if self.window_start.elapsed() >= TRACE_WINDOW {
+            if self.suppressed > 0 {
+                tracing::info!(target: EXIT_TRACE_TARGET, cpu_id = self.cpu_id, suppressed = self.suppressed,
+                    "{} vm-exits not traced (over {} per {:?})", self.suppressed, MAX_TRACES_PER_WINDOW, TRACE_WINDOW);
+            }
+            self.window_start = Instant::now();
+            self.traced = 0;
+            self.suppressed = 0;
+        }
+        if self.traced == MAX_TRACES_PER_WINDOW {
+            self.suppressed += 1;
+            return;
+        }
+        self.traced += 1;
+
+        let cpu_id = self.cpu_id;
+        let rip = vcpu::read_rip(self.fd).unwrap_or(0);
+        match exit {
+            VcpuExit::IoOut(port, data) => tracing::info!(target: EXIT_TRACE_TARGET,
+                cpu_id, rip, port, size = data.len(), data = ?data, "vm-exit: IoOut {:#x} at rip {:#x}", port, rip),
+            VcpuExit::IoIn(port, data) => tracing::info!(target: EXIT_TRACE_TARGET,
+                cpu_id, rip, port, size = data.len(), "vm-exit: IoIn {:#x} at rip {:#x}", port, rip),
+            VcpuExit::MmioWrite(addr, data) => tracing::info!(target: EXIT_TRACE_TARGET,
+                cpu_id, rip, addr, size = data.len(), data = ?data, "vm-exit: MmioWrite {:#x} at rip {:#x}", addr, rip),
+            VcpuExit::MmioRead(addr, data) => tracing::info!(target: EXIT_TRACE_TARGET,
+                cpu_id, rip, addr, size = data.len(), "vm-exit: MmioRead {:#x} at rip {:#x}", addr, rip),
+            other => tracing::info!(target: EXIT_TRACE_TARGET,
+                cpu_id, rip, exit = ?other, "vm-exit: {:?} at rip {:#x}", other, rip),
+        }
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traces_are_rate_limited() {
+        let mut tracer = ExitTracer::new(0, -1);
+        for _ in 0..MAX_TRACES_PER_WINDOW + 5 {
+            tracer.trace(&VcpuExit::Hlt);
+        }
+        assert_eq!((tracer.traced, tracer.suppressed), (MAX_TRACES_PER_WINDOW, 5));
+
+        tracer.window_start -= TRACE_WINDOW;
+        tracer.trace(&VcpuExit::Hlt);
+        assert_eq!((tracer.traced, tracer.suppressed), (1, 0));
+    }
+}
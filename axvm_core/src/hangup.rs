@@ -0,0 +1,47 @@
+// src/hangup.rs
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static HANGUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_hangup(_: libc::c_int) {
+    HANGUP_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// Record `SIGHUP` instead of terminating the process.
+pub fn install_hangup_handler() -> std::io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_hangup as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Whether a `SIGHUP` arrived since the last call.
+fn take_hangup() -> bool {
+    HANGUP_RECEIVED.swap(false, Ordering::SeqCst)
+}
+
+/// Run `on_hangup` on a helper thread for every `SIGHUP`, until `should_stop`.
+pub fn spawn_hangup_watcher(
+    should_stop: Arc<AtomicBool>,
+    on_hangup: impl Fn() + Send + 'static,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while !should_stop.load(Ordering::Relaxed) {
+            thread::sleep(POLL_INTERVAL);
+            if take_hangup() {
+                on_hangup();
+            }
+        }
+    })
+}
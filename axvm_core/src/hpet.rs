@@ -0,0 +1,225 @@
+// src/hpet.rs
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub const HPET_BASE: u64 = 0xFED00000;
+pub const HPET_SIZE: u64 = 0x400;
+
+pub const HPET_NUM_TIMERS: usize = 3;
+
+// 10 MHz main counter, period in femtoseconds
+const HPET_PERIOD_FS: u64 = 100_000_000;
+const HPET_VENDOR_ID: u64 = 0x8086;
+const HPET_REV_ID: u64 = 0x01;
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_INT_STATUS: u64 = 0x020;
+const REG_MAIN_COUNTER: u64 = 0x0F0;
+const REG_TIMER_BASE: u64 = 0x100;
+const REG_TIMER_STRIDE: u64 = 0x20;
+
+const CAP_COUNT_SIZE_64: u64 = 1 << 13;
+
+// LEG_RT_CNF (bit 1) is not supported and reads back as zero
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+const TIMER_INT_TYPE: u64 = 1 << 1;
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_TYPE_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_SIZE_64_CAP: u64 = 1 << 5;
+const TIMER_VAL_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_INT_ROUTE: u64 = 0x1F << 9;
+const TIMER_WRITABLE: u64 = TIMER_INT_TYPE | TIMER_INT_ENABLE | TIMER_TYPE_PERIODIC
+    | TIMER_VAL_SET | TIMER_32BIT_MODE | TIMER_INT_ROUTE;
+// Routable to IOAPIC pins 20-23, away from the legacy and virtio lines
+const TIMER_ROUTE_CAP: u64 = 0x00F0_0000 << 32;
+
+#[derive(Default, Clone, Copy)]
+struct Timer {
+    config: u64,
+    comparator: u64,
+}
+
+struct HpetState {
+    config: u64,
+    int_status: u64,
+    // Counter value at `started`, or the frozen value while halted
+    counter_base: u64,
+    started: Option<Instant>,
+    timers: [Timer; HPET_NUM_TIMERS],
+}
+
+impl HpetState {
+    fn counter(&self) -> u64 {
+        match self.started {
+            Some(start) => {
+                let ticks = (start.elapsed().as_nanos() * 1_000_000 / HPET_PERIOD_FS as u128) as u64;
+                self.counter_base.wrapping_add(ticks)
+            },
+            None => self.counter_base,
+        }
+    }
+}
+
+/// Minimal HPET at 0xFED00000.
+///
+/// Only the main counter is live: it ticks at 10 MHz off the host monotonic
+/// clock while enabled, which is enough for the guest to calibrate and use it
+/// as a clocksource. Timer comparators are stored but never fire, and legacy
+/// replacement routing is not advertised so the guest keeps the PIT/LAPIC for
+/// clock events.
+pub struct Hpet {
+    state: Mutex<HpetState>,
+}
+
+impl Hpet {
+    pub fn new() -> Self {
+        let timer = Timer {
+            config: TIMER_PERIODIC_CAP | TIMER_SIZE_64_CAP | TIMER_ROUTE_CAP,
+            comparator: u64::MAX,
+        };
+        Self {
+            state: Mutex::new(HpetState {
+                config: 0,
+                int_status: 0,
+                counter_base: 0,
+                started: None,
+                timers: [timer; HPET_NUM_TIMERS],
+            }),
+        }
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        (HPET_BASE..HPET_BASE + HPET_SIZE).contains(&addr)
+    }
+
+    fn capabilities() -> u64 {
+        (HPET_PERIOD_FS << 32)
+            | (HPET_VENDOR_ID << 16)
+            | CAP_COUNT_SIZE_64
+            | (((HPET_NUM_TIMERS - 1) as u64) << 8)
+            | HPET_REV_ID
+    }
+
+    fn timer_reg(offset: u64) -> Option<(usize, u64)> {
+        let rel = offset.checked_sub(REG_TIMER_BASE)?;
+        let index = (rel / REG_TIMER_STRIDE) as usize;
+        (index < HPET_NUM_TIMERS).then_some((index, rel % REG_TIMER_STRIDE))
+    }
+
+    fn read_reg(&self, reg: u64) -> u64 {
+        let state = self.state.lock().unwrap();
+        match reg {
+            REG_CAPABILITIES => Self::capabilities(),
+            REG_CONFIG => state.config,
+            REG_INT_STATUS => state.int_status,
+            REG_MAIN_COUNTER => state.counter(),
+            _ => match Self::timer_reg(reg) {
+                Some((i, 0x00)) => state.timers[i].config,
+                Some((i, 0x08)) => state.timers[i].comparator,
+                _ => 0,
+            },
+        }
+    }
+
+    fn write_reg(&self, reg: u64, value: u64, mask: u64) {
+        let mut state = self.state.lock().unwrap();
+        let merge = |old: u64| (old & !mask) | (value & mask);
+        match reg {
+            REG_CONFIG => {
+                let was_enabled = state.config & CONFIG_ENABLE != 0;
+                state.config = merge(state.config) & CONFIG_ENABLE;
+                match (was_enabled, state.config & CONFIG_ENABLE != 0) {
+                    (false, true) => state.started = Some(Instant::now()),
+                    (true, false) => {
+                        state.counter_base = state.counter();
+                        state.started = None;
+                    },
+                    _ => {}
+                }
+            },
+            // Write-1-to-clear
+            REG_INT_STATUS => state.int_status &= !(value & mask),
+            // Only writable while the counter is halted
+            REG_MAIN_COUNTER if state.started.is_none() => state.counter_base = merge(state.counter_base),
+            _ => match Self::timer_reg(reg) {
+                Some((i, 0x00)) => {
+                    let timer = &mut state.timers[i];
+                    timer.config = (timer.config & !TIMER_WRITABLE) | (merge(timer.config) & TIMER_WRITABLE);
+                },
+                Some((i, 0x08)) => state.timers[i].comparator = merge(state.timers[i].comparator),
+                _ => {}
+            },
+        }
+    }
+
+    /// Handle a 4- or 8-byte read at `offset` from the HPET base.
+    pub fn read(&self, offset: u64, data: &mut [u8]) {
+        let shift = (offset & 7) * 8;
+        let value = self.read_reg(offset & !7) >> shift;
+        let bytes = value.to_le_bytes();
+        let len = data.len().min(8);
+        data[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    /// Handle a 4- or 8-byte write at `offset` from the HPET base.
+    pub fn write(&self, offset: u64, data: &[u8]) {
+        let len = data.len().min(8);
+        let mut bytes = [0u8; 8];
+        bytes[..len].copy_from_slice(&data[..len]);
+
+        let shift = (offset & 7) * 8;
+        let mask = if len == 8 { u64::MAX } else { ((1u64 << (len * 8)) - 1) << shift };
+        self.write_reg(offset & !7, u64::from_le_bytes(bytes) << shift, mask);
+    }
+}
+
+impl Default for Hpet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn read_u64(hpet: &Hpet, offset: u64) -> u64 {
+        let mut data = [0u8; 8];
+        hpet.read(offset, &mut data);
+        u64::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_counter_runs_only_when_enabled() {
+        let hpet = Hpet::new();
+
+        let mut period = [0u8; 4];
+        hpet.read(REG_CAPABILITIES + 4, &mut period);
+        assert_eq!(u32::from_le_bytes(period) as u64, HPET_PERIOD_FS);
+
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(read_u64(&hpet, REG_MAIN_COUNTER), 0);
+
+        hpet.write(REG_MAIN_COUNTER, &1000u64.to_le_bytes());
+        hpet.write(REG_CONFIG, &(CONFIG_ENABLE as u32).to_le_bytes());
+        thread::sleep(Duration::from_millis(2));
+        let running = read_u64(&hpet, REG_MAIN_COUNTER);
+        // 2 ms at 10 MHz
+        assert!(running >= 1000 + 20_000, "counter only reached {}", running);
+
+        hpet.write(REG_CONFIG, &0u32.to_le_bytes());
+        let halted = read_u64(&hpet, REG_MAIN_COUNTER);
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(read_u64(&hpet, REG_MAIN_COUNTER), halted);
+    }
+}
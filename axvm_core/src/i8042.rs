@@ -0,0 +1,337 @@
+// src/i8042.rs
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const I8042_DATA_PORT: u16 = 0x60;
+pub const I8042_COMMAND_PORT: u16 = 0x64;
+pub const I8042_KBD_IRQ: u32 = 1;
+
+// Status register
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_SYSTEM_FLAG: u8 = 1 << 2;
+const STATUS_COMMAND: u8 = 1 << 3;
+
+// Controller configuration byte
+const CTR_KBD_INT: u8 = 1 << 0;
+const CTR_SYSTEM_FLAG: u8 = 1 << 2;
+const CTR_KBD_DISABLE: u8 = 1 << 4;
+const CTR_AUX_DISABLE: u8 = 1 << 5;
+const CTR_XLATE: u8 = 1 << 6;
+
+// Controller commands (port 0x64)
+const CMD_READ_CTR: u8 = 0x20;
+const CMD_WRITE_CTR: u8 = 0x60;
+const CMD_AUX_TEST: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_KBD_TEST: u8 = 0xAB;
+const CMD_KBD_DISABLE: u8 = 0xAD;
+const CMD_KBD_ENABLE: u8 = 0xAE;
+const CMD_WRITE_OUTPUT_PORT: u8 = 0xD1;
+const CMD_WRITE_KBD_OUTBUF: u8 = 0xD2;
+const CMD_PULSE_RESET: u8 = 0xFE;
+
+// Keyboard device commands (port 0x60)
+const KBD_CMD_SET_LEDS: u8 = 0xED;
+const KBD_CMD_ECHO: u8 = 0xEE;
+const KBD_CMD_SCANCODE_SET: u8 = 0xF0;
+const KBD_CMD_GET_ID: u8 = 0xF2;
+const KBD_CMD_SET_RATE: u8 = 0xF3;
+const KBD_CMD_RESET: u8 = 0xFF;
+
+const KBD_ACK: u8 = 0xFA;
+const KBD_SELF_TEST_OK: u8 = 0xAA;
+const SELF_TEST_OK: u8 = 0x55;
+
+// Set 1 scancodes
+const SC_LSHIFT: u8 = 0x2A;
+const SC_LCTRL: u8 = 0x1D;
+const SC_BREAK: u8 = 0x80;
+
+/// What the vCPU loop must do after a port write.
+#[derive(Debug, PartialEq, Eq)]
+pub enum I8042Event {
+    None,
+    Interrupt,
+    Reset,
+}
+
+// Multi-byte command awaiting its argument on the data port
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pending {
+    None,
+    WriteCtr,
+    WriteOutputPort,
+    WriteKbdOutbuf,
+    KbdArgument,
+}
+
+struct I8042State {
+    ctr: u8,
+    output: VecDeque<u8>,
+    pending: Pending,
+    last_was_command: bool,
+}
+
+/// PS/2 controller with a keyboard on the first port and no AUX device.
+pub struct I8042 {
+    state: Mutex<I8042State>,
+}
+
+impl I8042 {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(I8042State {
+                ctr: CTR_KBD_INT | CTR_SYSTEM_FLAG | CTR_AUX_DISABLE | CTR_XLATE,
+                output: VecDeque::new(),
+                pending: Pending::None,
+                last_was_command: false,
+            }),
+        }
+    }
+
+    pub fn read(&self, port: u16) -> u8 {
+        let mut st = self.state.lock().unwrap();
+        match port {
+            I8042_DATA_PORT => st.output.pop_front().unwrap_or(0),
+            I8042_COMMAND_PORT => {
+                let mut status = STATUS_SYSTEM_FLAG;
+                if !st.output.is_empty() {
+                    status |= STATUS_OUTPUT_FULL;
+                }
+                if st.last_was_command {
+                    status |= STATUS_COMMAND;
+                }
+                status
+            },
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&self, port: u16, data: &[u8]) -> I8042Event {
+        let Some(&val) = data.first() else { return I8042Event::None };
+        let mut st = self.state.lock().unwrap();
+
+        match port {
+            I8042_COMMAND_PORT => {
+                st.last_was_command = true;
+                match val {
+                    CMD_READ_CTR => {
+                        let ctr = st.ctr;
+                        st.output.push_back(ctr);
+                    },
+                    CMD_WRITE_CTR => st.pending = Pending::WriteCtr,
+                    CMD_AUX_TEST => st.output.push_back(0xFF), // no AUX port
+                    CMD_SELF_TEST => st.output.push_back(SELF_TEST_OK),
+                    CMD_KBD_TEST => st.output.push_back(0x00),
+                    CMD_KBD_DISABLE => st.ctr |= CTR_KBD_DISABLE,
+                    CMD_KBD_ENABLE => st.ctr &= !CTR_KBD_DISABLE,
+                    CMD_WRITE_OUTPUT_PORT => st.pending = Pending::WriteOutputPort,
+                    CMD_WRITE_KBD_OUTBUF => st.pending = Pending::WriteKbdOutbuf,
+                    CMD_PULSE_RESET => {
+                        tracing::info!("i8042 reset pulse requested by guest");
+                        return I8042Event::Reset;
+                    },
+                    _ => tracing::debug!(cmd = val, "Unhandled i8042 command"),
+                }
+                I8042Event::None
+            },
+            I8042_DATA_PORT => {
+                st.last_was_command = false;
+                let pending = std::mem::replace(&mut st.pending, Pending::None);
+                match pending {
+                    Pending::WriteCtr => {
+                        st.ctr = val;
+                        return I8042Event::None;
+                    },
+                    // Output port bit 0 low holds the CPU in reset
+                    Pending::WriteOutputPort if val & 1 == 0 => return I8042Event::Reset,
+                    Pending::WriteOutputPort => return I8042Event::None,
+                    Pending::WriteKbdOutbuf => st.output.push_back(val),
+                    Pending::KbdArgument => st.output.push_back(KBD_ACK),
+                    Pending::None => Self::keyboard_command(&mut st, val),
+                }
+                Self::irq_event(&st)
+            },
+            _ => I8042Event::None,
+        }
+    }
+
+    fn keyboard_command(st: &mut I8042State, val: u8) {
+        match val {
+            KBD_CMD_RESET => {
+                st.output.push_back(KBD_ACK);
+                st.output.push_back(KBD_SELF_TEST_OK);
+            },
+            KBD_CMD_GET_ID => {
+                st.output.push_back(KBD_ACK);
+                st.output.push_back(0xAB);
+                st.output.push_back(0x83);
+            },
+            KBD_CMD_ECHO => st.output.push_back(KBD_CMD_ECHO),
+            KBD_CMD_SET_LEDS | KBD_CMD_SCANCODE_SET | KBD_CMD_SET_RATE => {
+                st.output.push_back(KBD_ACK);
+                st.pending = Pending::KbdArgument;
+            },
+            _ => st.output.push_back(KBD_ACK),
+        }
+    }
+
+    fn irq_event(st: &I8042State) -> I8042Event {
+        if !st.output.is_empty() && st.ctr & CTR_KBD_INT != 0 && st.ctr & CTR_KBD_DISABLE == 0 {
+            I8042Event::Interrupt
+        } else {
+            I8042Event::None
+        }
+    }
+
+    /// True if a byte is waiting and the guest wants keyboard interrupts.
+    pub fn interrupt_pending(&self) -> bool {
+        Self::irq_event(&self.state.lock().unwrap()) == I8042Event::Interrupt
+    }
+
+    /// Queue the make/break sequence for a host byte. Returns false if unmapped.
+    pub fn push_key(&self, byte: u8) -> bool {
+        let Some((code, shift, ctrl)) = ascii_to_scancode(byte) else { return false };
+        let mut st = self.state.lock().unwrap();
+
+        if ctrl {
+            st.output.push_back(SC_LCTRL);
+        }
+        if shift {
+            st.output.push_back(SC_LSHIFT);
+        }
+        st.output.push_back(code);
+        st.output.push_back(code | SC_BREAK);
+        if shift {
+            st.output.push_back(SC_LSHIFT | SC_BREAK);
+        }
+        if ctrl {
+            st.output.push_back(SC_LCTRL | SC_BREAK);
+        }
+        true
+    }
+
+    /// Feed host stdin into the keyboard, calling `raise_irq` when input is queued.
+    pub fn spawn_stdin_reader<F>(self: Arc<Self>, should_stop: Arc<AtomicBool>, raise_irq: F) -> thread::JoinHandle<()>
+    where
+        F: Fn() + Send + 'static,
+    {
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let mut buf = [0u8; 64];
+            while !should_stop.load(Ordering::Relaxed) {
+                let n = match stdin.lock().read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                let mut queued = false;
+                for &b in &buf[..n] {
+                    queued |= self.push_key(b);
+                }
+                if queued && self.interrupt_pending() {
+                    raise_irq();
+                }
+            }
+        })
+    }
+}
+
+impl Default for I8042 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Returns (set 1 make code, needs shift, needs ctrl).
+fn ascii_to_scancode(byte: u8) -> Option<(u8, bool, bool)> {
+    const LETTERS: [u8; 26] = [
+        0x1E, 0x30, 0x2E, 0x20, 0x12, 0x21, 0x22, 0x23, 0x17, 0x24, 0x25, 0x26, 0x32,
+        0x31, 0x18, 0x19, 0x10, 0x13, 0x1F, 0x14, 0x16, 0x2F, 0x11, 0x2D, 0x15, 0x2C,
+    ];
+    const DIGITS: [u8; 10] = [0x0B, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A];
+    const SHIFTED_DIGITS: &[u8; 10] = b")!@#$%^&*(";
+
+    let mapped = match byte {
+        b'a'..=b'z' => (LETTERS[(byte - b'a') as usize], false, false),
+        b'A'..=b'Z' => (LETTERS[(byte - b'A') as usize], true, false),
+        b'0'..=b'9' => (DIGITS[(byte - b'0') as usize], false, false),
+        // Ctrl+letter arrives as 0x01..=0x1A (except the codes below)
+        b'\n' | b'\r' => (0x1C, false, false),
+        b'\t' => (0x0F, false, false),
+        0x08 | 0x7F => (0x0E, false, false),
+        0x1B => (0x01, false, false),
+        0x01..=0x1A => (LETTERS[(byte - 1) as usize], false, true),
+        b' ' => (0x39, false, false),
+        b'-' => (0x0C, false, false),
+        b'_' => (0x0C, true, false),
+        b'=' => (0x0D, false, false),
+        b'+' => (0x0D, true, false),
+        b'[' => (0x1A, false, false),
+        b'{' => (0x1A, true, false),
+        b']' => (0x1B, false, false),
+        b'}' => (0x1B, true, false),
+        b';' => (0x27, false, false),
+        b':' => (0x27, true, false),
+        b'\'' => (0x28, false, false),
+        b'"' => (0x28, true, false),
+        b'`' => (0x29, false, false),
+        b'~' => (0x29, true, false),
+        b'\\' => (0x2B, false, false),
+        b'|' => (0x2B, true, false),
+        b',' => (0x33, false, false),
+        b'<' => (0x33, true, false),
+        b'.' => (0x34, false, false),
+        b'>' => (0x34, true, false),
+        b'/' => (0x35, false, false),
+        b'?' => (0x35, true, false),
+        _ => {
+            let pos = SHIFTED_DIGITS.iter().position(|&c| c == byte)?;
+            (DIGITS[pos], true, false)
+        }
+    };
+    Some(mapped)
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_controller_self_test_and_ctr() {
+        let kbd = I8042::new();
+        assert_eq!(kbd.write(I8042_COMMAND_PORT, &[CMD_SELF_TEST]), I8042Event::None);
+        assert_ne!(kbd.read(I8042_COMMAND_PORT) & STATUS_OUTPUT_FULL, 0);
+        assert_eq!(kbd.read(I8042_DATA_PORT), SELF_TEST_OK);
+
+        kbd.write(I8042_COMMAND_PORT, &[CMD_WRITE_CTR]);
+        kbd.write(I8042_DATA_PORT, &[CTR_KBD_INT | CTR_XLATE]);
+        kbd.write(I8042_COMMAND_PORT, &[CMD_READ_CTR]);
+        assert_eq!(kbd.read(I8042_DATA_PORT), CTR_KBD_INT | CTR_XLATE);
+        assert_eq!(kbd.read(I8042_COMMAND_PORT) & STATUS_OUTPUT_FULL, 0);
+    }
+
+    #[test]
+    fn test_keyboard_reset_and_keys() {
+        let kbd = I8042::new();
+        assert_eq!(kbd.write(I8042_DATA_PORT, &[KBD_CMD_RESET]), I8042Event::Interrupt);
+        assert_eq!(kbd.read(I8042_DATA_PORT), KBD_ACK);
+        assert_eq!(kbd.read(I8042_DATA_PORT), KBD_SELF_TEST_OK);
+
+        assert!(kbd.push_key(b'A'));
+        let codes: Vec<u8> = (0..4).map(|_| kbd.read(I8042_DATA_PORT)).collect();
+        assert_eq!(codes, vec![SC_LSHIFT, 0x1E, 0x9E, SC_LSHIFT | SC_BREAK]);
+    }
+
+    #[test]
+    fn test_reset_pulse() {
+        let kbd = I8042::new();
+        assert_eq!(kbd.write(I8042_COMMAND_PORT, &[CMD_PULSE_RESET]), I8042Event::Reset);
+    }
+}
@@ -0,0 +1,85 @@
+// src/idle.rs
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Wakes halted vCPU threads when a device raises an interrupt.
+///
+/// With the in-kernel irqchip KVM normally handles HLT itself; this covers
+/// the exits that do reach userspace so they sleep instead of spinning.
+pub struct IdleWaker {
+    generation: Mutex<u64>,
+    cond: Condvar,
+}
+
+impl IdleWaker {
+    pub fn new() -> Self {
+        Self {
+            generation: Mutex::new(0),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Snapshot to pass to `wait`; take it before entering the guest so an
+    /// interrupt raised while it runs is not missed.
+    pub fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn notify(&self) {
+        let mut generation = self.generation.lock().unwrap_or_else(|e| e.into_inner());
+        *generation = generation.wrapping_add(1);
+        self.cond.notify_all();
+    }
+
+    /// Block until `notify` is called after `seen` was taken, or `timeout` elapses.
+    /// Returns `true` if woken by a notification.
+    pub fn wait(&self, seen: u64, timeout: Duration) -> bool {
+        let guard = self.generation.lock().unwrap_or_else(|e| e.into_inner());
+        let (guard, _) = self.cond
+            .wait_timeout_while(guard, timeout, |generation| *generation == seen)
+            .unwrap_or_else(|e| e.into_inner());
+        *guard != seen
+    }
+}
+
+impl Default for IdleWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_wait_wakes_on_notify_and_times_out() {
+        let waker = Arc::new(IdleWaker::new());
+
+        let seen = waker.generation();
+        let start = Instant::now();
+        assert!(!waker.wait(seen, Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let seen = waker.generation();
+        let w = Arc::clone(&waker);
+        let notifier = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            w.notify();
+        });
+        assert!(waker.wait(seen, Duration::from_secs(10)));
+        notifier.join().unwrap();
+
+        // A notification that raced ahead of the wait is not lost
+        let seen = waker.generation();
+        waker.notify();
+        assert!(waker.wait(seen, Duration::from_secs(10)));
+    }
+}
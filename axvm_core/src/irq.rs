@@ -0,0 +1,335 @@
+// src/irq.rs
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use kvm_bindings::{
+    kvm_irq_routing, kvm_irq_routing_entry, kvm_irq_routing_irqchip,
+    KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_IRQ_ROUTING_IRQCHIP,
+};
+use kvm_ioctls::VmFd;
+
+use crate::error::{AxvmResult, LockExt};
+use crate::idle::IdleWaker;
+use crate::metrics::VmMetrics;
+
+// IOAPIC input pins
+const IRQ_LINES: usize = 24;
+
+// GSIs wired to the cascaded PICs as well as the IOAPIC, as on a PC
+const PIC_LINES: u32 = 16;
+
+fn irqchip_route(gsi: u32, irqchip: u32, pin: u32) -> kvm_irq_routing_entry {
+    let mut entry = kvm_irq_routing_entry { gsi, type_: KVM_IRQ_ROUTING_IRQCHIP, ..Default::default() };
+    entry.u.irqchip = kvm_irq_routing_irqchip { irqchip, pin };
+    entry
+}
+
+/// The routing KVM sets up by itself, spelled out: GSI n drives IOAPIC pin
+/// n and, below 16, pin n % 8 of the master or slave PIC. MSI routes can
+/// be appended to this table later.
+pub fn legacy_routing() -> Vec<kvm_irq_routing_entry> {
+    (0..IRQ_LINES as u32).flat_map(|gsi| {
+        let pic = (gsi < PIC_LINES).then(|| {
+            let chip = if gsi < 8 { KVM_IRQCHIP_PIC_MASTER } else { KVM_IRQCHIP_PIC_SLAVE };
+            irqchip_route(gsi, chip, gsi % 8)
+        });
+        std::iter::once(irqchip_route(gsi, KVM_IRQCHIP_IOAPIC, gsi)).chain(pic)
+    }).collect()
+}
+
+/// Replace the VM's whole GSI routing table with `routes` (KVM_SET_GSI_ROUTING).
+/// Any GSI missing from `routes` stops reaching the guest.
+pub fn set_gsi_routing(vm: &VmFd, routes: &[kvm_irq_routing_entry]) -> Result<(), String> {
+    // kvm_irq_routing ends in a flexible array; back it with enough whole headers
+    let header = std::mem::size_of::<kvm_irq_routing>();
+    let len = header + std::mem::size_of_val(routes);
+    let mut buf: Vec<kvm_irq_routing> = (0..len.div_ceil(header)).map(|_| Default::default()).collect();
+    buf[0].nr = routes.len() as u32;
+    // SAFETY: `buf` holds at least `routes.len()` entries after the header
+    unsafe { buf[0].entries.as_mut_slice(routes.len()).copy_from_slice(routes) };
+    vm.set_gsi_routing(&buf[0]).map_err(|e| format!("KVM_SET_GSI_ROUTING failed: {}", e))
+}
+
+/// Device class an interrupt line is counted under in the metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    Block,
+    Net,
+    Serial,
+}
+
+#[derive(Default)]
+struct IrqLine {
+    source: Option<IrqSource>,
+    // An event arrived that has not been covered by an edge yet
+    pending: AtomicBool,
+    // Some thread is currently toggling this line
+    injecting: AtomicBool,
+    // Injection has failed before; later failures are not logged again
+    failed: AtomicBool,
+    // Current level of a level-triggered line
+    level: Mutex<bool>,
+}
+
+/// Drives interrupt lines on the in-kernel irqchip.
+///
+/// Edge sources call `pulse`: pulses of a line that arrive while another
+/// thread is already injecting it are folded into one extra edge from that
+/// thread instead of each taking the VM lock, so a burst of device events
+/// costs at most two edges. Level sources call `set_level`, which only
+/// touches the irqchip when the level actually changes.
+pub struct IrqManager {
+    vm_fd: Arc<Mutex<VmFd>>,
+    waker: Arc<IdleWaker>,
+    metrics: Arc<VmMetrics>,
+    lines: Vec<IrqLine>,
+}
+
+impl IrqManager {
+    pub fn new(vm_fd: Arc<Mutex<VmFd>>, waker: Arc<IdleWaker>, metrics: Arc<VmMetrics>) -> Self {
+        Self {
+            vm_fd,
+            waker,
+            metrics,
+            lines: (0..IRQ_LINES).map(|_| IrqLine::default()).collect(),
+        }
+    }
+
+    /// Count interrupts raised on `irq` as coming from `source`.
+    pub fn with_source(mut self, irq: u32, source: IrqSource) -> Self {
+        if let Some(line) = self.lines.get_mut(irq as usize) {
+            line.source = Some(source);
+        }
+        self
+    }
+
+    fn record_raised(&self, line: &IrqLine) {
+        match line.source {
+            Some(IrqSource::Block) => self.metrics.record_blk_irq(),
+            Some(IrqSource::Net) => self.metrics.record_net_irq(),
+            Some(IrqSource::Serial) => self.metrics.record_serial_irq(),
+            None => {},
+        }
+    }
+
+    /// Signal one interrupt on `irq` and wake any halted vCPU.
+    pub fn pulse(&self, irq: u32) {
+        self.waker.notify();
+        let Some(line) = self.lines.get(irq as usize) else {
+            self.inject(irq, &IrqLine::default());
+            return;
+        };
+
+        line.pending.store(true, Ordering::Release);
+        loop {
+            if line.injecting.swap(true, Ordering::AcqRel) {
+                // The injecting thread re-checks `pending` before it lets go
+                return;
+            }
+            while line.pending.swap(false, Ordering::AcqRel) {
+                self.inject(irq, line);
+            }
+            line.injecting.store(false, Ordering::Release);
+            if !line.pending.load(Ordering::Acquire) {
+                return;
+            }
+        }
+    }
+
+    /// Drive `irq` to the level returned by `sample`. `sample` runs under the
+    /// line's lock, so racing updates cannot leave the line at a stale level
+    /// as long as every change of the source is followed by a call. A line
+    /// whose source cannot be sampled keeps its level.
+    pub fn set_level(&self, irq: u32, sample: impl FnOnce() -> AxvmResult<bool>) {
+        let Some(line) = self.lines.get(irq as usize) else {
+            if self.sample_level(irq, sample) == Some(true) {
+                self.inject(irq, &IrqLine::default());
+            }
+            return;
+        };
+
+        let mut current = match line.level.lock_or_err() {
+            Ok(current) => current,
+            Err(e) => {
+                tracing::error!(irq = irq, error = %e, "Failed to lock IRQ line state");
+                self.metrics.record_error();
+                return;
+            }
+        };
+        let Some(level) = self.sample_level(irq, sample) else { return };
+        if level == *current {
+            return;
+        }
+        if level {
+            self.waker.notify();
+        }
+        if self.drive(irq, line, level) {
+            *current = level;
+        }
+    }
+
+    fn sample_level(&self, irq: u32, sample: impl FnOnce() -> AxvmResult<bool>) -> Option<bool> {
+        match sample() {
+            Ok(level) => Some(level),
+            Err(e) => {
+                tracing::error!(irq = irq, error = %e, "Failed to sample IRQ line level");
+                self.metrics.record_error();
+                None
+            }
+        }
+    }
+
+    fn inject(&self, irq: u32, line: &IrqLine) {
+        let vm = match self.vm_fd.lock_or_err() {
+            Ok(vm) => vm,
+            Err(e) => {
+                tracing::error!(irq = irq, error = %e, "Failed to lock VM fd for IRQ");
+                self.metrics.record_error();
+                return;
+            }
+        };
+        let result = vm.set_irq_line(irq, true)
+            .and_then(|_| vm.set_irq_line(irq, false));
+        match result {
+            Ok(()) => self.record_raised(line),
+            Err(e) => self.injection_failed(irq, line, &e),
+        }
+    }
+
+    fn drive(&self, irq: u32, line: &IrqLine, level: bool) -> bool {
+        let result = self.vm_fd.lock_or_err()
+            .map_err(|e| e.to_string())
+            .and_then(|vm| vm.set_irq_line(irq, level).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => {
+                if level {
+                    self.record_raised(line);
+                }
+                true
+            },
+            Err(e) => {
+                self.injection_failed(irq, line, &e);
+                false
+            }
+        }
+    }
+
+    fn injection_failed(&self, irq: u32, line: &IrqLine, error: &dyn std::fmt::Display) {
+        self.metrics.record_error();
+        if !line.failed.swap(true, Ordering::Relaxed) {
+            tracing::warn!(irq = irq, error = %error, "IRQ injection failed; further failures on this line are not logged");
+        }
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AxvmError;
+    use kvm_ioctls::Kvm;
+
+    fn target(entry: &kvm_irq_routing_entry) -> (u32, u32, u32) {
+        // SAFETY: legacy_routing only builds irqchip routes
+        let irqchip = unsafe { entry.u.irqchip };
+        (entry.gsi, irqchip.irqchip, irqchip.pin)
+    }
+
+    #[test]
+    fn test_legacy_routing() {
+        let routes = legacy_routing();
+        assert_eq!(routes.len(), 16 * 2 + 8);
+        assert!(routes.iter().all(|r| r.type_ == KVM_IRQ_ROUTING_IRQCHIP));
+
+        let targets: Vec<_> = routes.iter().map(target).collect();
+        assert!(targets.contains(&(4, KVM_IRQCHIP_IOAPIC, 4)));
+        assert!(targets.contains(&(4, KVM_IRQCHIP_PIC_MASTER, 4)));
+        assert!(targets.contains(&(10, KVM_IRQCHIP_PIC_SLAVE, 2)));
+        assert!(targets.contains(&(23, KVM_IRQCHIP_IOAPIC, 23)));
+        assert_eq!(targets.iter().filter(|t| t.0 == 20).count(), 1);
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        set_gsi_routing(&vm, &routes).unwrap();
+        vm.set_irq_line(4, true).unwrap();
+    }
+
+    #[test]
+    fn test_interrupts_counted_per_source() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        let metrics = Arc::new(VmMetrics::new());
+        let irq = IrqManager::new(Arc::new(Mutex::new(vm)), Arc::new(IdleWaker::new()), Arc::clone(&metrics))
+            .with_source(5, IrqSource::Block)
+            .with_source(6, IrqSource::Net);
+
+        irq.pulse(5);
+        irq.pulse(5);
+        // Only raising a level line counts, and only on a change
+        irq.set_level(6, || Ok(true));
+        irq.set_level(6, || Ok(true));
+        irq.set_level(6, || Ok(false));
+        irq.pulse(8);
+        assert_eq!((metrics.blk_irqs(), metrics.net_irqs(), metrics.serial_irqs()), (2, 1, 0));
+    }
+
+    #[test]
+    fn test_set_level_and_pulse_coalescing() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        let metrics = Arc::new(VmMetrics::new());
+        let irq = IrqManager::new(Arc::new(Mutex::new(vm)), Arc::new(IdleWaker::new()), Arc::clone(&metrics))
+            .with_source(5, IrqSource::Block)
+            .with_source(6, IrqSource::Net);
+        let level = |line: usize| *irq.lines[line].level.lock().unwrap();
+
+        irq.set_level(6, || Ok(true));
+        assert!(level(6));
+        irq.set_level(6, || Ok(false));
+        assert!(!level(6));
+        irq.set_level(6, || Ok(true));
+        // A source that cannot be sampled leaves the line where it was
+        irq.set_level(6, || Err(AxvmError::LockPoisoned("test".to_string())));
+        assert!(level(6));
+        assert_eq!((metrics.net_irqs(), metrics.errors()), (2, 1));
+
+        // Pulses that arrive while another thread injects are left to it
+        let line = &irq.lines[5];
+        line.injecting.store(true, Ordering::Release);
+        for _ in 0..3 {
+            irq.pulse(5);
+        }
+        assert_eq!(metrics.blk_irqs(), 0);
+        assert!(line.pending.load(Ordering::Acquire));
+        // One edge covers all of them once that thread is done
+        line.injecting.store(false, Ordering::Release);
+        irq.pulse(5);
+        assert_eq!(metrics.blk_irqs(), 1);
+        assert!(!line.pending.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_injection_failures_counted_and_flagged_once() {
+        // No irqchip: every KVM_IRQ_LINE fails
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let metrics = Arc::new(VmMetrics::new());
+        let irq = IrqManager::new(Arc::new(Mutex::new(vm)), Arc::new(IdleWaker::new()), Arc::clone(&metrics))
+            .with_source(5, IrqSource::Block);
+
+        irq.pulse(5);
+        assert!(irq.lines[5].failed.load(Ordering::Relaxed));
+        irq.pulse(5);
+        irq.set_level(6, || Ok(true));
+        assert_eq!((metrics.errors(), metrics.blk_irqs()), (3, 0));
+        // A failed raise is retried on the next call
+        assert!(!*irq.lines[6].level.lock().unwrap());
+        assert!(!irq.lines[7].failed.load(Ordering::Relaxed));
+    }
+}
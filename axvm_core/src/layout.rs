@@ -0,0 +1,96 @@
+// src/layout.rs
+//! Where the boot structures live, as offsets from the start of guest RAM.
+//!
+//! RAM starts at guest physical 0 unless `--ram-base` moves it; every
+//! writer adds `GuestMemory::base()` to these offsets.
+
+// Long-mode page tables for raw payloads: PML4, PDPT and one page directory
+pub const PML4_START: usize = 0x1000;
+pub const PDPT_START: usize = 0x2000;
+pub const PD_START: usize = 0x3000;
+pub const GDT_START: usize = 0x4000;
+
+/// Raw payloads start above the page tables and GDT
+pub const RAW_LOAD_MIN: u64 = 0x5000;
+
+pub const ZERO_PAGE_START: usize = 0x7000;
+pub const SETUP_DATA_START: usize = 0x8000;
+pub const CMDLINE_START: usize = 0x20000;
+// `--dtb`: a SETUP_DTB node for Linux, the bare blob for raw payloads
+pub const DTB_START: usize = 0x40000;
+pub const DTB_END: usize = 0x80000;
+pub const BOOT_STACK: u64 = 0x90000;
+
+// Conventional memory below the EBDA
+pub const LOW_RAM_END: usize = 0x9FC00;
+
+pub const RSDP_START: usize = 0xE0000;
+pub const SMBIOS_START: usize = 0xF0000;
+
+pub const KERNEL_START: usize = 0x100000;
+
+/// `--ram-base` granularity: one 2 MiB page directory entry
+pub const RAM_BASE_ALIGN: u64 = 2 * 1024 * 1024;
+
+/// Size of the identity map the raw long-mode page tables build: the
+/// 1 GiB-aligned block holding the start of RAM.
+pub const IDENTITY_MAP_SIZE: u64 = 1 << 30;
+
+// Interrupt controller windows KVM's in-kernel irqchip decodes
+pub const IOAPIC_BASE: u64 = 0xFEC00000;
+pub const IOAPIC_SIZE: u64 = 0x1000;
+pub const LAPIC_BASE: u64 = 0xFEE00000;
+pub const LAPIC_SIZE: u64 = 0x1000;
+
+/// Everything that claims guest-physical address space: RAM slots and
+/// device windows. Registering them all up front turns an overlap into an
+/// error naming both ranges, instead of an EINVAL from
+/// `KVM_SET_USER_MEMORY_REGION` or a device that silently never sees its
+/// accesses.
+#[derive(Debug, Default)]
+pub struct MemoryLayout {
+    ranges: Vec<(String, u64, u64)>,
+}
+
+impl MemoryLayout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reserve(&mut self, name: &str, start: u64, size: u64) -> Result<(), String> {
+        let end = start.checked_add(size)
+            .ok_or_else(|| format!("{} ({:#x}, {:#x} bytes) runs past the end of the address space", name, start, size))?;
+        if let Some((other, other_start, other_end)) = self.ranges.iter().find(|(_, s, e)| start < *e && *s < end) {
+            return Err(format!(
+                "{} ({:#x} - {:#x}) overlaps {} ({:#x} - {:#x})",
+                name, start, end, other, other_start, other_end
+            ));
+        }
+        self.ranges.push((name.to_string(), start, end));
+        Ok(())
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_layout_overlaps() {
+        let mut layout = MemoryLayout::new();
+        layout.reserve("guest RAM", 0, 0x8000000).unwrap();
+        layout.reserve("IOAPIC", IOAPIC_BASE, IOAPIC_SIZE).unwrap();
+        // Adjacent ranges are fine
+        layout.reserve("virtio-mmio", IOAPIC_BASE - 0x1000, 0x1000).unwrap();
+
+        let err = layout.reserve("high RAM", 0x7FFF000, 0x2000).unwrap_err();
+        assert_eq!(err, "high RAM (0x7fff000 - 0x8001000) overlaps guest RAM (0x0 - 0x8000000)");
+        let err = layout.reserve("virtio-net", IOAPIC_BASE + 0x800, 0x1000).unwrap_err();
+        assert!(err.ends_with("overlaps IOAPIC (0xfec00000 - 0xfec01000)"));
+        assert!(layout.reserve("wrap", u64::MAX - 0xFFF, 0x2000).is_err());
+    }
+}
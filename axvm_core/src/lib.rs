@@ -0,0 +1,1469 @@
+// src/lib.rs
+//! AxVM as a library.
+//!
+//! [`Vm`] owns the guest memory, devices and vCPUs of one virtual machine;
+//! the `axvm_core` binary is a thin CLI wrapper around it.
+
+/// `tracing` target of the human-oriented status lines, so they can be
+/// filtered separately from diagnostics.
+pub const STATUS_TARGET: &str = "axvm::status";
+
+/// Emit a status line (formerly a `>>> [Tag] ...` banner) as a `tracing`
+/// event on [`STATUS_TARGET`], tagged with its subsystem. Defaults to `info`.
+#[macro_export]
+macro_rules! status {
+    ($level:ident, $tag:literal, $($arg:tt)+) => {
+        tracing::$level!(target: "axvm::status", tag = $tag, $($arg)+)
+    };
+    ($tag:literal, $($arg:tt)+) => {
+        $crate::status!(info, $tag, $($arg)+)
+    };
+}
+
+mod memory;
+mod layout;
+mod vcpu;
+pub mod error;
+pub mod metrics;
+pub mod serial;
+mod linux;
+mod loader;
+mod acpi;
+mod smbios;
+mod virtio;
+pub mod config;
+mod tap;
+mod usernet;
+mod virtio_net;
+mod cpuid;
+mod virtio_vsock;
+mod mmio;
+mod vga;
+mod i8042;
+mod dirty;
+mod pcap;
+mod idle;
+mod hpet;
+mod blk_cache;
+mod direct_io;
+pub mod debug;
+pub mod state;
+mod crash;
+mod cpio;
+mod watchdog;
+mod irq;
+mod hangup;
+mod pause;
+mod coalesced;
+mod exit_trace;
+mod rtc;
+mod post;
+mod seccomp;
+mod sha256;
+mod unhandled;
+mod control;
+
+use kvm_ioctls::{Cap, IoEventAddress, Kvm, VcpuFd, VmFd};
+use kvm_bindings::{KVM_MAX_CPUID_ENTRIES, KVM_MEM_LOG_DIRTY_PAGES, kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::memory::GuestMemory;
+use crate::layout::{MemoryLayout, IOAPIC_BASE, IOAPIC_SIZE, LAPIC_BASE, LAPIC_SIZE};
+use crate::error::{AxvmError, AxvmResult, LockExt};
+use crate::metrics::VmMetrics;
+use crate::state::VmState;
+use crate::pause::PauseGate;
+use crate::serial::{SerialConsole, SerialOutput, SerialPorts, COM1_BASE, COM1_IRQ, COM2_BASE, COM2_IRQ};
+use crate::virtio::{VirtioBlock, VIRTIO_MMIO_QUEUE_NOTIFY};
+use crate::virtio_net::{LoopbackBackend, NetBackend, VirtioNet};
+use crate::virtio_vsock::VirtioVsock;
+use crate::config::{LogFormat, NetMode, RawMode, SerialTarget, VmConfig, MAX_DISKS, VIRTIO_MMIO_SIZE};
+use crate::mmio::{MmioBus, MmioWrite};
+use crate::hpet::{Hpet, HPET_BASE};
+use crate::rtc::Rtc;
+use crate::post::{PostCode, POST_PORT};
+use crate::watchdog::Ib700;
+use crate::idle::IdleWaker;
+use crate::irq::{IrqManager, IrqSource};
+use crate::coalesced::CoalescedRing;
+use crate::exit_trace::ExitTracer;
+use crate::unhandled::UnhandledPorts;
+use crate::control::{Control, VcpuMailbox};
+use crate::vga::{VgaText, VGA_CRTC_INDEX, VGA_CRTC_DATA};
+use crate::i8042::{I8042, I8042Event, I8042_DATA_PORT, I8042_COMMAND_PORT, I8042_KBD_IRQ};
+
+const VIRTIO_BLK_IRQ: u32 = 5;
+// Additional disks (vdb, vdc, ...) live at 0xFEB20000, 0xFEB30000, ...
+const VIRTIO_EXTRA_BLK_MMIO_BASE: u64 = 0xFEB20000;
+const VIRTIO_EXTRA_BLK_MMIO_STRIDE: u64 = 0x10000;
+const EXTRA_DISK_IRQS: [u32; MAX_DISKS - 1] = [9, 10, 11, 12, 14, 15];
+const VIRTIO_NET_IRQ: u32 = 6;
+const TAP_NAME: &str = "axvm-tap0";
+pub const VIRTIO_VSOCK_MMIO_BASE: u64 = 0xFEAF0000;
+const VIRTIO_VSOCK_IRQ: u32 = 7;
+
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Upper bound on a halted vCPU's sleep so in-kernel timer interrupts still get serviced
+const HLT_IDLE_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// How long `pause` waits for the vCPUs to leave the guest.
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn read_tsc() -> u64 {
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+/// Log an unrecoverable device error and ask every vCPU to stop.
+fn stop_on_fatal(cpu_id: u8, err: &AxvmError, should_stop: &AtomicBool, metrics: &VmMetrics) {
+    status!(error, "vCPU", cpu_id = cpu_id, error = %err, "FATAL: {}. Stopping VM.", err);
+    metrics.record_error();
+    should_stop.store(true, Ordering::SeqCst);
+}
+
+/// Log the `--crash-dump` register and page table dump of a vCPU that hit a fatal exit.
+fn dump_vcpu(cpu_id: u8, vcpu: &VcpuFd, guest_mem: &Mutex<GuestMemory>, post_code: &PostCode) {
+    let dump = guest_mem.lock_or_err()
+        .map_err(|e| e.to_string())
+        .and_then(|mem| crash::crash_dump(vcpu, &mem));
+    match dump {
+        Ok(lines) => {
+            for line in lines {
+                status!(error, "Crash", cpu_id = cpu_id, "{}", line);
+            }
+        },
+        Err(e) => status!(warn, "Crash", cpu_id = cpu_id, error = %e, "Register dump failed: {}", e),
+    }
+    match post_code.last() {
+        Some(code) => status!(error, "Crash", cpu_id = cpu_id, post_code = code, "Last POST code: {:#04x}", code),
+        None => status!(error, "Crash", cpu_id = cpu_id, "Last POST code: none written"),
+    }
+}
+
+/// Hold a vCPU beyond `--online-cpus` without ever entering the guest until
+/// the VM stops. The fd stays open so the CPU remains present for hotplug.
+fn park_vcpu(_vcpu: VcpuFd, cpu_id: u8, should_stop: Arc<AtomicBool>) {
+    status!("vCPU", cpu_id = cpu_id, "vCPU {} offline, parked", cpu_id);
+    while !should_stop.load(Ordering::Relaxed) {
+        thread::park_timeout(SHUTDOWN_POLL_INTERVAL);
+    }
+}
+
+/// `SIGHUP`: retry creating the TAP if startup ran without one, e.g. before
+/// permissions were fixed, and hand it to the running virtio-net device.
+fn reattach_tap(virtio_net: &VirtioNet, irq: &IrqManager) {
+    match virtio_net.has_backend() {
+        Ok(false) => {},
+        Ok(true) => {
+            status!("Net", "SIGHUP received, network backend already attached");
+            return;
+        },
+        Err(e) => {
+            status!(warn, "Net", error = %e, "SIGHUP received, network backend state unavailable: {}", e);
+            return;
+        }
+    }
+    let tap = match tap::TapInterface::new(Some(TAP_NAME)) {
+        Ok(tap) => tap,
+        Err(e) => {
+            status!(warn, "Net", error = %e, "SIGHUP received, TAP still unavailable: {}", e);
+            return;
+        }
+    };
+    let name = tap.name().to_string();
+    match virtio_net.attach_tap(tap) {
+        Ok(()) => {
+            status!("Net", name = %name, "TAP interface '{}' re-attached, link up", name);
+            irq.set_level(VIRTIO_NET_IRQ, || virtio_net.should_interrupt());
+        },
+        Err(e) => status!(warn, "Net", error = %e, "TAP re-attach failed: {}", e),
+    }
+}
+
+/// Ask KVM to buffer `QUEUE_NOTIFY` writes to every virtio window in the
+/// coalesced MMIO ring. The ring is mapped before any zone exists, so no
+/// buffered kick goes unread; on failure nothing stays registered.
+fn setup_coalesced_notify(kvm: &Kvm, vm: &VmFd, vcpu: &VcpuFd, mmio_bus: &MmioBus) -> Result<CoalescedRing, String> {
+    if !kvm.check_extension(Cap::CoalescedMmio) {
+        return Err("KVM_CAP_COALESCED_MMIO not supported by host KVM".to_string());
+    }
+    let ring = CoalescedRing::map(vcpu)?;
+
+    let zones: Vec<u64> = mmio_bus.regions().map(|(base, _, _)| base + VIRTIO_MMIO_QUEUE_NOTIFY).collect();
+    for (i, &addr) in zones.iter().enumerate() {
+        if let Err(e) = vm.register_coalesced_mmio(IoEventAddress::Mmio(addr), 4) {
+            for &registered in &zones[..i] {
+                let _ = vm.unregister_coalesced_mmio(IoEventAddress::Mmio(registered), 4);
+            }
+            return Err(format!("KVM_REGISTER_COALESCED_MMIO at {:#x} failed: {}", addr, e));
+        }
+    }
+    Ok(ring)
+}
+
+/// Replay the writes KVM buffered in the coalesced MMIO ring, oldest first.
+fn drain_coalesced_mmio(
+    ring: &Mutex<CoalescedRing>,
+    mmio_bus: &MmioBus,
+    guest_mem: &Mutex<GuestMemory>,
+    irq: &IrqManager,
+    metrics: &VmMetrics,
+) -> AxvmResult<()> {
+    let mut ring = ring.lock_or_err()?;
+    while let Some(entry) = ring.pop() {
+        let len = (entry.len as usize).min(entry.data.len());
+        let outcome = guest_mem.lock_or_err()
+            .and_then(|mut mem| mmio_bus.dispatch_write(entry.phys_addr, &entry.data[..len], &mut mem))?;
+        if let MmioWrite::Handled(line) = outcome {
+            irq.set_level(line, || mmio_bus.interrupt_level(line));
+        }
+        metrics.record_coalesced_mmio_write();
+    }
+    Ok(())
+}
+
+/// Move pending virtio-net RX/TX between the backend and guest memory. Any
+/// vCPU may call it; whoever holds guest memory at the time does the work
+/// and the others skip this round instead of waiting.
+fn poll_net(virtio_net: &VirtioNet, guest_mem: &Mutex<GuestMemory>, irq: &IrqManager) -> AxvmResult<()> {
+    let Ok(mut mem) = guest_mem.try_lock() else { return Ok(()) };
+    let mem_slice = mem.as_mut_slice();
+    let rx_work = virtio_net.process_rx(mem_slice)?;
+    if virtio_net.process_tx(mem_slice)? || rx_work {
+        irq.set_level(VIRTIO_NET_IRQ, || virtio_net.should_interrupt());
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_vcpu(
+    vcpu: VcpuFd,
+    irq: Arc<IrqManager>,
+    cpu_id: u8,
+    serial: Arc<SerialPorts>,
+    mmio_bus: Arc<MmioBus>,
+    hpet: Arc<Hpet>,
+    rtc: Arc<Rtc>,
+    post_code: Arc<PostCode>,
+    ib700: Option<Arc<Ib700>>,
+    virtio_net: Arc<VirtioNet>,
+    vga: Option<Arc<VgaText>>,
+    keyboard: Arc<I8042>,
+    waker: Arc<IdleWaker>,
+    should_stop: Arc<AtomicBool>,
+    pause: Arc<PauseGate>,
+    mailbox: Arc<VcpuMailbox>,
+    guest_mem: Arc<Mutex<GuestMemory>>,
+    metrics: Arc<VmMetrics>,
+    crash_dump: bool,
+    coalesced_ring: Option<Arc<Mutex<CoalescedRing>>>,
+    trace_exits: bool,
+    vcpu_timeout: Option<Duration>,
+    device_cpu: Option<u8>,
+) {
+    let mut vcpu = vcpu;
+    let mut exit_tracer = trace_exits.then(|| ExitTracer::new(cpu_id, vcpu.as_raw_fd()));
+    let mut unhandled_ports = UnhandledPorts::new(cpu_id);
+    let run_timer = vcpu_timeout.and_then(|timeout| match vcpu::RunTimer::new(timeout) {
+        Ok(timer) => Some(timer),
+        Err(e) => {
+            tracing::warn!(cpu_id = cpu_id, error = %e, "Failed to create vCPU run timer, --vcpu-timeout disabled");
+            None
+        }
+    });
+    let mut last_tsc = read_tsc();
+    let mut last_instant = Instant::now();
+    let mut run_retries = 0;
+    
+    tracing::info!(cpu_id = cpu_id, "vCPU thread started");
+    
+    loop {
+        if should_stop.load(Ordering::Relaxed) { 
+            tracing::debug!(cpu_id = cpu_id, "vCPU received stop signal");
+            break; 
+        }
+        if pause.is_paused() {
+            tracing::debug!(cpu_id = cpu_id, "vCPU paused");
+            pause.wait_while_paused(&should_stop, || mailbox.service(&vcpu));
+            last_tsc = read_tsc();
+            last_instant = Instant::now();
+            continue;
+        }
+
+        metrics.record_vcpu_run();
+        let now_tsc = read_tsc();
+        metrics.record_cycles(now_tsc.wrapping_sub(last_tsc));
+        metrics.record_runtime(last_instant.elapsed());
+        last_tsc = now_tsc;
+        last_instant = Instant::now();
+        let wake_gen = waker.generation();
+
+        if device_cpu.is_none_or(|cpu| cpu == cpu_id) {
+            if let Err(e) = poll_net(&virtio_net, &guest_mem, &irq) {
+                stop_on_fatal(cpu_id, &e, &should_stop, &metrics);
+                break;
+            }
+        }
+
+        let run_start = Instant::now();
+        if let Some(ref timer) = run_timer {
+            timer.arm();
+        }
+        let run_result = vcpu.run();
+        if run_timer.as_ref().is_some_and(vcpu::RunTimer::disarm) {
+            tracing::debug!(cpu_id = cpu_id, timeout = ?vcpu_timeout, "KVM_RUN exceeded --vcpu-timeout");
+            metrics.record_timeout();
+        }
+        metrics.record_vcpu_active_time(run_start.elapsed());
+
+        // Buffered kicks happened before this exit; replay them first
+        if let Some(ref ring) = coalesced_ring {
+            if let Err(e) = drain_coalesced_mmio(ring, &mmio_bus, &guest_mem, &irq, &metrics) {
+                if e.requires_shutdown() {
+                    stop_on_fatal(cpu_id, &e, &should_stop, &metrics);
+                    break;
+                }
+                tracing::warn!(cpu_id = cpu_id, error = %e, "Coalesced MMIO write error");
+            }
+        }
+
+        match run_result {
+            Ok(exit) => {
+                run_retries = 0;
+                metrics.record_vcpu_exit();
+                if let Some(ref mut tracer) = exit_tracer {
+                    tracer.trace(&exit);
+                }
+                
+                match exit {
+                    kvm_ioctls::VcpuExit::IoOut(port, data) if acpi::pm_handles(port) => {
+                        metrics.record_io_exit();
+                        if acpi::pm_write(port, data) {
+                            status!("CPU", cpu_id = cpu_id, "ACPI power off (S5)");
+                            should_stop.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                    },
+                    kvm_ioctls::VcpuExit::IoIn(port, data) if acpi::pm_handles(port) => {
+                        acpi::pm_read(port, data);
+                        metrics.record_io_exit();
+                    },
+                    kvm_ioctls::VcpuExit::IoOut(port, data) if Rtc::handles(port) => {
+                        rtc.write(port, data);
+                        metrics.record_io_exit();
+                    },
+                    kvm_ioctls::VcpuExit::IoIn(port, data) if Rtc::handles(port) => {
+                        let value = rtc.read(port);
+                        if !data.is_empty() {
+                            data[0] = value;
+                        }
+                        metrics.record_io_exit();
+                    },
+                    kvm_ioctls::VcpuExit::IoOut(POST_PORT, data) => {
+                        post_code.write(cpu_id, data);
+                        metrics.record_io_exit();
+                    },
+                    kvm_ioctls::VcpuExit::IoOut(port, data) if Ib700::handles(port) && ib700.is_some() => {
+                        if let Err(e) = ib700.as_ref().map_or(Ok(()), |ib700| ib700.write(port, data)) {
+                            stop_on_fatal(cpu_id, &e, &should_stop, &metrics);
+                            break;
+                        }
+                        metrics.record_io_exit();
+                    },
+                    kvm_ioctls::VcpuExit::IoOut(port, data) if serial.handles(port) => {
+                        serial.write(port, data);
+                        metrics.record_io_exit();
+                    },
+                    kvm_ioctls::VcpuExit::IoIn(port, data) if serial.handles(port) => {
+                        let value = serial.read(port);
+                        if !data.is_empty() {
+                            data[0] = value;
+                        }
+                        metrics.record_io_exit();
+                    },
+                    kvm_ioctls::VcpuExit::IoOut(port, data) if port == I8042_DATA_PORT || port == I8042_COMMAND_PORT => {
+                        match keyboard.write(port, data) {
+                            I8042Event::Interrupt => irq.pulse(I8042_KBD_IRQ),
+                            I8042Event::Reset => {
+                                status!("CPU", cpu_id = cpu_id, "RESET (i8042)");
+                                should_stop.store(true, Ordering::Relaxed);
+                                break;
+                            },
+                            I8042Event::None => {},
+                        }
+                        metrics.record_io_exit();
+                    },
+                    kvm_ioctls::VcpuExit::IoIn(port, data) if port == I8042_DATA_PORT || port == I8042_COMMAND_PORT => {
+                        if !data.is_empty() {
+                            data[0] = keyboard.read(port);
+                        }
+                        if port == I8042_DATA_PORT && keyboard.interrupt_pending() {
+                            irq.pulse(I8042_KBD_IRQ);
+                        }
+                        metrics.record_io_exit();
+                    },
+                    kvm_ioctls::VcpuExit::IoOut(port, data) if (VGA_CRTC_INDEX..=VGA_CRTC_DATA).contains(&port) && vga.is_some() => {
+                        if let Some(ref vga) = vga {
+                            vga.write(port, data);
+                            metrics.record_io_exit();
+                        }
+                    },
+                    kvm_ioctls::VcpuExit::IoIn(port, data) if (VGA_CRTC_INDEX..=VGA_CRTC_DATA).contains(&port) && vga.is_some() => {
+                        if let Some(ref vga) = vga {
+                            if !data.is_empty() {
+                                data[0] = vga.read(port);
+                            }
+                            metrics.record_io_exit();
+                        }
+                    },
+                    kvm_ioctls::VcpuExit::IoOut(port, data) => {
+                        unhandled_ports.record(port, true, data.len());
+                        metrics.record_io_exit();
+                    },
+                    kvm_ioctls::VcpuExit::IoIn(port, data) => {
+                        unhandled::float_bus(data);
+                        unhandled_ports.record(port, false, data.len());
+                        metrics.record_io_exit();
+                    },
+                    
+                    kvm_ioctls::VcpuExit::MmioRead(addr, data) if hpet.contains(addr) => {
+                        hpet.read(addr - HPET_BASE, data);
+                        metrics.record_mmio_exit();
+                    },
+                    kvm_ioctls::VcpuExit::MmioWrite(addr, data) if hpet.contains(addr) => {
+                        hpet.write(addr - HPET_BASE, data);
+                        metrics.record_mmio_exit();
+                    },
+                    kvm_ioctls::VcpuExit::MmioRead(addr, data) => {
+                        match mmio_bus.dispatch_read(addr, data) {
+                            Ok(true) => metrics.record_mmio_exit(),
+                            Ok(false) => {},
+                            Err(e) if e.requires_shutdown() => {
+                                stop_on_fatal(cpu_id, &e, &should_stop, &metrics);
+                                break;
+                            },
+                            Err(e) => {
+                                tracing::warn!(cpu_id = cpu_id, addr = addr, error = %e, "MMIO read error");
+                                metrics.record_mmio_exit();
+                            }
+                        }
+                    },
+                    kvm_ioctls::VcpuExit::MmioWrite(addr, data) => {
+                        let outcome = guest_mem.lock_or_err()
+                            .and_then(|mut mem| mmio_bus.dispatch_write(addr, data, &mut mem));
+
+                        match outcome {
+                            Ok(MmioWrite::Handled(line)) => {
+                                irq.set_level(line, || mmio_bus.interrupt_level(line));
+                                metrics.record_mmio_exit();
+                            },
+                            Ok(MmioWrite::Unmapped) => {},
+                            Err(e) if e.requires_shutdown() => {
+                                stop_on_fatal(cpu_id, &e, &should_stop, &metrics);
+                                break;
+                            },
+                            Err(e) => {
+                                tracing::warn!(cpu_id = cpu_id, addr = addr, error = %e, "MMIO write error");
+                                metrics.record_mmio_exit();
+                            }
+                        }
+                    },
+                    kvm_ioctls::VcpuExit::Debug(exit_info) => {
+                        // No gdb stub: single-step exits only feed the instruction counter
+                        tracing::trace!(cpu_id = cpu_id, pc = exit_info.pc, exception = exit_info.exception,
+                            dr6 = exit_info.dr6, dr7 = exit_info.dr7, "Debug exit");
+                        metrics.record_instructions(1);
+                    },
+                    kvm_ioctls::VcpuExit::InternalError => {
+                        let (suberror, data) = vcpu::internal_error_info(&mut vcpu);
+                        status!(error, "vCPU", cpu_id = cpu_id, suberror = suberror, data = ?data,
+                            "FATAL: KVM internal error, suberror={} data={:#x?}", suberror, data);
+                        if crash_dump {
+                            dump_vcpu(cpu_id, &vcpu, &guest_mem, &post_code);
+                        }
+                        metrics.record_hardware_failure();
+                        serial.log_capture();
+                        should_stop.store(true, Ordering::SeqCst);
+                        break;
+                    },
+                    kvm_ioctls::VcpuExit::FailEntry(reason, cpu) => {
+                        status!(error, "vCPU", cpu_id = cpu_id, hardware_entry_failure_reason = reason, cpu = cpu,
+                            "FATAL: VM entry failed, hardware_entry_failure_reason={:#x}", reason);
+                        if crash_dump {
+                            dump_vcpu(cpu_id, &vcpu, &guest_mem, &post_code);
+                        }
+                        metrics.record_hardware_failure();
+                        serial.log_capture();
+                        should_stop.store(true, Ordering::SeqCst);
+                        break;
+                    },
+                    kvm_ioctls::VcpuExit::Hlt => {
+                        if should_stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        metrics.record_hlt_exit();
+                        let idle_start = read_tsc();
+                        waker.wait(wake_gen, HLT_IDLE_TIMEOUT);
+                        metrics.record_idle_cycles(read_tsc().wrapping_sub(idle_start));
+                    },
+                    kvm_ioctls::VcpuExit::Shutdown => {
+                        status!("CPU", cpu_id = cpu_id, "SHUTDOWN!");
+                        // Also how a triple fault surfaces
+                        if crash_dump {
+                            dump_vcpu(cpu_id, &vcpu, &guest_mem, &post_code);
+                            serial.log_capture();
+                        }
+                        should_stop.store(true, Ordering::Relaxed);
+                        break;
+                    },
+                    _ => {}
+                }
+            },
+            Err(e) => {
+                // Check for EAGAIN (errno 11) and EINTR (errno 4)
+                let errno = e.errno();
+                
+                if errno == 11 {
+                    // EAGAIN = vCPU not ready yet (normal during SMP boot)
+                    tracing::trace!(cpu_id = cpu_id, "vCPU not ready (EAGAIN)");
+                    thread::yield_now();
+                    continue;
+                } else if errno == 4 {
+                    // EINTR = signal received
+                    tracing::debug!(cpu_id = cpu_id, "vCPU interrupted by signal");
+                    if should_stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    continue;
+                } else {
+                    if should_stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let err = vcpu::run_error(&e);
+                    if let Some(backoff) = vcpu::run_retry_backoff(&err, run_retries) {
+                        run_retries += 1;
+                        tracing::warn!(cpu_id = cpu_id, error = %err, errno = errno, attempt = run_retries,
+                            "Recoverable vCPU error, retrying in {:?}", backoff);
+                        metrics.record_vcpu_retry();
+                        thread::sleep(backoff);
+                        continue;
+                    }
+                    tracing::error!(cpu_id = cpu_id, error = %err, errno = errno, retries = run_retries, "Fatal vCPU error");
+                    if crash_dump {
+                        dump_vcpu(cpu_id, &vcpu, &guest_mem, &post_code);
+                    }
+                    metrics.record_error();
+                    serial.log_capture();
+                    should_stop.store(true, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+    }
+    
+    pause.unregister_current();
+    tracing::info!(cpu_id = cpu_id, "vCPU thread exiting");
+}
+
+/// Block until every thread has finished. Once a stop is requested, give the
+/// threads `timeout` to wind down, then keep signalling the stragglers out of
+/// `KVM_RUN`; if they still refuse to exit, terminate the process.
+fn wait_for_threads(handles: &[thread::JoinHandle<()>], should_stop: &AtomicBool, timeout: Duration) {
+    let all_finished = || handles.iter().all(|h| h.is_finished());
+
+    while !all_finished() && !should_stop.load(Ordering::SeqCst) {
+        thread::sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+
+    let deadline = Instant::now() + timeout;
+    while !all_finished() && Instant::now() < deadline {
+        thread::sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+    if all_finished() {
+        return;
+    }
+
+    status!(warn, "Exit", timeout = ?timeout, "vCPU threads did not stop within {:?}, forcing exit from KVM_RUN", timeout);
+
+    let deadline = Instant::now() + timeout;
+    while !all_finished() && Instant::now() < deadline {
+        for h in handles.iter().filter(|h| !h.is_finished()) {
+            vcpu::kick_thread(h);
+        }
+        thread::sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+    if !all_finished() {
+        status!(error, "Exit", "Threads still running after kick, terminating");
+        std::process::exit(1);
+    }
+}
+
+/// Guest memory and devices, loaded but not yet backed by KVM.
+struct Guest {
+    mem: GuestMemory,
+    mmio_bus: MmioBus,
+    disks: Vec<Arc<VirtioBlock>>,
+    virtio_net: Arc<VirtioNet>,
+    vga: Option<Arc<VgaText>>,
+    entry_point: u64,
+    // Where a raw payload finds its --dtb
+    dtb_addr: Option<u64>,
+}
+
+impl Guest {
+    fn build(config: &VmConfig, metrics: &Arc<VmMetrics>) -> AxvmResult<Self> {
+        // A dry run must not create or resize the memory file
+        let mem_file = config.mem_file.as_deref().filter(|_| !config.dry_run);
+        let mut mem = GuestMemory::allocate(config.ram_base as usize, config.memory_bytes(), mem_file, config.mlock && !config.dry_run)
+            .map_err(|e| AxvmError::MemoryAllocation(e.to_string()))?;
+
+        status!("✓", memory_mb = config.memory, ram_base = config.ram_base,
+            "Guest memory: {} MB at {:#x}", config.memory, config.ram_base);
+
+        // Raw payloads get no firmware tables, just their own image
+        if config.raw.is_none() {
+            let acpi_len = acpi::setup_acpi(&mut mem, config.vcpus, config.online_cpus())
+                .map_err(|e| AxvmError::MemoryWrite(format!("ACPI Error: {}", e)))?;
+            if let Some(ref path) = config.dump_acpi {
+                let tables = mem.read_slice(mem.base() + layout::RSDP_START, acpi_len)
+                    .map_err(|e| AxvmError::MemoryWrite(format!("ACPI Error: {}", e)))?;
+                std::fs::write(path, tables)
+                    .map_err(|e| AxvmError::InvalidConfiguration(format!("Failed to write ACPI dump {}: {}", path.display(), e)))?;
+                status!("ACPI", path = %path.display(), "Wrote {} bytes of ACPI tables to {}", acpi_len, path.display());
+            }
+            smbios::setup_smbios(&mut mem, &config.smbios_product, config.smbios_serial.as_deref())
+                .map_err(|e| AxvmError::MemoryWrite(format!("SMBIOS Error: {}", e)))?;
+        }
+
+        
+        let mut mmio_bus = MmioBus::new();
+
+        let disk_paths = config.disk_paths();
+        let cache_bytes = config.disk_cache_mb * 1024 * 1024;
+        let root_blk = Arc::new(VirtioBlock::open(disk_paths.first().map(String::as_str), config.disk_direct)
+            .with_cache(config.disk_cache, cache_bytes)
+            .with_queue_size(config.virtio_queue_size)
+            .with_metrics(Arc::clone(metrics)));
+        mmio_bus.register(config.virtio_blk_base, VIRTIO_MMIO_SIZE, VIRTIO_BLK_IRQ, root_blk.clone())
+            .map_err(AxvmError::InvalidConfiguration)?;
+        let mut disks = vec![root_blk];
+        for (i, path) in disk_paths.iter().enumerate().skip(1) {
+            let base = VIRTIO_EXTRA_BLK_MMIO_BASE + (i as u64 - 1) * VIRTIO_EXTRA_BLK_MMIO_STRIDE;
+            let blk = Arc::new(VirtioBlock::open(Some(path), config.disk_direct)
+                .with_serial(&format!("AXVM-BLK-{:04}", i + 1))
+                .with_cache(config.disk_cache, cache_bytes)
+                .with_queue_size(config.virtio_queue_size)
+            .with_metrics(Arc::clone(metrics)));
+            mmio_bus.register(base, VIRTIO_MMIO_SIZE, EXTRA_DISK_IRQS[i - 1], blk.clone())
+                .map_err(AxvmError::InvalidConfiguration)?;
+            disks.push(blk);
+        }
+
+        let mac = config.mac_bytes().map_err(AxvmError::InvalidConfiguration)?;
+        let backend: std::io::Result<Box<dyn NetBackend + Send>> = if config.dry_run {
+            Err(std::io::Error::other("dry run"))
+        } else if config.net == NetMode::User {
+            let user = usernet::UserNet::new(mac, &config.hostfwd)
+                .map_err(|e| AxvmError::InvalidConfiguration(format!("User networking failed: {}", e)))?;
+            for rule in &config.hostfwd {
+                status!("Net", rule = %rule, "Forwarding {}", rule);
+            }
+            Ok(Box::new(user))
+        } else if config.net == NetMode::Loopback {
+            Ok(Box::new(LoopbackBackend::new()))
+        } else {
+            tap::TapInterface::new(Some(TAP_NAME)).map(|tap_iface| {
+                status!("Net", name = tap_iface.name(), "TAP interface '{}' created successfully", tap_iface.name());
+                Box::new(tap_iface) as Box<dyn NetBackend + Send>
+            })
+        };
+        let virtio_net = match backend {
+            Ok(backend) => VirtioNet::new(Some(backend)).with_mac(mac).with_queue_size(config.virtio_queue_size)
+                .with_metrics(Arc::clone(metrics)),
+            Err(e) => {
+                status!(warn, "Net", error = %e, "Failed to create TAP (run with sudo?): {}. Network disabled.", e);
+                VirtioNet::new(None).with_mac(mac).with_queue_size(config.virtio_queue_size)
+                    .with_metrics(Arc::clone(metrics))
+            }
+        };
+        let virtio_net = match config.pcap {
+            Some(ref path) => {
+                let file = pcap::create(path)
+                    .map_err(|e| AxvmError::InvalidConfiguration(format!("Failed to create pcap {}: {}", path.display(), e)))?;
+                status!("Net", path = %path.display(), "Capturing frames to {}", path.display());
+                Arc::new(virtio_net.with_pcap(file))
+            },
+            None => Arc::new(virtio_net),
+        };
+        mmio_bus.register(config.virtio_net_base, VIRTIO_MMIO_SIZE, VIRTIO_NET_IRQ, virtio_net.clone())
+            .map_err(AxvmError::InvalidConfiguration)?;
+
+        if let Some(cid) = config.vsock_cid {
+            mmio_bus.register(VIRTIO_VSOCK_MMIO_BASE, VIRTIO_MMIO_SIZE, VIRTIO_VSOCK_IRQ, Arc::new(VirtioVsock::new(cid)
+                .with_queue_size(config.virtio_queue_size)
+                .with_metrics(Arc::clone(metrics))))
+                .map_err(AxvmError::InvalidConfiguration)?;
+        }
+
+        let dtb = match config.dtb {
+            Some(ref path) => loader::read_dtb(&path.to_string_lossy()).map_err(AxvmError::InvalidConfiguration)?,
+            None => Vec::new(),
+        };
+        let mut dtb_addr = None;
+
+        let entry_point = match config.raw {
+            Some(ref raw) => {
+                let entry = config.raw_entry();
+                let len = loader::load_raw(&mut mem, &raw.to_string_lossy(), config.load_addr, entry)
+                    .map_err(AxvmError::InvalidConfiguration)?;
+                status!("✓", entry_point = entry, "Raw payload loaded. Entry: {:#x}", entry);
+                if !dtb.is_empty() {
+                    let addr = loader::load_raw_dtb(&mut mem, &dtb, config.load_addr, len)
+                        .map_err(AxvmError::InvalidConfiguration)?;
+                    status!("✓", dtb_addr = addr, "Device tree at {:#x}, passed in RDI", addr);
+                    dtb_addr = Some(addr);
+                }
+                entry
+            },
+            None => {
+                let cmdline = config.kernel_cmdline(&mmio_bus.cmdline_tokens());
+                let initrd = match config.initrd_dir {
+                    Some(ref dir) => {
+                        let archive = cpio::archive_dir(dir).map_err(AxvmError::InvalidConfiguration)?;
+                        status!("Initrd", dir = %dir.display(), bytes = archive.len(),
+                            "Packed {} into a {} byte cpio archive", dir.display(), archive.len());
+                        archive
+                    },
+                    None => Vec::new(),
+                };
+
+                loader::check_fit(&config.kernel_path(), config.memory_bytes(), &cmdline, initrd.len())
+                    .map_err(AxvmError::InvalidConfiguration)?;
+
+                let entry_point = loader::load_linux(
+                    &mut mem, 
+                    &config.kernel_path(), 
+                    config.memory_bytes(), 
+                    &cmdline,
+                    &config.e820.iter()
+                        .map(|r| linux::E820Entry { addr: r.addr, size: r.size, type_: r.type_ })
+                        .collect::<Vec<_>>(),
+                    &initrd,
+                    &dtb,
+                ).map_err(AxvmError::InternalError)?;
+                
+                status!("✓", entry_point = entry_point, "Kernel loaded. Entry: {:#x}", entry_point);
+                entry_point
+            }
+        };
+
+        let vga = if config.vga {
+            VgaText::setup(&mut mem)
+                .map_err(|e| AxvmError::MemoryWrite(format!("VGA Error: {}", e)))?;
+            status!("✓", "VGA text console @ {:#x}", vga::VGA_TEXT_START);
+            Some(Arc::new(VgaText::new()))
+        } else {
+            None
+        };
+
+        check_memory_layout(&mem, &mmio_bus).map_err(AxvmError::MemorySetup)?;
+
+        Ok(Self { mem, mmio_bus, disks, virtio_net, vga, entry_point, dtb_addr })
+    }
+}
+
+/// Reserve guest RAM and every device window in one `MemoryLayout`, so a
+/// conflict is reported by name before KVM sees the RAM slot.
+fn check_memory_layout(mem: &GuestMemory, mmio_bus: &MmioBus) -> Result<(), String> {
+    let mut layout = MemoryLayout::new();
+    layout.reserve("guest RAM", mem.base() as u64, mem.size() as u64)?;
+    for (base, size, irq) in mmio_bus.regions() {
+        layout.reserve(&format!("virtio-mmio device on IRQ {}", irq), base, size)?;
+    }
+    layout.reserve("IOAPIC", IOAPIC_BASE, IOAPIC_SIZE)?;
+    layout.reserve("HPET", HPET_BASE, hpet::HPET_SIZE)?;
+    layout.reserve("LAPIC", LAPIC_BASE, LAPIC_SIZE)
+}
+
+/// Explain why `/dev/kvm` could not be opened and what usually fixes it.
+fn kvm_open_error(e: &kvm_ioctls::Error) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    let hint = match e.errno() {
+        libc::ENOENT => "/dev/kvm does not exist; load the KVM module (`modprobe kvm_intel` or \
+            `modprobe kvm_amd`) and check that virtualization is enabled in the firmware".to_string(),
+        libc::EACCES | libc::EPERM => match std::fs::metadata("/dev/kvm") {
+            Ok(meta) if meta.mode() & 0o060 == 0o060 && meta.gid() != 0 => {
+                // SAFETY: getgrgid returns NULL or a pointer to a static entry read immediately
+                let group = unsafe {
+                    let entry = libc::getgrgid(meta.gid());
+                    (!entry.is_null()).then(|| std::ffi::CStr::from_ptr((*entry).gr_name).to_string_lossy().into_owned())
+                }.unwrap_or_else(|| meta.gid().to_string());
+                format!("/dev/kvm is only accessible to group {}; add your user to it \
+                    (`sudo usermod -aG {} $USER`) and log in again", group, group)
+            },
+            Ok(meta) => format!("/dev/kvm is mode {:o} and only usable by root; give it to the kvm group \
+                (`sudo chgrp kvm /dev/kvm && sudo chmod 660 /dev/kvm`) and add your user to that group", meta.mode() & 0o777),
+            Err(_) => "no permission to open /dev/kvm; add your user to the kvm group".to_string(),
+        },
+        libc::ENXIO | libc::ENODEV => "/dev/kvm exists but no KVM backend is loaded; \
+            `modprobe kvm_intel` or `modprobe kvm_amd`, and check that virtualization is enabled in the firmware".to_string(),
+        _ => return e.to_string(),
+    };
+    format!("{} ({})", hint, e)
+}
+
+/// Load the guest for `--dry-run` and report its layout instead of booting it.
+pub fn dry_run(config: &VmConfig) -> AxvmResult<()> {
+    config.validate().map_err(AxvmError::InvalidConfiguration)?;
+
+    let guest = Guest::build(config, &Arc::new(VmMetrics::disabled()))?;
+
+    status!("Dry-run", entry_point = guest.entry_point, "Entry point: {:#x}", guest.entry_point);
+    // No zero page, hence no E820 map, for raw payloads
+    let e820 = match config.raw {
+        Some(_) => Vec::new(),
+        None => loader::read_e820(&guest.mem).map_err(AxvmError::MemoryRead)?,
+    };
+    for entry in e820 {
+        let (addr, size, type_) = (entry.addr, entry.size, entry.type_);
+        status!("Dry-run", addr = addr, size = size, type_ = type_,
+            "E820: {:#012x} - {:#012x}  type {}", addr, addr + size, type_);
+    }
+    status!("Dry-run", "Configuration and kernel OK, exiting without starting vCPUs");
+    Ok(())
+}
+
+// `--print-caps`: how each capability is reported, and what in AxVM depends on it
+#[derive(Clone, Copy)]
+enum CapValue {
+    Flag,
+    Count,
+}
+
+const REPORTED_CAPS: &[(&str, Cap, CapValue, &str)] = &[
+    ("KVM_CAP_NR_VCPUS", Cap::NrVcpus, CapValue::Count, "recommended vCPU limit"),
+    ("KVM_CAP_MAX_VCPUS", Cap::MaxVcpus, CapValue::Count, "--vcpus"),
+    ("KVM_CAP_NR_MEMSLOTS", Cap::NrMemslots, CapValue::Count, "memory slots"),
+    ("KVM_CAP_USER_MEMORY", Cap::UserMemory, CapValue::Flag, "guest RAM (required)"),
+    ("KVM_CAP_IRQCHIP", Cap::Irqchip, CapValue::Flag, "in-kernel PIC/IOAPIC (required)"),
+    ("KVM_CAP_IRQ_ROUTING", Cap::IrqRouting, CapValue::Flag, "GSI routing (required)"),
+    ("KVM_CAP_PIT2", Cap::Pit2, CapValue::Flag, "in-kernel PIT (required)"),
+    ("KVM_CAP_EXT_CPUID", Cap::ExtCpuid, CapValue::Flag, "CPUID setup (required)"),
+    ("KVM_CAP_IRQFD", Cap::Irqfd, CapValue::Flag, ""),
+    ("KVM_CAP_IOEVENTFD", Cap::Ioeventfd, CapValue::Flag, ""),
+    ("KVM_CAP_COALESCED_MMIO", Cap::CoalescedMmio, CapValue::Flag, "--coalesced-mmio"),
+    ("KVM_CAP_SET_GUEST_DEBUG", Cap::SetGuestDebug, CapValue::Flag, "--count-instructions"),
+    ("KVM_CAP_ADJUST_CLOCK", Cap::AdjustClock, CapValue::Flag, ""),
+];
+
+/// `--print-caps`: open /dev/kvm, list what the host supports and exit.
+pub fn print_caps() -> AxvmResult<()> {
+    let kvm = Kvm::new()
+        .map_err(|e| AxvmError::KvmInit(kvm_open_error(&e)))?;
+
+    println!("KVM capabilities (API version {}):", kvm.get_api_version());
+    for line in cap_lines(&kvm) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// One aligned `--print-caps` line per entry of `REPORTED_CAPS`.
+fn cap_lines(kvm: &Kvm) -> Vec<String> {
+    REPORTED_CAPS.iter().map(|&(name, cap, value, used_for)| {
+        let reported = kvm.check_extension_int(cap);
+        let shown = match value {
+            CapValue::Count => reported.to_string(),
+            CapValue::Flag if reported > 0 => "yes".to_string(),
+            CapValue::Flag => "no".to_string(),
+        };
+        format!("  {:<28} {:>5}   {}", name, shown, used_for).trim_end().to_string()
+    }).collect()
+}
+
+/// Cloneable handle that asks a running [`Vm`] to stop, e.g. from a signal handler.
+#[derive(Clone)]
+pub struct StopHandle {
+    should_stop: Arc<AtomicBool>,
+    waker: Arc<IdleWaker>,
+    pause: Arc<PauseGate>,
+}
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.should_stop.store(true, Ordering::SeqCst);
+        self.waker.notify();
+        self.pause.release();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.should_stop.load(Ordering::SeqCst)
+    }
+}
+
+/// Cloneable handle that pauses and resumes a running [`Vm`]'s vCPUs.
+#[derive(Clone)]
+pub struct PauseHandle {
+    gate: Arc<PauseGate>,
+    state: Arc<Mutex<VmState>>,
+}
+
+impl PauseHandle {
+    /// Hold every vCPU outside the guest; devices stop processing their
+    /// queues until [`resume`](Self::resume).
+    pub fn pause(&self) -> AxvmResult<()> {
+        let mut state = self.state.lock_or_err()?;
+        state.transition(VmState::Paused)?;
+        if self.gate.pause(PAUSE_TIMEOUT) {
+            status!("Pause", "VM paused");
+        } else {
+            status!(warn, "Pause", timeout = ?PAUSE_TIMEOUT, "VM paused, but not every vCPU left the guest within {:?}", PAUSE_TIMEOUT);
+        }
+        Ok(())
+    }
+
+    pub fn resume(&self) -> AxvmResult<()> {
+        let mut state = self.state.lock_or_err()?;
+        state.transition(VmState::Running)?;
+        self.gate.resume();
+        status!("Pause", "VM resumed");
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.gate.is_paused()
+    }
+}
+
+/// A fully set up virtual machine, ready to [`run`](Vm::run).
+pub struct Vm {
+    config: VmConfig,
+    vm_fd: Arc<Mutex<VmFd>>,
+    irq: Arc<IrqManager>,
+    vcpus: Vec<VcpuFd>,
+    guest_mem: Arc<Mutex<GuestMemory>>,
+    mmio_bus: Arc<MmioBus>,
+    disks: Vec<Arc<VirtioBlock>>,
+    hpet: Arc<Hpet>,
+    rtc: Arc<Rtc>,
+    post_code: Arc<PostCode>,
+    ib700: Option<Arc<Ib700>>,
+    virtio_net: Arc<VirtioNet>,
+    vga: Option<Arc<VgaText>>,
+    keyboard: Arc<I8042>,
+    serial: Arc<SerialPorts>,
+    waker: Arc<IdleWaker>,
+    should_stop: Arc<AtomicBool>,
+    pause: Arc<PauseGate>,
+    metrics: Arc<VmMetrics>,
+    dirty_logging: bool,
+    // Shared by all vCPUs, which drain it after every exit
+    coalesced_ring: Option<Arc<Mutex<CoalescedRing>>>,
+    state: Arc<Mutex<VmState>>,
+}
+
+impl Vm {
+    /// Load the kernel, create the KVM VM and its vCPUs, and wire up devices.
+    pub fn new(config: VmConfig) -> AxvmResult<Self> {
+        config.validate().map_err(AxvmError::InvalidConfiguration)?;
+        let mut state = VmState::Created;
+
+        let metrics = if config.no_metrics {
+            Arc::new(VmMetrics::disabled())
+        } else {
+            Arc::new(VmMetrics::new())
+        };
+        let Guest { mut mem, mmio_bus, disks, virtio_net, vga, entry_point, dtb_addr } = Guest::build(&config, &metrics)?;
+
+        let kvm = Kvm::new()
+            .map_err(|e| AxvmError::KvmInit(kvm_open_error(&e)))?;
+        status!("INFO", api_version = kvm.get_api_version(), "KVM API Version: {}", kvm.get_api_version());
+        
+        let vm = kvm.create_vm()
+            .map_err(|e| AxvmError::VmCreation(e.to_string()))?;
+
+        
+        vm.create_irq_chip()
+            .map_err(|e| AxvmError::VmCreation(format!("IRQ Chip Error: {}", e)))?;
+        status!("✓", "IRQ Chip created");
+
+        let routes = irq::legacy_routing();
+        irq::set_gsi_routing(&vm, &routes)
+            .map_err(|e| AxvmError::VmCreation(format!("IRQ Routing Error: {}", e)))?;
+        status!("✓", routes = routes.len(), "GSI routing: {} routes, IRQs 0-15 to PIC + IOAPIC, 16-23 to IOAPIC", routes.len());
+        tracing::debug!(com1 = COM1_IRQ, com2 = COM2_IRQ, blk = VIRTIO_BLK_IRQ, net = VIRTIO_NET_IRQ, vsock = VIRTIO_VSOCK_IRQ,
+            "Legacy device IRQs routed");
+
+        
+        let pit_config = kvm_pit_config {
+            flags: KVM_PIT_SPEAKER_DUMMY,
+            ..Default::default()
+        };
+        vm.create_pit2(pit_config)
+            .map_err(|e| AxvmError::VmCreation(format!("PIT Error: {}", e)))?;
+        status!("✓", "PIT Timer created");
+
+        let mut mem_region = kvm_bindings::kvm_userspace_memory_region {
+            slot: dirty::GUEST_MEM_SLOT,
+            guest_phys_addr: mem.base() as u64,
+            memory_size: mem.size() as u64,
+            userspace_addr: mem.as_ptr() as u64,
+            flags: if config.dirty_stats { KVM_MEM_LOG_DIRTY_PAGES } else { 0 },
+        };
+        
+        let mut dirty_logging = config.dirty_stats;
+        if let Err(e) = unsafe { vm.set_user_memory_region(mem_region) } {
+            if !dirty_logging {
+                return Err(AxvmError::MemorySetup(e.to_string()));
+            }
+            status!(warn, "Dirty", error = %e, "Dirty page logging not supported by host KVM: {}", e);
+            dirty_logging = false;
+            mem_region.flags = 0;
+            unsafe {
+                vm.set_user_memory_region(mem_region)
+                    .map_err(|e| AxvmError::MemorySetup(e.to_string()))?;
+            }
+        }
+        if dirty_logging {
+            status!("✓", "Dirty page logging enabled");
+        }
+
+        
+        let mut vcpus = Vec::new();
+        for cpu_id in 0..config.vcpus {
+            let mut vcpu = vm.create_vcpu(cpu_id as u64)
+                .map_err(|e| AxvmError::VcpuCreation(e.to_string()))?;
+            
+            let mut kvm_cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+                .map_err(|e| AxvmError::CpuidSetup(e.to_string()))?;
+            cpuid::filter_cpuid(&mut kvm_cpuid)
+                .map_err(AxvmError::CpuidSetup)?;
+            if config.clock_offset.is_some() {
+                cpuid::hide_kvmclock(&mut kvm_cpuid);
+            }
+            if let Some(vendor) = config.cpu_vendor {
+                cpuid::set_vendor(&mut kvm_cpuid, vendor.id());
+            }
+            if let Some(signature) = config.hv_signature() {
+                cpuid::set_hypervisor_signature(&mut kvm_cpuid, &signature);
+            }
+            cpuid::set_topology(&mut kvm_cpuid, cpu_id, config.vcpus)
+                .map_err(AxvmError::CpuidSetup)?;
+            vcpu.set_cpuid2(&kvm_cpuid)
+                .map_err(|e| AxvmError::CpuidSetup(e.to_string()))?;
+            
+            match (&config.raw, config.raw_mode) {
+                (Some(_), RawMode::Real) => {
+                    let (segment, offset) = vcpu::real_mode_address(entry_point);
+                    vcpu::setup_real_mode(&mut vcpu, segment, offset)
+                        .map_err(|e| AxvmError::SregSetup(e.to_string()))?;
+                },
+                (Some(_), RawMode::Long) => {
+                    vcpu::setup_long_mode_with_entry(&mut vcpu, &mut mem, entry_point)
+                        .map_err(|e| AxvmError::LongModeSetup(e.to_string()))?;
+                },
+                (None, _) => {
+                    let boot_params = (mem.base() + layout::ZERO_PAGE_START) as u64;
+                    vcpu::setup_long_mode(&mut vcpu, &mut mem, entry_point, boot_params)
+                        .map_err(|e| AxvmError::LongModeSetup(e.to_string()))?;
+                },
+            }
+            if let Some(addr) = dtb_addr {
+                vcpu::set_boot_arg(&vcpu, addr)
+                    .map_err(|e| AxvmError::RegisterAccess(e.to_string()))?;
+            }
+            
+            if config.count_instructions {
+                vcpu::enable_single_step(&vcpu)
+                    .map_err(|e| AxvmError::RegisterAccess(format!("Guest debug setup failed: {}", e)))?;
+            }
+            
+            vcpus.push(vcpu);
+        }
+        status!("✓", vcpus = config.vcpus, "Created {} vCPUs", config.vcpus);
+
+        let coalesced_ring = if config.coalesced_mmio {
+            match setup_coalesced_notify(&kvm, &vm, &vcpus[0], &mmio_bus) {
+                Ok(ring) => {
+                    status!("✓", "Virtio kicks go through the coalesced MMIO ring");
+                    Some(Arc::new(Mutex::new(ring)))
+                },
+                Err(e) => {
+                    status!(warn, "MMIO", error = %e, "Coalesced MMIO disabled: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        if config.trace_exits {
+            status!(warn, "WARN", "Exit tracing enabled: every vm-exit is logged (rate-limited per vCPU)");
+        }
+        if config.count_instructions {
+            status!(warn, "WARN", "Instruction counting enabled: every guest instruction causes a VM exit (orders of magnitude slower)");
+        }
+
+        let com1_output = SerialOutput::open(&config.serial)
+            .map_err(AxvmError::InvalidConfiguration)?;
+        if let Some(path) = com1_output.pty_path() {
+            status!("Serial", pty = path, "COM1 attached to {} (connect with: screen {})", path, path);
+        }
+        let com2_target = config.com2_log.clone().map_or(SerialTarget::Stdout, SerialTarget::File);
+        let com2_output = SerialOutput::open(&com2_target)
+            .map_err(AxvmError::InvalidConfiguration)?;
+        let serial = Arc::new(SerialPorts::new(vec![
+            match config.serial_capture {
+                Some(bytes) => SerialConsole::new(COM1_BASE, COM1_IRQ, com1_output).with_capture(bytes),
+                None => SerialConsole::new(COM1_BASE, COM1_IRQ, com1_output),
+            },
+            SerialConsole::new(COM2_BASE, COM2_IRQ, com2_output),
+        ]));
+        for console in serial.consoles() {
+            tracing::debug!(base = console.base(), irq = console.irq(), "Serial port registered");
+        }
+
+        let vm_fd = Arc::new(Mutex::new(vm));
+        let waker = Arc::new(IdleWaker::new());
+        let mut irq = IrqManager::new(Arc::clone(&vm_fd), Arc::clone(&waker), Arc::clone(&metrics))
+            .with_source(VIRTIO_BLK_IRQ, IrqSource::Block)
+            .with_source(VIRTIO_NET_IRQ, IrqSource::Net)
+            .with_source(COM1_IRQ, IrqSource::Serial)
+            .with_source(COM2_IRQ, IrqSource::Serial);
+        for &line in &EXTRA_DISK_IRQS {
+            irq = irq.with_source(line, IrqSource::Block);
+        }
+        let irq = Arc::new(irq);
+        let pause = Arc::new(PauseGate::new(Arc::clone(&waker)));
+        if let Some(offset) = config.clock_offset {
+            status!("✓", "Guest RTC offset {:+}s from host, kvmclock hidden", offset);
+        }
+        let rtc = Arc::new(Rtc::new(config.clock_offset.unwrap_or(0)));
+        let ib700 = config.watchdog.then(|| Arc::new(Ib700::new()));
+
+        state.transition(VmState::Configured)?;
+
+        Ok(Self {
+            config,
+            vm_fd,
+            irq,
+            vcpus,
+            guest_mem: Arc::new(Mutex::new(mem)),
+            mmio_bus: Arc::new(mmio_bus),
+            disks,
+            hpet: Arc::new(Hpet::new()),
+            rtc,
+            post_code: Arc::new(PostCode::new()),
+            ib700,
+            virtio_net,
+            vga,
+            keyboard: Arc::new(I8042::new()),
+            serial,
+            waker,
+            should_stop: Arc::new(AtomicBool::new(false)),
+            pause,
+            metrics,
+            dirty_logging,
+            coalesced_ring,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    pub fn state(&self) -> VmState {
+        *self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    pub fn metrics(&self) -> &VmMetrics {
+        &self.metrics
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle {
+            should_stop: Arc::clone(&self.should_stop),
+            waker: Arc::clone(&self.waker),
+            pause: Arc::clone(&self.pause),
+        }
+    }
+
+    pub fn pause_handle(&self) -> PauseHandle {
+        PauseHandle {
+            gate: Arc::clone(&self.pause),
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Ask every vCPU to leave its run loop; `run` returns once they have.
+    pub fn stop(&self) {
+        self.stop_handle().stop();
+    }
+
+    /// Freeze the guest; fails unless the VM is running.
+    pub fn pause(&self) -> AxvmResult<()> {
+        self.pause_handle().pause()
+    }
+
+    /// Let a paused guest run again.
+    pub fn resume(&self) -> AxvmResult<()> {
+        self.pause_handle().resume()
+    }
+
+    /// Once the vCPUs are gone, whether the guest shut down or was stopped:
+    /// write back and sync every disk image and flush the serial sinks.
+    fn shutdown(&self) {
+        let mut clean = true;
+        for (i, disk) in self.disks.iter().enumerate() {
+            if let Err(e) = disk.sync() {
+                status!(error, "Exit", disk = i, error = %e, "Failed to sync disk {}: {}", i, e);
+                clean = false;
+            }
+        }
+        if let Err(e) = self.serial.flush() {
+            status!(warn, "Exit", error = %e, "Failed to flush serial output: {}", e);
+            clean = false;
+        }
+        if clean {
+            status!("Exit", "Clean shutdown: disk images synced, serial output flushed");
+        }
+    }
+
+    /// Run the guest until it shuts down or is stopped. A VM runs only once:
+    /// it is `Stopped` afterwards.
+    pub fn run(&mut self) -> AxvmResult<()> {
+        let control_listener = match self.config.control_socket {
+            Some(ref path) => Some(control::bind(path).map_err(|e| AxvmError::InvalidConfiguration(
+                format!("--control-socket {}: {}", path.display(), e)))?),
+            None => None,
+        };
+        self.state.lock_or_err()?.transition(VmState::Running)?;
+
+        status!("Run", vcpus = self.vcpus.len(), "Spawning {} vCPU threads...", self.vcpus.len());
+
+        // Before any vCPU runs: the run timers deliver the kick signal
+        let kick_handler = vcpu::install_kick_handler();
+        if let Err(ref e) = kick_handler {
+            tracing::warn!(error = %e, "Failed to install vCPU kick handler");
+        }
+        let vcpu_timeout = self.config.vcpu_timeout().filter(|_| kick_handler.is_ok());
+
+        let mut handles = Vec::new();
+        let online_cpus = self.config.online_cpus() as usize;
+        let mailboxes: Vec<_> = (0..self.vcpus.len().min(online_cpus)).map(|_| Arc::new(VcpuMailbox::new())).collect();
+        for (cpu_id, vcpu) in std::mem::take(&mut self.vcpus).into_iter().enumerate() {
+            if cpu_id >= online_cpus {
+                let should_stop = Arc::clone(&self.should_stop);
+                handles.push(thread::spawn(move || park_vcpu(vcpu, cpu_id as u8, should_stop)));
+                continue;
+            }
+            let serial = Arc::clone(&self.serial);
+            let mmio_bus = Arc::clone(&self.mmio_bus);
+            let hpet = Arc::clone(&self.hpet);
+            let rtc = Arc::clone(&self.rtc);
+            let post_code = Arc::clone(&self.post_code);
+            let ib700 = self.ib700.clone();
+            let virtio_net = Arc::clone(&self.virtio_net);
+            let vga = self.vga.clone();
+            let keyboard = Arc::clone(&self.keyboard);
+            let waker = Arc::clone(&self.waker);
+            let should_stop = Arc::clone(&self.should_stop);
+            let pause = Arc::clone(&self.pause);
+            let mailbox = Arc::clone(&mailboxes[cpu_id]);
+            let kickable = kick_handler.is_ok();
+            let irq = Arc::clone(&self.irq);
+            let guest_mem = Arc::clone(&self.guest_mem);
+            let metrics = Arc::clone(&self.metrics);
+            let crash_dump = self.config.crash_dump;
+            let coalesced_ring = self.coalesced_ring.clone();
+            let trace_exits = self.config.trace_exits;
+            let device_cpu = self.config.device_cpu;
+            
+            let handle = thread::spawn(move || {
+                if kickable {
+                    pause.register_current();
+                }
+                run_vcpu(vcpu, irq, cpu_id as u8, serial, mmio_bus, hpet, rtc, post_code, ib700, virtio_net, vga, keyboard, waker, should_stop, pause, mailbox, guest_mem, metrics, crash_dump, coalesced_ring, trace_exits, vcpu_timeout, device_cpu);
+            });
+            handles.push(handle);
+        }
+
+        if let Some(ref vga) = self.vga {
+            handles.push(Arc::clone(vga).spawn_renderer(Arc::clone(&self.guest_mem), Arc::clone(&self.should_stop)));
+        }
+
+        if self.dirty_logging {
+            handles.push(dirty::spawn_dirty_stats(Arc::clone(&self.vm_fd), self.config.memory_bytes(), Arc::clone(&self.should_stop)));
+        }
+
+        let timed_out = Arc::new(AtomicBool::new(false));
+        if let Some(timeout) = self.config.boot_timeout() {
+            handles.push(watchdog::spawn_boot_watchdog(
+                timeout,
+                Arc::clone(&self.serial),
+                Arc::clone(&self.metrics),
+                self.stop_handle(),
+                self.pause_handle(),
+                Arc::clone(&timed_out),
+            ));
+        }
+
+        if let Some(ref ib700) = self.ib700 {
+            handles.push(watchdog::spawn_guest_watchdog(
+                Arc::clone(ib700),
+                self.config.watchdog_action,
+                Arc::clone(&self.metrics),
+                self.stop_handle(),
+                self.pause_handle(),
+            ));
+        }
+
+        if let Some(listener) = control_listener {
+            let control = Control::new(mailboxes, Arc::clone(&self.guest_mem), Arc::clone(&self.metrics),
+                self.pause_handle(), Arc::clone(&self.pause));
+            handles.push(control::spawn_control_socket(listener, control, Arc::clone(&self.should_stop)));
+            status!("Control", "Control socket listening on {}", self.config.control_socket.as_ref().unwrap().display());
+        }
+
+        if self.config.stdin_keyboard {
+            let irq = Arc::clone(&self.irq);
+            // Not joined: the reader stays blocked on stdin until the process exits
+            let _ = Arc::clone(&self.keyboard).spawn_stdin_reader(Arc::clone(&self.should_stop), move || {
+                irq.pulse(I8042_KBD_IRQ);
+            });
+            status!("Kbd", "Host stdin routed to PS/2 keyboard");
+        }
+
+        match hangup::install_hangup_handler() {
+            Ok(()) => {
+                let virtio_net = Arc::clone(&self.virtio_net);
+                let irq = Arc::clone(&self.irq);
+                handles.push(hangup::spawn_hangup_watcher(Arc::clone(&self.should_stop), move || {
+                    reattach_tap(&virtio_net, &irq);
+                }));
+            },
+            Err(e) => tracing::warn!(error = %e, "Failed to install SIGHUP handler"),
+        }
+
+        match pause::install_pause_handlers() {
+            Ok(()) => {
+                let (on_pause, on_resume) = (self.pause_handle(), self.pause_handle());
+                handles.push(pause::spawn_pause_watcher(Arc::clone(&self.should_stop), move || {
+                    if let Err(e) = on_pause.pause() {
+                        tracing::debug!(error = %e, "SIGTSTP ignored");
+                    }
+                }, move || {
+                    if let Err(e) = on_resume.resume() {
+                        tracing::debug!(error = %e, "SIGCONT ignored");
+                    }
+                }));
+            },
+            Err(e) => tracing::warn!(error = %e, "Failed to install SIGTSTP/SIGCONT handlers"),
+        }
+
+        // Last: everything above may still need syscalls outside the runtime set
+        let mut seccomp_error = None;
+        if self.config.seccomp {
+            match seccomp::install_runtime_filter() {
+                Ok(()) => status!("✓", "Seccomp filter installed, other syscalls kill AxVM"),
+                Err(e) => {
+                    // Not running the guest unconfined when a sandbox was asked for
+                    status!(error, "Run", error = %e, "Failed to install seccomp filter: {}", e);
+                    self.stop_handle().stop();
+                    seccomp_error = Some(e);
+                }
+            }
+        }
+
+        wait_for_threads(&handles, &self.should_stop, self.config.shutdown_timeout());
+        for h in handles {
+            let _ = h.join();
+        }
+        self.shutdown();
+        self.state.lock_or_err()?.transition(VmState::Stopped)?;
+
+        status!("Exit", "AxVM terminated.");
+        // Same rule as the startup banner: stdout stays clean for --quiet and JSON logs
+        if !self.config.quiet && self.config.log_format == LogFormat::Text {
+            println!("\n{}", self.metrics);
+        } else {
+            status!("Metrics", metrics = %self.metrics, "VM metrics at exit");
+        }
+        match self.guest_mem.lock_or_err()?.resident_usage() {
+            Ok(usage) => status!("Mem", rss_kb = usage.rss_kb, anon_huge_kb = usage.anon_huge_kb,
+                "Guest RAM: {}", usage),
+            Err(e) => tracing::debug!(error = %e, "Guest memory usage unavailable"),
+        }
+
+        if let Some(e) = seccomp_error {
+            return Err(AxvmError::UnsupportedFeature(format!("seccomp: {}", e)));
+        }
+        if timed_out.load(Ordering::SeqCst) {
+            self.serial.log_capture();
+            return Err(AxvmError::Timeout(format!(
+                "guest made no progress within {}s", self.config.boot_timeout.unwrap_or_default()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kvm_open_error_hints() {
+        let missing = kvm_open_error(&kvm_ioctls::Error::new(libc::ENOENT));
+        assert!(missing.starts_with("/dev/kvm does not exist") && missing.contains("modprobe kvm_intel"));
+        let no_backend = kvm_open_error(&kvm_ioctls::Error::new(libc::ENXIO));
+        assert!(no_backend.contains("no KVM backend is loaded"));
+        // The hint depends on the host's /dev/kvm, but always names a fix
+        let denied = kvm_open_error(&kvm_ioctls::Error::new(libc::EACCES));
+        assert!(denied.contains("group") && denied.ends_with(&format!("({})", kvm_ioctls::Error::new(libc::EACCES))));
+
+        let other = kvm_ioctls::Error::new(libc::EINVAL);
+        assert_eq!(kvm_open_error(&other), other.to_string());
+    }
+
+    #[test]
+    fn test_device_irqs_avoid_the_sci() {
+        let lines = [I8042_KBD_IRQ, COM1_IRQ, COM2_IRQ, VIRTIO_BLK_IRQ, VIRTIO_NET_IRQ, VIRTIO_VSOCK_IRQ];
+        for line in lines.iter().chain(&EXTRA_DISK_IRQS) {
+            assert_ne!(*line, acpi::SCI_IRQ as u32);
+        }
+    }
+
+    #[test]
+    fn test_poll_net_skips_while_memory_is_busy() {
+        let vm = Kvm::new().unwrap().create_vm().unwrap();
+        let irq = IrqManager::new(Arc::new(Mutex::new(vm)), Arc::new(IdleWaker::new()), Arc::new(VmMetrics::new()));
+        let net = VirtioNet::new(None);
+        let guest_mem = Mutex::new(GuestMemory::new(0x10000).unwrap());
+
+        // Another vCPU holds guest memory: this one moves on instead of waiting
+        let held = guest_mem.lock().unwrap();
+        poll_net(&net, &guest_mem, &irq).unwrap();
+        drop(held);
+        poll_net(&net, &guest_mem, &irq).unwrap();
+    }
+
+    #[test]
+    fn test_cap_lines() {
+        let lines = cap_lines(&Kvm::new().unwrap());
+        assert_eq!(lines.len(), REPORTED_CAPS.len());
+        assert!(lines.iter().all(|l| l.starts_with("  KVM_CAP_") && l == l.trim_end()));
+
+        let line = |name: &str| lines.iter().find(|l| l.split_whitespace().next() == Some(name)).unwrap().clone();
+        let fields = |name: &str| line(name).split_whitespace().map(str::to_string).collect::<Vec<_>>();
+        // Flags say yes or no, counts are numbers; user-facing flags are named
+        assert_eq!(fields("KVM_CAP_USER_MEMORY")[1], "yes");
+        assert!(fields("KVM_CAP_MAX_VCPUS")[1].parse::<u32>().unwrap() >= 1);
+        assert!(line("KVM_CAP_SET_GUEST_DEBUG").ends_with("--count-instructions"));
+        assert_eq!(fields("KVM_CAP_IRQFD").len(), 2);
+    }
+}
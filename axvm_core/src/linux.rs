@@ -4,11 +4,13 @@
 
 
 
-pub const ZERO_PAGE_START: usize = 0x7000;
-pub const CMDLINE_START: usize = 0x20000;
-pub const KERNEL_START: usize = 0x100000;
 pub const E820_RAM: u32 = 1;
+pub const E820_MAX_ENTRIES: usize = 128;
 pub const HDRS_MAGIC: u32 = 0x53726448;
+// Where `header` sits in the boot sector, i.e. 0x1F1 + offsetof(SetupHeader, header)
+pub const HDRS_MAGIC_OFFSET: u64 = 0x202;
+pub const SETUP_DTB: u32 = 2;
+pub const SETUP_RNG_SEED: u32 = 9;
 
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
@@ -61,10 +63,24 @@ pub struct E820Entry {
     pub type_: u32,
 }
 
+/// Header of a `setup_data` node; the payload follows immediately.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SetupData {
+    pub next: u64,
+    pub type_: u32,
+    pub len: u32,
+}
+
 #[repr(C, packed)]
 pub struct BootParams {
     
-    pub _pad1: [u8; 0x1e8], 
+    pub _pad0: [u8; 0x70],
+    
+    // Boot protocol 2.14: lets the kernel skip the RSDP scan of 0xE0000-0xFFFFF
+    pub acpi_rsdp_addr: u64,
+    
+    pub _pad1: [u8; 0x1e8 - 0x78], 
     
     
     pub e820_entries: u8,
@@ -94,7 +110,7 @@ pub struct BootParams {
     pub _pad4: [u8; 0x2d0 - 0x1f1 - std::mem::size_of::<SetupHeader>()],
     
     
-    pub e820_table: [E820Entry; 128],
+    pub e820_table: [E820Entry; E820_MAX_ENTRIES],
     
     
     pub _pad5: [u8; 4096 - 0x2d0 - (128 * 20)],
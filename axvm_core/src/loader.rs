@@ -11,9 +11,12 @@ use std::slice;
 
 use crate::memory::GuestMemory;
 use crate::linux::{
-    BootParams, SetupHeader, E820Entry,
-    ZERO_PAGE_START, CMDLINE_START, KERNEL_START,
-    E820_RAM, HDRS_MAGIC,
+    BootParams, SetupHeader, SetupData, E820Entry,
+    E820_RAM, E820_MAX_ENTRIES, HDRS_MAGIC, SETUP_DTB, SETUP_RNG_SEED,
+};
+use crate::layout::{
+    ZERO_PAGE_START, CMDLINE_START, KERNEL_START, SETUP_DATA_START, LOW_RAM_END, RSDP_START,
+    RAW_LOAD_MIN, IDENTITY_MAP_SIZE, DTB_START, DTB_END,
 };
 
 
@@ -30,6 +33,27 @@ const DEFAULT_SETUP_SECTS: u8 = 4;
 const SECTOR_SIZE: u64 = 512;
 
 
+const LEGACY_CMDLINE_MAX: usize = 255;
+
+
+const RNG_SEED_LEN: usize = 32;
+
+// Flattened device tree header: magic, then the blob's total size, both big-endian
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_HEADER_LEN: usize = 40;
+
+// initrd_addr_max of boot protocols before 2.03
+const LEGACY_INITRD_ADDR_MAX: u64 = 0x37FF_FFFF;
+
+const PAGE_SIZE: usize = 4096;
+
+// Low and high RAM
+const BUILTIN_E820_ENTRIES: usize = 2;
+
+/// Room left in the zero page for `--e820` entries.
+pub const MAX_EXTRA_E820: usize = E820_MAX_ENTRIES - BUILTIN_E820_ENTRIES;
+
+
 
 
 
@@ -74,6 +98,9 @@ pub fn load_linux(
     kernel_path: &str,
     mem_size: usize,
     cmdline: &str,
+    extra_e820: &[E820Entry],
+    initrd: &[u8],
+    dtb: &[u8],
 ) -> Result<u64, String> {
     let mut file = File::open(kernel_path)
         .map_err(|e| format!("Failed to open kernel file '{}': {}", kernel_path, e))?;
@@ -82,29 +109,10 @@ pub fn load_linux(
     
     
 
-    let mut boot_params = BootParams::default();
-
-    file.seek(SeekFrom::Start(SETUP_HEADER_OFFSET))
-        .map_err(|e| format!("Failed to seek to kernel header: {}", e))?;
-
-    
-    unsafe {
-        let header_slice = slice::from_raw_parts_mut(
-            ptr::addr_of_mut!(boot_params.hdr) as *mut u8,
-            mem::size_of::<SetupHeader>(),
-        );
-        file.read_exact(header_slice)
-            .map_err(|e| format!("Failed to read kernel header: {}", e))?;
-    }
-
-    
-    let header_magic = read_packed!(boot_params.hdr, header);
-    if header_magic != HDRS_MAGIC {
-        return Err(format!(
-            "Invalid kernel header magic: {:#x} (expected {:#x}). Not a valid bzImage?",
-            header_magic, HDRS_MAGIC
-        ));
-    }
+    let mut boot_params = BootParams {
+        hdr: read_setup_header(&mut file)?,
+        ..Default::default()
+    };
 
     let version = read_packed!(boot_params.hdr, version);
     log_loader(&format!(
@@ -113,31 +121,47 @@ pub fn load_linux(
         version & 0xFF
     ));
 
+    let kernel_offset = kernel_code_offset(&boot_params.hdr);
+    let kernel_len = kernel_code_len(&file, kernel_offset)?;
+    check_layout(mem_size, kernel_len, initrd.len(), cmdline.len(), cmdline_max(&boot_params.hdr))?;
+
+    let base = guest_mem.base();
+    check_extra_e820(extra_e820, (base + KERNEL_START) as u64, kernel_len as u64)?;
+    if base != 0 && read_packed!(boot_params.hdr, relocatable_kernel) == 0 {
+        return Err(format!("Kernel is not relocatable and cannot run with RAM at {:#x}", base));
+    }
+
     
     
     
     
     
 
-    write_packed!(boot_params, e820_entries, 2u8);
+    write_packed!(boot_params, e820_entries, (BUILTIN_E820_ENTRIES + extra_e820.len()) as u8);
 
     
     boot_params.e820_table[0] = E820Entry {
-        addr: 0,
-        size: 0x9FC00,  
+        addr: base as u64,
+        size: LOW_RAM_END as u64,  
         type_: E820_RAM,
     };
 
     
     boot_params.e820_table[1] = E820Entry {
-        addr: 0x100000,  
-        size: (mem_size - 0x100000) as u64,
+        addr: (base + KERNEL_START) as u64,  
+        size: (mem_size - KERNEL_START) as u64,
         type_: E820_RAM,
     };
 
-    log_loader(&format!("E820: Low RAM 0x0 - 0x9FC00 (639 KB)"));
-    log_loader(&format!("E820: High RAM 0x100000 - {:#x} ({} MB)", 
-        mem_size, (mem_size - 0x100000) / (1024 * 1024)));
+    log_loader(&format!("E820: Low RAM {:#x} - {:#x} (639 KB)", base, base + LOW_RAM_END));
+    log_loader(&format!("E820: High RAM {:#x} - {:#x} ({} MB)", 
+        base + KERNEL_START, base + mem_size, (mem_size - KERNEL_START) / (1024 * 1024)));
+
+    for (slot, entry) in boot_params.e820_table[BUILTIN_E820_ENTRIES..].iter_mut().zip(extra_e820) {
+        *slot = *entry;
+        let (addr, size, type_) = (entry.addr, entry.size, entry.type_);
+        log_loader(&format!("E820: Extra {:#x} - {:#x} (type {})", addr, addr + size, type_));
+    }
 
     
     
@@ -146,14 +170,14 @@ pub fn load_linux(
     if !cmdline.is_empty() {
         let cmdline_bytes = cmdline.as_bytes();
 
-        guest_mem.write_slice(CMDLINE_START, cmdline_bytes)
+        guest_mem.write_slice(base + CMDLINE_START, cmdline_bytes)
             .map_err(|e| format!("Failed to write cmdline: {}", e))?;
 
         
-        guest_mem.write_u8(CMDLINE_START + cmdline_bytes.len(), 0)
+        guest_mem.write_u8(base + CMDLINE_START + cmdline_bytes.len(), 0)
             .map_err(|e| format!("Failed to write cmdline terminator: {}", e))?;
 
-        write_packed!(boot_params.hdr, cmd_line_ptr, CMDLINE_START as u32);
+        write_packed!(boot_params.hdr, cmd_line_ptr, (base + CMDLINE_START) as u32);
         write_packed!(boot_params.hdr, cmdline_size, (cmdline_bytes.len() + 1) as u32);
 
         log_loader(&format!("Cmdline: '{}'", cmdline));
@@ -172,19 +196,45 @@ pub fn load_linux(
         write_packed!(boot_params.hdr, loadflags, loadflags | 0x80);
     }
 
+    // Seed the early RNG so KASLR has entropy to work with; `nokaslr`
+    // is no longer required on kernels that honour setup_data
+    if version >= 0x0209 {
+        let mut next = 0;
+        if !dtb.is_empty() {
+            guest_mem.write_slice(base + DTB_START, &setup_data_node(SETUP_DTB, 0, dtb))
+                .map_err(|e| format!("Failed to write device tree setup_data: {}", e))?;
+            next = (base + DTB_START) as u64;
+            log_loader(&format!("setup_data: {} byte device tree at {:#x}", dtb.len(), base + DTB_START));
+        }
+        let node = setup_data_node(SETUP_RNG_SEED, next, &read_rng_seed()?);
+        guest_mem.write_slice(base + SETUP_DATA_START, &node)
+            .map_err(|e| format!("Failed to write setup_data: {}", e))?;
+        write_packed!(boot_params.hdr, setup_data, (base + SETUP_DATA_START) as u64);
+        log_loader(&format!("setup_data: {} byte RNG seed at {:#x}", RNG_SEED_LEN, base + SETUP_DATA_START));
+    } else if !dtb.is_empty() {
+        return Err(format!(
+            "Kernel boot protocol {}.{} has no setup_data; --dtb needs 2.09 or later",
+            version >> 8, version & 0xFF
+        ));
+    }
+
+    if !initrd.is_empty() {
+        let addr = initrd_addr(&boot_params.hdr, base as u64, mem_size, kernel_len, initrd.len())?;
+        guest_mem.write_slice(addr as usize, initrd)
+            .map_err(|e| format!("Failed to write initrd: {}", e))?;
+        write_packed!(boot_params.hdr, ramdisk_image, addr as u32);
+        write_packed!(boot_params.hdr, ramdisk_size, initrd.len() as u32);
+        log_loader(&format!("Initrd: {} bytes at {:#x}", initrd.len(), addr));
+    }
+
+    // The legacy RSDP scan only looks at physical 0xE0000, which is not RAM
+    // once --ram-base moves it; point the kernel at `setup_acpi`'s tables
+    write_packed!(boot_params, acpi_rsdp_addr, (base + RSDP_START) as u64);
+
     
     
     
 
-    let setup_sects_raw = read_packed!(boot_params.hdr, setup_sects);
-    let setup_sects = if setup_sects_raw == 0 {
-        DEFAULT_SETUP_SECTS
-    } else {
-        setup_sects_raw
-    };
-
-    let kernel_offset = (setup_sects as u64 + 1) * SECTOR_SIZE;
-
     file.seek(SeekFrom::Start(kernel_offset))
         .map_err(|e| format!("Failed to seek to kernel code: {}", e))?;
 
@@ -192,16 +242,25 @@ pub fn load_linux(
     file.read_to_end(&mut kernel_code)
         .map_err(|e| format!("Failed to read kernel code: {}", e))?;
 
-    guest_mem.write_slice(KERNEL_START, &kernel_code)
+    guest_mem.write_slice(base + KERNEL_START, &kernel_code)
         .map_err(|e| format!("Failed to write kernel to memory: {}", e))?;
 
     log_loader(&format!(
         "Kernel loaded at {:#x}. Size: {} bytes ({} KB)",
-        KERNEL_START,
+        base + KERNEL_START,
         kernel_code.len(),
         kernel_code.len() / 1024
     ));
 
+    // code32_start assumes the kernel sits at 1 MiB; move it along with RAM
+    let code32_start = read_packed!(boot_params.hdr, code32_start);
+    let entry_point = if code32_start != 0 {
+        code32_start as u64 + base as u64
+    } else {
+        (base + KERNEL_START) as u64
+    };
+    write_packed!(boot_params.hdr, code32_start, entry_point as u32);
+
     
     
     
@@ -212,26 +271,18 @@ pub fn load_linux(
             ptr::addr_of!(boot_params) as *const u8,
             mem::size_of::<BootParams>(),
         );
-        guest_mem.write_slice(ZERO_PAGE_START, params_slice)
+        guest_mem.write_slice(base + ZERO_PAGE_START, params_slice)
             .map_err(|e| format!("Failed to write Zero Page: {}", e))?;
     }
 
-    log_loader(&format!("Zero Page written at {:#x}", ZERO_PAGE_START));
-
-    
-    let code32_start = read_packed!(boot_params.hdr, code32_start);
-    let entry_point = if code32_start != 0 {
-        code32_start as u64
-    } else {
-        KERNEL_START as u64
-    };
+    log_loader(&format!("Zero Page written at {:#x}", base + ZERO_PAGE_START));
 
     log_loader(&format!("Entry point (code32_start): {:#x}", entry_point));
 
     
-    let first_bytes = guest_mem.read_slice(KERNEL_START, 16)
+    let first_bytes = guest_mem.read_slice(base + KERNEL_START, 16)
         .map_err(|e| format!("Debug read failed: {}", e))?;
-    log_loader(&format!("Kernel first 16 bytes at {:#x}: {:02x?}", KERNEL_START, first_bytes));
+    log_loader(&format!("Kernel first 16 bytes at {:#x}: {:02x?}", base + KERNEL_START, first_bytes));
 
     Ok(entry_point)
 }
@@ -240,8 +291,263 @@ pub fn load_linux(
 
 
 
+/// Read back the E820 table `load_linux` stored in the zero page.
+pub fn read_e820(guest_mem: &GuestMemory) -> Result<Vec<E820Entry>, String> {
+    let zero_page = guest_mem.base() + ZERO_PAGE_START;
+    let count = guest_mem.read_slice(zero_page + mem::offset_of!(BootParams, e820_entries), 1)?[0] as usize;
+    let table = zero_page + mem::offset_of!(BootParams, e820_table);
+    let entry_size = mem::size_of::<E820Entry>();
+
+    let bytes = guest_mem.read_slice(table, count.min(E820_MAX_ENTRIES) * entry_size)?;
+    Ok(bytes.chunks(entry_size).map(|chunk| unsafe {
+        ptr::read_unaligned(chunk.as_ptr() as *const E820Entry)
+    }).collect())
+}
+
+/// Check that the kernel, an initrd of `initrd_size` bytes and the command
+/// line fit in a guest of `mem_size` bytes, without writing anything.
+pub fn check_fit(kernel_path: &str, mem_size: usize, cmdline: &str, initrd_size: usize) -> Result<(), String> {
+    let mut file = File::open(kernel_path)
+        .map_err(|e| format!("Failed to open kernel file '{}': {}", kernel_path, e))?;
+    let hdr = read_setup_header(&mut file)?;
+    let kernel_len = kernel_code_len(&file, kernel_code_offset(&hdr))?;
+    check_layout(mem_size, kernel_len, initrd_size, cmdline.len(), cmdline_max(&hdr))
+}
+
+/// Where the initrd goes: as high as the kernel allows, page aligned, and
+/// clear of the kernel's in-place decompression (`init_size`).
+fn initrd_addr(hdr: &SetupHeader, base: u64, mem_size: usize, kernel_len: usize, initrd_len: usize) -> Result<u64, String> {
+    let addr_max = match read_packed!(hdr, initrd_addr_max) {
+        max if max != 0 && read_packed!(hdr, version) >= 0x0203 => max as u64,
+        _ => LEGACY_INITRD_ADDR_MAX,
+    };
+    let kernel_end = base + (KERNEL_START + kernel_len.max(read_packed!(hdr, init_size) as usize)) as u64;
+    let top = (base + mem_size as u64).min(addr_max + 1);
+    let addr = top.checked_sub(initrd_len as u64).map(|a| a & !(PAGE_SIZE as u64 - 1));
+    match addr {
+        Some(addr) if addr >= kernel_end => Ok(addr),
+        _ => Err(format!(
+            "Initrd of {} bytes does not fit between the kernel (ends at {:#x}) and initrd_addr_max {:#x}",
+            initrd_len, kernel_end, addr_max
+        )),
+    }
+}
+
+/// `--e820` entries must fit in the zero page and leave the kernel image alone.
+fn check_extra_e820(extra: &[E820Entry], kernel_start: u64, kernel_len: u64) -> Result<(), String> {
+    if extra.len() > MAX_EXTRA_E820 {
+        return Err(format!("Too many extra E820 entries: {}. Maximum: {}", extra.len(), MAX_EXTRA_E820));
+    }
+    let kernel_end = kernel_start + kernel_len;
+    for entry in extra {
+        let (addr, size) = (entry.addr, entry.size);
+        if addr < kernel_end && kernel_start < addr + size {
+            return Err(format!(
+                "E820 entry {:#x} - {:#x} overlaps the kernel loaded at {:#x} - {:#x}",
+                addr, addr + size, kernel_start, kernel_end
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_layout(
+    mem_size: usize,
+    kernel_len: usize,
+    initrd_len: usize,
+    cmdline_len: usize,
+    cmdline_max: usize,
+) -> Result<(), String> {
+    let available = mem_size.saturating_sub(KERNEL_START);
+    if kernel_len > available {
+        return Err(format!(
+            "Kernel does not fit in guest RAM: requires {} bytes at {:#x}, {} bytes available",
+            kernel_len, KERNEL_START, available
+        ));
+    }
+
+    if initrd_len > available - kernel_len {
+        return Err(format!(
+            "Initrd does not fit in guest RAM: requires {} bytes, {} bytes available after the kernel",
+            initrd_len, available - kernel_len
+        ));
+    }
+
+    // Terminating NUL included
+    if cmdline_len + 1 > cmdline_max {
+        return Err(format!(
+            "Kernel command line too long: requires {} bytes, kernel accepts {} bytes",
+            cmdline_len + 1, cmdline_max
+        ));
+    }
+
+    Ok(())
+}
+
+fn read_setup_header(file: &mut File) -> Result<SetupHeader, String> {
+    let mut hdr = SetupHeader::default();
+
+    file.seek(SeekFrom::Start(SETUP_HEADER_OFFSET))
+        .map_err(|e| format!("Failed to seek to kernel header: {}", e))?;
+
+    
+    unsafe {
+        let header_slice = slice::from_raw_parts_mut(
+            ptr::addr_of_mut!(hdr) as *mut u8,
+            mem::size_of::<SetupHeader>(),
+        );
+        file.read_exact(header_slice)
+            .map_err(|e| format!("Failed to read kernel header: {}", e))?;
+    }
+
+    
+    let header_magic = read_packed!(hdr, header);
+    if header_magic != HDRS_MAGIC {
+        return Err(format!(
+            "Invalid kernel header magic: {:#x} (expected {:#x}). Not a valid bzImage?",
+            header_magic, HDRS_MAGIC
+        ));
+    }
+
+    Ok(hdr)
+}
+
+fn kernel_code_offset(hdr: &SetupHeader) -> u64 {
+    let setup_sects_raw = read_packed!(hdr, setup_sects);
+    let setup_sects = if setup_sects_raw == 0 {
+        DEFAULT_SETUP_SECTS
+    } else {
+        setup_sects_raw
+    };
+
+    (setup_sects as u64 + 1) * SECTOR_SIZE
+}
+
+fn kernel_code_len(file: &File, kernel_offset: u64) -> Result<usize, String> {
+    let file_len = file.metadata()
+        .map_err(|e| format!("Failed to stat kernel file: {}", e))?
+        .len();
+    Ok(file_len.saturating_sub(kernel_offset) as usize)
+}
+
+/// Longest command line (including NUL) the kernel advertises.
+fn cmdline_max(hdr: &SetupHeader) -> usize {
+    if read_packed!(hdr, version) >= 0x0206 {
+        read_packed!(hdr, cmdline_size) as usize
+    } else {
+        LEGACY_CMDLINE_MAX
+    }
+}
+
+fn read_rng_seed() -> Result<[u8; RNG_SEED_LEN], String> {
+    let mut seed = [0u8; RNG_SEED_LEN];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut seed))
+        .map_err(|e| format!("Failed to read RNG seed from /dev/urandom: {}", e))?;
+    Ok(seed)
+}
+
+/// Serialize one setup_data node of `type_` holding `data`, linked to `next` (0 ends the list).
+fn setup_data_node(type_: u32, next: u64, data: &[u8]) -> Vec<u8> {
+    let header = SetupData {
+        next,
+        type_,
+        len: data.len() as u32,
+    };
+
+    let mut node = Vec::with_capacity(mem::size_of::<SetupData>() + data.len());
+    unsafe {
+        node.extend_from_slice(slice::from_raw_parts(
+            ptr::addr_of!(header) as *const u8,
+            mem::size_of::<SetupData>(),
+        ));
+    }
+    node.extend_from_slice(data);
+    node
+}
+
+/// Read a `--dtb` file and check its header. Returns the blob, trimmed
+/// to the size the header declares.
+pub fn read_dtb(path: &str) -> Result<Vec<u8>, String> {
+    let mut blob = std::fs::read(path)
+        .map_err(|e| format!("Failed to read device tree '{}': {}", path, e))?;
+    let be32 = |at: usize| u32::from_be_bytes([blob[at], blob[at + 1], blob[at + 2], blob[at + 3]]);
+    if blob.len() < FDT_HEADER_LEN || be32(0) != FDT_MAGIC {
+        return Err(format!("'{}' is not a flattened device tree (no {:#x} magic)", path, FDT_MAGIC));
+    }
+
+    let total = be32(4) as usize;
+    if total < FDT_HEADER_LEN || total > blob.len() {
+        return Err(format!("Device tree '{}' is truncated: header says {} bytes, file has {}", path, total, blob.len()));
+    }
+    // Room for the setup_data header in front of it on the Linux path
+    let max = DTB_END - DTB_START - mem::size_of::<SetupData>();
+    if total > max {
+        return Err(format!("Device tree '{}' is too large: {} bytes, at most {} fit", path, total, max));
+    }
+    blob.truncate(total);
+    Ok(blob)
+}
+
+/// Copy `dtb` into guest RAM for a raw payload occupying `payload_len`
+/// bytes at `load_addr`. Returns the blob's guest address.
+pub fn load_raw_dtb(guest_mem: &mut GuestMemory, dtb: &[u8], load_addr: u64, payload_len: usize) -> Result<u64, String> {
+    let addr = (guest_mem.base() + DTB_START) as u64;
+    let end = addr + dtb.len() as u64;
+    if load_addr < end && addr < load_addr + payload_len as u64 {
+        return Err(format!(
+            "Raw payload at {:#x}-{:#x} overlaps the device tree at {:#x}-{:#x}",
+            load_addr, load_addr + payload_len as u64, addr, end
+        ));
+    }
+    guest_mem.write_slice(addr as usize, dtb)?;
+    log_loader(&format!("Device tree: {} bytes at {:#x}", dtb.len(), addr));
+    Ok(addr)
+}
+
+/// Copy a flat binary to `load_addr` with no boot protocol: no zero page,
+/// command line or ACPI handoff. Returns the payload size.
+pub fn load_raw(guest_mem: &mut GuestMemory, path: &str, load_addr: u64, entry: u64) -> Result<usize, String> {
+    let payload = std::fs::read(path)
+        .map_err(|e| format!("Failed to read raw payload '{}': {}", path, e))?;
+    check_raw_layout(guest_mem.base() as u64, guest_mem.len() as u64, load_addr, payload.len(), entry)?;
+
+    guest_mem.write_slice(load_addr as usize, &payload)?;
+    log_loader(&format!("Raw payload: {} bytes at {:#x}, entry {:#x}", payload.len(), load_addr, entry));
+    Ok(payload.len())
+}
+
+/// Check a raw payload against guest RAM `[ram_base, ram_end)` and the
+/// identity map `setup_long_mode_with_entry` builds.
+fn check_raw_layout(ram_base: u64, ram_end: u64, load_addr: u64, len: usize, entry: u64) -> Result<(), String> {
+    if load_addr < ram_base + RAW_LOAD_MIN {
+        return Err(format!(
+            "Raw load address {:#x} overlaps the boot page tables and GDT below {:#x}",
+            load_addr, ram_base + RAW_LOAD_MIN
+        ));
+    }
+
+    let end = load_addr.saturating_add(len as u64);
+    let identity_map_end = (ram_base & !(IDENTITY_MAP_SIZE - 1)) + IDENTITY_MAP_SIZE;
+    let limit = ram_end.min(identity_map_end);
+    if end > limit {
+        return Err(format!(
+            "Raw payload does not fit: {} bytes at {:#x} end past {:#x}",
+            len, load_addr, limit
+        ));
+    }
+
+    if !(load_addr..end).contains(&entry) {
+        return Err(format!(
+            "Raw entry point {:#x} is outside the payload at {:#x}-{:#x}",
+            entry, load_addr, end
+        ));
+    }
+    Ok(())
+}
+
 fn log_loader(msg: &str) {
-    println!(">>> [Loader] {}", msg);
+    status!("Loader", "{}", msg);
 }
 
 
@@ -257,5 +563,125 @@ mod tests {
         assert_eq!(SETUP_HEADER_OFFSET, 0x1F1);
         assert_eq!(SECTOR_SIZE, 512);
         assert_eq!(KERNEL_START, 0x100000);
+        assert_eq!(SETUP_HEADER_OFFSET + mem::offset_of!(SetupHeader, header) as u64, crate::linux::HDRS_MAGIC_OFFSET);
+    }
+
+    #[test]
+    fn test_rng_seed_setup_data() {
+        let node = setup_data_node(SETUP_RNG_SEED, 0, &[0xAB; RNG_SEED_LEN]);
+        assert_eq!(node.len(), 16 + RNG_SEED_LEN);
+        assert_eq!(&node[0..8], &[0; 8]);
+        assert_eq!(u32::from_le_bytes(node[8..12].try_into().unwrap()), SETUP_RNG_SEED);
+        assert_eq!(u32::from_le_bytes(node[12..16].try_into().unwrap()), RNG_SEED_LEN as u32);
+        assert!(node[16..].iter().all(|&b| b == 0xAB));
+    }
+
+    #[test]
+    fn test_check_layout() {
+        let mem = 128 * 1024 * 1024;
+        assert!(check_layout(mem, 8 << 20, 0, 100, 2048).is_ok());
+
+        let err = check_layout(mem, mem, 0, 100, 2048).unwrap_err();
+        assert!(err.starts_with("Kernel does not fit"));
+        assert!(err.contains(&format!("{} bytes available", mem - KERNEL_START)));
+
+        let err = check_layout(mem, 8 << 20, mem, 100, 2048).unwrap_err();
+        assert!(err.starts_with("Initrd does not fit"));
+
+        let err = check_layout(mem, 8 << 20, 0, 4096, 2048).unwrap_err();
+        assert!(err.starts_with("Kernel command line too long"));
+        assert!(err.contains("requires 4097 bytes"));
+    }
+
+    #[test]
+    fn test_initrd_addr() {
+        let mut hdr = SetupHeader { version: 0x020F, initrd_addr_max: 0x7FFF_FFFF, ..Default::default() };
+        let mem = 128 << 20;
+        assert_eq!(initrd_addr(&hdr, 0, mem, 8 << 20, 0x1800), Ok(mem as u64 - 0x2000));
+
+        // Capped by initrd_addr_max, and by what the kernel decompresses into
+        hdr.initrd_addr_max = 0x3FF_FFFF;
+        assert_eq!(initrd_addr(&hdr, 0, mem, 8 << 20, 0x1000), Ok(0x3FF_F000));
+        hdr.init_size = 0x3F0_0000;
+        assert!(initrd_addr(&hdr, 0, mem, 8 << 20, 0x10_0000).unwrap_err().starts_with("Initrd of"));
+        assert!(initrd_addr(&hdr, 0x1_0000_0000, mem, 8 << 20, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_check_extra_e820() {
+        let entry = |addr, size| E820Entry { addr, size, type_: 2 };
+        let kernel = (KERNEL_START as u64, 0x80000);
+        assert!(check_extra_e820(&[entry(0xA0000, 0x60000)], kernel.0, kernel.1).is_ok());
+        assert!(check_extra_e820(&[entry(0x180000, 0x1000)], kernel.0, kernel.1).is_ok());
+        assert!(check_extra_e820(&[entry(0x17F000, 0x1000)], kernel.0, kernel.1).is_err());
+        assert!(check_extra_e820(&[entry(0xF0000, 0x20000)], kernel.0, kernel.1).is_err());
+        assert!(check_extra_e820(&vec![entry(0, 0x1000); MAX_EXTRA_E820 + 1], kernel.0, kernel.1).is_err());
+    }
+
+    #[test]
+    fn test_check_raw_layout() {
+        let mem = 128 * 1024 * 1024;
+        assert!(check_raw_layout(0, mem, 0x100000, 4096, 0x100000).is_ok());
+        assert!(check_raw_layout(0, mem, 0x100000, 4096, 0x100FFF).is_ok());
+
+        assert!(check_raw_layout(0, mem, 0x1000, 4096, 0x1000).unwrap_err().contains("page tables"));
+        assert!(check_raw_layout(0, mem, mem - 16, 32, mem - 16).unwrap_err().contains("does not fit"));
+        assert!(check_raw_layout(0, mem, 0x100000, 4096, 0x101000).unwrap_err().contains("outside the payload"));
+
+        // With RAM moved up, the page tables and the identity map move with it
+        let base = 0x3FE0_0000;
+        assert!(check_raw_layout(base, base + mem, 0x100000, 4096, 0x100000).unwrap_err().contains("page tables"));
+        assert!(check_raw_layout(base, base + mem, base + 0x5000, 4096, base + 0x5000).is_ok());
+        assert!(check_raw_layout(base, base + mem, base + 0x200000, 4096, base + 0x200000).unwrap_err().contains("does not fit"));
+    }
+
+    #[test]
+    fn test_read_and_place_dtb() {
+        let path = std::env::temp_dir().join(format!("axvm-test-dtb-{}", std::process::id()));
+        let mut blob = vec![0u8; 64];
+        blob[0..4].copy_from_slice(&FDT_MAGIC.to_be_bytes());
+        blob[4..8].copy_from_slice(&48u32.to_be_bytes());
+        std::fs::write(&path, &blob).unwrap();
+        let dtb = read_dtb(path.to_str().unwrap()).unwrap();
+        assert_eq!(dtb, &blob[..48]);
+
+        blob[4..8].copy_from_slice(&65u32.to_be_bytes());
+        std::fs::write(&path, &blob).unwrap();
+        assert!(read_dtb(path.to_str().unwrap()).unwrap_err().contains("truncated"));
+        blob[0] = 0;
+        std::fs::write(&path, &blob).unwrap();
+        assert!(read_dtb(path.to_str().unwrap()).unwrap_err().contains("not a flattened device tree"));
+        let _ = std::fs::remove_file(path);
+
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+        assert_eq!(load_raw_dtb(&mut mem, &dtb, 0x100000, 4096).unwrap(), DTB_START as u64);
+        assert!(mem.compare_region(DTB_START, &dtb));
+        assert!(load_raw_dtb(&mut mem, &dtb, DTB_START as u64 + 16, 4096).unwrap_err().contains("overlaps"));
+    }
+
+    #[test]
+    fn test_read_e820_after_load() {
+        // Boot protocol 2.15 header, 4 setup sectors, then 8 sectors of code
+        let path = std::env::temp_dir().join(format!("axvm-test-bzimage-{}", std::process::id()));
+        let mut image = vec![0u8; 13 * 512];
+        image[0x1F1] = 4;
+        image[0x202..0x206].copy_from_slice(b"HdrS");
+        image[0x206..0x208].copy_from_slice(&0x020Fu16.to_le_bytes());
+        image[0x238..0x23C].copy_from_slice(&2047u32.to_le_bytes());
+        std::fs::write(&path, image).unwrap();
+
+        let mem_size = 16 * 1024 * 1024;
+        let mut mem = GuestMemory::new(mem_size).unwrap();
+        let extra = E820Entry { addr: 0xA0000, size: 0x60000, type_: 2 };
+        let loaded = load_linux(&mut mem, path.to_str().unwrap(), mem_size, "console=ttyS0", &[extra], &[], &[]);
+        let _ = std::fs::remove_file(path);
+        loaded.unwrap();
+
+        let e820: Vec<_> = read_e820(&mem).unwrap().iter().map(|e| (e.addr, e.size, e.type_)).collect();
+        assert_eq!(e820, [
+            (0, LOW_RAM_END as u64, E820_RAM),
+            (KERNEL_START as u64, (mem_size - KERNEL_START) as u64, E820_RAM),
+            (0xA0000, 0x60000, 2),
+        ]);
     }
 }
\ No newline at end of file
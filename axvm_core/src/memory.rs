@@ -6,10 +6,15 @@
 
 #![allow(dead_code)]
 
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::ptr;
 use libc::{
-    c_void, mmap, munmap, madvise, 
-    MAP_PRIVATE, MAP_ANONYMOUS, PROT_READ, PROT_WRITE, MAP_FAILED, 
+    c_void, mmap, munmap, madvise, mlock, munlock,
+    MAP_PRIVATE, MAP_ANONYMOUS, MAP_SHARED, MAP_FIXED, PROT_READ, PROT_WRITE, MAP_FAILED, 
     MADV_HUGEPAGE
 };
 
@@ -17,7 +22,11 @@ use libc::{
 pub struct GuestMemory {
     ptr: *mut u8,
     len: usize,
+    // Guest physical address of the first byte of RAM; the mapping below it is unused
+    base: usize,
     owned: bool, 
+    // RAM is mlock()ed and must be unlocked before the unmap
+    locked: bool,
 }
 
 
@@ -27,62 +36,156 @@ unsafe impl Sync for GuestMemory {}
 impl GuestMemory {
     
     pub fn new(size: usize) -> Result<Self, String> {
+        Self::new_at(0, size)
+    }
+
+    /// Guest RAM of `size` bytes starting at guest physical `base`. The host
+    /// mapping still covers `[0, base)` so guest physical addresses index it
+    /// directly, but those pages are never touched or handed to KVM.
+    pub fn new_at(base: usize, size: usize) -> Result<Self, String> {
+        Self::map(base, size, None, false)
+    }
+
+    pub fn new_file_backed(path: &Path, size: usize) -> Result<Self, String> {
+        Self::new_file_backed_at(0, path, size)
+    }
+
+    /// Like [`new_at`](Self::new_at), but RAM is a `MAP_SHARED` mapping of
+    /// `path`, created if needed and sized to the RAM. Its existing contents
+    /// are kept, and everything the guest writes ends up in the file.
+    pub fn new_file_backed_at(base: usize, path: &Path, size: usize) -> Result<Self, String> {
+        Self::allocate(base, size, Some(path), false)
+    }
+
+    /// Anonymous or file-backed RAM as above; with `lock` it is pinned in
+    /// host memory (mlock) so it is never swapped out.
+    pub fn allocate(base: usize, size: usize, path: Option<&Path>, lock: bool) -> Result<Self, String> {
+        match path {
+            Some(path) => {
+                let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+                    .map_err(|e| format!("Failed to open memory file {}: {}", path.display(), e))?;
+                Self::map(base, size, Some(&file), lock)
+            },
+            None => Self::map(base, size, None, lock),
+        }
+    }
+
+    fn map(base: usize, size: usize, file: Option<&File>, lock: bool) -> Result<Self, String> {
         
         let align_mask = (2 * 1024 * 1024) - 1;
         let aligned_size = (size + align_mask) & !align_mask;
 
+        if let Some(file) = file {
+            file.set_len(aligned_size as u64)
+                .map_err(|e| format!("Failed to size memory file to {} MB: {}", aligned_size / 1024 / 1024, e))?;
+        }
+
         unsafe {
             
-            let ptr = mmap(
+            let map = mmap(
                 ptr::null_mut(),
-                aligned_size,
+                base + aligned_size,
                 PROT_READ | PROT_WRITE,
                 MAP_PRIVATE | MAP_ANONYMOUS,
                 -1,
                 0,
             );
 
-            if ptr == MAP_FAILED {
+            if map == MAP_FAILED {
                 return Err(format!("mmap failed (Size: {} MB)", aligned_size / 1024 / 1024));
             }
+            let ptr = (map as *mut u8).add(base) as *mut c_void;
 
-            
-            if madvise(ptr, aligned_size, MADV_HUGEPAGE) != 0 {
-                println!(">>> [WARN] Failed to enable Huge Pages (madvise error). Using 4KB pages.");
-            } else {
-                println!(">>> [Mem] Huge Pages (THP) hints enabled for guest RAM.");
+            match file {
+                // Replace the RAM part of the reservation with the file
+                Some(file) => {
+                    let shared = mmap(ptr, aligned_size, PROT_READ | PROT_WRITE, MAP_SHARED | MAP_FIXED, file.as_raw_fd(), 0);
+                    if shared == MAP_FAILED {
+                        let err = std::io::Error::last_os_error();
+                        munmap(map, base + aligned_size);
+                        return Err(format!("mmap of memory file failed (Size: {} MB): {}", aligned_size / 1024 / 1024, err));
+                    }
+                    status!("Mem", "Guest RAM is a shared mapping of the memory file.");
+                },
+                None => {
+                    if madvise(ptr, aligned_size, MADV_HUGEPAGE) != 0 {
+                        status!(warn, "WARN", "Failed to enable Huge Pages (madvise error). Using 4KB pages.");
+                    } else {
+                        status!("Mem", "Huge Pages (THP) hints enabled for guest RAM.");
+                    }
+                },
             }
 
-            
-            ptr::write_bytes(ptr as *mut u8, 0, aligned_size);
+            // mlock faults every page in, so the prefault below only writes resident memory
+            if lock {
+                if mlock(ptr, aligned_size) != 0 {
+                    let err = std::io::Error::last_os_error();
+                    munmap(map, base + aligned_size);
+                    return Err(format!("mlock of {} MB of guest RAM failed: {} ({})",
+                        aligned_size / 1024 / 1024, err, memlock_limit()));
+                }
+                status!("Mem", "Guest RAM locked in host memory (mlock).");
+            }
+
+            if file.is_none() {
+                ptr::write_bytes(ptr as *mut u8, 0, aligned_size);
+            }
 
             Ok(Self {
-                ptr: ptr as *mut u8,
-                len: size,
+                ptr: map as *mut u8,
+                len: base + size,
+                base,
                 owned: true,
+                locked: lock,
             })
         }
     }
-
     
     
 
+    /// Host address of the first byte of guest RAM (guest physical `base()`).
     #[inline]
     pub fn as_ptr(&self) -> *mut u8 {
-        self.ptr
+        unsafe { self.ptr.add(self.base) }
     }
 
+    /// Guest physical address one past the end of RAM.
     #[inline]
     pub fn len(&self) -> usize {
         self.len
     }
 
+    #[inline]
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Guest physical addresses backed by RAM, `[base(), len())`; the device
+    /// view from [`as_mut_slice`](Self::as_mut_slice) must stay inside it.
+    #[inline]
+    pub fn ram(&self) -> Range<usize> {
+        self.base..self.len
+    }
+
+    /// Bytes of guest RAM.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.len - self.base
+    }
+
+    /// Guest physical `[0, len())`; indices below `base()` hit host memory
+    /// the guest cannot see.
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
     
     
     
 
     pub fn write_slice(&mut self, offset: usize, data: &[u8]) -> Result<(), String> {
-        if offset + data.len() > self.len {
+        if offset < self.base || offset.checked_add(data.len()).is_none_or(|end| end > self.len) {
             return Err(format!("Memory write overflow: addr={:#x}, len={}", offset, data.len()));
         }
         unsafe {
@@ -93,7 +196,7 @@ impl GuestMemory {
     }
 
     pub fn read_slice(&self, offset: usize, len: usize) -> Result<&[u8], String> {
-        if offset + len > self.len {
+        if offset < self.base || offset.checked_add(len).is_none_or(|end| end > self.len) {
             return Err(format!("Memory read overflow: addr={:#x}, len={}", offset, len));
         }
         unsafe {
@@ -102,6 +205,23 @@ impl GuestMemory {
         }
     }
 
+    /// Set `len` bytes at `offset` to `byte`.
+    pub fn fill(&mut self, offset: usize, byte: u8, len: usize) -> Result<(), String> {
+        if offset < self.base || offset.checked_add(len).is_none_or(|end| end > self.len) {
+            return Err(format!("Memory fill overflow: addr={:#x}, len={}", offset, len));
+        }
+        unsafe {
+            ptr::write_bytes(self.ptr.add(offset), byte, len);
+        }
+        Ok(())
+    }
+
+    /// Whether guest memory at `offset` holds exactly `expected`; a region
+    /// that runs out of RAM never matches.
+    pub fn compare_region(&self, offset: usize, expected: &[u8]) -> bool {
+        self.read_slice(offset, expected.len()).is_ok_and(|actual| actual == expected)
+    }
+
     
     
     pub fn write_u8(&mut self, offset: usize, val: u8) -> Result<(), String> {
@@ -121,14 +241,208 @@ impl GuestMemory {
     }
 }
 
+/// Host memory backing the guest RAM mapping, from `/proc/self/smaps`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub size_kb: u64,
+    pub rss_kb: u64,
+    /// Part of `rss_kb` backed by transparent huge pages
+    pub anon_huge_kb: u64,
+}
+
+impl MemoryUsage {
+    /// Sum the smaps entries of every mapping overlapping `[start, end)`.
+    fn from_smaps(smaps: &str, start: usize, end: usize) -> Self {
+        let mut usage = Self::default();
+        let mut in_range = false;
+        for line in smaps.lines() {
+            let mut fields = line.split_whitespace();
+            let Some(key) = fields.next() else { continue };
+
+            let Some(key) = key.strip_suffix(':') else {
+                // Mapping header: "start-end perms offset dev inode [path]"
+                in_range = key.split_once('-')
+                    .and_then(|(lo, hi)| Some((usize::from_str_radix(lo, 16).ok()?, usize::from_str_radix(hi, 16).ok()?)))
+                    .is_some_and(|(lo, hi)| lo < end && hi > start);
+                continue;
+            };
+            if !in_range {
+                continue;
+            }
+            let kb = fields.next().and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+            match key {
+                "Size" => usage.size_kb += kb,
+                "Rss" => usage.rss_kb += kb,
+                "AnonHugePages" => usage.anon_huge_kb += kb,
+                _ => {}
+            }
+        }
+        usage
+    }
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} MB resident of {} MB ({} MB in huge pages, {} MB in 4KB pages)",
+            self.rss_kb / 1024, self.size_kb / 1024,
+            self.anon_huge_kb / 1024, self.rss_kb.saturating_sub(self.anon_huge_kb) / 1024)
+    }
+}
+
+impl GuestMemory {
+    /// Report how much of the guest RAM mapping is resident on the host.
+    pub fn resident_usage(&self) -> Result<MemoryUsage, String> {
+        let smaps = std::fs::read_to_string("/proc/self/smaps")
+            .map_err(|e| format!("Failed to read /proc/self/smaps: {}", e))?;
+        let start = self.as_ptr() as usize;
+        Ok(MemoryUsage::from_smaps(&smaps, start, start + self.size()))
+    }
+}
+
+/// `len` bytes at guest physical `addr` of the RAM slice `mem`, or `None`
+/// if any of it falls outside. Descriptor addresses come from the guest, so
+/// the end is computed without overflowing.
+pub fn guest_slice(mem: &[u8], addr: u64, len: usize) -> Option<&[u8]> {
+    let start = usize::try_from(addr).ok()?;
+    mem.get(start..start.checked_add(len)?)
+}
+
+/// Mutable counterpart of [`guest_slice`].
+pub fn guest_slice_mut(mem: &mut [u8], addr: u64, len: usize) -> Option<&mut [u8]> {
+    let start = usize::try_from(addr).ok()?;
+    mem.get_mut(start..start.checked_add(len)?)
+}
+
+/// What the caller may lock, for the mlock error message.
+fn memlock_limit() -> String {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return "RLIMIT_MEMLOCK unknown".to_string();
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return "RLIMIT_MEMLOCK is unlimited".to_string();
+    }
+    format!("RLIMIT_MEMLOCK is {} KB; raise it with `ulimit -l` or grant CAP_IPC_LOCK", limit.rlim_cur / 1024)
+}
+
 impl Drop for GuestMemory {
     fn drop(&mut self) {
         
         
         if self.owned && !self.ptr.is_null() {
             unsafe {
+                if self.locked {
+                    munlock(self.as_ptr() as *const c_void, self.len - self.base);
+                }
                 munmap(self.ptr as *mut c_void, self.len);
             }
         }
     }
-}
\ No newline at end of file
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_slice_bounds() {
+        let mut ram = vec![0u8; 0x1000];
+        assert_eq!(guest_slice(&ram, 0xFF0, 0x10).map(<[u8]>::len), Some(0x10));
+        assert!(guest_slice(&ram, 0xFF0, 0x11).is_none());
+        assert!(guest_slice(&ram, u64::MAX, 2).is_none());
+        guest_slice_mut(&mut ram, 0x10, 2).unwrap().copy_from_slice(&[1, 2]);
+        assert_eq!(&ram[0x10..0x12], &[1, 2]);
+
+        let mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+        assert!(mem.read_slice(usize::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn test_ram_base_offsets_addresses() {
+        let mut mem = GuestMemory::new_at(0x200000, 0x200000).unwrap();
+        assert_eq!((mem.base(), mem.size(), mem.len()), (0x200000, 0x200000, 0x400000));
+        assert!(mem.write_u8(0x1000, 1).is_err());
+        assert!(mem.read_slice(0x1FFFFF, 2).is_err());
+        mem.write_u16(0x200000, 0xBEEF).unwrap();
+        assert_eq!(&mem.as_mut_slice()[0x200000..0x200002], &[0xEF, 0xBE]);
+        assert_eq!(unsafe { *mem.as_ptr() }, 0xEF);
+    }
+
+    #[test]
+    fn test_fill_and_compare_region() {
+        let mut mem = GuestMemory::new_at(0x200000, 0x200000).unwrap();
+        mem.fill(0x200100, 0xAA, 0x10).unwrap();
+        assert!(mem.compare_region(0x200100, &[0xAA; 0x10]));
+        assert!(mem.compare_region(0x2000FF, &[0x00, 0xAA]));
+        assert!(!mem.compare_region(0x200100, &[0xAA; 0x11]));
+
+        assert!(mem.fill(0x1000, 0, 1).is_err());
+        assert!(mem.fill(0x3FFFFF, 0, 2).is_err());
+        assert!(mem.fill(usize::MAX, 0, 2).is_err());
+        mem.fill(0x3FFFFF, 0x55, 1).unwrap();
+        assert!(mem.compare_region(0x3FFFFF, &[0x55]));
+        assert!(!mem.compare_region(0x3FFFFF, &[0x55, 0]));
+        assert!(!mem.compare_region(0x1000, &[]));
+    }
+
+    #[test]
+    fn test_mlock_pins_guest_ram() {
+        let locked_kb = || {
+            let status = std::fs::read_to_string("/proc/self/status").unwrap();
+            status.lines().find_map(|l| l.strip_prefix("VmLck:"))
+                .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok()).unwrap()
+        };
+        match GuestMemory::allocate(0x200000, 0x200000, None, true) {
+            Ok(mut mem) => {
+                assert!(locked_kb() >= 2048);
+                mem.write_u8(0x200000, 1).unwrap();
+            },
+            // Unprivileged runs with the default limit
+            Err(e) => assert!(e.contains("RLIMIT_MEMLOCK"), "{}", e),
+        }
+    }
+
+    #[test]
+    fn test_file_backed_memory_persists() {
+        let path = std::env::temp_dir().join(format!("axvm-test-memfile-{}", std::process::id()));
+        {
+            let mut mem = GuestMemory::new_file_backed_at(0x200000, &path, 0x200000).unwrap();
+            mem.write_u32(0x200010, 0xCAFEF00D).unwrap();
+        }
+        let contents = std::fs::read(&path).unwrap();
+        assert_eq!(contents.len(), 0x200000);
+        assert_eq!(&contents[0x10..0x14], &0xCAFEF00Du32.to_le_bytes());
+
+        // Reopening keeps what the previous run left behind
+        let mem = GuestMemory::new_file_backed(&path, 0x200000).unwrap();
+        assert_eq!(mem.read_slice(0x10, 4).unwrap(), &0xCAFEF00Du32.to_le_bytes());
+        drop(mem);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_resident_usage_counts_guest_mapping() {
+        let smaps = concat!(
+            "7f0000000000-7f0040000000 rw-p 00000000 00:00 0 \n",
+            "Size:            1048576 kB\n",
+            "Rss:              204800 kB\n",
+            "AnonHugePages:    198656 kB\n",
+            "VmFlags: rd wr mr mw me ac hg \n",
+            "7f0040000000-7f0040001000 r--p 00000000 08:01 42   /usr/lib/libc.so.6\n",
+            "Size:                  4 kB\n",
+            "Rss:                   4 kB\n",
+            "AnonHugePages:         0 kB\n",
+        );
+        let usage = MemoryUsage::from_smaps(smaps, 0x7f0000000000, 0x7f0040000000);
+        assert_eq!(usage, MemoryUsage { size_kb: 1048576, rss_kb: 204800, anon_huge_kb: 198656 });
+
+        let mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+        let live = mem.resident_usage().unwrap();
+        assert_eq!(live.size_kb, 2048);
+        assert!(live.rss_kb <= live.size_kb);
+    }
+}
@@ -24,11 +24,20 @@ pub struct VmMetrics {
     hlt_exits: AtomicU64,
     interrupt_exits: AtomicU64,
     exception_exits: AtomicU64,
+    // MMIO writes KVM buffered instead of exiting
+    coalesced_mmio_writes: AtomicU64,
+    
+    // Interrupts raised per device, counted at the irqchip
+    blk_irqs: AtomicU64,
+    net_irqs: AtomicU64,
+    serial_irqs: AtomicU64,
     
     
     errors: AtomicU64,
     hardware_failures: AtomicU64,
     timeout_events: AtomicU64,
+    // KVM_RUN retried after a recoverable error
+    vcpu_retries: AtomicU64,
     
     
     memory_reads: AtomicU64,
@@ -57,9 +66,14 @@ impl VmMetrics {
             hlt_exits: AtomicU64::new(0),
             interrupt_exits: AtomicU64::new(0),
             exception_exits: AtomicU64::new(0),
+            coalesced_mmio_writes: AtomicU64::new(0),
+            blk_irqs: AtomicU64::new(0),
+            net_irqs: AtomicU64::new(0),
+            serial_irqs: AtomicU64::new(0),
             errors: AtomicU64::new(0),
             hardware_failures: AtomicU64::new(0),
             timeout_events: AtomicU64::new(0),
+            vcpu_retries: AtomicU64::new(0),
             memory_reads: AtomicU64::new(0),
             memory_writes: AtomicU64::new(0),
             memory_faults: AtomicU64::new(0),
@@ -93,6 +107,14 @@ impl VmMetrics {
         self.enabled.store(false, Ordering::Release);
     }
 
+    /// Start collecting from zero, e.g. to measure a window after boot.
+    /// Counters are cleared before collection resumes, so nothing recorded
+    /// by the new window is wiped.
+    pub fn enable_and_reset(&self) {
+        self.reset();
+        self.enabled.store(true, Ordering::Release);
+    }
+
     
     
     
@@ -139,6 +161,14 @@ impl VmMetrics {
         }
     }
 
+    /// A write replayed from the coalesced MMIO ring; not a vCPU exit.
+    #[inline]
+    pub fn record_coalesced_mmio_write(&self) {
+        if self.is_enabled() {
+            self.coalesced_mmio_writes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
     
     #[inline]
     pub fn record_hlt_exit(&self) {
@@ -158,6 +188,30 @@ impl VmMetrics {
     }
 
     
+    #[inline]
+    pub fn record_blk_irq(&self) {
+        if self.is_enabled() {
+            self.blk_irqs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    
+    #[inline]
+    pub fn record_net_irq(&self) {
+        if self.is_enabled() {
+            self.net_irqs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    
+    #[inline]
+    pub fn record_serial_irq(&self) {
+        if self.is_enabled() {
+            self.serial_irqs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    
     #[inline]
     pub fn record_exception_exit(&self) {
         if self.is_enabled() {
@@ -192,6 +246,14 @@ impl VmMetrics {
     }
 
     
+    #[inline]
+    pub fn record_vcpu_retry(&self) {
+        if self.is_enabled() {
+            self.vcpu_retries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    
     #[inline]
     pub fn record_memory_read(&self) {
         if self.is_enabled() {
@@ -277,6 +339,10 @@ impl VmMetrics {
         self.mmio_exits.load(Ordering::Relaxed)
     }
 
+    pub fn coalesced_mmio_writes(&self) -> u64 {
+        self.coalesced_mmio_writes.load(Ordering::Relaxed)
+    }
+
     pub fn hlt_exits(&self) -> u64 {
         self.hlt_exits.load(Ordering::Relaxed)
     }
@@ -285,6 +351,18 @@ impl VmMetrics {
         self.interrupt_exits.load(Ordering::Relaxed)
     }
 
+    pub fn blk_irqs(&self) -> u64 {
+        self.blk_irqs.load(Ordering::Relaxed)
+    }
+
+    pub fn net_irqs(&self) -> u64 {
+        self.net_irqs.load(Ordering::Relaxed)
+    }
+
+    pub fn serial_irqs(&self) -> u64 {
+        self.serial_irqs.load(Ordering::Relaxed)
+    }
+
     pub fn exception_exits(&self) -> u64 {
         self.exception_exits.load(Ordering::Relaxed)
     }
@@ -301,6 +379,10 @@ impl VmMetrics {
         self.timeout_events.load(Ordering::Relaxed)
     }
 
+    pub fn vcpu_retries(&self) -> u64 {
+        self.vcpu_retries.load(Ordering::Relaxed)
+    }
+
     pub fn memory_reads(&self) -> u64 {
         self.memory_reads.load(Ordering::Relaxed)
     }
@@ -400,11 +482,8 @@ impl VmMetrics {
     
 
     
+    /// Zero every counter, whether or not collection is enabled.
     pub fn reset(&self) {
-        if !self.is_enabled() {
-            return;
-        }
-
         self.vcpu_runs.store(0, Ordering::Relaxed);
         self.vcpu_exits.store(0, Ordering::Relaxed);
         self.total_instructions.store(0, Ordering::Relaxed);
@@ -413,9 +492,14 @@ impl VmMetrics {
         self.hlt_exits.store(0, Ordering::Relaxed);
         self.interrupt_exits.store(0, Ordering::Relaxed);
         self.exception_exits.store(0, Ordering::Relaxed);
+        self.coalesced_mmio_writes.store(0, Ordering::Relaxed);
+        self.blk_irqs.store(0, Ordering::Relaxed);
+        self.net_irqs.store(0, Ordering::Relaxed);
+        self.serial_irqs.store(0, Ordering::Relaxed);
         self.errors.store(0, Ordering::Relaxed);
         self.hardware_failures.store(0, Ordering::Relaxed);
         self.timeout_events.store(0, Ordering::Relaxed);
+        self.vcpu_retries.store(0, Ordering::Relaxed);
         self.memory_reads.store(0, Ordering::Relaxed);
         self.memory_writes.store(0, Ordering::Relaxed);
         self.memory_faults.store(0, Ordering::Relaxed);
@@ -492,11 +576,19 @@ impl fmt::Display for VmMetrics {
         writeln!(f, "  vCPU Exits:        {}", self.vcpu_exits())?;
         writeln!(f, "  - I/O Exits:       {}", self.io_exits())?;
         writeln!(f, "  - MMIO Exits:      {}", self.mmio_exits())?;
+        if self.coalesced_mmio_writes() > 0 {
+            writeln!(f, "    (+ {} coalesced MMIO writes)", self.coalesced_mmio_writes())?;
+        }
         writeln!(f, "  - HLT Exits:       {}", self.hlt_exits())?;
         writeln!(f, "  - Interrupts:      {}", self.interrupt_exits())?;
         writeln!(f, "  - Exceptions:      {}", self.exception_exits())?;
+        writeln!(f, "  Device IRQs:       {} blk, {} net, {} serial",
+            self.blk_irqs(), self.net_irqs(), self.serial_irqs())?;
+        writeln!(f, "  Instructions:      {}", self.total_instructions())?;
         writeln!(f, "  Errors:            {}", self.errors())?;
         writeln!(f, "  Hardware Failures: {}", self.hardware_failures())?;
+        writeln!(f, "  Timeouts:          {}", self.timeout_events())?;
+        writeln!(f, "  vCPU Retries:      {}", self.vcpu_retries())?;
         writeln!(f, "  Memory Ops:        {} reads, {} writes", 
             self.memory_reads(), self.memory_writes())?;
         writeln!(f, "  Total Runtime:     {:?}", self.total_runtime())?;
@@ -543,6 +635,21 @@ mod tests {
         assert_eq!(metrics.vcpu_exits(), 1);
     }
 
+    #[test]
+    fn test_device_irq_counters() {
+        let metrics = VmMetrics::new();
+        metrics.record_blk_irq();
+        metrics.record_blk_irq();
+        metrics.record_net_irq();
+        assert_eq!((metrics.blk_irqs(), metrics.net_irqs(), metrics.serial_irqs()), (2, 1, 0));
+        // Raising an interrupt is not a vCPU exit
+        assert_eq!(metrics.vcpu_exits(), 0);
+        assert!(metrics.to_string().contains("2 blk, 1 net, 0 serial"));
+
+        metrics.reset();
+        assert_eq!(metrics.blk_irqs(), 0);
+    }
+
     #[test]
     fn test_metrics_computed() {
         let metrics = VmMetrics::new();
@@ -570,6 +677,24 @@ mod tests {
         assert_eq!(metrics.errors(), 0);
     }
 
+    #[test]
+    fn test_metrics_reset_while_disabled() {
+        let metrics = VmMetrics::new();
+        metrics.record_vcpu_run();
+        metrics.disable();
+        metrics.reset();
+        assert_eq!(metrics.vcpu_runs(), 0);
+
+        metrics.enable();
+        metrics.record_vcpu_run();
+        metrics.disable();
+        metrics.enable_and_reset();
+        assert!(metrics.is_enabled());
+        assert_eq!(metrics.vcpu_runs(), 0);
+        metrics.record_vcpu_run();
+        assert_eq!(metrics.vcpu_runs(), 1);
+    }
+
     #[test]
     fn test_metrics_snapshot() {
         let metrics = VmMetrics::new();
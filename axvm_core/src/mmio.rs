@@ -0,0 +1,256 @@
+// src/mmio.rs
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::error::{AxvmResult, LockExt};
+use crate::memory::GuestMemory;
+
+/// A device mapped into the guest physical MMIO space.
+///
+/// Offsets are relative to the device base. An error from `read` or
+/// `write` that `requires_shutdown` stops the vCPU.
+///
+/// Interrupts are level-triggered: the line is held at `interrupt_level`,
+/// which for virtio-mmio is "interrupt status is non-zero", so it stays high
+/// until the guest clears every bit through `INTERRUPT_ACK`.
+pub trait MmioDevice: Send + Sync {
+    fn read(&self, offset: u64, data: &mut [u8]) -> AxvmResult<()>;
+    fn write(&self, offset: u64, data: &[u8], mem: &mut GuestMemory) -> AxvmResult<()>;
+    fn interrupt_level(&self) -> AxvmResult<bool>;
+}
+
+struct MmioSlot {
+    base: u64,
+    size: u64,
+    irq: u32,
+    device: Arc<dyn MmioDevice>,
+}
+
+impl MmioSlot {
+    fn contains(&self, addr: u64) -> bool {
+        (self.base..self.base + self.size).contains(&addr)
+    }
+}
+
+/// Outcome of routing a guest MMIO write.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MmioWrite {
+    /// No device is mapped at the address
+    Unmapped,
+    /// The device handled the write; line `irq` must be brought back in sync
+    /// with `interrupt_level`
+    Handled(u32),
+}
+
+#[derive(Default)]
+pub struct MmioBus {
+    slots: Vec<MmioSlot>,
+}
+
+impl MmioBus {
+    pub fn new() -> Self {
+        Self { slots: Vec::new() }
+    }
+
+    pub fn register(&mut self, base: u64, size: u64, irq: u32, device: Arc<dyn MmioDevice>) -> Result<(), String> {
+        if let Some(other) = self.slots.iter().find(|s| base < s.base + s.size && s.base < base + size) {
+            return Err(format!(
+                "MMIO region {:#x}-{:#x} overlaps existing region {:#x}-{:#x}",
+                base, base + size, other.base, other.base + other.size
+            ));
+        }
+        self.slots.push(MmioSlot { base, size, irq, device });
+        Ok(())
+    }
+
+    /// Iterate over `(base, size, irq)` of every mapped device, in registration order.
+    pub fn regions(&self) -> impl Iterator<Item = (u64, u64, u32)> + '_ {
+        self.slots.iter().map(|s| (s.base, s.size, s.irq))
+    }
+
+    /// `virtio_mmio.device=<size>@<base>:<irq>` for every mapped device.
+    pub fn cmdline_tokens(&self) -> Vec<String> {
+        self.regions()
+            .map(|(base, size, irq)| format!("virtio_mmio.device={}@{:#X}:{}", format_size(size), base, irq))
+            .collect()
+    }
+
+    /// Whether any device on `irq` currently asserts its interrupt.
+    pub fn interrupt_level(&self, irq: u32) -> AxvmResult<bool> {
+        for slot in self.slots.iter().filter(|s| s.irq == irq) {
+            if slot.device.interrupt_level()? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn find(&self, addr: u64) -> Option<&MmioSlot> {
+        self.slots.iter().find(|s| s.contains(addr))
+    }
+
+    /// Returns `false` if no device is mapped at `addr`.
+    pub fn dispatch_read(&self, addr: u64, data: &mut [u8]) -> AxvmResult<bool> {
+        match self.find(addr) {
+            Some(slot) => {
+                slot.device.read(addr - slot.base, data)?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    pub fn dispatch_write(&self, addr: u64, data: &[u8], mem: &mut GuestMemory) -> AxvmResult<MmioWrite> {
+        match self.find(addr) {
+            Some(slot) => {
+                slot.device.write(addr - slot.base, data, mem)?;
+                Ok(MmioWrite::Handled(slot.irq))
+            },
+            None => Ok(MmioWrite::Unmapped),
+        }
+    }
+}
+
+// Aligned 32-bit registers covered by an access of `len` bytes at `offset`,
+// each with the byte range of the access that falls into it.
+fn covered_registers(offset: u64, len: usize) -> impl Iterator<Item = (u64, std::ops::Range<usize>)> {
+    let end = offset + len as u64;
+    (offset & !3..end).step_by(4).map(move |reg| {
+        let first = reg.max(offset);
+        let last = (reg + 4).min(end);
+        (reg, (first - offset) as usize..(last - offset) as usize)
+    })
+}
+
+/// Serve a read of exactly `data.len()` bytes from a bank of 32-bit
+/// registers, where `register` gives the value at an aligned offset.
+pub fn read_registers(offset: u64, data: &mut [u8], mut register: impl FnMut(u64) -> AxvmResult<u32>) -> AxvmResult<()> {
+    for (reg, range) in covered_registers(offset, data.len()) {
+        let skip = (offset + range.start as u64 - reg) as usize;
+        let bytes = register(reg)?.to_le_bytes();
+        data[range.clone()].copy_from_slice(&bytes[skip..skip + range.len()]);
+    }
+    Ok(())
+}
+
+/// Turns writes of any width into whole 32-bit register writes. Bytes the
+/// guest did not write keep that register's last written value, so a
+/// register programmed a byte at a time ends up with every byte.
+#[derive(Default)]
+pub struct RegisterLatch {
+    last: Mutex<HashMap<u64, u32>>,
+}
+
+impl RegisterLatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hand each register touched by the access to `write_register` with its merged value.
+    pub fn write(&self, offset: u64, data: &[u8], mut write_register: impl FnMut(u64, u32) -> AxvmResult<()>) -> AxvmResult<()> {
+        for (reg, range) in covered_registers(offset, data.len()) {
+            let skip = (offset + range.start as u64 - reg) as usize;
+            let val = {
+                let mut last = self.last.lock_or_err()?;
+                let entry = last.entry(reg).or_insert(0);
+                let mut bytes = entry.to_le_bytes();
+                bytes[skip..skip + range.len()].copy_from_slice(&data[range]);
+                *entry = u32::from_le_bytes(bytes);
+                *entry
+            };
+            write_register(reg, val)?;
+        }
+        Ok(())
+    }
+}
+
+fn format_size(size: u64) -> String {
+    match size {
+        s if s >= 1 << 20 && s.is_multiple_of(1 << 20) => format!("{}M", s >> 20),
+        s if s >= 1 << 10 && s.is_multiple_of(1 << 10) => format!("{}K", s >> 10),
+        s => s.to_string(),
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Scratch(Mutex<u32>);
+
+    impl MmioDevice for Scratch {
+        fn read(&self, _offset: u64, data: &mut [u8]) -> AxvmResult<()> {
+            data[..4].copy_from_slice(&self.0.lock_or_err()?.to_le_bytes());
+            Ok(())
+        }
+
+        fn write(&self, _offset: u64, data: &[u8], _mem: &mut GuestMemory) -> AxvmResult<()> {
+            *self.0.lock_or_err()? = u32::from_le_bytes(data[..4].try_into().unwrap());
+            Ok(())
+        }
+
+        fn interrupt_level(&self) -> AxvmResult<bool> {
+            Ok(*self.0.lock_or_err()? != 0)
+        }
+    }
+
+    #[test]
+    fn test_dispatch_by_address() {
+        let mut bus = MmioBus::new();
+        bus.register(0x1000, 0x1000, 5, Arc::new(Scratch(Mutex::new(0)))).unwrap();
+        bus.register(0x3000, 0x1000, 6, Arc::new(Scratch(Mutex::new(0)))).unwrap();
+
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+        assert_eq!(bus.dispatch_write(0x3004, &42u32.to_le_bytes(), &mut mem).unwrap(), MmioWrite::Handled(6));
+        assert!(bus.interrupt_level(6).unwrap());
+        assert!(!bus.interrupt_level(5).unwrap());
+        assert_eq!(bus.dispatch_write(0x2000, &1u32.to_le_bytes(), &mut mem).unwrap(), MmioWrite::Unmapped);
+
+        let mut data = [0u8; 4];
+        assert!(bus.dispatch_read(0x3000, &mut data).unwrap());
+        assert_eq!(u32::from_le_bytes(data), 42);
+        assert!(!bus.dispatch_read(0x5000, &mut data).unwrap());
+    }
+
+    #[test]
+    fn test_cmdline_generated_from_regions() {
+        let mut bus = MmioBus::new();
+        bus.register(0xFEB00000, 0x1000, 5, Arc::new(Scratch(Mutex::new(0)))).unwrap();
+        bus.register(0xFEB10000, 0x1000, 6, Arc::new(Scratch(Mutex::new(0)))).unwrap();
+
+        assert_eq!(bus.cmdline_tokens(),
+            ["virtio_mmio.device=4K@0xFEB00000:5", "virtio_mmio.device=4K@0xFEB10000:6"]);
+    }
+
+    #[test]
+    fn test_overlap_rejected() {
+        let mut bus = MmioBus::new();
+        bus.register(0x1000, 0x1000, 5, Arc::new(Scratch(Mutex::new(0)))).unwrap();
+        assert!(bus.register(0x1800, 0x1000, 6, Arc::new(Scratch(Mutex::new(0)))).is_err());
+    }
+
+    #[test]
+    fn test_partial_register_access() {
+        let regs = [0x11223344u32, 0x55667788];
+        let read = |offset: u64, len: usize| {
+            let mut data = vec![0u8; len];
+            read_registers(offset, &mut data, |reg| Ok(regs[reg as usize / 4])).unwrap();
+            data
+        };
+        assert_eq!(read(0, 4), [0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(read(1, 1), [0x33]);
+        assert_eq!(read(2, 2), [0x22, 0x11]);
+        assert_eq!(read(3, 2), [0x11, 0x88]);
+
+        let latch = RegisterLatch::new();
+        let mut writes = Vec::new();
+        for (i, byte) in [0xEFu8, 0xBE, 0xAD, 0xDE].into_iter().enumerate() {
+            latch.write(0x30 + i as u64, &[byte], |reg, val| { writes.push((reg, val)); Ok(()) }).unwrap();
+        }
+        latch.write(0x42, &[0x34, 0x12], |reg, val| { writes.push((reg, val)); Ok(()) }).unwrap();
+        assert_eq!(writes, [(0x30, 0xEF), (0x30, 0xBEEF), (0x30, 0xADBEEF), (0x30, 0xDEADBEEF), (0x40, 0x12340000)]);
+    }
+}
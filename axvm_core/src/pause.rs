@@ -0,0 +1,202 @@
+// src/pause.rs
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::idle::IdleWaker;
+use crate::vcpu;
+
+const KICK_INTERVAL: Duration = Duration::from_millis(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Holds the vCPU threads at the top of their run loop while the VM is paused.
+///
+/// Devices are driven from the vCPU threads, so their queues sit untouched
+/// until `resume`.
+pub struct PauseGate {
+    paused: AtomicBool,
+    // Number of vCPU threads currently blocked in `wait_while_paused`
+    parked: Mutex<usize>,
+    cond: Condvar,
+    // Threads that may be inside KVM_RUN and need a kick to notice a pause
+    threads: Mutex<Vec<libc::pthread_t>>,
+    waker: Arc<IdleWaker>,
+}
+
+impl PauseGate {
+    pub fn new(waker: Arc<IdleWaker>) -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            parked: Mutex::new(0),
+            cond: Condvar::new(),
+            threads: Mutex::new(Vec::new()),
+            waker,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Let `pause` kick the calling thread out of `KVM_RUN`. Only call this
+    /// once the kick signal handler is installed, and `unregister_current`
+    /// before the thread exits.
+    pub fn register_current(&self) {
+        let thread = unsafe { libc::pthread_self() };
+        self.threads.lock().unwrap_or_else(|e| e.into_inner()).push(thread);
+        self.cond.notify_all();
+    }
+
+    pub fn unregister_current(&self) {
+        let thread = unsafe { libc::pthread_self() };
+        self.threads.lock().unwrap_or_else(|e| e.into_inner()).retain(|&t| t != thread);
+        self.cond.notify_all();
+    }
+
+    /// Ask every vCPU to park and wait up to `timeout` for the registered
+    /// ones to do so, kicking stragglers out of the guest meanwhile.
+    /// Returns `false` if some were still running when the wait gave up.
+    pub fn pause(&self, timeout: Duration) -> bool {
+        self.paused.store(true, Ordering::SeqCst);
+        // Halted vCPUs sleep on the idle waker rather than in KVM_RUN
+        self.waker.notify();
+
+        let deadline = Instant::now() + timeout;
+        let mut parked = self.parked.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            let threads = self.threads.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            if *parked >= threads.len() {
+                return true;
+            }
+            if Instant::now() >= deadline || !self.is_paused() {
+                return false;
+            }
+            // Repeated: a kick that lands just before KVM_RUN is lost
+            for &thread in &threads {
+                vcpu::kick_pthread(thread);
+            }
+            parked = self.cond.wait_timeout(parked, KICK_INTERVAL).unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.release();
+    }
+
+    /// Wake parked vCPUs so they re-check their stop flag.
+    pub fn release(&self) {
+        let _parked = self.parked.lock().unwrap_or_else(|e| e.into_inner());
+        self.cond.notify_all();
+    }
+
+    /// Called by a vCPU thread: block while paused, until `resume` or `should_stop`.
+    /// `service` runs once parked and again each time `release` wakes the
+    /// thread while it is still paused.
+    pub fn wait_while_paused(&self, should_stop: &AtomicBool, mut service: impl FnMut()) {
+        let mut parked = self.parked.lock().unwrap_or_else(|e| e.into_inner());
+        *parked += 1;
+        self.cond.notify_all();
+        while self.is_paused() && !should_stop.load(Ordering::SeqCst) {
+            service();
+            parked = self.cond.wait(parked).unwrap_or_else(|e| e.into_inner());
+        }
+        *parked -= 1;
+    }
+}
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+static CONTINUE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_pause_signal(signal: libc::c_int) {
+    if signal == libc::SIGTSTP {
+        STOP_REQUESTED.store(true, Ordering::SeqCst);
+    } else {
+        CONTINUE_REQUESTED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Record `SIGTSTP` and `SIGCONT` instead of stopping the process, so
+/// Ctrl+Z pauses the guest and `kill -CONT` resumes it.
+pub fn install_pause_handlers() -> std::io::Result<()> {
+    for signal in [libc::SIGTSTP, libc::SIGCONT] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_pause_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run `on_pause` for every `SIGTSTP` and `on_resume` for every `SIGCONT`
+/// on a helper thread, until `should_stop`.
+pub fn spawn_pause_watcher(
+    should_stop: Arc<AtomicBool>,
+    on_pause: impl Fn() + Send + 'static,
+    on_resume: impl Fn() + Send + 'static,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while !should_stop.load(Ordering::Relaxed) {
+            thread::sleep(POLL_INTERVAL);
+            if STOP_REQUESTED.swap(false, Ordering::SeqCst) {
+                on_pause();
+            }
+            if CONTINUE_REQUESTED.swap(false, Ordering::SeqCst) {
+                on_resume();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pause_parks_until_resume() {
+        vcpu::install_kick_handler().unwrap();
+        let gate = Arc::new(PauseGate::new(Arc::new(IdleWaker::new())));
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let loops = Arc::new(Mutex::new(0u64));
+
+        let worker = {
+            let (gate, should_stop, loops) = (Arc::clone(&gate), Arc::clone(&should_stop), Arc::clone(&loops));
+            thread::spawn(move || {
+                gate.register_current();
+                while !should_stop.load(Ordering::SeqCst) {
+                    if gate.is_paused() {
+                        gate.wait_while_paused(&should_stop, || {});
+                        continue;
+                    }
+                    *loops.lock().unwrap() += 1;
+                    thread::yield_now();
+                }
+                gate.unregister_current();
+            })
+        };
+
+        assert!(gate.pause(Duration::from_secs(10)));
+        let frozen = *loops.lock().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*loops.lock().unwrap(), frozen);
+
+        gate.resume();
+        let start = Instant::now();
+        while *loops.lock().unwrap() == frozen {
+            assert!(start.elapsed() < Duration::from_secs(10), "worker did not resume");
+            thread::yield_now();
+        }
+
+        // Stopping while paused releases the worker
+        assert!(gate.pause(Duration::from_secs(10)));
+        should_stop.store(true, Ordering::SeqCst);
+        gate.release();
+        worker.join().unwrap();
+    }
+}
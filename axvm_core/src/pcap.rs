@@ -0,0 +1,67 @@
+// src/pcap.rs
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xA1B2C3D4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Create a capture file and write the pcap global header.
+pub fn create(path: &Path) -> io::Result<File> {
+    let mut file = File::create(path)?;
+    write_global_header(&mut file)?;
+    Ok(file)
+}
+
+pub fn write_global_header(out: &mut impl Write) -> io::Result<()> {
+    let mut hdr = Vec::with_capacity(24);
+    hdr.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+    hdr.extend_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+    hdr.extend_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+    hdr.extend_from_slice(&0i32.to_le_bytes()); // thiszone
+    hdr.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+    hdr.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+    hdr.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    out.write_all(&hdr)
+}
+
+/// Append one Ethernet frame, timestamped with the current wall clock.
+pub fn write_record(out: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let captured = frame.len().min(PCAP_SNAPLEN as usize);
+
+    let mut rec = Vec::with_capacity(16 + captured);
+    rec.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+    rec.extend_from_slice(&now.subsec_micros().to_le_bytes());
+    rec.extend_from_slice(&(captured as u32).to_le_bytes());
+    rec.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    rec.extend_from_slice(&frame[..captured]);
+    out.write_all(&rec)
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_and_record_layout() {
+        let mut out = Vec::new();
+        write_global_header(&mut out).unwrap();
+        assert_eq!(out.len(), 24);
+        assert_eq!(&out[..4], &[0xD4, 0xC3, 0xB2, 0xA1]);
+        assert_eq!(u32::from_le_bytes(out[20..24].try_into().unwrap()), LINKTYPE_ETHERNET);
+
+        write_record(&mut out, &[0xAA; 60]).unwrap();
+        assert_eq!(out.len(), 24 + 16 + 60);
+        assert_eq!(u32::from_le_bytes(out[32..36].try_into().unwrap()), 60);
+        assert_eq!(u32::from_le_bytes(out[36..40].try_into().unwrap()), 60);
+    }
+}
@@ -0,0 +1,56 @@
+// src/post.rs
+use std::sync::atomic::{AtomicU16, Ordering};
+
+/// Firmware and guests write boot progress codes here
+pub const POST_PORT: u16 = 0x80;
+
+// Only the low byte is a code; the high bits mean nothing was written yet
+const NO_CODE: u16 = 0x100;
+
+/// Last byte written to the POST diagnostic port, shared by all vCPUs so a
+/// crash dump can tell how far the boot got.
+pub struct PostCode {
+    last: AtomicU16,
+}
+
+impl PostCode {
+    pub fn new() -> Self {
+        Self { last: AtomicU16::new(NO_CODE) }
+    }
+
+    pub fn write(&self, cpu_id: u8, data: &[u8]) {
+        let Some(&code) = data.first() else { return };
+        let previous = self.last.swap(code as u16, Ordering::Relaxed);
+        // Linux also uses the port as an I/O delay, so only changes are logged
+        if previous != code as u16 {
+            tracing::debug!(cpu_id = cpu_id, code = code, "POST code {:#04x}", code);
+        }
+    }
+
+    pub fn last(&self) -> Option<u8> {
+        match self.last.load(Ordering::Relaxed) {
+            NO_CODE => None,
+            code => Some(code as u8),
+        }
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_last_code() {
+        let post = PostCode::new();
+        assert_eq!(post.last(), None);
+        post.write(0, &[0x00]);
+        assert_eq!(post.last(), Some(0x00));
+        post.write(1, &[0xA5, 0x12]);
+        post.write(0, &[]);
+        assert_eq!(post.last(), Some(0xA5));
+    }
+}
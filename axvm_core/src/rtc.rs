@@ -0,0 +1,234 @@
+// src/rtc.rs
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const RTC_INDEX_PORT: u16 = 0x70;
+pub const RTC_DATA_PORT: u16 = 0x71;
+
+// CMOS registers
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_WEEKDAY: u8 = 0x06;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_A: u8 = 0x0A;
+const REG_B: u8 = 0x0B;
+const REG_C: u8 = 0x0C;
+const REG_D: u8 = 0x0D;
+/// Also named in the FADT so the guest knows where to find it
+pub const REG_CENTURY: u8 = 0x32;
+
+const CMOS_SIZE: usize = 128;
+// Bit 7 of the index port is the NMI mask, not part of the index
+const INDEX_MASK: u8 = 0x7F;
+
+// Register A: 32.768 kHz time base, 1024 Hz periodic rate; UIP never set
+const REG_A_DEFAULT: u8 = 0x26;
+// Register B: 24-hour mode, BCD unless DM is set
+const REG_B_24H: u8 = 1 << 1;
+const REG_B_DM_BINARY: u8 = 1 << 2;
+// Register D: battery good
+const REG_D_VRT: u8 = 1 << 7;
+const HOUR_PM: u8 = 1 << 7;
+
+const SECS_PER_DAY: i64 = 86400;
+
+/// MC146818-style CMOS real-time clock on ports 0x70/0x71.
+///
+/// The clock reads host wall time shifted by `--clock-offset`; writes to the
+/// time registers are ignored, the rest of the CMOS is plain scratch RAM.
+pub struct Rtc {
+    offset_secs: i64,
+    state: Mutex<RtcState>,
+}
+
+struct RtcState {
+    index: u8,
+    cmos: [u8; CMOS_SIZE],
+}
+
+/// A point in guest wall time, broken down the way the RTC reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DateTime {
+    year: i64,
+    month: u8,
+    day: u8,
+    // 1 = Sunday
+    weekday: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+impl DateTime {
+    fn from_unix(secs: i64) -> Self {
+        let days = secs.div_euclid(SECS_PER_DAY);
+        let time = secs.rem_euclid(SECS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 4).rem_euclid(7) + 1) as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+/// Days since 1970-01-01 to (year, month, day) in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+impl Rtc {
+    pub fn new(offset_secs: i64) -> Self {
+        let mut cmos = [0u8; CMOS_SIZE];
+        cmos[REG_A as usize] = REG_A_DEFAULT;
+        cmos[REG_B as usize] = REG_B_24H;
+        cmos[REG_D as usize] = REG_D_VRT;
+        Self {
+            offset_secs,
+            state: Mutex::new(RtcState { index: 0, cmos }),
+        }
+    }
+
+    pub fn handles(port: u16) -> bool {
+        port == RTC_INDEX_PORT || port == RTC_DATA_PORT
+    }
+
+    fn now(&self) -> DateTime {
+        let host = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+        DateTime::from_unix(host.saturating_add(self.offset_secs))
+    }
+
+    pub fn read(&self, port: u16) -> u8 {
+        let st = self.state.lock().unwrap();
+        if port != RTC_DATA_PORT {
+            return 0xFF;
+        }
+        let reg_b = st.cmos[REG_B as usize];
+        let encode = |value: u8| if reg_b & REG_B_DM_BINARY != 0 { value } else { to_bcd(value) };
+
+        match st.index {
+            REG_SECONDS..=REG_YEAR | REG_CENTURY => {
+                let now = self.now();
+                match st.index {
+                    REG_SECONDS => encode(now.second),
+                    REG_MINUTES => encode(now.minute),
+                    REG_HOURS if reg_b & REG_B_24H != 0 => encode(now.hour),
+                    REG_HOURS => {
+                        let hour12 = match now.hour % 12 { 0 => 12, h => h };
+                        encode(hour12) | if now.hour >= 12 { HOUR_PM } else { 0 }
+                    },
+                    REG_WEEKDAY => encode(now.weekday),
+                    REG_DAY => encode(now.day),
+                    REG_MONTH => encode(now.month),
+                    REG_YEAR => encode(now.year.rem_euclid(100) as u8),
+                    REG_CENTURY => encode(now.year.div_euclid(100).clamp(0, 99) as u8),
+                    // Alarm registers
+                    _ => 0,
+                }
+            },
+            index => st.cmos[index as usize],
+        }
+    }
+
+    pub fn write(&self, port: u16, data: &[u8]) {
+        let Some(&val) = data.first() else { return };
+        let mut st = self.state.lock().unwrap();
+        match port {
+            RTC_INDEX_PORT => st.index = val & INDEX_MASK,
+            RTC_DATA_PORT => match st.index {
+                REG_SECONDS..=REG_YEAR | REG_CENTURY => {
+                    tracing::debug!(reg = st.index, value = val, "Ignoring guest write to an RTC time register");
+                },
+                // Read-only status registers
+                REG_C | REG_D => {},
+                index => st.cmos[index as usize] = val,
+            },
+            _ => {}
+        }
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_reg(rtc: &Rtc, reg: u8) -> u8 {
+        rtc.write(RTC_INDEX_PORT, &[reg]);
+        rtc.read(RTC_DATA_PORT)
+    }
+
+    #[test]
+    fn test_date_conversion() {
+        let epoch = DateTime::from_unix(0);
+        assert_eq!((epoch.year, epoch.month, epoch.day, epoch.weekday), (1970, 1, 1, 5));
+        // 2000-02-29 12:34:56, a Tuesday
+        let leap = DateTime::from_unix(951827696);
+        assert_eq!((leap.year, leap.month, leap.day, leap.weekday), (2000, 2, 29, 3));
+        assert_eq!((leap.hour, leap.minute, leap.second), (12, 34, 56));
+        let before = DateTime::from_unix(-1);
+        assert_eq!((before.year, before.month, before.day, before.hour), (1969, 12, 31, 23));
+    }
+
+    #[test]
+    fn test_clock_offset() {
+        // 400 Gregorian years: same calendar date and weekday, four centuries earlier
+        let host = Rtc::new(0);
+        let skewed = Rtc::new(-146097 * SECS_PER_DAY);
+        for rtc in [&host, &skewed] {
+            rtc.write(RTC_INDEX_PORT, &[REG_B]);
+            rtc.write(RTC_DATA_PORT, &[REG_B_24H | REG_B_DM_BINARY]);
+        }
+        assert_eq!(read_reg(&skewed, REG_CENTURY) + 4, read_reg(&host, REG_CENTURY));
+        assert_eq!(read_reg(&skewed, REG_YEAR), read_reg(&host, REG_YEAR));
+        assert_eq!(read_reg(&skewed, REG_WEEKDAY), read_reg(&host, REG_WEEKDAY));
+    }
+
+    #[test]
+    fn test_bcd_binary_and_12_hour_modes() {
+        let rtc = Rtc::new(0);
+        assert_eq!(read_reg(&rtc, REG_B), REG_B_24H);
+        assert_eq!(read_reg(&rtc, REG_D), REG_D_VRT);
+        let month_bcd = read_reg(&rtc, REG_MONTH);
+
+        rtc.write(RTC_INDEX_PORT, &[REG_B]);
+        rtc.write(RTC_DATA_PORT, &[REG_B_DM_BINARY]);
+        let month = read_reg(&rtc, REG_MONTH);
+        assert!((1..=12).contains(&month));
+        assert_eq!(to_bcd(month), month_bcd);
+        let hour = read_reg(&rtc, REG_HOURS);
+        assert!((1..=12).contains(&(hour & !HOUR_PM)));
+
+        // Time writes are ignored, scratch CMOS keeps what was written
+        rtc.write(RTC_INDEX_PORT, &[REG_MONTH]);
+        rtc.write(RTC_DATA_PORT, &[0x42]);
+        assert_eq!(read_reg(&rtc, REG_MONTH), month);
+        rtc.write(RTC_INDEX_PORT, &[0x80 | 0x40]);
+        rtc.write(RTC_DATA_PORT, &[0x5A]);
+        assert_eq!(read_reg(&rtc, 0x40), 0x5A);
+    }
+}
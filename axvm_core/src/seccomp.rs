@@ -0,0 +1,195 @@
+// src/seccomp.rs
+use libc::{sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+// struct seccomp_data: nr at 0, arch at 4, low half of args[0] at 16
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARG0: u32 = 16;
+const AUDIT_ARCH_X86_64: u32 = 0xC000_003E;
+
+/// Everything the vCPU, device and helper threads call once the VM runs,
+/// plus what shutdown needs. Arguments are not filtered.
+const RUNTIME_SYSCALLS: &[libc::c_long] = &[
+    // KVM_RUN and device emulation: TAP, disks, serial, pcap, memory file
+    libc::SYS_ioctl,
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_lseek,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_fallocate,
+    libc::SYS_fcntl,
+    libc::SYS_fstat,
+    libc::SYS_statx,
+    libc::SYS_poll,
+    libc::SYS_ppoll,
+    // SIGHUP reopens the TAP; shutdown reads /proc/self/smaps
+    libc::SYS_openat,
+    libc::SYS_close,
+    // User networking
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept4,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_shutdown,
+    // Locks, sleeps, clocks, vCPU run timers and kicks
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_gettimeofday,
+    libc::SYS_timer_create,
+    libc::SYS_timer_settime,
+    libc::SYS_timer_gettime,
+    libc::SYS_timer_delete,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigprocmask,
+    // Allocator
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_getrandom,
+    // Threads spawned just before the filter may still be starting up, and
+    // all of them exit at shutdown
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sigaltstack,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code: code as u16, jt, jf, k }
+}
+
+/// BPF program allowing `allowed` on x86-64 and killing the process on
+/// anything else, including syscalls made through another ABI. `prctl` is
+/// only allowed for PR_SET_NAME, which a starting thread may still issue.
+fn filter_program(allowed: &[libc::c_long]) -> Vec<sock_filter> {
+    let mut program = vec![
+        stmt(BPF_LD | BPF_W | BPF_ABS, DATA_ARCH),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH_X86_64, 1, 0),
+        stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD | BPF_W | BPF_ABS, DATA_NR),
+    ];
+    for &nr in allowed {
+        program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1));
+        program.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    }
+    program.extend([
+        jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_prctl as u32, 0, 3),
+        stmt(BPF_LD | BPF_W | BPF_ABS, DATA_ARG0),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, libc::PR_SET_NAME as u32, 0, 1),
+        stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW),
+    ]);
+    program.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS));
+    program
+}
+
+fn apply(program: &[sock_filter], flags: libc::c_ulong) -> Result<(), String> {
+    let prog = sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_ptr() as *mut sock_filter,
+    };
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(format!("PR_SET_NO_NEW_PRIVS failed: {}", std::io::Error::last_os_error()));
+        }
+        if libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, flags, &prog as *const sock_fprog) != 0 {
+            return Err(format!("seccomp filter rejected: {}", std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// Restrict every thread of the process to the runtime syscall set. A
+/// thread that makes any other syscall kills the whole process with SIGSYS.
+pub fn install_runtime_filter() -> Result<(), String> {
+    apply(&filter_program(RUNTIME_SYSCALLS), libc::SECCOMP_FILTER_FLAG_TSYNC as libc::c_ulong)
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run `body` in a forked child under the runtime filter; returns its wait status.
+    // The program is built before the fork: the child must not allocate.
+    fn run_filtered(body: fn()) -> libc::c_int {
+        let program = filter_program(RUNTIME_SYSCALLS);
+        unsafe {
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                if apply(&program, 0).is_err() {
+                    libc::_exit(2);
+                }
+                body();
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            status
+        }
+    }
+
+    #[test]
+    fn test_filter_allows_runtime_and_kills_others() {
+        let status = run_filtered(|| unsafe {
+            libc::syscall(libc::SYS_getpid);
+        });
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "status {:#x}", status);
+
+        let status = run_filtered(|| unsafe {
+            libc::prctl(libc::PR_SET_NAME, c"filtered".as_ptr());
+        });
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "status {:#x}", status);
+
+        for body in [
+            (|| unsafe { libc::syscall(libc::SYS_chdir, c"/".as_ptr()); }) as fn(),
+            || unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 1); },
+        ] {
+            let status = run_filtered(body);
+            assert!(libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSYS, "status {:#x}", status);
+        }
+    }
+
+    #[test]
+    fn test_filter_program_layout() {
+        let program = filter_program(&[libc::SYS_read, libc::SYS_write]);
+        assert_eq!(program.len(), 4 + 2 * 2 + 4 + 1);
+        assert_eq!(program[4].k, libc::SYS_read as u32);
+        assert_eq!(program[5].k, libc::SECCOMP_RET_ALLOW);
+        assert_eq!(program.last().unwrap().k, libc::SECCOMP_RET_KILL_PROCESS);
+    }
+}
@@ -3,24 +3,102 @@
 
 
 
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::config::SerialTarget;
 
 pub const COM1_BASE: u16 = 0x3F8;
+pub const COM1_IRQ: u32 = 4;
+pub const COM2_BASE: u16 = 0x2F8;
+pub const COM2_IRQ: u32 = 3;
+pub const PORT_COUNT: u16 = 8;
+
 pub const DATA_REGISTER: u16 = 0;
+pub const INTERRUPT_ENABLE_REGISTER: u16 = 1;
+pub const INTERRUPT_ID_REGISTER: u16 = 2;
+pub const LINE_CONTROL_REGISTER: u16 = 3;
+pub const MODEM_CONTROL_REGISTER: u16 = 4;
 pub const LINE_STATUS_REGISTER: u16 = 5;
+pub const MODEM_STATUS_REGISTER: u16 = 6;
+pub const SCRATCH_REGISTER: u16 = 7;
 
-pub struct SerialConsole;
+const LCR_DLAB: u8 = 0x80;
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const MCR_LOOP: u8 = 0x10;
+// DCD | DSR | CTS
+const MSR_CONNECTED: u8 = 0xB0;
 
-impl SerialConsole {
-    pub fn new() -> Self {
-        Self
+// 115200 baud
+const DEFAULT_DIVISOR: u16 = 1;
+
+/// Where a UART's transmitted bytes end up on the host.
+pub enum SerialOutput {
+    Stdout,
+    File(File),
+    /// Master side of a pseudo-terminal; `path` is the slave device
+    Pty { master: File, path: String },
+}
+
+impl SerialOutput {
+    pub fn open(target: &SerialTarget) -> Result<Self, String> {
+        match target {
+            SerialTarget::Stdout => Ok(Self::Stdout),
+            SerialTarget::File(path) => File::create(path)
+                .map(Self::File)
+                .map_err(|e| format!("Failed to create serial log {}: {}", path.display(), e)),
+            SerialTarget::Pty => Self::open_pty(),
+        }
     }
 
-    pub fn write(&self, port: u16, data: &[u8]) {
-        let offset = port - COM1_BASE;
-        
-        if offset == DATA_REGISTER {
-            if let Some(&byte) = data.first() {
+    fn open_pty() -> Result<Self, String> {
+        let master = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open("/dev/ptmx")
+            .map_err(|e| format!("Failed to open /dev/ptmx: {}", e))?;
+
+        let fd = master.as_raw_fd();
+        let mut name = [0 as libc::c_char; 64];
+        unsafe {
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(format!("Failed to unlock pty: {}", io::Error::last_os_error()));
+            }
+            let ret = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+            if ret != 0 {
+                return Err(format!("ptsname_r failed: {}", io::Error::from_raw_os_error(ret)));
+            }
+        }
+        let path = unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned();
+        Ok(Self::Pty { master, path })
+    }
+
+    /// Slave device path when the output is a pty.
+    pub fn pty_path(&self) -> Option<&str> {
+        match self {
+            Self::Pty { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout => io::stdout().flush(),
+            Self::File(file) => file.sync_data(),
+            Self::Pty { .. } => Ok(()),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match self {
+            Self::Stdout => {
                 let stdout = io::stdout();
                 let mut handle = stdout.lock();
                 
@@ -31,23 +109,294 @@ impl SerialConsole {
                     let _ = handle.write_all(&[byte]);
                 }
                 let _ = handle.flush();
+            },
+            Self::File(file) => {
+                let _ = file.write_all(&[byte]);
+            },
+            Self::Pty { master, .. } => {
+                // Nobody may be attached yet; drop output rather than block
+                if byte == b'\n' {
+                    let _ = master.write_all(b"\r\n");
+                } else {
+                    let _ = master.write_all(&[byte]);
+                }
             }
         }
     }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct UartRegs {
+    divisor: u16,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scratch: u8,
+}
+
+impl Default for UartRegs {
+    fn default() -> Self {
+        Self { divisor: DEFAULT_DIVISOR, ier: 0, lcr: 0, mcr: 0, scratch: 0 }
+    }
+}
+
+impl UartRegs {
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    /// In loopback mode the modem outputs are wired back to the status inputs.
+    fn msr(&self) -> u8 {
+        if self.mcr & MCR_LOOP == 0 {
+            return MSR_CONNECTED;
+        }
+        let mut msr = 0;
+        if self.mcr & 0x01 != 0 { msr |= 0x20; } // DTR -> DSR
+        if self.mcr & 0x02 != 0 { msr |= 0x10; } // RTS -> CTS
+        if self.mcr & 0x04 != 0 { msr |= 0x40; } // OUT1 -> RI
+        if self.mcr & 0x08 != 0 { msr |= 0x80; } // OUT2 -> DCD
+        msr
+    }
+}
+
+/// One 16550-style UART at a fixed I/O base.
+pub struct SerialConsole {
+    base: u16,
+    irq: u32,
+    output: Mutex<SerialOutput>,
+    regs: Mutex<UartRegs>,
+    tx_bytes: AtomicU64,
+    // Last transmitted bytes, up to `capture_limit`, for post-mortems
+    capture: Option<Mutex<VecDeque<u8>>>,
+    capture_limit: usize,
+}
+
+impl SerialConsole {
+    pub fn new(base: u16, irq: u32, output: SerialOutput) -> Self {
+        Self {
+            base,
+            irq,
+            output: Mutex::new(output),
+            regs: Mutex::new(UartRegs::default()),
+            tx_bytes: AtomicU64::new(0),
+            capture: None,
+            capture_limit: 0,
+        }
+    }
+
+    /// Also keep the last `bytes` bytes the guest transmits in memory.
+    pub fn with_capture(mut self, bytes: usize) -> Self {
+        self.capture = Some(Mutex::new(VecDeque::with_capacity(bytes)));
+        self.capture_limit = bytes;
+        self
+    }
+
+    /// Drain the captured output, oldest byte first; empty without `with_capture`.
+    pub fn take_capture(&self) -> Vec<u8> {
+        self.capture.as_ref()
+            .and_then(|capture| capture.lock().ok().map(|mut c| c.drain(..).collect()))
+            .unwrap_or_default()
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.base..self.base + PORT_COUNT).contains(&port)
+    }
+
+    /// Number of bytes the guest has transmitted through this port
+    pub fn tx_bytes(&self) -> u64 {
+        self.tx_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn write(&self, port: u16, data: &[u8]) {
+        let offset = port - self.base;
+        let Some(&byte) = data.first() else { return };
+        let mut regs = self.regs.lock().unwrap();
+        
+        match offset {
+            DATA_REGISTER if regs.dlab() => {
+                regs.divisor = (regs.divisor & 0xFF00) | byte as u16;
+            },
+            DATA_REGISTER => {
+                drop(regs);
+                self.tx_bytes.fetch_add(1, Ordering::Relaxed);
+                if let Some(Ok(mut capture)) = self.capture.as_ref().map(Mutex::lock) {
+                    if capture.len() == self.capture_limit {
+                        capture.pop_front();
+                    }
+                    capture.push_back(byte);
+                }
+                if let Ok(mut output) = self.output.lock() {
+                    output.write_byte(byte);
+                }
+            },
+            INTERRUPT_ENABLE_REGISTER if regs.dlab() => {
+                regs.divisor = (regs.divisor & 0x00FF) | ((byte as u16) << 8);
+            },
+            INTERRUPT_ENABLE_REGISTER => regs.ier = byte & 0x0F,
+            LINE_CONTROL_REGISTER => regs.lcr = byte,
+            MODEM_CONTROL_REGISTER => regs.mcr = byte & 0x1F,
+            SCRATCH_REGISTER => regs.scratch = byte,
+            _ => {}
+        }
+    }
 
     pub fn read(&self, port: u16) -> u8 {
-        let offset = port - COM1_BASE;
+        let offset = port - self.base;
+        let regs = self.regs.lock().unwrap();
         match offset {
+            DATA_REGISTER if regs.dlab() => regs.divisor as u8,
+            INTERRUPT_ENABLE_REGISTER if regs.dlab() => (regs.divisor >> 8) as u8,
+            INTERRUPT_ENABLE_REGISTER => regs.ier,
+            INTERRUPT_ID_REGISTER => IIR_NO_INTERRUPT,
+            LINE_CONTROL_REGISTER => regs.lcr,
+            MODEM_CONTROL_REGISTER => regs.mcr,
             
             
             LINE_STATUS_REGISTER => 0x20 | 0x40,
+            MODEM_STATUS_REGISTER => regs.msr(),
+            SCRATCH_REGISTER => regs.scratch,
             _ => 0,
         }
     }
 }
 
-impl Default for SerialConsole {
-    fn default() -> Self {
-        Self::new()
+/// Routes port I/O to the UART that owns the port.
+pub struct SerialPorts {
+    consoles: Vec<SerialConsole>,
+}
+
+impl SerialPorts {
+    pub fn new(consoles: Vec<SerialConsole>) -> Self {
+        Self { consoles }
+    }
+
+    pub fn consoles(&self) -> &[SerialConsole] {
+        &self.consoles
+    }
+
+    fn find(&self, port: u16) -> Option<&SerialConsole> {
+        self.consoles.iter().find(|c| c.contains(port))
+    }
+
+    pub fn handles(&self, port: u16) -> bool {
+        self.find(port).is_some()
+    }
+
+    pub fn write(&self, port: u16, data: &[u8]) {
+        if let Some(console) = self.find(port) {
+            console.write(port, data);
+        }
+    }
+
+    pub fn read(&self, port: u16) -> u8 {
+        self.find(port).map_or(0xFF, |c| c.read(port))
+    }
+
+    /// Total bytes transmitted by the guest across all ports
+    pub fn tx_bytes(&self) -> u64 {
+        self.consoles.iter().map(SerialConsole::tx_bytes).sum()
+    }
+
+    /// Push buffered output to its sink; log files are synced to disk.
+    pub fn flush(&self) -> Result<(), String> {
+        for console in &self.consoles {
+            let mut output = console.output.lock().map_err(|_| "serial output lock poisoned".to_string())?;
+            output.flush().map_err(|e| format!("port {:#x}: {}", console.base, e))?;
+        }
+        Ok(())
+    }
+
+    /// Log and clear what `--serial-capture` recorded, e.g. once the guest
+    /// has died; later calls only see output produced in between.
+    pub fn log_capture(&self) {
+        for console in &self.consoles {
+            let captured = console.take_capture();
+            if captured.is_empty() {
+                continue;
+            }
+            status!(error, "Serial", base = console.base(), bytes = captured.len(),
+                "Last {} bytes of output on port {:#x}:", captured.len(), console.base());
+            for line in String::from_utf8_lossy(&captured).lines() {
+                status!(error, "Serial", "| {}", line);
+            }
+        }
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ports_route_to_owning_console() {
+        let path = std::env::temp_dir().join(format!("axvm-test-com2-{}.log", std::process::id()));
+        let output = SerialOutput::open(&SerialTarget::File(path.clone())).unwrap();
+        let com2 = SerialConsole::new(COM2_BASE, COM2_IRQ, output);
+        let ports = SerialPorts::new(vec![SerialConsole::new(COM1_BASE, COM1_IRQ, SerialOutput::Stdout), com2]);
+
+        assert!(ports.handles(COM1_BASE + LINE_STATUS_REGISTER));
+        assert!(ports.handles(COM2_BASE));
+        assert!(!ports.handles(COM2_BASE + PORT_COUNT));
+        assert_eq!(ports.read(COM2_BASE + LINE_STATUS_REGISTER), 0x60);
+
+        for &b in b"ttyS1\n" {
+            ports.write(COM2_BASE + DATA_REGISTER, &[b]);
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"ttyS1\n");
+        assert_eq!(ports.tx_bytes(), 6);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_capture_keeps_the_tail() {
+        let path = std::env::temp_dir().join(format!("axvm-test-capture-{}.log", std::process::id()));
+        let output = SerialOutput::open(&SerialTarget::File(path.clone())).unwrap();
+        let uart = SerialConsole::new(COM1_BASE, COM1_IRQ, output).with_capture(4);
+        for &b in b"kernel panic" {
+            uart.write(COM1_BASE + DATA_REGISTER, &[b]);
+        }
+        assert_eq!(uart.take_capture(), b"anic");
+        assert!(uart.take_capture().is_empty());
+        assert_eq!(std::fs::read(&path).unwrap(), b"kernel panic");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_divisor_latch_round_trips() {
+        let uart = SerialConsole::new(COM1_BASE, COM1_IRQ, SerialOutput::Stdout);
+        let port = |reg: u16| COM1_BASE + reg;
+
+        // 8250_port.c: set DLAB, program 0x0C01, read it back, restore 8N1
+        uart.write(port(LINE_CONTROL_REGISTER), &[LCR_DLAB | 0x03]);
+        uart.write(port(DATA_REGISTER), &[0x01]);
+        uart.write(port(INTERRUPT_ENABLE_REGISTER), &[0x0C]);
+        assert_eq!(uart.read(port(DATA_REGISTER)), 0x01);
+        assert_eq!(uart.read(port(INTERRUPT_ENABLE_REGISTER)), 0x0C);
+        uart.write(port(LINE_CONTROL_REGISTER), &[0x03]);
+
+        // With DLAB clear, offset 1 is IER again and the divisor is retained
+        assert_eq!(uart.read(port(INTERRUPT_ENABLE_REGISTER)), 0);
+        assert_eq!(uart.read(port(LINE_CONTROL_REGISTER)), 0x03);
+        assert_eq!(uart.regs.lock().unwrap().divisor, 0x0C01);
+
+        uart.write(port(SCRATCH_REGISTER), &[0xA5]);
+        assert_eq!(uart.read(port(SCRATCH_REGISTER)), 0xA5);
+        assert_eq!(uart.read(port(INTERRUPT_ID_REGISTER)) & 0x01, 1);
+
+        // Loopback check: RTS | OUT2 must read back as CTS | DCD
+        uart.write(port(MODEM_CONTROL_REGISTER), &[MCR_LOOP | 0x0A]);
+        assert_eq!(uart.read(port(MODEM_STATUS_REGISTER)) & 0xF0, 0x90);
     }
 }
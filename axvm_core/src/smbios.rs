@@ -0,0 +1,160 @@
+// src/smbios.rs
+use crate::memory::GuestMemory;
+use crate::layout::SMBIOS_START;
+
+// SMBIOS 2.1 entry point; the guest finds it by scanning 0xF0000-0xFFFFF
+// for `_SM_` on a 16-byte boundary, so only when RAM starts at 0
+const ENTRY_POINT_LEN: usize = 0x1F;
+// Structure table right after the (16-byte aligned) entry point
+const TABLE_OFFSET: usize = 0x20;
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_END: u8 = 127;
+
+// BIOS characteristics: "BIOS Characteristics are not supported"
+const BIOS_CHARACTERISTICS_UNSUPPORTED: u64 = 1 << 3;
+// System wake-up type: power switch
+const WAKE_UP_POWER_SWITCH: u8 = 6;
+
+const MANUFACTURER: &str = "AxVM";
+const BIOS_RELEASE_DATE: &str = "01/01/2024";
+
+/// One SMBIOS structure: formatted area followed by its string set.
+struct Structure {
+    data: Vec<u8>,
+    strings: Vec<String>,
+}
+
+impl Structure {
+    fn new(type_: u8, handle: u16) -> Self {
+        let mut data = vec![type_, 0];
+        data.extend_from_slice(&handle.to_le_bytes());
+        Self { data, strings: Vec::new() }
+    }
+
+    /// Append a string field: its 1-based index, or 0 for "none".
+    fn string(mut self, value: Option<&str>) -> Self {
+        let index = match value {
+            Some(s) if !s.is_empty() => {
+                self.strings.push(s.to_string());
+                self.strings.len() as u8
+            },
+            _ => 0,
+        };
+        self.data.push(index);
+        self
+    }
+
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.data.extend_from_slice(bytes);
+        self
+    }
+
+    fn encode(mut self) -> Vec<u8> {
+        self.data[1] = self.data.len() as u8;
+        let mut out = self.data;
+        for s in &self.strings {
+            out.extend_from_slice(s.as_bytes());
+            out.push(0);
+        }
+        // The string set ends with a double NUL, even when empty
+        if self.strings.is_empty() {
+            out.push(0);
+        }
+        out.push(0);
+        out
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    0u8.wrapping_sub(data.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)))
+}
+
+fn structures(product: &str, serial: Option<&str>) -> Vec<Vec<u8>> {
+    let version = env!("CARGO_PKG_VERSION");
+    vec![
+        Structure::new(TYPE_BIOS, 0)
+            .string(Some(MANUFACTURER))
+            .string(Some(version))
+            .bytes(&0xE800u16.to_le_bytes()) // BIOS starting segment
+            .string(Some(BIOS_RELEASE_DATE))
+            .bytes(&[0]) // ROM size: 64 KB
+            .bytes(&BIOS_CHARACTERISTICS_UNSUPPORTED.to_le_bytes())
+            .encode(),
+        Structure::new(TYPE_SYSTEM, 1)
+            .string(Some(MANUFACTURER))
+            .string(Some(product))
+            .string(Some(version))
+            .string(serial)
+            .bytes(&[0; 16]) // UUID: not present
+            .bytes(&[WAKE_UP_POWER_SWITCH])
+            .encode(),
+        Structure::new(TYPE_END, 2).encode(),
+    ]
+}
+
+fn entry_point(table_addr: usize, table_len: usize, count: usize, max_size: usize) -> [u8; ENTRY_POINT_LEN] {
+    let mut ep = [0u8; ENTRY_POINT_LEN];
+    ep[0..4].copy_from_slice(b"_SM_");
+    ep[5] = ENTRY_POINT_LEN as u8;
+    ep[6] = 2; // SMBIOS 2.1
+    ep[7] = 1;
+    ep[8..10].copy_from_slice(&(max_size as u16).to_le_bytes());
+    ep[0x10..0x15].copy_from_slice(b"_DMI_");
+    ep[0x16..0x18].copy_from_slice(&(table_len as u16).to_le_bytes());
+    ep[0x18..0x1C].copy_from_slice(&(table_addr as u32).to_le_bytes());
+    ep[0x1C..0x1E].copy_from_slice(&(count as u16).to_le_bytes());
+    ep[0x1E] = 0x21;
+    // Intermediate checksum covers the _DMI_ part, then the whole entry point
+    ep[0x15] = checksum(&ep[0x10..]);
+    ep[4] = checksum(&ep);
+    ep
+}
+
+/// Write the SMBIOS entry point and a BIOS (type 0) and System (type 1)
+/// structure reporting "AxVM" as manufacturer.
+pub fn setup_smbios(mem: &mut GuestMemory, product: &str, serial: Option<&str>) -> Result<(), String> {
+    let structures = structures(product, serial);
+    let max_size = structures.iter().map(Vec::len).max().unwrap_or(0);
+    let table: Vec<u8> = structures.concat();
+    let start = mem.base() + SMBIOS_START;
+
+    mem.write_slice(start + TABLE_OFFSET, &table)?;
+    mem.write_slice(start, &entry_point(start + TABLE_OFFSET, table.len(), structures.len(), max_size))?;
+
+    status!("SMBIOS", "Entry point at {:#x}, {} bytes of structures", start, table.len());
+    Ok(())
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smbios_tables() {
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+        setup_smbios(&mut mem, "Test Box", None).unwrap();
+
+        let ep = mem.read_slice(SMBIOS_START, ENTRY_POINT_LEN).unwrap();
+        assert_eq!(&ep[0..4], b"_SM_");
+        assert_eq!(checksum(ep), 0);
+        assert_eq!(checksum(&ep[0x10..]), 0);
+        assert_eq!(u16::from_le_bytes([ep[0x1C], ep[0x1D]]), 3);
+
+        let len = u16::from_le_bytes([ep[0x16], ep[0x17]]) as usize;
+        let table = mem.read_slice(SMBIOS_START + TABLE_OFFSET, len).unwrap();
+        assert_eq!(&table[..2], &[TYPE_BIOS, 0x12]);
+
+        // Type 1 follows type 0's strings; no serial means string index 0
+        let type1 = 0x12 + table[0x12..].windows(2).position(|w| w == [0, 0]).unwrap() + 2;
+        assert_eq!(&table[type1..type1 + 2], &[TYPE_SYSTEM, 0x19]);
+        assert_eq!(&table[type1 + 4..type1 + 8], &[1, 2, 3, 0]);
+        assert!(table[type1 + 0x19..].starts_with(b"AxVM\0Test Box\0"));
+        assert!(table.ends_with(&[TYPE_END, 4, 2, 0, 0, 0]));
+    }
+}
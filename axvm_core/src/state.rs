@@ -0,0 +1,92 @@
+// src/state.rs
+use std::fmt;
+
+use crate::error::{AxvmError, AxvmResult};
+
+/// Lifecycle of a [`Vm`](crate::Vm).
+///
+/// `Created` while `Vm::new` wires up KVM and the devices, `Configured`
+/// once it returns, `Running` inside `run`, and `Stopped` for good after
+/// `run` returns. `Paused` is only ever entered from `Running`, and a VM
+/// can leave it for `Running` again or stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    Created,
+    Configured,
+    Running,
+    Paused,
+    Stopped,
+}
+
+impl VmState {
+    pub fn can_transition_to(self, next: VmState) -> bool {
+        use VmState::*;
+        matches!(
+            (self, next),
+            (Created, Configured)
+                | (Configured, Running)
+                | (Configured, Stopped)
+                | (Running, Paused)
+                | (Running, Stopped)
+                | (Paused, Running)
+                | (Paused, Stopped)
+        )
+    }
+
+    /// Move to `next`, or fail with `InvalidState` if the lifecycle forbids it.
+    pub fn transition(&mut self, next: VmState) -> AxvmResult<()> {
+        if !self.can_transition_to(next) {
+            return Err(AxvmError::InvalidState(format!("cannot go from {} to {}", self, next)));
+        }
+        tracing::debug!(from = %self, to = %next, "VM state changed");
+        *self = next;
+        Ok(())
+    }
+}
+
+impl fmt::Display for VmState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Created => "created",
+            Self::Configured => "configured",
+            Self::Running => "running",
+            Self::Paused => "paused",
+            Self::Stopped => "stopped",
+        };
+        f.write_str(name)
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifecycle() {
+        let mut state = VmState::Created;
+        for next in [VmState::Configured, VmState::Running, VmState::Paused, VmState::Running, VmState::Stopped] {
+            state.transition(next).unwrap();
+        }
+        assert_eq!(state, VmState::Stopped);
+    }
+
+    #[test]
+    fn test_illegal_transitions() {
+        let mut stopped = VmState::Stopped;
+        let err = stopped.transition(VmState::Running).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid VM state transition: cannot go from stopped to running");
+        assert_eq!(stopped, VmState::Stopped);
+
+        assert!(!VmState::Created.can_transition_to(VmState::Running));
+        assert!(!VmState::Configured.can_transition_to(VmState::Paused));
+        assert!(!VmState::Running.can_transition_to(VmState::Running));
+        assert!(!VmState::Running.can_transition_to(VmState::Configured));
+        for next in [VmState::Created, VmState::Configured, VmState::Running, VmState::Paused, VmState::Stopped] {
+            assert!(!VmState::Stopped.can_transition_to(next));
+        }
+    }
+}
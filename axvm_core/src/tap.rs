@@ -9,6 +9,7 @@ use std::mem;
 const IFF_TAP: i16 = 0x0002;
 const IFF_NO_PI: i16 = 0x1000;
 const TUNSETIFF: u64 = 0x400454ca; // Macro _IOW('T', 202, int)
+const IFF_UP: i16 = 0x0001;
 
 #[repr(C)]
 struct IfReq {
@@ -74,6 +75,7 @@ impl TapInterface {
         &self.name
     }
 
+    #[allow(dead_code)]
     pub fn as_raw_fd(&self) -> RawFd {
         self.file.as_raw_fd()
     }
@@ -88,3 +90,86 @@ impl TapInterface {
         self.file.write(buf)
     }
 }
+
+impl Drop for TapInterface {
+    /// Bring the link administratively down before the fd closes and the
+    /// kernel removes the device, so bridges and routes see an orderly
+    /// carrier loss rather than the port vanishing while up.
+    fn drop(&mut self) {
+        if let Err(e) = self.set_down() {
+            tracing::debug!(name = %self.name, error = %e, "Could not bring TAP interface down");
+        }
+    }
+}
+
+impl TapInterface {
+    fn set_down(&self) -> io::Result<()> {
+        let mut ifr: IfReq = unsafe { mem::zeroed() };
+        let bytes = self.name.as_bytes();
+        ifr.ifr_name[..bytes.len()].copy_from_slice(bytes);
+
+        // SIOC[GS]IFFLAGS go through any socket, not the TUN fd
+        let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = unsafe {
+            if libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut ifr) < 0 {
+                Err(io::Error::last_os_error())
+            } else if ifr.ifr_flags & IFF_UP == 0 {
+                Ok(())
+            } else {
+                ifr.ifr_flags &= !IFF_UP;
+                if libc::ioctl(sock, libc::SIOCSIFFLAGS, &ifr) < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    tracing::info!(name = %self.name, "TAP interface brought down");
+                    Ok(())
+                }
+            }
+        };
+        unsafe { libc::close(sock) };
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SIOCGIFFLAGS, or with `set` SIOCSIFFLAGS, on the interface `name`
+    fn if_flags(name: &str, set: Option<i16>) -> i16 {
+        let mut ifr: IfReq = unsafe { mem::zeroed() };
+        ifr.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+        unsafe {
+            let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+            assert!(sock >= 0);
+            assert_eq!(libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut ifr), 0);
+            if let Some(flags) = set {
+                ifr.ifr_flags = flags;
+                assert_eq!(libc::ioctl(sock, libc::SIOCSIFFLAGS, &ifr), 0);
+            }
+            libc::close(sock);
+        }
+        ifr.ifr_flags
+    }
+
+    #[test]
+    fn test_set_down() {
+        let tap = match TapInterface::new(Some(&format!("axvmt{}", std::process::id() % 100_000))) {
+            Ok(tap) => tap,
+            Err(e) => {
+                eprintln!("skipping: cannot create a TAP interface: {}", e);
+                return;
+            }
+        };
+        let flags = if_flags(tap.name(), None);
+        if_flags(tap.name(), Some(flags | IFF_UP));
+        assert_ne!(if_flags(tap.name(), None) & IFF_UP, 0);
+
+        tap.set_down().unwrap();
+        assert_eq!(if_flags(tap.name(), None) & IFF_UP, 0);
+        // Already down: nothing to do
+        tap.set_down().unwrap();
+    }
+}
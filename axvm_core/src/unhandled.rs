@@ -0,0 +1,56 @@
+// src/unhandled.rs
+use std::collections::HashMap;
+
+/// Port I/O that no emulated device claimed, per vCPU. Each port and
+/// direction is logged on its 1st, 2nd, 4th, 8th... access, so a guest
+/// polling an absent device does not flood the log.
+pub struct UnhandledPorts {
+    cpu_id: u8,
+    counts: HashMap<(u16, bool), u64>,
+}
+
+impl UnhandledPorts {
+    pub fn new(cpu_id: u8) -> Self {
+        Self { cpu_id, counts: HashMap::new() }
+    }
+
+    /// Note an access; returns whether it was logged.
+    pub fn record(&mut self, port: u16, write: bool, size: usize) -> bool {
+        let count = self.counts.entry((port, write)).or_insert(0);
+        *count += 1;
+        if !count.is_power_of_two() {
+            return false;
+        }
+        let direction = if write { "out" } else { "in" };
+        tracing::debug!(cpu_id = self.cpu_id, port = port, direction = direction, size = size, count = *count,
+            "Unhandled port {} {:#x} ({} bytes), seen {} times", direction, port, size, count);
+        true
+    }
+}
+
+/// What a read from a port with no device behind it returns: the bus floats high.
+pub fn float_bus(data: &mut [u8]) {
+    data.fill(0xFF);
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logging_is_throttled_per_port_and_direction() {
+        let mut ports = UnhandledPorts::new(0);
+        let logged: Vec<bool> = (0..9).map(|_| ports.record(0x80, true, 1)).collect();
+        assert_eq!(logged, [true, true, false, true, false, false, false, true, false]);
+        assert!(ports.record(0x80, false, 1));
+        assert!(ports.record(0xCF8, true, 4));
+
+        let mut data = [0u8; 4];
+        float_bus(&mut data);
+        assert_eq!(data, [0xFF; 4]);
+    }
+}
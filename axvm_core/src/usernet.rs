@@ -0,0 +1,1109 @@
+// src/usernet.rs
+//! `--net user`: a minimal user-mode network stack, so the guest gets a
+//! network without a TAP device (and without root).
+//!
+//! The guest sits alone on 10.0.2.0/24 behind a virtual gateway at 10.0.2.2
+//! that answers ARP, DHCP and pings. TCP and UDP are terminated here and
+//! replayed through ordinary host sockets; 10.0.2.2 itself stands for the
+//! host's loopback and 10.0.2.3:53 for the host's resolver. `--hostfwd`
+//! rules accept host TCP connections and open them towards the guest.
+//! There is no IP fragmentation, no IPv6 and no TCP option beyond MSS.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::HostFwd;
+
+pub const GATEWAY_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const DNS_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+pub const GUEST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+// Answers for every address on the virtual subnet
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const BROADCAST_MAC: [u8; 6] = [0xFF; 6];
+
+const ETH_HLEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+// Keeps every frame within a 1514-byte Ethernet frame
+const TCP_MSS: usize = 1460;
+const UDP_MAX_PAYLOAD: usize = 1472;
+const TCP_WINDOW: u16 = 65535;
+// Host data read ahead of the guest's acknowledgements, per connection
+const TCP_SEND_BUFFER: usize = 64 * 1024;
+const TCP_RTO: Duration = Duration::from_millis(500);
+const TCP_MAX_RETRIES: u32 = 8;
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// Host connects block, so a few threads started with the stack run them
+// (the seccomp filter allows no thread creation later); further SYNs wait
+// in a bounded queue and are refused once it is full
+const CONNECT_WORKERS: usize = 4;
+const MAX_PENDING_CONNECTS: usize = 32;
+const MAX_TCP_CONNS: usize = 256;
+const MAX_UDP_FLOWS: usize = 256;
+// Stop producing frames while this many wait for guest RX buffers
+const MAX_QUEUED_FRAMES: usize = 256;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTIONS_START: usize = 240;
+const DHCP_MIN_LEN: usize = 300;
+const DHCP_LEASE_SECS: u32 = 24 * 60 * 60;
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCP_OPT_SUBNET_MASK: u8 = 1;
+const DHCP_OPT_ROUTER: u8 = 3;
+const DHCP_OPT_DNS: u8 = 6;
+const DHCP_OPT_LEASE_TIME: u8 = 51;
+const DHCP_OPT_MESSAGE_TYPE: u8 = 53;
+const DHCP_OPT_SERVER_ID: u8 = 54;
+const DHCP_OPT_END: u8 = 255;
+
+// Source ports of the gateway side of forwarded connections
+const FWD_PORT_FIRST: u16 = 49152;
+
+fn be16(b: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([b[at], b[at + 1]])
+}
+
+fn be32(b: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+fn ipv4_at(b: &[u8], at: usize) -> Ipv4Addr {
+    Ipv4Addr::new(b[at], b[at + 1], b[at + 2], b[at + 3])
+}
+
+/// Internet checksum of `data`, folded on top of the partial sum `sum`.
+fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    for chunk in data.chunks(2) {
+        sum += u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// TCP/UDP checksum, including the IPv4 pseudo-header.
+fn l4_checksum(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, segment: &[u8]) -> u16 {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&src.octets());
+    pseudo[4..8].copy_from_slice(&dst.octets());
+    pseudo[9] = proto;
+    pseudo[10..12].copy_from_slice(&(segment.len() as u16).to_be_bytes());
+    let sum = pseudo.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]]) as u32).sum();
+    checksum(segment, sum)
+}
+
+fn on_subnet(addr: Ipv4Addr) -> bool {
+    u32::from(addr) & u32::from(NETMASK) == u32::from(GATEWAY_ADDR) & u32::from(NETMASK)
+}
+
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+}
+
+/// Frames on their way to the guest.
+struct Wire {
+    guest_mac: [u8; 6],
+    frames: VecDeque<Vec<u8>>,
+    ip_id: u16,
+}
+
+impl Wire {
+    fn has_room(&self) -> bool {
+        self.frames.len() < MAX_QUEUED_FRAMES
+    }
+
+    fn ethernet(&mut self, dst_mac: [u8; 6], ethertype: u16, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(ETH_HLEN + payload.len());
+        frame.extend_from_slice(&dst_mac);
+        frame.extend_from_slice(&GATEWAY_MAC);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.frames.push_back(frame);
+    }
+
+    fn ipv4(&mut self, dst_mac: [u8; 6], src: Ipv4Addr, dst: Ipv4Addr, proto: u8, l4: &[u8]) {
+        let mut packet = Vec::with_capacity(20 + l4.len());
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&((20 + l4.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&self.ip_id.to_be_bytes());
+        // Don't fragment, TTL 64
+        packet.extend_from_slice(&[0x40, 0, 64, proto, 0, 0]);
+        packet.extend_from_slice(&src.octets());
+        packet.extend_from_slice(&dst.octets());
+        let csum = checksum(&packet, 0);
+        packet[10..12].copy_from_slice(&csum.to_be_bytes());
+        packet.extend_from_slice(l4);
+        self.ip_id = self.ip_id.wrapping_add(1);
+        self.ethernet(dst_mac, ETHERTYPE_IPV4, &packet);
+    }
+
+    fn udp(&mut self, dst_mac: [u8; 6], src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) {
+        let mut segment = Vec::with_capacity(8 + payload.len());
+        segment.extend_from_slice(&src.port().to_be_bytes());
+        segment.extend_from_slice(&dst.port().to_be_bytes());
+        segment.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        segment.extend_from_slice(&[0, 0]);
+        segment.extend_from_slice(payload);
+        let csum = match l4_checksum(*src.ip(), *dst.ip(), IPPROTO_UDP, &segment) {
+            0 => 0xFFFF,
+            csum => csum,
+        };
+        segment[6..8].copy_from_slice(&csum.to_be_bytes());
+        self.ipv4(dst_mac, *src.ip(), *dst.ip(), IPPROTO_UDP, &segment);
+    }
+
+    fn tcp(&mut self, src: SocketAddrV4, dst: SocketAddrV4, seq: u32, ack: u32, flags: u8, payload: &[u8]) {
+        // SYNs carry an MSS option
+        let header_len = if flags & TCP_SYN != 0 { 24 } else { 20 };
+        let mut segment = Vec::with_capacity(header_len + payload.len());
+        segment.extend_from_slice(&src.port().to_be_bytes());
+        segment.extend_from_slice(&dst.port().to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&ack.to_be_bytes());
+        segment.extend_from_slice(&[((header_len / 4) as u8) << 4, flags]);
+        segment.extend_from_slice(&TCP_WINDOW.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        if flags & TCP_SYN != 0 {
+            segment.extend_from_slice(&[2, 4]);
+            segment.extend_from_slice(&(TCP_MSS as u16).to_be_bytes());
+        }
+        segment.extend_from_slice(payload);
+        let csum = l4_checksum(*src.ip(), *dst.ip(), IPPROTO_TCP, &segment);
+        segment[16..18].copy_from_slice(&csum.to_be_bytes());
+        self.ipv4(self.guest_mac, *src.ip(), *dst.ip(), IPPROTO_TCP, &segment);
+    }
+}
+
+struct TcpSegment<'a> {
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> TcpSegment<'a> {
+    fn parse(l4: &'a [u8]) -> Option<Self> {
+        if l4.len() < 20 {
+            return None;
+        }
+        let data_offset = (l4[12] >> 4) as usize * 4;
+        if data_offset < 20 || data_offset > l4.len() {
+            return None;
+        }
+        Some(Self {
+            seq: be32(l4, 4),
+            ack: be32(l4, 8),
+            flags: l4[13],
+            window: be16(l4, 14),
+            payload: &l4[data_offset..],
+        })
+    }
+
+    /// Sequence space the segment occupies.
+    fn len(&self) -> u32 {
+        self.payload.len() as u32 + (self.flags & TCP_SYN != 0) as u32 + (self.flags & TCP_FIN != 0) as u32
+    }
+}
+
+enum TcpState {
+    /// Guest sent a SYN; the host connect is queued for a connect worker
+    Connecting(Receiver<io::Result<TcpStream>>),
+    /// SYN-ACK sent to the guest for an outbound connection
+    SynReceived,
+    /// SYN sent to the guest for a `--hostfwd` connection
+    SynSent,
+    Established,
+}
+
+/// One guest TCP connection and the host socket it is spliced to. Host data
+/// is kept until the guest acknowledges it and resent from the oldest
+/// unacknowledged byte when no acknowledgement arrives in time.
+struct TcpConn {
+    state: TcpState,
+    stream: Option<TcpStream>,
+    guest: SocketAddrV4,
+    // The peer as the guest sees it
+    remote: SocketAddrV4,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    guest_window: u32,
+    // Host bytes from `snd_una` on
+    unacked: Vec<u8>,
+    host_eof: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    guest_fin: bool,
+    last_progress: Instant,
+    retries: u32,
+}
+
+impl TcpConn {
+    fn new(state: TcpState, stream: Option<TcpStream>, guest: SocketAddrV4, remote: SocketAddrV4, iss: u32) -> Self {
+        Self {
+            state,
+            stream,
+            guest,
+            remote,
+            snd_una: iss,
+            snd_nxt: iss,
+            rcv_nxt: 0,
+            guest_window: 0,
+            unacked: Vec::new(),
+            host_eof: false,
+            fin_sent: false,
+            fin_acked: false,
+            guest_fin: false,
+            last_progress: Instant::now(),
+            retries: 0,
+        }
+    }
+
+    fn send(&self, wire: &mut Wire, seq: u32, flags: u8, payload: &[u8]) {
+        wire.tcp(self.remote, self.guest, seq, self.rcv_nxt, flags, payload);
+    }
+
+    fn send_syn(&mut self, wire: &mut Wire) {
+        let flags = match self.state {
+            TcpState::SynSent => TCP_SYN,
+            _ => TCP_SYN | TCP_ACK,
+        };
+        self.send(wire, self.snd_una, flags, &[]);
+        self.snd_nxt = self.snd_una.wrapping_add(1);
+        self.last_progress = Instant::now();
+    }
+
+    fn reset(&self, wire: &mut Wire) {
+        self.send(wire, self.snd_nxt, TCP_RST | TCP_ACK, &[]);
+        if let Some(ref stream) = self.stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.guest_fin && self.fin_acked
+    }
+
+    fn on_ack(&mut self, ack: u32) {
+        let acked = ack.wrapping_sub(self.snd_una);
+        if acked == 0 || acked > self.snd_nxt.wrapping_sub(self.snd_una) {
+            return;
+        }
+        let data = (acked as usize).min(self.unacked.len());
+        self.unacked.drain(..data);
+        self.snd_una = ack;
+        if self.fin_sent && ack == self.snd_nxt {
+            self.fin_acked = true;
+        }
+        self.last_progress = Instant::now();
+        self.retries = 0;
+    }
+
+    /// Handle a segment from the guest; `false` once the connection is gone.
+    fn on_segment(&mut self, wire: &mut Wire, seg: &TcpSegment) -> bool {
+        if seg.flags & TCP_RST != 0 {
+            if let Some(ref stream) = self.stream {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            return false;
+        }
+
+        match self.state {
+            // The guest retransmitting its SYN while the host connect runs
+            TcpState::Connecting(_) => return true,
+            TcpState::SynSent => {
+                if seg.flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK && seg.ack == self.snd_nxt {
+                    self.rcv_nxt = seg.seq.wrapping_add(1);
+                    self.snd_una = self.snd_nxt;
+                    self.guest_window = seg.window as u32;
+                    self.state = TcpState::Established;
+                    self.last_progress = Instant::now();
+                    self.send(wire, self.snd_nxt, TCP_ACK, &[]);
+                }
+                return true;
+            },
+            TcpState::SynReceived => {
+                if seg.flags & TCP_SYN != 0 {
+                    self.send_syn(wire);
+                    return true;
+                }
+                if seg.flags & TCP_ACK == 0 || seg.ack != self.snd_nxt {
+                    return true;
+                }
+                self.snd_una = self.snd_nxt;
+                self.state = TcpState::Established;
+                self.last_progress = Instant::now();
+            },
+            TcpState::Established => {},
+        }
+
+        if seg.flags & TCP_ACK != 0 {
+            self.on_ack(seg.ack);
+            self.guest_window = seg.window as u32;
+        }
+
+        if seg.payload.is_empty() && seg.flags & TCP_FIN == 0 {
+            return !self.finished();
+        }
+        // Only in-order data is taken; anything else gets a duplicate ACK
+        if seg.seq == self.rcv_nxt && !self.guest_fin {
+            let mut taken = 0;
+            if !seg.payload.is_empty() {
+                let Some(ref mut stream) = self.stream else { return false };
+                match stream.write(seg.payload) {
+                    Ok(n) => taken = n,
+                    Err(e) if would_block(&e) => {},
+                    Err(e) => {
+                        tracing::debug!(remote = %self.remote, error = %e, "usernet: host TCP write failed");
+                        self.reset(wire);
+                        return false;
+                    }
+                }
+            }
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(taken as u32);
+            if taken == seg.payload.len() && seg.flags & TCP_FIN != 0 {
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                self.guest_fin = true;
+                if let Some(ref stream) = self.stream {
+                    let _ = stream.shutdown(Shutdown::Write);
+                }
+            }
+        }
+        self.send(wire, self.snd_nxt, TCP_ACK, &[]);
+        !self.finished()
+    }
+
+    /// Move host data towards the guest and run the timers; `false` once
+    /// the connection is gone.
+    fn poll(&mut self, wire: &mut Wire) -> bool {
+        match self.state {
+            TcpState::Connecting(ref pending) => {
+                let result = match pending.try_recv() {
+                    Ok(result) => result,
+                    Err(TryRecvError::Empty) => return true,
+                    Err(TryRecvError::Disconnected) => Err(io::Error::other("connect worker gone")),
+                };
+                match result {
+                    Ok(stream) if stream.set_nonblocking(true).is_ok() => {
+                        self.stream = Some(stream);
+                        self.state = TcpState::SynReceived;
+                        self.send_syn(wire);
+                        return true;
+                    },
+                    result => {
+                        if let Err(e) = result {
+                            tracing::debug!(remote = %self.remote, error = %e, "usernet: host TCP connect failed");
+                        }
+                        self.send(wire, self.snd_una, TCP_RST | TCP_ACK, &[]);
+                        return false;
+                    }
+                }
+            },
+            TcpState::SynReceived | TcpState::SynSent => {
+                if self.last_progress.elapsed() < TCP_RTO {
+                    return true;
+                }
+                self.retries += 1;
+                if self.retries > TCP_MAX_RETRIES {
+                    self.reset(wire);
+                    return false;
+                }
+                self.send_syn(wire);
+                return true;
+            },
+            TcpState::Established => {},
+        }
+
+        // Go back to the oldest unacknowledged byte
+        if self.snd_nxt != self.snd_una && self.last_progress.elapsed() >= TCP_RTO {
+            self.retries += 1;
+            if self.retries > TCP_MAX_RETRIES {
+                self.reset(wire);
+                return false;
+            }
+            self.snd_nxt = self.snd_una;
+            self.fin_sent = false;
+            self.last_progress = Instant::now();
+        }
+
+        if !self.host_eof && self.unacked.len() < TCP_SEND_BUFFER {
+            let mut buf = [0u8; 16 * 1024];
+            let room = buf.len().min(TCP_SEND_BUFFER - self.unacked.len());
+            let Some(ref mut stream) = self.stream else { return false };
+            match stream.read(&mut buf[..room]) {
+                Ok(0) => self.host_eof = true,
+                Ok(n) => self.unacked.extend_from_slice(&buf[..n]),
+                Err(e) if would_block(&e) => {},
+                Err(e) => {
+                    tracing::debug!(remote = %self.remote, error = %e, "usernet: host TCP read failed");
+                    self.reset(wire);
+                    return false;
+                }
+            }
+        }
+
+        while wire.has_room() && !self.fin_sent {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let window = (self.guest_window as usize).saturating_sub(in_flight);
+            let len = TCP_MSS.min(self.unacked.len() - in_flight).min(window);
+            if len == 0 {
+                break;
+            }
+            if in_flight == 0 {
+                self.last_progress = Instant::now();
+            }
+            let payload = &self.unacked[in_flight..in_flight + len];
+            self.send(wire, self.snd_nxt, TCP_ACK | TCP_PSH, payload);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+
+        let all_sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize == self.unacked.len();
+        if self.host_eof && !self.fin_sent && all_sent && wire.has_room() {
+            if self.unacked.is_empty() {
+                self.last_progress = Instant::now();
+            }
+            self.send(wire, self.snd_nxt, TCP_FIN | TCP_ACK, &[]);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+        }
+        !self.finished()
+    }
+}
+
+struct UdpFlow {
+    socket: UdpSocket,
+    last_used: Instant,
+}
+
+struct ConnectJob {
+    host: SocketAddr,
+    reply: mpsc::Sender<io::Result<TcpStream>>,
+}
+
+/// Start the connect workers; they exit once the returned queue is dropped.
+fn spawn_connect_workers(count: usize) -> SyncSender<ConnectJob> {
+    let (jobs, queue) = mpsc::sync_channel::<ConnectJob>(MAX_PENDING_CONNECTS);
+    let queue = Arc::new(Mutex::new(queue));
+    for _ in 0..count {
+        let queue = Arc::clone(&queue);
+        thread::spawn(move || loop {
+            let job = match queue.lock() {
+                Ok(queue) => queue.recv(),
+                Err(_) => return,
+            };
+            let Ok(job) = job else { return };
+            let _ = job.reply.send(TcpStream::connect_timeout(&job.host, TCP_CONNECT_TIMEOUT));
+        });
+    }
+    jobs
+}
+
+struct Forward {
+    listener: TcpListener,
+    guest: SocketAddrV4,
+}
+
+/// The user-mode backend of virtio-net. Like a TAP, it takes Ethernet frames
+/// from the guest with `write` and hands frames back with a non-blocking
+/// `read`; the host sockets are polled whenever the guest has nothing left
+/// to read.
+pub struct UserNet {
+    wire: Wire,
+    // Keyed by (guest end, remote end as the guest sees it)
+    tcp: HashMap<(SocketAddrV4, SocketAddrV4), TcpConn>,
+    udp: HashMap<(SocketAddrV4, SocketAddrV4), UdpFlow>,
+    forwards: Vec<Forward>,
+    connects: SyncSender<ConnectJob>,
+    dns_server: Option<SocketAddr>,
+    next_iss: u32,
+    next_fwd_port: u16,
+}
+
+impl UserNet {
+    pub fn new(guest_mac: [u8; 6], hostfwd: &[HostFwd]) -> Result<Self, String> {
+        let mut forwards = Vec::new();
+        for rule in hostfwd {
+            let listener = TcpListener::bind((rule.host_addr, rule.host_port))
+                .and_then(|l| l.set_nonblocking(true).map(|_| l))
+                .map_err(|e| format!("Failed to listen on {}:{} for {}: {}", rule.host_addr, rule.host_port, rule, e))?;
+            forwards.push(Forward { listener, guest: SocketAddrV4::new(rule.guest_addr, rule.guest_port) });
+        }
+        let dns_server = std::fs::read_to_string("/etc/resolv.conf").ok().and_then(|conf| host_nameserver(&conf));
+        if dns_server.is_none() {
+            tracing::warn!("usernet: no IPv4 nameserver in /etc/resolv.conf, guest DNS disabled");
+        }
+
+        Ok(Self {
+            wire: Wire { guest_mac, frames: VecDeque::new(), ip_id: 0 },
+            tcp: HashMap::new(),
+            udp: HashMap::new(),
+            forwards,
+            connects: spawn_connect_workers(CONNECT_WORKERS),
+            dns_server,
+            next_iss: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos()),
+            next_fwd_port: FWD_PORT_FIRST,
+        })
+    }
+
+    /// Next frame for the guest, or `WouldBlock` when there is none.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.wire.frames.is_empty() {
+            self.poll();
+        }
+        let frame = self.wire.frames.pop_front().ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+        let n = frame.len().min(buf.len());
+        buf[..n].copy_from_slice(&frame[..n]);
+        Ok(n)
+    }
+
+    /// Take a frame from the guest. Frames the stack has no use for are
+    /// dropped, as a switch port would.
+    pub fn write(&mut self, frame: &[u8]) -> io::Result<usize> {
+        if frame.len() >= ETH_HLEN {
+            let payload = &frame[ETH_HLEN..];
+            match be16(frame, 12) {
+                ETHERTYPE_ARP => self.handle_arp(payload),
+                ETHERTYPE_IPV4 => self.handle_ipv4(payload),
+                _ => {}
+            }
+        }
+        Ok(frame.len())
+    }
+
+    fn iss(&mut self) -> u32 {
+        self.next_iss = self.next_iss.wrapping_add(64_000);
+        self.next_iss
+    }
+
+    /// Where a guest packet for `remote` goes on the host, if anywhere.
+    fn host_addr(&self, remote: SocketAddrV4, proto: u8) -> Option<SocketAddr> {
+        match *remote.ip() {
+            GATEWAY_ADDR => Some(SocketAddr::from((Ipv4Addr::LOCALHOST, remote.port()))),
+            DNS_ADDR if proto == IPPROTO_UDP && remote.port() == 53 => self.dns_server,
+            ip if on_subnet(ip) || ip.is_broadcast() || ip.is_multicast() || ip.is_unspecified() => None,
+            _ => Some(SocketAddr::V4(remote)),
+        }
+    }
+
+    fn handle_arp(&mut self, arp: &[u8]) {
+        if arp.len() < 28 || be16(arp, 2) != ETHERTYPE_IPV4 || be16(arp, 6) != ARP_REQUEST {
+            return;
+        }
+        let target = ipv4_at(arp, 24);
+        if !on_subnet(target) || target == GUEST_ADDR {
+            return;
+        }
+        let mut reply = arp[..28].to_vec();
+        reply[6..8].copy_from_slice(&ARP_REPLY.to_be_bytes());
+        reply[8..14].copy_from_slice(&GATEWAY_MAC);
+        reply[14..18].copy_from_slice(&target.octets());
+        reply[18..28].copy_from_slice(&arp[8..18]);
+        let mut requester = [0u8; 6];
+        requester.copy_from_slice(&arp[8..14]);
+        self.wire.ethernet(requester, ETHERTYPE_ARP, &reply);
+    }
+
+    fn handle_ipv4(&mut self, packet: &[u8]) {
+        if packet.len() < 20 || packet[0] >> 4 != 4 {
+            return;
+        }
+        let header_len = (packet[0] & 0xF) as usize * 4;
+        let total_len = be16(packet, 2) as usize;
+        if header_len < 20 || total_len < header_len || total_len > packet.len() {
+            return;
+        }
+        if be16(packet, 6) & 0x3FFF != 0 {
+            tracing::debug!("usernet: dropping IP fragment");
+            return;
+        }
+        let (src, dst) = (ipv4_at(packet, 12), ipv4_at(packet, 16));
+        let l4 = &packet[header_len..total_len];
+        match packet[9] {
+            IPPROTO_ICMP => self.handle_icmp(src, dst, l4),
+            IPPROTO_UDP => self.handle_udp(src, dst, l4),
+            IPPROTO_TCP => self.handle_tcp(src, dst, l4),
+            _ => {}
+        }
+    }
+
+    /// Pings are only answered for the gateway and DNS addresses.
+    fn handle_icmp(&mut self, src: Ipv4Addr, dst: Ipv4Addr, icmp: &[u8]) {
+        if icmp.len() < 8 || icmp[0] != ICMP_ECHO_REQUEST || (dst != GATEWAY_ADDR && dst != DNS_ADDR) {
+            return;
+        }
+        let mut reply = icmp.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        reply[2..4].copy_from_slice(&[0, 0]);
+        let csum = checksum(&reply, 0);
+        reply[2..4].copy_from_slice(&csum.to_be_bytes());
+        let guest_mac = self.wire.guest_mac;
+        self.wire.ipv4(guest_mac, dst, src, IPPROTO_ICMP, &reply);
+    }
+
+    fn handle_udp(&mut self, src: Ipv4Addr, dst: Ipv4Addr, udp: &[u8]) {
+        if udp.len() < 8 {
+            return;
+        }
+        let len = be16(udp, 4) as usize;
+        if len < 8 || len > udp.len() {
+            return;
+        }
+        let guest = SocketAddrV4::new(src, be16(udp, 0));
+        let remote = SocketAddrV4::new(dst, be16(udp, 2));
+        let payload = &udp[8..len];
+        if remote.port() == DHCP_SERVER_PORT {
+            self.handle_dhcp(payload);
+            return;
+        }
+        let Some(host) = self.host_addr(remote, IPPROTO_UDP) else { return };
+
+        if self.udp.len() >= MAX_UDP_FLOWS && !self.udp.contains_key(&(guest, remote)) {
+            let idlest = self.udp.iter().min_by_key(|(_, flow)| flow.last_used).map(|(&key, _)| key);
+            if let Some(key) = idlest {
+                tracing::debug!(guest = %key.0, remote = %key.1, "usernet: UDP table full, dropping idlest flow");
+                self.udp.remove(&key);
+            }
+        }
+        let flow = match self.udp.entry((guest, remote)) {
+            std::collections::hash_map::Entry::Occupied(flow) => flow.into_mut(),
+            std::collections::hash_map::Entry::Vacant(slot) => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+                    .and_then(|s| s.connect(host).map(|_| s))
+                    .and_then(|s| s.set_nonblocking(true).map(|_| s));
+                match socket {
+                    Ok(socket) => slot.insert(UdpFlow { socket, last_used: Instant::now() }),
+                    Err(e) => {
+                        tracing::debug!(remote = %host, error = %e, "usernet: host UDP socket failed");
+                        return;
+                    }
+                }
+            }
+        };
+        flow.last_used = Instant::now();
+        if let Err(e) = flow.socket.send(payload) {
+            tracing::debug!(remote = %host, error = %e, "usernet: host UDP send failed");
+        }
+    }
+
+    fn handle_dhcp(&mut self, msg: &[u8]) {
+        if msg.len() < DHCP_OPTIONS_START || msg[0] != 1 || msg[236..240] != DHCP_MAGIC {
+            return;
+        }
+        let reply_type = match dhcp_option(&msg[DHCP_OPTIONS_START..], DHCP_OPT_MESSAGE_TYPE).and_then(|v| v.first()) {
+            Some(&DHCPDISCOVER) => DHCPOFFER,
+            Some(&DHCPREQUEST) => DHCPACK,
+            _ => return,
+        };
+
+        let mut reply = vec![0u8; DHCP_OPTIONS_START];
+        reply[..4].copy_from_slice(&[2, 1, 6, 0]);
+        // xid, secs and flags, then chaddr
+        reply[4..12].copy_from_slice(&msg[4..12]);
+        reply[16..20].copy_from_slice(&GUEST_ADDR.octets());
+        reply[20..24].copy_from_slice(&GATEWAY_ADDR.octets());
+        reply[28..44].copy_from_slice(&msg[28..44]);
+        reply[236..240].copy_from_slice(&DHCP_MAGIC);
+        let options: [(u8, &[u8]); 6] = [
+            (DHCP_OPT_MESSAGE_TYPE, &[reply_type]),
+            (DHCP_OPT_SERVER_ID, &GATEWAY_ADDR.octets()),
+            (DHCP_OPT_LEASE_TIME, &DHCP_LEASE_SECS.to_be_bytes()),
+            (DHCP_OPT_SUBNET_MASK, &NETMASK.octets()),
+            (DHCP_OPT_ROUTER, &GATEWAY_ADDR.octets()),
+            (DHCP_OPT_DNS, &DNS_ADDR.octets()),
+        ];
+        for (code, value) in options {
+            reply.extend_from_slice(&[code, value.len() as u8]);
+            reply.extend_from_slice(value);
+        }
+        reply.push(DHCP_OPT_END);
+        reply.resize(reply.len().max(DHCP_MIN_LEN), 0);
+
+        // The client has no address yet
+        self.wire.udp(
+            BROADCAST_MAC,
+            SocketAddrV4::new(GATEWAY_ADDR, DHCP_SERVER_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT),
+            &reply,
+        );
+    }
+
+    fn handle_tcp(&mut self, src: Ipv4Addr, dst: Ipv4Addr, tcp: &[u8]) {
+        let Some(seg) = TcpSegment::parse(tcp) else { return };
+        let guest = SocketAddrV4::new(src, be16(tcp, 0));
+        let remote = SocketAddrV4::new(dst, be16(tcp, 2));
+
+        if let Some(conn) = self.tcp.get_mut(&(guest, remote)) {
+            if !conn.on_segment(&mut self.wire, &seg) {
+                self.tcp.remove(&(guest, remote));
+            }
+            return;
+        }
+
+        let host = self.host_addr(remote, IPPROTO_TCP);
+        match host {
+            Some(host) if seg.flags & (TCP_SYN | TCP_ACK) == TCP_SYN => {
+                if !self.connect_tcp(guest, remote, host, &seg) {
+                    self.wire.tcp(remote, guest, 0, seg.seq.wrapping_add(seg.len()), TCP_RST | TCP_ACK, &[]);
+                }
+            },
+            _ if seg.flags & TCP_RST != 0 => {},
+            // Nothing listens there: refuse like a closed port
+            _ if seg.flags & TCP_ACK != 0 => self.wire.tcp(remote, guest, seg.ack, 0, TCP_RST, &[]),
+            _ => self.wire.tcp(remote, guest, 0, seg.seq.wrapping_add(seg.len()), TCP_RST | TCP_ACK, &[]),
+        }
+    }
+
+    /// Queue the host connect for a guest SYN; `false` when the stack
+    /// already tracks or connects as much as it will.
+    fn connect_tcp(&mut self, guest: SocketAddrV4, remote: SocketAddrV4, host: SocketAddr, seg: &TcpSegment) -> bool {
+        if !self.make_tcp_room() {
+            return false;
+        }
+        let (reply, pending) = mpsc::channel();
+        if self.connects.try_send(ConnectJob { host, reply }).is_err() {
+            tracing::debug!(remote = %host, "usernet: too many pending host connects");
+            return false;
+        }
+        let iss = self.iss();
+        let mut conn = TcpConn::new(TcpState::Connecting(pending), None, guest, remote, iss);
+        conn.rcv_nxt = seg.seq.wrapping_add(1);
+        conn.guest_window = seg.window as u32;
+        self.tcp.insert((guest, remote), conn);
+        true
+    }
+
+    /// With the TCP table full, reset the oldest half-open connection to
+    /// make room; `false` when every entry is established.
+    fn make_tcp_room(&mut self) -> bool {
+        if self.tcp.len() < MAX_TCP_CONNS {
+            return true;
+        }
+        let oldest = self.tcp.iter()
+            .filter(|(_, conn)| !matches!(conn.state, TcpState::Established))
+            .min_by_key(|(_, conn)| conn.last_progress)
+            .map(|(&key, _)| key);
+        match oldest.and_then(|key| self.tcp.remove(&key)) {
+            Some(conn) => {
+                tracing::debug!(guest = %conn.guest, remote = %conn.remote, "usernet: TCP table full, dropping half-open connection");
+                conn.reset(&mut self.wire);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn poll(&mut self) {
+        for i in 0..self.forwards.len() {
+            loop {
+                let (stream, peer) = match self.forwards[i].listener.accept() {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        if !would_block(&e) {
+                            tracing::debug!(error = %e, "usernet: hostfwd accept failed");
+                        }
+                        break;
+                    }
+                };
+                if stream.set_nonblocking(true).is_err() {
+                    continue;
+                }
+                if !self.make_tcp_room() {
+                    tracing::debug!(peer = %peer, "usernet: TCP table full, dropping forwarded connection");
+                    continue;
+                }
+                let guest = self.forwards[i].guest;
+                let remote = SocketAddrV4::new(GATEWAY_ADDR, self.next_fwd_port);
+                self.next_fwd_port = self.next_fwd_port.checked_add(1).unwrap_or(FWD_PORT_FIRST);
+                tracing::debug!(peer = %peer, guest = %guest, "usernet: forwarding host connection");
+                let iss = self.iss();
+                let mut conn = TcpConn::new(TcpState::SynSent, Some(stream), guest, remote, iss);
+                conn.send_syn(&mut self.wire);
+                self.tcp.insert((guest, remote), conn);
+            }
+        }
+
+        let wire = &mut self.wire;
+        self.tcp.retain(|_, conn| conn.poll(wire));
+
+        let mut buf = [0u8; UDP_MAX_PAYLOAD];
+        self.udp.retain(|&(guest, remote), flow| {
+            while wire.has_room() {
+                match flow.socket.recv(&mut buf) {
+                    Ok(n) => {
+                        flow.last_used = Instant::now();
+                        wire.udp(wire.guest_mac, remote, guest, &buf[..n]);
+                    },
+                    Err(_) => break,
+                }
+            }
+            flow.last_used.elapsed() < UDP_IDLE_TIMEOUT
+        });
+    }
+}
+
+fn dhcp_option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+    while let Some((&opt, rest)) = options.split_first() {
+        match opt {
+            0 => options = rest,
+            DHCP_OPT_END => return None,
+            _ => {
+                let len = *rest.first()? as usize;
+                let value = rest.get(1..1 + len)?;
+                if opt == code {
+                    return Some(value);
+                }
+                options = &rest[1 + len..];
+            }
+        }
+    }
+    None
+}
+
+/// First IPv4 `nameserver` of a resolv.conf.
+fn host_nameserver(resolv_conf: &str) -> Option<SocketAddr> {
+    resolv_conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse::<Ipv4Addr>().ok())
+        .map(|ip| SocketAddr::from((ip, 53)))
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn guest_frame(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = GATEWAY_MAC.to_vec();
+        frame.extend_from_slice(&GUEST_MAC);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Guest TCP segment, built with the same encoder the stack uses.
+    fn guest_tcp(src: SocketAddrV4, dst: SocketAddrV4, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut wire = Wire { guest_mac: GATEWAY_MAC, frames: VecDeque::new(), ip_id: 0 };
+        wire.tcp(src, dst, seq, ack, flags, payload);
+        wire.frames.pop_front().unwrap()
+    }
+
+    /// Wait for the next frame, polling the host sockets.
+    fn next_frame(net: &mut UserNet) -> Vec<u8> {
+        let mut buf = [0u8; 2048];
+        for _ in 0..500 {
+            match net.read(&mut buf) {
+                Ok(n) => return buf[..n].to_vec(),
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        panic!("no frame from usernet");
+    }
+
+    #[test]
+    fn test_arp_reply() {
+        let mut net = UserNet::new(GUEST_MAC, &[]).unwrap();
+        let mut arp = vec![0, 1, 8, 0, 6, 4, 0, 1];
+        arp.extend_from_slice(&GUEST_MAC);
+        arp.extend_from_slice(&GUEST_ADDR.octets());
+        arp.extend_from_slice(&[0; 6]);
+        arp.extend_from_slice(&GATEWAY_ADDR.octets());
+        net.write(&guest_frame(ETHERTYPE_ARP, &arp)).unwrap();
+
+        let reply = next_frame(&mut net);
+        assert_eq!(&reply[..6], &GUEST_MAC);
+        assert_eq!(be16(&reply, 12), ETHERTYPE_ARP);
+        let arp = &reply[ETH_HLEN..];
+        assert_eq!(be16(arp, 6), ARP_REPLY);
+        assert_eq!(&arp[8..14], &GATEWAY_MAC);
+        assert_eq!(ipv4_at(arp, 14), GATEWAY_ADDR);
+        assert_eq!(ipv4_at(arp, 24), GUEST_ADDR);
+    }
+
+    #[test]
+    fn test_dhcp_offer() {
+        let mut net = UserNet::new(GUEST_MAC, &[]).unwrap();
+        let mut discover = vec![0u8; DHCP_OPTIONS_START];
+        discover[..4].copy_from_slice(&[1, 1, 6, 0]);
+        discover[4..8].copy_from_slice(&0x1234_5678u32.to_be_bytes());
+        discover[28..34].copy_from_slice(&GUEST_MAC);
+        discover[236..240].copy_from_slice(&DHCP_MAGIC);
+        discover.extend_from_slice(&[DHCP_OPT_MESSAGE_TYPE, 1, DHCPDISCOVER, DHCP_OPT_END]);
+        let mut wire = Wire { guest_mac: BROADCAST_MAC, frames: VecDeque::new(), ip_id: 0 };
+        wire.udp(BROADCAST_MAC, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DHCP_CLIENT_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_SERVER_PORT), &discover);
+        net.write(&wire.frames.pop_front().unwrap()).unwrap();
+
+        let offer = next_frame(&mut net);
+        let ip = &offer[ETH_HLEN..];
+        assert_eq!(checksum(&ip[..20], 0), 0);
+        let dhcp = &ip[28..];
+        assert_eq!(dhcp[0], 2);
+        assert_eq!(be32(dhcp, 4), 0x1234_5678);
+        assert_eq!(ipv4_at(dhcp, 16), GUEST_ADDR);
+        let options = &dhcp[DHCP_OPTIONS_START..];
+        assert_eq!(dhcp_option(options, DHCP_OPT_MESSAGE_TYPE), Some(&[DHCPOFFER][..]));
+        assert_eq!(dhcp_option(options, DHCP_OPT_ROUTER), Some(&GATEWAY_ADDR.octets()[..]));
+        assert_eq!(dhcp_option(options, DHCP_OPT_DNS), Some(&DNS_ADDR.octets()[..]));
+    }
+
+    #[test]
+    fn test_tcp_to_host() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut net = UserNet::new(GUEST_MAC, &[]).unwrap();
+        let guest = SocketAddrV4::new(GUEST_ADDR, 40000);
+        let remote = SocketAddrV4::new(GATEWAY_ADDR, port);
+
+        net.write(&guest_tcp(guest, remote, 1000, 0, TCP_SYN, &[])).unwrap();
+        let (mut host, _) = listener.accept().unwrap();
+        let syn_ack = next_frame(&mut net);
+        let tcp = &syn_ack[ETH_HLEN + 20..];
+        assert_eq!(l4_checksum(*remote.ip(), GUEST_ADDR, IPPROTO_TCP, tcp), 0);
+        let seg = TcpSegment::parse(tcp).unwrap();
+        assert_eq!(seg.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(seg.ack, 1001);
+
+        let iss = seg.seq;
+        net.write(&guest_tcp(guest, remote, 1001, iss + 1, TCP_ACK, &[])).unwrap();
+        net.write(&guest_tcp(guest, remote, 1001, iss + 1, TCP_ACK | TCP_PSH, b"ping")).unwrap();
+        let mut buf = [0u8; 4];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        let ack = next_frame(&mut net);
+        assert_eq!(TcpSegment::parse(&ack[ETH_HLEN + 20..]).unwrap().ack, 1005);
+
+        host.write_all(b"pong").unwrap();
+        let data = next_frame(&mut net);
+        let seg = TcpSegment::parse(&data[ETH_HLEN + 20..]).unwrap();
+        assert_eq!((seg.seq, seg.payload), (iss + 1, &b"pong"[..]));
+    }
+
+    fn track(net: &mut UserNet, guest_port: u16, state: TcpState) -> (SocketAddrV4, SocketAddrV4) {
+        let key = (SocketAddrV4::new(GUEST_ADDR, guest_port), SocketAddrV4::new(GATEWAY_ADDR, 80));
+        net.tcp.insert(key, TcpConn::new(state, None, key.0, key.1, 0));
+        key
+    }
+
+    fn sent_rst(net: &mut UserNet, guest_port: u16) -> bool {
+        net.wire.frames.drain(..).any(|frame| {
+            let tcp = &frame[ETH_HLEN + 20..];
+            be16(tcp, 2) == guest_port && TcpSegment::parse(tcp).unwrap().flags & TCP_RST != 0
+        })
+    }
+
+    #[test]
+    fn test_tcp_to_host_under_seccomp() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let remote = SocketAddrV4::new(GATEWAY_ADDR, listener.local_addr().unwrap().port());
+        // The filter covers the whole process, so it goes on a forked child
+        unsafe {
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                let connected = std::panic::catch_unwind(|| {
+                    let mut net = UserNet::new(GUEST_MAC, &[]).unwrap();
+                    crate::seccomp::install_runtime_filter().unwrap();
+                    let guest = SocketAddrV4::new(GUEST_ADDR, 40000);
+                    net.write(&guest_tcp(guest, remote, 1000, 0, TCP_SYN, &[])).unwrap();
+                    let syn_ack = next_frame(&mut net);
+                    TcpSegment::parse(&syn_ack[ETH_HLEN + 20..]).unwrap().flags == TCP_SYN | TCP_ACK
+                });
+                libc::_exit(if connected.unwrap_or(false) { 0 } else { 1 });
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "status {:#x}", status);
+        }
+    }
+
+    #[test]
+    fn test_tcp_table_is_capped() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let remote = SocketAddrV4::new(GATEWAY_ADDR, listener.local_addr().unwrap().port());
+        let mut net = UserNet::new(GUEST_MAC, &[]).unwrap();
+        let half_open = track(&mut net, 1, TcpState::SynSent);
+        for port in 2..MAX_TCP_CONNS as u16 + 1 {
+            track(&mut net, port, TcpState::Established);
+        }
+
+        // The half-open entry makes room for the new connection
+        let guest = SocketAddrV4::new(GUEST_ADDR, 40000);
+        net.write(&guest_tcp(guest, remote, 1000, 0, TCP_SYN, &[])).unwrap();
+        assert_eq!(net.tcp.len(), MAX_TCP_CONNS);
+        assert!(!net.tcp.contains_key(&half_open));
+        assert!(matches!(net.tcp[&(guest, remote)].state, TcpState::Connecting(_)));
+        assert!(sent_rst(&mut net, half_open.0.port()));
+
+        // Established connections are never evicted: the SYN is refused
+        net.tcp.get_mut(&(guest, remote)).unwrap().state = TcpState::Established;
+        let late = SocketAddrV4::new(GUEST_ADDR, 40001);
+        net.write(&guest_tcp(late, remote, 1000, 0, TCP_SYN, &[])).unwrap();
+        assert_eq!(net.tcp.len(), MAX_TCP_CONNS);
+        assert!(!net.tcp.contains_key(&(late, remote)));
+        assert!(sent_rst(&mut net, late.port()));
+    }
+
+    #[test]
+    fn test_pending_connects_are_bounded() {
+        let mut net = UserNet::new(GUEST_MAC, &[]).unwrap();
+        // No workers: every connect stays queued
+        let (connects, _queue) = mpsc::sync_channel(MAX_PENDING_CONNECTS);
+        net.connects = connects;
+        let remote = SocketAddrV4::new(GATEWAY_ADDR, 80);
+        for port in 0..MAX_PENDING_CONNECTS as u16 + 1 {
+            let guest = SocketAddrV4::new(GUEST_ADDR, 40000 + port);
+            net.write(&guest_tcp(guest, remote, 1000, 0, TCP_SYN, &[])).unwrap();
+        }
+        assert_eq!(net.tcp.len(), MAX_PENDING_CONNECTS);
+        assert!(sent_rst(&mut net, 40000 + MAX_PENDING_CONNECTS as u16));
+    }
+
+    #[test]
+    fn test_udp_table_drops_idlest_flow() {
+        let mut net = UserNet::new(GUEST_MAC, &[]).unwrap();
+        let remote = SocketAddrV4::new(GATEWAY_ADDR, 9);
+        let mut wire = Wire { guest_mac: GATEWAY_MAC, frames: VecDeque::new(), ip_id: 0 };
+        for port in 0..MAX_UDP_FLOWS as u16 + 1 {
+            wire.udp(GATEWAY_MAC, SocketAddrV4::new(GUEST_ADDR, 40000 + port), remote, b"x");
+            net.write(&wire.frames.pop_front().unwrap()).unwrap();
+        }
+        assert_eq!(net.udp.len(), MAX_UDP_FLOWS);
+        assert!(!net.udp.contains_key(&(SocketAddrV4::new(GUEST_ADDR, 40000), remote)));
+        assert!(net.udp.contains_key(&(SocketAddrV4::new(GUEST_ADDR, 40000 + MAX_UDP_FLOWS as u16), remote)));
+    }
+}
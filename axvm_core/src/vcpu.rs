@@ -4,8 +4,17 @@
 
 
 use kvm_ioctls::VcpuFd;
-use kvm_bindings::kvm_segment;
+use std::os::unix::io::RawFd;
+use std::os::unix::thread::JoinHandleExt;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use kvm_bindings::{
+    kvm_segment, kvm_msr_entry, kvm_guest_debug, kvm_regs, Msrs, KVMIO,
+    KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP,
+};
+use crate::error::AxvmError;
 use crate::memory::GuestMemory;
+use crate::layout::{PML4_START, PDPT_START, PD_START, GDT_START, ZERO_PAGE_START, BOOT_STACK, IDENTITY_MAP_SIZE};
 
 
 
@@ -27,6 +36,23 @@ const EFER_LME: u64 = 1 << 8;
 const EFER_LMA: u64 = 1 << 10;
 
 
+const MSR_IA32_TSC: u32 = 0x10;
+const MSR_IA32_SYSENTER_CS: u32 = 0x174;
+const MSR_IA32_SYSENTER_ESP: u32 = 0x175;
+const MSR_IA32_SYSENTER_EIP: u32 = 0x176;
+const MSR_IA32_MISC_ENABLE: u32 = 0x1A0;
+const MSR_STAR: u32 = 0xC0000081;
+const MSR_LSTAR: u32 = 0xC0000082;
+const MSR_CSTAR: u32 = 0xC0000083;
+const MSR_SYSCALL_MASK: u32 = 0xC0000084;
+const MSR_KERNEL_GS_BASE: u32 = 0xC0000102;
+const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4B564D00;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4B564D01;
+
+
+const MISC_ENABLE_FAST_STRING: u64 = 1 << 0;
+
+
 
 
 
@@ -41,6 +67,7 @@ pub fn setup_long_mode(
     entry_point: u64,
     boot_params: u64,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    setup_msrs(vcpu)?;
     setup_protected_mode_32bit(vcpu, mem, entry_point, boot_params)
 }
 
@@ -57,18 +84,253 @@ pub fn setup_long_mode(
 
 
 
-#[allow(dead_code)]
 pub fn setup_long_mode_with_entry(
     vcpu: &mut VcpuFd, 
     mem: &mut GuestMemory,
     entry_point: u64,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    setup_msrs(vcpu)?;
     setup_page_tables_extended(mem)?;
     setup_gdt(mem)?;
-    setup_registers_with_entry(vcpu, entry_point)?;
+    setup_registers_with_entry(vcpu, mem.base() as u64, entry_point)?;
+    Ok(())
+}
+
+
+/// Start the vCPU in 16-bit real mode at `entry_seg:entry_off`, the way a
+/// BIOS hands over to a boot sector: DL = 0x80 (first hard disk), stack
+/// just below 0x7C00 and the real-mode IVT at 0.
+pub fn setup_real_mode(vcpu: &mut VcpuFd, entry_seg: u16, entry_off: u16) -> Result<(), kvm_ioctls::Error> {
+    setup_msrs(vcpu)?;
+
+    // TR and LDT keep their reset values, which KVM accepts in real mode
+    let mut sregs = vcpu.get_sregs()?;
+    sregs.cr0 &= !(CR0_PE | CR0_PG);
+    sregs.cr4 &= !CR4_PAE;
+    sregs.efer &= !(EFER_LME | EFER_LMA);
+
+    let real_seg = |selector: u16, type_: u8| kvm_segment {
+        base: (selector as u64) << 4,
+        limit: 0xFFFF,
+        selector,
+        type_,
+        present: 1,
+        dpl: 0,
+        db: 0,
+        s: 1,
+        l: 0,
+        g: 0,
+        avl: 0,
+        unusable: 0,
+        padding: 0,
+    };
+    sregs.cs = real_seg(entry_seg, 11);
+    sregs.ds = real_seg(0, 3);
+    sregs.es = real_seg(0, 3);
+    sregs.fs = real_seg(0, 3);
+    sregs.gs = real_seg(0, 3);
+    sregs.ss = real_seg(0, 3);
+
+    sregs.idt.base = 0;
+    sregs.idt.limit = 0x3FF;
+    vcpu.set_sregs(&sregs)?;
+
+    let mut regs = vcpu.get_regs()?;
+    regs.rflags = 2;
+    regs.rip = entry_off as u64;
+    regs.rsp = 0x7C00;
+    regs.rdx = 0x80;
+    vcpu.set_regs(&regs)?;
+
+    Ok(())
+}
+
+/// Hand a raw payload one boot argument in RDI, e.g. the `--dtb` address.
+pub fn set_boot_arg(vcpu: &VcpuFd, value: u64) -> Result<(), kvm_ioctls::Error> {
+    let mut regs = vcpu.get_regs()?;
+    regs.rdi = value;
+    vcpu.set_regs(&regs)
+}
+
+/// Split a linear real-mode address below 1 MiB into a 64K-aligned segment and offset.
+pub fn real_mode_address(linear: u64) -> (u16, u16) {
+    let segment = ((linear >> 4) & 0xF000) as u16;
+    (segment, (linear - ((segment as u64) << 4)) as u16)
+}
+
+
+// TSC starts at 0; SYSENTER/SYSCALL entry MSRs are cleared so the kernel programs them itself.
+// kvmclock starts disabled: KVM fills the pvclock pages once the guest writes their addresses.
+pub fn setup_msrs(vcpu: &VcpuFd) -> Result<(), kvm_ioctls::Error> {
+    let entries = [
+        msr_entry(MSR_IA32_TSC, 0),
+        msr_entry(MSR_IA32_MISC_ENABLE, MISC_ENABLE_FAST_STRING),
+        msr_entry(MSR_IA32_SYSENTER_CS, 0),
+        msr_entry(MSR_IA32_SYSENTER_ESP, 0),
+        msr_entry(MSR_IA32_SYSENTER_EIP, 0),
+        msr_entry(MSR_STAR, 0),
+        msr_entry(MSR_LSTAR, 0),
+        msr_entry(MSR_CSTAR, 0),
+        msr_entry(MSR_SYSCALL_MASK, 0),
+        msr_entry(MSR_KERNEL_GS_BASE, 0),
+        msr_entry(MSR_KVM_WALL_CLOCK_NEW, 0),
+        msr_entry(MSR_KVM_SYSTEM_TIME_NEW, 0),
+    ];
+
+    let msrs = Msrs::from_entries(&entries)
+        .map_err(|_| kvm_ioctls::Error::new(libc::EINVAL))?;
+
+    let written = vcpu.set_msrs(&msrs)?;
+    if written != entries.len() {
+        return Err(kvm_ioctls::Error::new(libc::EINVAL));
+    }
+
+    Ok(())
+}
+
+// Every KVM_RUN then retires exactly one guest instruction and exits with VcpuExit::Debug.
+pub fn enable_single_step(vcpu: &VcpuFd) -> Result<(), kvm_ioctls::Error> {
+    let debug = kvm_guest_debug {
+        control: KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_SINGLESTEP,
+        ..Default::default()
+    };
+    vcpu.set_guest_debug(&debug)
+}
+
+/// Suberror and raw data words of the last `KVM_EXIT_INTERNAL_ERROR`.
+pub fn internal_error_info(vcpu: &mut VcpuFd) -> (u32, Vec<u64>) {
+    let internal = unsafe { vcpu.get_kvm_run().__bindgen_anon_1.internal };
+    let ndata = (internal.ndata as usize).min(internal.data.len());
+    (internal.suberror, internal.data[..ndata].to_vec())
+}
+
+/// Classify a `KVM_RUN` failure other than EAGAIN/EINTR. Allocation and
+/// busy errors can clear up on their own and are recoverable; anything else
+/// means vCPU state or KVM itself is broken.
+pub fn run_error(err: &kvm_ioctls::Error) -> AxvmError {
+    match err.errno() {
+        libc::ENOMEM | libc::ENOBUFS | libc::EBUSY => AxvmError::VcpuRuntime(format!("KVM_RUN failed: {}", err)),
+        _ => AxvmError::HardwareFailure(format!("KVM_RUN failed: {}", err)),
+    }
+}
+
+// Recoverable KVM_RUN errors in a row before the vCPU gives up; the wait
+// doubles from RUN_RETRY_BACKOFF each time
+const MAX_RUN_RETRIES: u32 = 5;
+const RUN_RETRY_BACKOFF: Duration = Duration::from_millis(1);
+
+/// How long to wait before retrying `KVM_RUN` after `err`, with `retries`
+/// retries already made in a row; `None` when the vCPU should give up.
+pub fn run_retry_backoff(err: &AxvmError, retries: u32) -> Option<Duration> {
+    (err.is_recoverable() && retries < MAX_RUN_RETRIES).then(|| RUN_RETRY_BACKOFF * (1 << retries))
+}
+
+// _IOR(KVMIO, 0x81, struct kvm_regs)
+const KVM_GET_REGS: libc::c_ulong =
+    (2 << 30) | ((std::mem::size_of::<kvm_regs>() as libc::c_ulong) << 16) | ((KVMIO as libc::c_ulong) << 8) | 0x81;
+
+/// Guest RIP through a raw `KVM_GET_REGS` on the vCPU's fd, usable while a
+/// `VcpuExit` still borrows the `VcpuFd`.
+pub fn read_rip(fd: RawFd) -> Option<u64> {
+    let mut regs = kvm_regs::default();
+    let ret = unsafe { libc::ioctl(fd, KVM_GET_REGS, &mut regs as *mut kvm_regs) };
+    (ret == 0).then_some(regs.rip)
+}
+
+/// Signal used to knock a vCPU thread out of `KVM_RUN`.
+pub const VCPU_KICK_SIGNAL: libc::c_int = libc::SIGUSR1;
+
+extern "C" fn handle_kick_signal(_: libc::c_int) {}
+
+/// Install a no-op handler for `VCPU_KICK_SIGNAL` without `SA_RESTART`,
+/// so a blocked `KVM_RUN` returns `EINTR` when the thread is signalled.
+pub fn install_kick_handler() -> std::io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_kick_signal as *const () as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(VCPU_KICK_SIGNAL, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
     Ok(())
 }
 
+/// Send `VCPU_KICK_SIGNAL` to the thread behind `handle`.
+pub fn kick_thread<T>(handle: &JoinHandle<T>) {
+    kick_pthread(handle.as_pthread_t());
+}
+
+pub fn kick_pthread(thread: libc::pthread_t) {
+    unsafe {
+        libc::pthread_kill(thread, VCPU_KICK_SIGNAL);
+    }
+}
+
+/// `--vcpu-timeout`: a one-shot POSIX timer that sends `VCPU_KICK_SIGNAL`
+/// to the thread that created it, armed around each `KVM_RUN` so a run
+/// that lasts too long returns `EINTR`.
+pub struct RunTimer {
+    timer: libc::timer_t,
+    timeout: libc::timespec,
+}
+
+impl RunTimer {
+    pub fn new(timeout: std::time::Duration) -> std::io::Result<Self> {
+        let mut timer: libc::timer_t = std::ptr::null_mut();
+        unsafe {
+            let mut event: libc::sigevent = std::mem::zeroed();
+            event.sigev_notify = libc::SIGEV_THREAD_ID;
+            event.sigev_signo = VCPU_KICK_SIGNAL;
+            event.sigev_notify_thread_id = libc::gettid();
+            if libc::timer_create(libc::CLOCK_MONOTONIC, &mut event, &mut timer) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        let timeout = libc::timespec {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        };
+        Ok(Self { timer, timeout })
+    }
+
+    fn set(&self, value: libc::timespec) {
+        let spec = libc::itimerspec { it_interval: libc::timespec { tv_sec: 0, tv_nsec: 0 }, it_value: value };
+        unsafe {
+            libc::timer_settime(self.timer, 0, &spec, std::ptr::null_mut());
+        }
+    }
+
+    pub fn arm(&self) {
+        self.set(self.timeout);
+    }
+
+    /// Stop the timer; `true` if it had already fired.
+    pub fn disarm(&self) -> bool {
+        let mut current: libc::itimerspec = unsafe { std::mem::zeroed() };
+        let fired = unsafe { libc::timer_gettime(self.timer, &mut current) } == 0
+            && current.it_value.tv_sec == 0 && current.it_value.tv_nsec == 0;
+        self.set(libc::timespec { tv_sec: 0, tv_nsec: 0 });
+        fired
+    }
+}
+
+impl Drop for RunTimer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::timer_delete(self.timer);
+        }
+    }
+}
+
+fn msr_entry(index: u32, data: u64) -> kvm_msr_entry {
+    kvm_msr_entry {
+        index,
+        data,
+        ..Default::default()
+    }
+}
+
 
 
 
@@ -99,7 +361,7 @@ pub fn setup_protected_mode_32bit(
     boot_params_addr: u64,
 ) -> Result<(), Box<dyn std::error::Error>> {
     setup_gdt_32bit(mem)?;
-    setup_registers_32bit(vcpu, entry_point, boot_params_addr)?;
+    setup_registers_32bit(vcpu, mem.base() as u64, entry_point, boot_params_addr)?;
     Ok(())
 }
 
@@ -110,19 +372,20 @@ pub fn setup_protected_mode_32bit(
 
 
 fn setup_gdt_32bit(mem: &mut GuestMemory) -> Result<(), String> {
+    let gdt = mem.base() + GDT_START;
     
-    mem.write_u64(0x4000, 0)?;
+    mem.write_u64(gdt, 0)?;
 
     
     
     
     
-    mem.write_u64(0x4008, 0x00CF9A000000FFFF)?;
+    mem.write_u64(gdt + 8, 0x00CF9A000000FFFF)?;
 
     
     
     
-    mem.write_u64(0x4010, 0x00CF92000000FFFF)?;
+    mem.write_u64(gdt + 0x10, 0x00CF92000000FFFF)?;
 
     Ok(())
 }
@@ -130,6 +393,7 @@ fn setup_gdt_32bit(mem: &mut GuestMemory) -> Result<(), String> {
 
 fn setup_registers_32bit(
     vcpu: &mut VcpuFd,
+    ram_base: u64,
     entry_point: u64,
     boot_params_addr: u64,
 ) -> Result<(), kvm_ioctls::Error> {
@@ -220,7 +484,7 @@ fn setup_registers_32bit(
     sregs.ldt = ldt_seg;
 
     
-    sregs.gdt.base = 0x4000;
+    sregs.gdt.base = ram_base + GDT_START as u64;
     sregs.gdt.limit = 23;
 
     
@@ -240,26 +504,31 @@ fn setup_registers_32bit(
     regs.rdx = 0;
     regs.rdi = 0;
     regs.rbp = 0;
-    regs.rsp = 0x90000;            
+    regs.rsp = ram_base + BOOT_STACK;
     vcpu.set_regs(&regs)?;
 
     Ok(())
 }
 
 
+// Identity-map the 1 GiB block holding the start of RAM with 2 MiB pages
 fn setup_page_tables_extended(mem: &mut GuestMemory) -> Result<(), String> {
+    let base = mem.base();
+    let window = base as u64 & !(IDENTITY_MAP_SIZE - 1);
+    let pml4_index = ((window >> 39) & 0x1FF) as usize;
+    let pdpt_index = ((window >> 30) & 0x1FF) as usize;
     
-    mem.write_u64(0x1000, 0x2000 | 0x3)?;
+    mem.write_u64(base + PML4_START + pml4_index * 8, (base + PDPT_START) as u64 | 0x3)?;
 
     
-    mem.write_u64(0x2000, 0x3000 | 0x3)?;
+    mem.write_u64(base + PDPT_START + pdpt_index * 8, (base + PD_START) as u64 | 0x3)?;
 
     
     
     for i in 0u64..512 {
-        let physical_addr = i * 0x200000; 
+        let physical_addr = window + i * 0x200000; 
         let pd_entry = physical_addr | 0x83; 
-        mem.write_u64(0x3000 + (i * 8) as usize, pd_entry)?;
+        mem.write_u64(base + PD_START + (i * 8) as usize, pd_entry)?;
     }
 
     Ok(())
@@ -276,18 +545,19 @@ fn setup_page_tables_extended(mem: &mut GuestMemory) -> Result<(), String> {
 
 
 fn setup_gdt(mem: &mut GuestMemory) -> Result<(), String> {
+    let gdt = mem.base() + GDT_START;
     
-    mem.write_u64(0x4000, 0)?;
+    mem.write_u64(gdt, 0)?;
 
     
     
     
-    mem.write_u64(0x4008, 0x00209A0000000000)?;
+    mem.write_u64(gdt + 8, 0x00209A0000000000)?;
 
     
     
     
-    mem.write_u64(0x4010, 0x0000920000000000)?;
+    mem.write_u64(gdt + 0x10, 0x0000920000000000)?;
 
     Ok(())
 }
@@ -305,11 +575,11 @@ fn setup_gdt(mem: &mut GuestMemory) -> Result<(), String> {
 
 
 
-fn setup_registers_with_entry(vcpu: &mut VcpuFd, entry_point: u64) -> Result<(), kvm_ioctls::Error> {
+fn setup_registers_with_entry(vcpu: &mut VcpuFd, ram_base: u64, entry_point: u64) -> Result<(), kvm_ioctls::Error> {
     let mut sregs = vcpu.get_sregs()?;
 
     
-    sregs.cr3 = 0x1000;
+    sregs.cr3 = ram_base + PML4_START as u64;
 
     
     sregs.cr4 |= CR4_PAE;
@@ -405,7 +675,7 @@ fn setup_registers_with_entry(vcpu: &mut VcpuFd, entry_point: u64) -> Result<(),
     sregs.ldt = ldt_seg;
 
     
-    sregs.gdt.base = 0x4000;
+    sregs.gdt.base = ram_base + GDT_START as u64;
     sregs.gdt.limit = 23; 
 
     
@@ -421,8 +691,127 @@ fn setup_registers_with_entry(vcpu: &mut VcpuFd, entry_point: u64) -> Result<(),
     regs.rax = 0;
     regs.rbx = 0;
     
-    regs.rsi = crate::linux::ZERO_PAGE_START as u64;
+    regs.rsi = ram_base + ZERO_PAGE_START as u64;
     vcpu.set_regs(&regs)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Read one byte from `fd`, returning the result and errno
+    fn blocking_read(fd: RawFd) -> (isize, Option<i32>) {
+        let mut byte = 0u8;
+        let ret = unsafe { libc::read(fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        (ret, std::io::Error::last_os_error().raw_os_error())
+    }
+
+    #[test]
+    fn test_run_error_classification() {
+        for errno in [libc::ENOMEM, libc::ENOBUFS, libc::EBUSY] {
+            let err = run_error(&kvm_ioctls::Error::new(errno));
+            assert!(matches!(err, AxvmError::VcpuRuntime(_)) && err.is_recoverable(), "errno {}", errno);
+        }
+        for errno in [libc::EFAULT, libc::EINVAL, libc::ENOEXEC] {
+            let err = run_error(&kvm_ioctls::Error::new(errno));
+            assert!(matches!(err, AxvmError::HardwareFailure(_)) && err.requires_shutdown(), "errno {}", errno);
+        }
+    }
+
+    #[test]
+    fn test_run_retry_budget() {
+        let busy = run_error(&kvm_ioctls::Error::new(libc::EBUSY));
+        let waits: Vec<_> = (0..).map_while(|retries| run_retry_backoff(&busy, retries)).collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16].map(Duration::from_millis));
+
+        let fault = run_error(&kvm_ioctls::Error::new(libc::EFAULT));
+        assert_eq!(run_retry_backoff(&fault, 0), None);
+    }
+
+    #[test]
+    fn test_setup_msrs_disables_kvmclock() {
+        let kvm = kvm_ioctls::Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        // As left behind by a guest that enabled kvmclock
+        let enabled = [msr_entry(MSR_KVM_WALL_CLOCK_NEW, 0x2000), msr_entry(MSR_KVM_SYSTEM_TIME_NEW, 0x1001)];
+        assert_eq!(vcpu.set_msrs(&Msrs::from_entries(&enabled).unwrap()).unwrap(), 2);
+
+        setup_msrs(&vcpu).unwrap();
+        let mut msrs = Msrs::from_entries(&[msr_entry(MSR_KVM_WALL_CLOCK_NEW, !0), msr_entry(MSR_KVM_SYSTEM_TIME_NEW, !0)]).unwrap();
+        assert_eq!(vcpu.get_msrs(&mut msrs).unwrap(), 2);
+        assert!(msrs.as_slice().iter().all(|e| e.data == 0));
+    }
+
+    #[test]
+    fn test_setup_real_mode() {
+        assert_eq!(real_mode_address(0x7C00), (0, 0x7C00));
+        assert_eq!(real_mode_address(0x12345), (0x1000, 0x2345));
+        assert_eq!(real_mode_address(0xFFFF0), (0xF000, 0xFFF0));
+
+        let kvm = kvm_ioctls::Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let (segment, offset) = real_mode_address(0x12345);
+        setup_real_mode(&mut vcpu, segment, offset).unwrap();
+
+        let sregs = vcpu.get_sregs().unwrap();
+        assert_eq!(sregs.cr0 & (CR0_PE | CR0_PG), 0);
+        assert_eq!(sregs.efer & (EFER_LME | EFER_LMA), 0);
+        assert_eq!((sregs.cs.selector, sregs.cs.base, sregs.cs.limit), (0x1000, 0x10000, 0xFFFF));
+        assert_eq!((sregs.ss.base, sregs.idt.base, sregs.idt.limit), (0, 0, 0x3FF));
+
+        let regs = vcpu.get_regs().unwrap();
+        assert_eq!(sregs.cs.base + regs.rip, 0x12345);
+        assert_eq!((regs.rsp, regs.rdx, regs.rflags), (0x7C00, 0x80, 2));
+    }
+
+    #[test]
+    fn test_kick_interrupts_blocking_syscall() {
+        install_kick_handler().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        // Nothing is ever written: only the kick (no SA_RESTART) ends the read,
+        // just as it ends a KVM_RUN that never exits to the hypervisor
+        let reader = fds[0];
+        let blocked = std::thread::spawn(move || blocking_read(reader));
+
+        let start = std::time::Instant::now();
+        while !blocked.is_finished() {
+            assert!(start.elapsed() < Duration::from_secs(10), "kick did not interrupt the read");
+            kick_thread(&blocked);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(blocked.join().unwrap(), (-1, Some(libc::EINTR)));
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn test_run_timer_interrupts_and_reports_firing() {
+        install_kick_handler().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        let timer = RunTimer::new(Duration::from_millis(20)).unwrap();
+        timer.arm();
+        assert!(!timer.disarm());
+
+        // Signals the thread that created it, ending its blocked call
+        timer.arm();
+        let start = std::time::Instant::now();
+        assert_eq!(blocking_read(fds[0]), (-1, Some(libc::EINTR)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(timer.disarm());
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// src/vga.rs
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::layout::ZERO_PAGE_START;
+use crate::memory::GuestMemory;
+
+pub const VGA_TEXT_START: usize = 0xB8000;
+pub const VGA_TEXT_END: usize = 0xC0000;
+
+pub const VGA_CRTC_INDEX: u16 = 0x3D4;
+pub const VGA_CRTC_DATA: u16 = 0x3D5;
+
+const COLS: usize = 80;
+const ROWS: usize = 25;
+const TEXT_BYTES: usize = COLS * ROWS * 2;
+
+// Blank cell: space, light grey on black
+const BLANK_CELL: u16 = 0x0720;
+
+const CRTC_REG_COUNT: usize = 0x19;
+const CRTC_CURSOR_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOW: u8 = 0x0F;
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+
+// boot_params.screen_info offsets
+const SCREEN_INFO_VIDEO_MODE: usize = 0x06;
+const SCREEN_INFO_VIDEO_COLS: usize = 0x07;
+const SCREEN_INFO_VIDEO_LINES: usize = 0x0E;
+const SCREEN_INFO_IS_VGA: usize = 0x0F;
+const SCREEN_INFO_POINTS: usize = 0x10;
+const VIDEO_TYPE_VGAC: u8 = 0x22;
+
+/// 80x25 colour text console backed by guest RAM at 0xB8000.
+///
+/// The guest writes characters straight into memory; only the CRTC cursor
+/// registers need port emulation.
+pub struct VgaText {
+    crtc_index: Mutex<u8>,
+    crtc_regs: Mutex<[u8; CRTC_REG_COUNT]>,
+}
+
+impl VgaText {
+    pub fn new() -> Self {
+        Self {
+            crtc_index: Mutex::new(0),
+            crtc_regs: Mutex::new([0; CRTC_REG_COUNT]),
+        }
+    }
+
+    /// Blank the text buffer and advertise a VGA text console in the zero page.
+    pub fn setup(mem: &mut GuestMemory) -> Result<(), String> {
+        let mut cells = Vec::with_capacity(VGA_TEXT_END - VGA_TEXT_START);
+        while cells.len() < VGA_TEXT_END - VGA_TEXT_START {
+            cells.extend_from_slice(&BLANK_CELL.to_le_bytes());
+        }
+        mem.write_slice(VGA_TEXT_START, &cells)?;
+
+        mem.write_u8(ZERO_PAGE_START + SCREEN_INFO_VIDEO_MODE, 3)?;
+        mem.write_u8(ZERO_PAGE_START + SCREEN_INFO_VIDEO_COLS, COLS as u8)?;
+        mem.write_u8(ZERO_PAGE_START + SCREEN_INFO_VIDEO_LINES, ROWS as u8)?;
+        mem.write_u8(ZERO_PAGE_START + SCREEN_INFO_IS_VGA, VIDEO_TYPE_VGAC)?;
+        mem.write_u16(ZERO_PAGE_START + SCREEN_INFO_POINTS, 16)?;
+        Ok(())
+    }
+
+    pub fn write(&self, port: u16, data: &[u8]) {
+        let Some(&val) = data.first() else { return };
+        match port {
+            VGA_CRTC_INDEX => *self.crtc_index.lock().unwrap() = val,
+            VGA_CRTC_DATA => {
+                let idx = *self.crtc_index.lock().unwrap() as usize;
+                if idx < CRTC_REG_COUNT {
+                    self.crtc_regs.lock().unwrap()[idx] = val;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    pub fn read(&self, port: u16) -> u8 {
+        match port {
+            VGA_CRTC_INDEX => *self.crtc_index.lock().unwrap(),
+            VGA_CRTC_DATA => {
+                let idx = *self.crtc_index.lock().unwrap() as usize;
+                if idx < CRTC_REG_COUNT {
+                    self.crtc_regs.lock().unwrap()[idx]
+                } else {
+                    0
+                }
+            },
+            _ => 0xFF,
+        }
+    }
+
+    /// Cursor position as (row, col), clamped to the screen.
+    pub fn cursor(&self) -> (usize, usize) {
+        let regs = self.crtc_regs.lock().unwrap();
+        let pos = ((regs[CRTC_CURSOR_HIGH as usize] as usize) << 8) | regs[CRTC_CURSOR_LOW as usize] as usize;
+        let pos = pos.min(COLS * ROWS - 1);
+        (pos / COLS, pos % COLS)
+    }
+
+    /// Render the character cells as plain text, one line per row.
+    pub fn render(cells: &[u8]) -> Vec<String> {
+        cells.chunks(COLS * 2).take(ROWS).map(|row| {
+            let line: String = row.chunks(2).map(|cell| match cell[0] {
+                c @ 0x20..=0x7E => c as char,
+                _ => ' ',
+            }).collect();
+            line.trim_end().to_string()
+        }).collect()
+    }
+
+    /// Periodically redraw the guest screen on the host terminal.
+    pub fn spawn_renderer(
+        self: Arc<Self>,
+        guest_mem: Arc<Mutex<GuestMemory>>,
+        should_stop: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut last_frame: Option<(Vec<u8>, (usize, usize))> = None;
+
+            while !should_stop.load(Ordering::Relaxed) {
+                thread::sleep(REFRESH_INTERVAL);
+
+                let cells = match guest_mem.lock() {
+                    Ok(mem) => match mem.read_slice(VGA_TEXT_START, TEXT_BYTES) {
+                        Ok(cells) => cells.to_vec(),
+                        Err(_) => break,
+                    },
+                    Err(_) => break,
+                };
+                let frame = (cells, self.cursor());
+                if last_frame.as_ref() == Some(&frame) {
+                    continue;
+                }
+
+                let stdout = io::stdout();
+                let mut out = stdout.lock();
+                let _ = write!(out, "\x1b[H\x1b[2J");
+                for line in Self::render(&frame.0) {
+                    let _ = write!(out, "{}\r\n", line);
+                }
+                let (row, col) = frame.1;
+                let _ = write!(out, "\x1b[{};{}H", row + 1, col + 1);
+                let _ = out.flush();
+
+                last_frame = Some(frame);
+            }
+        })
+    }
+}
+
+impl Default for VgaText {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_and_cursor() {
+        let mut cells = vec![0u8; TEXT_BYTES];
+        for (i, &c) in b"AxVM".iter().enumerate() {
+            cells[COLS * 2 + i * 2] = c;
+            cells[COLS * 2 + i * 2 + 1] = 0x07;
+        }
+        let lines = VgaText::render(&cells);
+        assert_eq!(lines.len(), ROWS);
+        assert_eq!(lines[0], "");
+        assert_eq!(lines[1], "AxVM");
+
+        let vga = VgaText::new();
+        let pos = (COLS + 4) as u16;
+        vga.write(VGA_CRTC_INDEX, &[CRTC_CURSOR_HIGH]);
+        vga.write(VGA_CRTC_DATA, &[(pos >> 8) as u8]);
+        vga.write(VGA_CRTC_INDEX, &[CRTC_CURSOR_LOW]);
+        vga.write(VGA_CRTC_DATA, &[pos as u8]);
+        assert_eq!(vga.cursor(), (1, 4));
+        assert_eq!(vga.read(VGA_CRTC_DATA), pos as u8);
+    }
+}
@@ -6,10 +6,18 @@
 
 #![allow(dead_code)]
 
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::ops::Range;
 use crate::memory::GuestMemory;
+use crate::error::{AxvmResult, LockExt};
+use crate::metrics::VmMetrics;
+use crate::mmio::{self, MmioDevice, RegisterLatch};
+use crate::blk_cache::WriteCache;
+use crate::config::DiskCache;
+use crate::direct_io::{self, DIRECT_IO_ALIGN};
 
 
 pub const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x000;
@@ -47,24 +55,214 @@ const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
 const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
 const VIRTIO_BLK_F_GEOMETRY: u64 = 1 << 4;
 const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
-const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_TOPOLOGY: u64 = 1 << 10;
+const VIRTIO_BLK_F_CONFIG_WCE: u64 = 1 << 11;
+const VIRTIO_BLK_F_MQ: u64 = 1 << 12;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+pub const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Device status bits
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+pub const VIRTIO_STATUS_FAILED: u32 = 0x80;
 
 
-const DISK_SIZE_SECTORS: u64 = 204800; 
 const SECTOR_SIZE: u32 = 512;
 
+// Largest queue the driver may configure, unless lowered with --virtio-queue-size
+pub const QUEUE_NUM_MAX: u16 = 256;
+
+const BLK_SIZE_MAX: u32 = 1024 * 1024;
+// DISCARD and WRITE_ZEROES: segments per request, sectors per segment (1 GB)
+const BLK_MAX_DISCARD_SEG: u32 = 32;
+const BLK_MAX_DISCARD_SECTORS: u32 = 1 << 21;
+
+// Conventional LBA-to-CHS translation
+const GEOMETRY_HEADS: u8 = 16;
+const GEOMETRY_SECTORS: u8 = 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlkConfigField {
+    Capacity,
+    SizeMax,
+    SegMax,
+    Cylinders,
+    Heads,
+    Sectors,
+    BlkSize,
+    PhysicalBlockExp,
+    AlignmentOffset,
+    MinIoSize,
+    OptIoSize,
+    Writeback,
+    NumQueues,
+    MaxDiscardSectors,
+    MaxDiscardSeg,
+    DiscardSectorAlignment,
+    MaxWriteZeroesSectors,
+    MaxWriteZeroesSeg,
+    WriteZeroesMayUnmap,
+}
+
+// struct virtio_blk_config up to and including write_zeroes_may_unmap: (field, offset,
+// width, feature that makes it valid). A field whose feature is not offered
+// reads as zero. Capacity is always valid.
+const BLK_CONFIG_LAYOUT: [(BlkConfigField, usize, usize, u64); 19] = [
+    (BlkConfigField::Capacity, 0x00, 8, 0),
+    (BlkConfigField::SizeMax, 0x08, 4, VIRTIO_BLK_F_SIZE_MAX),
+    (BlkConfigField::SegMax, 0x0C, 4, VIRTIO_BLK_F_SEG_MAX),
+    (BlkConfigField::Cylinders, 0x10, 2, VIRTIO_BLK_F_GEOMETRY),
+    (BlkConfigField::Heads, 0x12, 1, VIRTIO_BLK_F_GEOMETRY),
+    (BlkConfigField::Sectors, 0x13, 1, VIRTIO_BLK_F_GEOMETRY),
+    (BlkConfigField::BlkSize, 0x14, 4, VIRTIO_BLK_F_BLK_SIZE),
+    (BlkConfigField::PhysicalBlockExp, 0x18, 1, VIRTIO_BLK_F_TOPOLOGY),
+    (BlkConfigField::AlignmentOffset, 0x19, 1, VIRTIO_BLK_F_TOPOLOGY),
+    (BlkConfigField::MinIoSize, 0x1A, 2, VIRTIO_BLK_F_TOPOLOGY),
+    (BlkConfigField::OptIoSize, 0x1C, 4, VIRTIO_BLK_F_TOPOLOGY),
+    (BlkConfigField::Writeback, 0x20, 1, VIRTIO_BLK_F_CONFIG_WCE),
+    (BlkConfigField::NumQueues, 0x22, 2, VIRTIO_BLK_F_MQ),
+    (BlkConfigField::MaxDiscardSectors, 0x24, 4, VIRTIO_BLK_F_DISCARD),
+    (BlkConfigField::MaxDiscardSeg, 0x28, 4, VIRTIO_BLK_F_DISCARD),
+    (BlkConfigField::DiscardSectorAlignment, 0x2C, 4, VIRTIO_BLK_F_DISCARD),
+    (BlkConfigField::MaxWriteZeroesSectors, 0x30, 4, VIRTIO_BLK_F_WRITE_ZEROES),
+    (BlkConfigField::MaxWriteZeroesSeg, 0x34, 4, VIRTIO_BLK_F_WRITE_ZEROES),
+    (BlkConfigField::WriteZeroesMayUnmap, 0x38, 1, VIRTIO_BLK_F_WRITE_ZEROES),
+];
+const BLK_CONFIG_LEN: usize = 0x3C;
+
 
 const VIRTIO_BLK_T_IN: u32 = 0;  
 const VIRTIO_BLK_T_OUT: u32 = 1; 
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+// struct virtio_blk_discard_write_zeroes: le64 sector, le32 num_sectors, le32 flags
+const DISCARD_SEGMENT_LEN: usize = 16;
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+const DEFAULT_SERIAL: &str = "AXVM-BLK-0001";
 
 
 const VIRTIO_BLK_S_OK: u8 = 0;
 const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
 
 
 const VRING_DESC_F_NEXT: u16 = 1;
 const VRING_DESC_F_WRITE: u16 = 2;
 
+// Every ring base must sit on this boundary
+pub const VIRTQ_RING_ALIGN: u64 = 16;
+
+/// `vring_need_event` from the virtio spec: whether moving the index from
+/// `old` to `new` crosses the `event` index the other side asked to be told about.
+pub fn vring_need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+/// The status to latch for a driver's status write. When it sets DRIVER_OK,
+/// the `accepted` features must include VERSION_1 (AxVM has no legacy
+/// interface) and nothing outside `offered`; otherwise FAILED is added.
+pub fn checked_status(device: &str, status: u32, offered: u64, accepted: u64) -> u32 {
+    if status & VIRTIO_STATUS_DRIVER_OK == 0 {
+        return status;
+    }
+    let error = if accepted & VIRTIO_F_VERSION_1 == 0 {
+        "driver did not accept VIRTIO_F_VERSION_1".to_string()
+    } else if accepted & !offered != 0 {
+        format!("driver accepted unsupported features {:#x}", accepted & !offered)
+    } else {
+        return status;
+    };
+    tracing::warn!(device, error = %error, "{}: feature negotiation failed, marking device FAILED", device);
+    status | VIRTIO_STATUS_FAILED
+}
+
+/// `checked_status`, plus a warning and an error metric the first time the
+/// device ends up FAILED, whether the driver gave up or negotiation did.
+pub fn latch_status(device: &str, old: u32, written: u32, offered: u64, accepted: u64, metrics: Option<&VmMetrics>) -> u32 {
+    let status = checked_status(device, written, offered, accepted);
+    if status & VIRTIO_STATUS_FAILED != 0 && old & VIRTIO_STATUS_FAILED == 0 {
+        if written & VIRTIO_STATUS_FAILED != 0 {
+            tracing::warn!(device, status = written, offered = offered, accepted = accepted,
+                "{}: driver set FAILED, device unusable until reset (offered {:#x}, accepted {:#x})", device, offered, accepted);
+        }
+        if let Some(metrics) = metrics {
+            metrics.record_error();
+        }
+    }
+    status
+}
+
+/// Check a guest-written QUEUE_NUM. The split-ring indices are free-running
+/// u16s reduced `% size`, which only stays consistent across wraparound for
+/// a power of two; it must also fit the advertised QUEUE_NUM_MAX.
+pub fn check_queue_size(val: u32, max: u16) -> Result<u16, String> {
+    if val > max as u32 {
+        return Err(format!("queue size {} exceeds the maximum of {}", val, max));
+    }
+    if !val.is_power_of_two() {
+        return Err(format!("queue size {} is not a power of two", val));
+    }
+    Ok(val as u16)
+}
+
+/// Check that the three rings of a split virtqueue with `size` entries lie
+/// inside the guest RAM range `ram` and are `VIRTQ_RING_ALIGN`-aligned.
+pub fn validate_rings(desc: u64, avail: u64, used: u64, size: u16, ram: Range<usize>) -> Result<(), String> {
+    if size == 0 {
+        return Err("queue size is zero".to_string());
+    }
+
+    let n = size as u64;
+    // flags + idx + ring + used_event / avail_event
+    let rings = [
+        ("descriptor table", desc, 16 * n),
+        ("available ring", avail, 6 + 2 * n),
+        ("used ring", used, 6 + 8 * n),
+    ];
+
+    for (name, base, len) in rings {
+        if base % VIRTQ_RING_ALIGN != 0 {
+            return Err(format!("{} at {:#x} is not {}-byte aligned", name, base, VIRTQ_RING_ALIGN));
+        }
+        if base < ram.start as u64 {
+            return Err(format!("{} at {:#x} lies below guest RAM at {:#x}", name, base, ram.start));
+        }
+        if base.checked_add(len).is_none_or(|end| end > ram.end as u64) {
+            return Err(format!(
+                "{} at {:#x} ({} bytes) exceeds guest memory ending at {:#x}",
+                name, base, len, ram.end
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Guest address `offset` bytes into a ring at the guest-written `base`;
+// None where the sum overflows rather than wrapping
+fn ring_slot(base: u64, offset: u64) -> Option<usize> {
+    usize::try_from(base.checked_add(offset)?).ok()
+}
+
+fn ring_read_u16(mem: &GuestMemory, base: u64, offset: u64) -> Option<u16> {
+    let bytes = mem.read_slice(ring_slot(base, offset)?, 2).ok()?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+// Out-of-range ring writes are dropped, like the guest reads above
+fn ring_write(mem: &mut GuestMemory, base: u64, offset: u64, data: &[u8]) {
+    if let Some(at) = ring_slot(base, offset) {
+        let _ = mem.write_slice(at, data);
+    }
+}
+
 pub struct VirtioBlock {
     status: Mutex<u32>,
     features_sel: Mutex<u32>,
@@ -79,15 +277,32 @@ pub struct VirtioBlock {
     queue_used: Mutex<u64>,
     
     last_avail_idx: Mutex<u16>,
+    // Used index at the last interrupt decision, for EVENT_IDX suppression
+    signalled_used: Mutex<u16>,
     disk: Mutex<Option<File>>,
     disk_size: u64,  // Size in bytes
+    serial: [u8; VIRTIO_BLK_ID_BYTES],
+    // Image opened with O_DIRECT; all I/O goes through aligned buffers
+    direct: bool,
+    cache_mode: DiskCache,
+    // Only used in writeback mode; always locked after `disk`
+    cache: Mutex<Option<WriteCache>>,
+    registers: RegisterLatch,
+    queue_num_max: u16,
+    metrics: Option<Arc<VmMetrics>>,
 }
 
 impl VirtioBlock {
     pub fn new(disk_path: Option<&str>) -> Self {
+        Self::open(disk_path, false)
+    }
+
+    /// Like `new`, but with `direct` the image is opened with `O_DIRECT`.
+    /// Falls back to buffered I/O with a warning if that is not possible.
+    pub fn open(disk_path: Option<&str>, direct: bool) -> Self {
         tracing::info!("Initializing VirtIO block device");
         
-        let (file, disk_size) = disk_path.map_or((None, 0), |path| {
+        let (file, disk_size, direct) = disk_path.map_or((None, 0, false), |path| {
             match OpenOptions::new()
                 .read(true)
                 .write(true)
@@ -98,21 +313,30 @@ impl VirtioBlock {
                         .map(|m| m.len())
                         .unwrap_or(0);
                     
-                    println!(">>> [VirtIO] Disk opened: {} ({} MB)", path, size / 1024 / 1024);
-                    tracing::info!(path = path, size_mb = size / 1024 / 1024, "Disk image opened");
-                    (Some(f), size)
+                    status!("VirtIO", path = path, size_mb = size / 1024 / 1024, "Disk opened: {} ({} MB)", path, size / 1024 / 1024);
+                    if !direct {
+                        return (Some(f), size, false);
+                    }
+                    match direct_io::open(path, size) {
+                        Ok(direct_file) => {
+                            status!("VirtIO", path = path, "Using O_DIRECT for {}", path);
+                            (Some(direct_file), size, true)
+                        },
+                        Err(e) => {
+                            status!(warn, "VirtIO", path = path, error = %e, "O_DIRECT unavailable for {} ({}), using buffered I/O", path, e);
+                            (Some(f), size, false)
+                        }
+                    }
                 },
                 Err(e) => {
-                    println!(">>> [VirtIO] Warning: {} not found - {}", path, e);
-                    tracing::warn!(path = path, error = %e, "Disk image not found");
-                    (None, 0)
+                    status!(warn, "VirtIO", path = path, error = %e, "{} not found - {}", path, e);
+                    (None, 0, false)
                 }
             }
         });
         
         if disk_path.is_none() {
-            println!(">>> [VirtIO] No disk image specified");
-            tracing::info!("No disk image specified");
+            status!("VirtIO", "No disk image specified");
         }
 
         Self {
@@ -127,155 +351,186 @@ impl VirtioBlock {
             queue_avail: Mutex::new(0),
             queue_used: Mutex::new(0),
             last_avail_idx: Mutex::new(0),
+            signalled_used: Mutex::new(0),
             disk: Mutex::new(file),
             disk_size,
+            serial: serial_bytes(DEFAULT_SERIAL),
+            direct,
+            cache_mode: DiskCache::None,
+            cache: Mutex::new(None),
+            registers: RegisterLatch::new(),
+            queue_num_max: QUEUE_NUM_MAX,
+            metrics: None,
         }
     }
 
-    
-    pub fn read(&self, offset: u64, data: &mut [u8]) {
-        let val: u32 = match offset {
-            VIRTIO_MMIO_MAGIC_VALUE => MAGIC_VALUE,
-            VIRTIO_MMIO_VERSION => VERSION,
-            VIRTIO_MMIO_DEVICE_ID => DEVICE_ID_BLOCK,
-            VIRTIO_MMIO_VENDOR_ID => VENDOR_ID,
-            VIRTIO_MMIO_DEVICE_FEATURES => {
-                let sel = *self.features_sel.lock().unwrap();
-                if sel == 0 {
-                    (VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX | 
-                     VIRTIO_BLK_F_GEOMETRY | VIRTIO_BLK_F_BLK_SIZE) as u32
-                } else {
-                    (VIRTIO_F_VERSION_1 >> 32) as u32
-                }
-            },
-            VIRTIO_MMIO_QUEUE_NUM_MAX => 256,
-            VIRTIO_MMIO_QUEUE_READY => *self.queue_ready.lock().unwrap(),
-            VIRTIO_MMIO_INTERRUPT_STATUS => *self.interrupt_status.lock().unwrap(),
-            VIRTIO_MMIO_STATUS => *self.status.lock().unwrap(),
-            VIRTIO_MMIO_CONFIG => {
-                // Capacity in 512-byte sectors (low 32 bits)
-                let sectors = self.disk_size / 512;
-                (sectors & 0xFFFFFFFF) as u32
-            },
-            0x104 => {
-                // Capacity in 512-byte sectors (high 32 bits)
-                let sectors = self.disk_size / 512;
-                (sectors >> 32) as u32
-            },
-            0x114 => SECTOR_SIZE,
-            _ => 0,
-        };
+    /// Set the ID string returned to VIRTIO_BLK_T_GET_ID (truncated to 20 bytes)
+    pub fn with_serial(mut self, serial: &str) -> Self {
+        self.serial = serial_bytes(serial);
+        self
+    }
 
-        let bytes = val.to_le_bytes();
-        let len = data.len().min(4);
-        data[..len].copy_from_slice(&bytes[..len]);
+    /// Select how writes reach the disk image; `limit_bytes` bounds the write-back cache
+    pub fn with_cache(mut self, mode: DiskCache, limit_bytes: usize) -> Self {
+        self.cache_mode = mode;
+        self.cache = Mutex::new((mode == DiskCache::Writeback)
+            .then(|| WriteCache::new(SECTOR_SIZE as usize, limit_bytes)));
+        self
     }
 
-    
-    pub fn write(&self, offset: u64, data: &[u8], mem: &mut GuestMemory) -> Result<bool, String> {
-        if data.len() < 4 { return Ok(false); }
-        let val = u32::from_le_bytes(data[0..4].try_into().unwrap_or([0; 4]));
-        let mut trigger_irq = false;
-
-        match offset {
-            VIRTIO_MMIO_DEVICE_FEATURES_SEL => *self.features_sel.lock().unwrap() = val,
-            VIRTIO_MMIO_DRIVER_FEATURES_SEL => *self.features_sel.lock().unwrap() = val,
-            VIRTIO_MMIO_DRIVER_FEATURES => {
-                let sel = *self.features_sel.lock().unwrap();
-                let mut feat = self.driver_features.lock().unwrap();
-                if sel == 0 { *feat = (*feat & !0xFFFFFFFF) | val as u64; }
-                else { *feat = (*feat & 0xFFFFFFFF) | ((val as u64) << 32); }
-            },
-            VIRTIO_MMIO_QUEUE_SEL => *self.queue_sel.lock().unwrap() = val,
-            VIRTIO_MMIO_QUEUE_NUM => *self.queue_num.lock().unwrap() = val,
-            VIRTIO_MMIO_QUEUE_READY => *self.queue_ready.lock().unwrap() = val,
-            VIRTIO_MMIO_QUEUE_NOTIFY => {
-                trigger_irq = self.process_queue(mem);
-            },
-            VIRTIO_MMIO_INTERRUPT_ACK => *self.interrupt_status.lock().unwrap() &= !val,
-            VIRTIO_MMIO_STATUS => {
-                let old = *self.status.lock().unwrap();
-                *self.status.lock().unwrap() = val;
-                if val == 0 && old != 0 { 
-                    *self.queue_ready.lock().unwrap() = 0;
-                    *self.last_avail_idx.lock().unwrap() = 0;
-                }
-            },
-            VIRTIO_MMIO_QUEUE_DESC_LOW => self.set_low(&self.queue_desc, val),
-            VIRTIO_MMIO_QUEUE_DESC_HIGH => self.set_high(&self.queue_desc, val),
-            VIRTIO_MMIO_QUEUE_AVAIL_LOW => self.set_low(&self.queue_avail, val),
-            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => self.set_high(&self.queue_avail, val),
-            VIRTIO_MMIO_QUEUE_USED_LOW => self.set_low(&self.queue_used, val),
-            VIRTIO_MMIO_QUEUE_USED_HIGH => self.set_high(&self.queue_used, val),
-            _ => {}
+    /// Advertise `max` instead of QUEUE_NUM_MAX as the largest queue
+    pub fn with_queue_size(mut self, max: u16) -> Self {
+        self.queue_num_max = max;
+        self
+    }
+
+    /// Count a FAILED device status as an error in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<VmMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    // Data descriptors per request: whatever fits between header and status
+    fn seg_max(&self) -> u32 {
+        (self.queue_num_max as u32).saturating_sub(2).max(1)
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX
+            | VIRTIO_BLK_F_GEOMETRY | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES
+            | VIRTIO_RING_F_EVENT_IDX | VIRTIO_F_VERSION_1;
+        // Tells the guest the device has a volatile write cache it must flush
+        if self.cache_mode == DiskCache::Writeback {
+            features |= VIRTIO_BLK_F_FLUSH;
         }
-        
-        Ok(trigger_irq)
+        features
     }
 
-    fn set_low(&self, mutex: &Mutex<u64>, val: u32) {
-        let mut g = mutex.lock().unwrap();
-        *g = (*g & 0xFFFFFFFF00000000) | val as u64;
+    // Steer the guest towards whole-block requests when the image needs aligned I/O
+    fn block_size(&self) -> u32 {
+        if self.direct { DIRECT_IO_ALIGN as u32 } else { SECTOR_SIZE }
     }
 
-    fn set_high(&self, mutex: &Mutex<u64>, val: u32) {
-        let mut g = mutex.lock().unwrap();
-        *g = (*g & 0x00000000FFFFFFFF) | ((val as u64) << 32);
+    fn config_value(&self, field: BlkConfigField) -> u64 {
+        let capacity = self.disk_size / SECTOR_SIZE as u64;
+        let per_cylinder = GEOMETRY_HEADS as u64 * GEOMETRY_SECTORS as u64;
+        match field {
+            BlkConfigField::Capacity => capacity,
+            BlkConfigField::SizeMax => BLK_SIZE_MAX as u64,
+            BlkConfigField::SegMax => self.seg_max() as u64,
+            BlkConfigField::Cylinders => (capacity / per_cylinder).min(u16::MAX as u64),
+            BlkConfigField::Heads => GEOMETRY_HEADS as u64,
+            BlkConfigField::Sectors => GEOMETRY_SECTORS as u64,
+            BlkConfigField::BlkSize => self.block_size() as u64,
+            BlkConfigField::MaxDiscardSectors | BlkConfigField::MaxWriteZeroesSectors => BLK_MAX_DISCARD_SECTORS as u64,
+            BlkConfigField::MaxDiscardSeg | BlkConfigField::MaxWriteZeroesSeg => BLK_MAX_DISCARD_SEG as u64,
+            BlkConfigField::DiscardSectorAlignment => (self.block_size() / SECTOR_SIZE) as u64,
+            BlkConfigField::WriteZeroesMayUnmap => 1,
+            BlkConfigField::PhysicalBlockExp
+            | BlkConfigField::AlignmentOffset
+            | BlkConfigField::MinIoSize
+            | BlkConfigField::OptIoSize
+            | BlkConfigField::Writeback
+            | BlkConfigField::NumQueues => 0,
+        }
+    }
+
+    /// Little-endian `virtio_blk_config`, filled from `BLK_CONFIG_LAYOUT`.
+    fn config_space(&self) -> [u8; BLK_CONFIG_LEN] {
+        let features = self.features();
+        let mut config = [0u8; BLK_CONFIG_LEN];
+        for (field, offset, width, feature) in BLK_CONFIG_LAYOUT {
+            if feature != 0 && features & feature == 0 {
+                continue;
+            }
+            let bytes = self.config_value(field).to_le_bytes();
+            config[offset..offset + width].copy_from_slice(&bytes[..width]);
+        }
+        config
+    }
+
+    /// Update the low or high half of one ring address. The rings were
+    /// validated when the queue was enabled, so they are fixed while ready.
+    fn set_ring_addr(&self, ring: &Mutex<u64>, high: bool, val: u32) -> AxvmResult<()> {
+        if *self.queue_ready.lock_or_err()? != 0 {
+            tracing::warn!("VirtIO-Blk: ignoring ring address written while the queue is ready");
+            return Ok(());
+        }
+        let mut addr = ring.lock_or_err()?;
+        *addr = if high {
+            (*addr & 0x00000000FFFFFFFF) | ((val as u64) << 32)
+        } else {
+            (*addr & 0xFFFFFFFF00000000) | val as u64
+        };
+        Ok(())
     }
 
     
     
     
     
-    fn process_queue(&self, mem: &mut GuestMemory) -> bool {
-        let queue_size = *self.queue_num.lock().unwrap() as u16;
-        if queue_size == 0 || *self.queue_ready.lock().unwrap() == 0 { 
-            return false; 
+    fn process_queue(&self, mem: &mut GuestMemory) -> AxvmResult<bool> {
+        let queue_size = *self.queue_num.lock_or_err()? as u16;
+        if queue_size == 0 || *self.queue_ready.lock_or_err()? == 0 { 
+            return Ok(false); 
         }
 
-        let desc_addr = *self.queue_desc.lock().unwrap();
-        let avail_addr = *self.queue_avail.lock().unwrap();
-        let used_addr = *self.queue_used.lock().unwrap();
+        let desc_addr = *self.queue_desc.lock_or_err()?;
+        let avail_addr = *self.queue_avail.lock_or_err()?;
+        let used_addr = *self.queue_used.lock_or_err()?;
 
         
-        let avail_idx = match mem.read_slice(avail_addr as usize + 2, 2) {
-            Ok(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]),
-            Err(_) => return false,
+        let Some(avail_idx) = ring_read_u16(mem, avail_addr, 2) else {
+            return Ok(false);
         };
 
-        let mut last_idx = self.last_avail_idx.lock().unwrap();
+        let mut last_idx = self.last_avail_idx.lock_or_err()?;
         let mut work_done = false;
+        let mut used_idx = 0u16;
 
         
         while *last_idx != avail_idx {
-            let ring_offset = 4 + (*last_idx % queue_size) as usize * 2;
-            let head_idx = match mem.read_slice(avail_addr as usize + ring_offset, 2) {
-                Ok(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]),
-                Err(_) => break,
+            let ring_offset = 4 + (*last_idx % queue_size) as u64 * 2;
+            let Some(head_idx) = ring_read_u16(mem, avail_addr, ring_offset) else {
+                break;
             };
 
             let written = self.process_descriptor_chain(mem, desc_addr, head_idx);
 
             
-            let used_idx = match mem.read_slice(used_addr as usize + 2, 2) {
-                Ok(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]),
-                Err(_) => 0,
-            };
+            used_idx = ring_read_u16(mem, used_addr, 2).unwrap_or(0);
             
-            let used_ring_offset = 4 + (used_idx % queue_size) as usize * 8;
-            let _ = mem.write_u32(used_addr as usize + used_ring_offset, head_idx as u32);
-            let _ = mem.write_u32(used_addr as usize + used_ring_offset + 4, written);
-            let _ = mem.write_u16(used_addr as usize + 2, used_idx.wrapping_add(1));
+            let used_ring_offset = 4 + (used_idx % queue_size) as u64 * 8;
+            let mut elem = [0u8; 8];
+            elem[..4].copy_from_slice(&(head_idx as u32).to_le_bytes());
+            elem[4..].copy_from_slice(&written.to_le_bytes());
+            ring_write(mem, used_addr, used_ring_offset, &elem);
+            used_idx = used_idx.wrapping_add(1);
+            ring_write(mem, used_addr, 2, &used_idx.to_le_bytes());
 
             *last_idx = last_idx.wrapping_add(1);
             work_done = true;
         }
 
-        if work_done {
-            *self.interrupt_status.lock().unwrap() |= 1;
-            return true;
+        if !work_done {
+            return Ok(false);
+        }
+
+        let mut interrupt = true;
+        if *self.driver_features.lock_or_err()? & VIRTIO_RING_F_EVENT_IDX != 0 {
+            // avail_event: ask for a notification once the guest passes what we consumed
+            ring_write(mem, used_addr, 4 + queue_size as u64 * 8, &last_idx.to_le_bytes());
+
+            let used_event = ring_read_u16(mem, avail_addr, 4 + queue_size as u64 * 2).unwrap_or(used_idx);
+            let mut signalled = self.signalled_used.lock_or_err()?;
+            interrupt = vring_need_event(used_event, used_idx, *signalled);
+            *signalled = used_idx;
+        }
+
+        if interrupt {
+            *self.interrupt_status.lock_or_err()? |= 1;
         }
-        false
+        Ok(interrupt)
     }
 
     fn process_descriptor_chain(&self, mem: &mut GuestMemory, desc_table: u64, head_idx: u16) -> u32 {
@@ -283,17 +538,16 @@ impl VirtioBlock {
         let mut total_written = 0u32;
         
         let mut sector = 0u64;
-        let mut is_write = false;
-        let mut data_addr = 0u64;
-        let mut data_len = 0u32;
+        let mut req_type = VIRTIO_BLK_T_IN;
+        // (guest address, length) of each data descriptor, in order
+        let mut segments: Vec<(u64, u32)> = Vec::new();
         let mut status_addr = 0u64;
         let mut phase = 0; 
 
-        loop {
-            let desc_offset = desc_table as usize + (next_idx as usize * 16);
-            let desc_bytes = match mem.read_slice(desc_offset, 16) {
-                Ok(b) => b,
-                Err(_) => break,
+        // A looping chain can't be longer than the largest queue
+        for _ in 0..QUEUE_NUM_MAX {
+            let Some(desc_bytes) = ring_slot(desc_table, next_idx as u64 * 16).and_then(|at| mem.read_slice(at, 16).ok()) else {
+                break;
             };
             
             let addr = u64::from_le_bytes(desc_bytes[0..8].try_into().unwrap());
@@ -306,9 +560,8 @@ impl VirtioBlock {
                     
                     if let Ok(header) = mem.read_slice(addr as usize, 16.min(len as usize)) {
                         if header.len() >= 16 {
-                            let type_ = u32::from_le_bytes(header[0..4].try_into().unwrap());
+                            req_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
                             sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
-                            is_write = type_ == VIRTIO_BLK_T_OUT;
                         }
                     }
                     phase = 1;
@@ -316,8 +569,9 @@ impl VirtioBlock {
                 1 => {
                     if (flags & VRING_DESC_F_NEXT) != 0 {
                         
-                        data_addr = addr;
-                        data_len = len;
+                        if len > 0 {
+                            segments.push((addr, len));
+                        }
                     } else {
                         
                         status_addr = addr;
@@ -334,36 +588,344 @@ impl VirtioBlock {
         }
 
         
-        if data_addr != 0 && data_len > 0 {
-            let offset = sector * 512;
-            let mut disk = self.disk.lock().unwrap();
-            
-            if let Some(ref mut file) = *disk {
-                if file.seek(SeekFrom::Start(offset)).is_ok() {
-                    if is_write {
-                        if let Ok(data) = mem.read_slice(data_addr as usize, data_len as usize) {
-                            let _ = file.write_all(data);
-                        }
-                    } else {
-                        let mut buf = vec![0u8; data_len as usize];
-                        let bytes_read = file.read(&mut buf).unwrap_or(0);
-                        if bytes_read > 0 {
-                            let _ = mem.write_slice(data_addr as usize, &buf[..bytes_read]);
-                            total_written += bytes_read as u32;
-                        }
+        let mut status = VIRTIO_BLK_S_OK;
+        if req_type == VIRTIO_BLK_T_FLUSH {
+            if let Err(e) = self.flush() {
+                tracing::warn!(error = %e, "VirtIO block flush failed");
+                status = VIRTIO_BLK_S_IOERR;
+            }
+        } else if let Some(&(id_addr, id_len)) = segments.first() {
+            let result = match req_type {
+                VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+                    self.do_io(mem, sector, req_type == VIRTIO_BLK_T_OUT, &segments)
+                },
+                VIRTIO_BLK_T_GET_ID => {
+                    let n = VIRTIO_BLK_ID_BYTES.min(id_len as usize);
+                    mem.write_slice(id_addr as usize, &self.serial[..n]).map(|_| n as u32)
+                },
+                VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                    let zero = req_type == VIRTIO_BLK_T_WRITE_ZEROES;
+                    let known_flags = if zero { VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP } else { 0 };
+                    match discard_segments(mem, &segments) {
+                        Ok(ranges) if ranges.iter().any(|r| r.flags & !known_flags != 0) => {
+                            status = VIRTIO_BLK_S_UNSUPP;
+                            Ok(0)
+                        },
+                        Ok(ranges) => self.do_discard(zero, &ranges).map(|_| 0),
+                        Err(e) => Err(e),
                     }
+                },
+                _ => {
+                    tracing::debug!(req_type = req_type, "Unsupported VirtIO block request");
+                    status = VIRTIO_BLK_S_UNSUPP;
+                    Ok(0)
+                }
+            };
+
+            match result {
+                Ok(bytes) => total_written += bytes,
+                Err(e) => {
+                    let len: u32 = segments.iter().map(|&(_, len)| len).sum();
+                    tracing::warn!(sector = sector, len = len, segments = segments.len(), req_type = req_type, error = %e,
+                        "VirtIO block I/O failed");
+                    status = VIRTIO_BLK_S_IOERR;
                 }
             }
         }
 
         
         if status_addr != 0 {
-            let _ = mem.write_u8(status_addr as usize, VIRTIO_BLK_S_OK);
+            let _ = mem.write_u8(status_addr as usize, status);
             total_written += 1;
         }
 
         total_written
     }
+
+    /// Write back any cached sectors and sync the disk image.
+    fn flush(&self) -> Result<(), String> {
+        let mut disk = self.disk.lock_or_err().map_err(|e| e.to_string())?;
+        let file = disk.as_mut().ok_or("no disk image attached")?;
+        match self.cache.lock_or_err().map_err(|e| e.to_string())?.as_mut() {
+            Some(cache) => cache.flush(file),
+            None => file.sync_data().map_err(|e| format!("sync failed: {}", e)),
+        }
+    }
+
+    /// Write back the cache and `fsync` the image, e.g. before exiting.
+    pub fn sync(&self) -> Result<(), String> {
+        let mut disk = self.disk.lock_or_err().map_err(|e| e.to_string())?;
+        let Some(file) = disk.as_mut() else { return Ok(()) };
+        if let Some(cache) = self.cache.lock_or_err().map_err(|e| e.to_string())?.as_mut() {
+            cache.flush(file)?;
+        }
+        file.sync_all().map_err(|e| format!("sync failed: {}", e))
+    }
+
+    // Punches holes for DISCARD, or zeroes each range for WRITE_ZEROES
+    // (punching when the guest allows unmapping). Discard is only a hint, so
+    // a filesystem without hole punching leaves the data in place; zeroing
+    // falls back to writing zeros.
+    fn do_discard(&self, zero: bool, ranges: &[DiscardSegment]) -> Result<(), String> {
+        let mut disk = self.disk.lock_or_err().map_err(|e| e.to_string())?;
+        let file = disk.as_mut().ok_or("no disk image attached")?;
+        let capacity = self.disk_size / SECTOR_SIZE as u64;
+        for r in ranges {
+            if r.count > BLK_MAX_DISCARD_SECTORS || r.sector.checked_add(r.count as u64).is_none_or(|end| end > capacity) {
+                return Err(format!("{} sectors at {} exceed the disk or the segment limit", r.count, r.sector));
+            }
+        }
+
+        let mut cache = self.cache.lock_or_err().map_err(|e| e.to_string())?;
+        for r in ranges {
+            if let Some(cache) = cache.as_mut() {
+                cache.discard(r.sector, r.count as u64);
+            }
+            let (offset, len) = (r.sector * SECTOR_SIZE as u64, r.count as u64 * SECTOR_SIZE as u64);
+            let unmap = !zero || r.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+            let mode = if unmap { libc::FALLOC_FL_PUNCH_HOLE } else { libc::FALLOC_FL_ZERO_RANGE };
+            match fallocate(file, mode | libc::FALLOC_FL_KEEP_SIZE, offset, len) {
+                Ok(()) => {},
+                Err(e) if !zero => tracing::debug!(sector = r.sector, error = %e, "Discard not supported by the image"),
+                Err(_) => self.write_zeroes(file, offset, len)?,
+            }
+        }
+        if self.cache_mode == DiskCache::Writethrough {
+            file.sync_data().map_err(|e| format!("sync failed: {}", e))?;
+        }
+        Ok(())
+    }
+
+    fn write_zeroes(&self, file: &mut File, offset: u64, len: u64) -> Result<(), String> {
+        let zeros = vec![0u8; len.min(BLK_SIZE_MAX as u64) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = &zeros[..(len - done).min(zeros.len() as u64) as usize];
+            if self.direct {
+                direct_io::write_at(file, offset + done, chunk)?;
+            } else {
+                file.seek(SeekFrom::Start(offset + done))
+                    .map_err(|e| format!("seek failed: {}", e))?;
+                file.write_all(chunk).map_err(|e| format!("write failed: {}", e))?;
+            }
+            done += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    // Transfers the segments back to back from `sector` on, so each lands at
+    // the disk offset where the previous one ended. Returns the number of
+    // bytes written into guest memory.
+    fn do_io(&self, mem: &mut GuestMemory, sector: u64, is_write: bool, segments: &[(u64, u32)]) -> Result<u32, String> {
+        // Checked against the advertised limits before anything is allocated
+        if segments.len() > self.seg_max() as usize {
+            return Err(format!("{} segments exceed seg_max {}", segments.len(), self.seg_max()));
+        }
+        if let Some(&(_, len)) = segments.iter().find(|&&(_, len)| len > BLK_SIZE_MAX) {
+            return Err(format!("{} byte segment exceeds size_max {}", len, BLK_SIZE_MAX));
+        }
+        let data_len = segments.iter().try_fold(0u32, |total, &(_, len)| total.checked_add(len))
+            .ok_or("request length overflows")?;
+        // Also keeps out-of-range writes from reaching the writeback cache
+        let offset = sector.checked_mul(SECTOR_SIZE as u64)
+            .filter(|offset| offset.checked_add(data_len as u64).is_some_and(|end| end <= self.disk_size))
+            .ok_or_else(|| format!("{} bytes at sector {} exceed the disk", data_len, sector))?;
+        let mut disk = self.disk.lock_or_err().map_err(|e| e.to_string())?;
+        let file = disk.as_mut().ok_or("no disk image attached")?;
+        let mut cache = self.cache.lock_or_err().map_err(|e| e.to_string())?;
+
+        if is_write {
+            let mut data = Vec::with_capacity(data_len as usize);
+            for &(addr, len) in segments {
+                data.extend_from_slice(mem.read_slice(addr as usize, len as usize)?);
+            }
+            let data = &data[..];
+            if let Some(cache) = cache.as_mut() {
+                if data_len.is_multiple_of(SECTOR_SIZE) {
+                    cache.insert(sector, data);
+                    if cache.over_limit() {
+                        cache.flush(file)?;
+                    }
+                    return Ok(0);
+                }
+                // A partial sector can't be cached; write back first so it lands on top
+                cache.flush(file)?;
+            }
+
+            if self.direct {
+                direct_io::write_at(file, offset, data)?;
+            } else {
+                file.seek(SeekFrom::Start(offset))
+                    .map_err(|e| format!("seek failed: {}", e))?;
+                // write_all retries short writes and fails on a zero-length one
+                file.write_all(data).map_err(|e| format!("write failed: {}", e))?;
+            }
+            if self.cache_mode == DiskCache::Writethrough {
+                file.sync_data().map_err(|e| format!("sync failed: {}", e))?;
+            }
+            Ok(0)
+        } else {
+            let mut buf = vec![0u8; data_len as usize];
+            if self.direct {
+                direct_io::read_at(file, offset, &mut buf)?;
+            } else {
+                file.seek(SeekFrom::Start(offset))
+                    .map_err(|e| format!("seek failed: {}", e))?;
+                // read_exact retries short reads and fails at end of file
+                file.read_exact(&mut buf).map_err(|e| format!("read failed: {}", e))?;
+            }
+            if let Some(cache) = cache.as_ref() {
+                cache.overlay(sector, &mut buf);
+            }
+            let mut chunks = &buf[..];
+            for &(addr, len) in segments {
+                let (chunk, rest) = chunks.split_at(len as usize);
+                mem.write_slice(addr as usize, chunk)?;
+                chunks = rest;
+            }
+            Ok(data_len)
+        }
+    }
+}
+
+impl MmioDevice for VirtioBlock {
+    fn read(&self, offset: u64, data: &mut [u8]) -> AxvmResult<()> {
+        mmio::read_registers(offset, data, |reg| Ok(match reg {
+            VIRTIO_MMIO_MAGIC_VALUE => MAGIC_VALUE,
+            VIRTIO_MMIO_VERSION => VERSION,
+            VIRTIO_MMIO_DEVICE_ID => DEVICE_ID_BLOCK,
+            VIRTIO_MMIO_VENDOR_ID => VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                let sel = *self.features_sel.lock_or_err()?;
+                if sel == 0 {
+                    self.features() as u32
+                } else {
+                    (self.features() >> 32) as u32
+                }
+            },
+            VIRTIO_MMIO_QUEUE_NUM_MAX => self.queue_num_max as u32,
+            VIRTIO_MMIO_QUEUE_READY => *self.queue_ready.lock_or_err()?,
+            VIRTIO_MMIO_INTERRUPT_STATUS => *self.interrupt_status.lock_or_err()?,
+            VIRTIO_MMIO_STATUS => *self.status.lock_or_err()?,
+            reg if reg >= VIRTIO_MMIO_CONFIG => {
+                let start = (reg - VIRTIO_MMIO_CONFIG) as usize;
+                let config = self.config_space();
+                let word = config.get(start..start + 4).unwrap_or(&[0; 4]);
+                u32::from_le_bytes(word.try_into().unwrap())
+            },
+            _ => 0,
+        }))
+    }
+
+    fn write(&self, offset: u64, data: &[u8], mem: &mut GuestMemory) -> AxvmResult<()> {
+        self.registers.write(offset, data, |reg, val| {
+            match reg {
+                VIRTIO_MMIO_DEVICE_FEATURES_SEL => *self.features_sel.lock_or_err()? = val,
+                VIRTIO_MMIO_DRIVER_FEATURES_SEL => *self.features_sel.lock_or_err()? = val,
+                VIRTIO_MMIO_DRIVER_FEATURES => {
+                    let sel = *self.features_sel.lock_or_err()?;
+                    let mut feat = self.driver_features.lock_or_err()?;
+                    if sel == 0 { *feat = (*feat & !0xFFFFFFFF) | val as u64; }
+                    else { *feat = (*feat & 0xFFFFFFFF) | ((val as u64) << 32); }
+                },
+                VIRTIO_MMIO_QUEUE_SEL => *self.queue_sel.lock_or_err()? = val,
+                VIRTIO_MMIO_QUEUE_NUM => {
+                    // Same rules as VirtQueue::set_size: a ready or rejected
+                    // write leaves the previous size in place
+                    if *self.queue_ready.lock_or_err()? != 0 {
+                        tracing::warn!(size = val, "VirtIO-Blk: ignoring queue size written while the queue is ready");
+                    } else {
+                        match check_queue_size(val, self.queue_num_max) {
+                            Ok(size) => *self.queue_num.lock_or_err()? = size as u32,
+                            Err(e) => tracing::warn!(error = %e, "VirtIO-Blk: rejecting queue size"),
+                        }
+                    }
+                },
+                VIRTIO_MMIO_QUEUE_READY => {
+                    let mut ready = val & 1;
+                    if ready == 1 {
+                        let check = validate_rings(
+                            *self.queue_desc.lock_or_err()?,
+                            *self.queue_avail.lock_or_err()?,
+                            *self.queue_used.lock_or_err()?,
+                            *self.queue_num.lock_or_err()? as u16,
+                            mem.ram(),
+                        );
+                        if let Err(e) = check {
+                            tracing::warn!(error = %e, "VirtIO-Blk: refusing to enable queue");
+                            ready = 0;
+                        }
+                    }
+                    *self.queue_ready.lock_or_err()? = ready;
+                },
+                VIRTIO_MMIO_QUEUE_NOTIFY => {
+                    self.process_queue(mem)?;
+                },
+                VIRTIO_MMIO_INTERRUPT_ACK => *self.interrupt_status.lock_or_err()? &= !val,
+                VIRTIO_MMIO_STATUS => {
+                    let old = *self.status.lock_or_err()?;
+                    let accepted = *self.driver_features.lock_or_err()?;
+                    *self.status.lock_or_err()? = latch_status("VirtIO-Blk", old, val, self.features(), accepted, self.metrics.as_deref());
+                    if val == 0 && old != 0 { 
+                        *self.queue_ready.lock_or_err()? = 0;
+                        *self.last_avail_idx.lock_or_err()? = 0;
+                        *self.signalled_used.lock_or_err()? = 0;
+                        *self.interrupt_status.lock_or_err()? = 0;
+                    }
+                },
+                VIRTIO_MMIO_QUEUE_DESC_LOW => self.set_ring_addr(&self.queue_desc, false, val)?,
+                VIRTIO_MMIO_QUEUE_DESC_HIGH => self.set_ring_addr(&self.queue_desc, true, val)?,
+                VIRTIO_MMIO_QUEUE_AVAIL_LOW => self.set_ring_addr(&self.queue_avail, false, val)?,
+                VIRTIO_MMIO_QUEUE_AVAIL_HIGH => self.set_ring_addr(&self.queue_avail, true, val)?,
+                VIRTIO_MMIO_QUEUE_USED_LOW => self.set_ring_addr(&self.queue_used, false, val)?,
+                VIRTIO_MMIO_QUEUE_USED_HIGH => self.set_ring_addr(&self.queue_used, true, val)?,
+                _ => {}
+            }
+            Ok(())
+        })
+    }
+
+    fn interrupt_level(&self) -> AxvmResult<bool> {
+        Ok(*self.interrupt_status.lock_or_err()? != 0)
+    }
+}
+
+struct DiscardSegment {
+    sector: u64,
+    count: u32,
+    flags: u32,
+}
+
+// Parse the `virtio_blk_discard_write_zeroes` array spread over the data descriptors.
+fn discard_segments(mem: &GuestMemory, segments: &[(u64, u32)]) -> Result<Vec<DiscardSegment>, String> {
+    let mut data = Vec::new();
+    for &(addr, len) in segments {
+        data.extend_from_slice(mem.read_slice(addr as usize, len as usize)?);
+    }
+    if !data.len().is_multiple_of(DISCARD_SEGMENT_LEN) || data.len() / DISCARD_SEGMENT_LEN > BLK_MAX_DISCARD_SEG as usize {
+        return Err(format!("bad discard segment list of {} bytes", data.len()));
+    }
+    Ok(data.chunks_exact(DISCARD_SEGMENT_LEN).map(|seg| DiscardSegment {
+        sector: u64::from_le_bytes(seg[0..8].try_into().unwrap()),
+        count: u32::from_le_bytes(seg[8..12].try_into().unwrap()),
+        flags: u32::from_le_bytes(seg[12..16].try_into().unwrap()),
+    }).collect())
+}
+
+fn fallocate(file: &File, mode: i32, offset: u64, len: u64) -> std::io::Result<()> {
+    // SAFETY: fallocate only acts on the open file descriptor
+    match unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+// Zero-padded; the spec does not require NUL termination when all 20 bytes are used.
+fn serial_bytes(serial: &str) -> [u8; VIRTIO_BLK_ID_BYTES] {
+    let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+    let n = serial.len().min(VIRTIO_BLK_ID_BYTES);
+    id[..n].copy_from_slice(&serial.as_bytes()[..n]);
+    id
 }
 
 impl Default for VirtioBlock {
@@ -371,3 +933,621 @@ impl Default for VirtioBlock {
         Self::new(None)
     }
 }
+
+impl Drop for VirtioBlock {
+    fn drop(&mut self) {
+        let dirty = self.cache.get_mut().ok()
+            .and_then(|c| c.as_ref())
+            .is_some_and(|c| !c.is_empty());
+        if dirty {
+            if let Err(e) = self.flush() {
+                status!(error, "VirtIO", error = %e, "Failed to write back disk cache: {}", e);
+            }
+        }
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    const REQ_HDR: u64 = 0x4000;
+    const DATA_BUF: u64 = 0x5000;
+    const STATUS_BYTE: u64 = 0x6000;
+
+    fn write_desc(mem: &mut GuestMemory, idx: u64, addr: u64, len: u32, flags: u16, next: u16) {
+        let base = (DESC_TABLE + idx * 16) as usize;
+        mem.write_u64(base, addr).unwrap();
+        mem.write_u32(base + 8, len).unwrap();
+        mem.write_u16(base + 12, flags).unwrap();
+        mem.write_u16(base + 14, next).unwrap();
+    }
+
+    // Make a single header/data/status request available without running the queue.
+    fn queue_request(blk: &VirtioBlock, mem: &mut GuestMemory, type_: u32, sector: u64) {
+        *blk.queue_num.lock().unwrap() = 16;
+        *blk.queue_ready.lock().unwrap() = 1;
+        *blk.queue_desc.lock().unwrap() = DESC_TABLE;
+        *blk.queue_avail.lock().unwrap() = AVAIL_RING;
+        *blk.queue_used.lock().unwrap() = USED_RING;
+
+        mem.write_u32(REQ_HDR as usize, type_).unwrap();
+        mem.write_u64(REQ_HDR as usize + 8, sector).unwrap();
+        mem.write_u8(STATUS_BYTE as usize, 0xFF).unwrap();
+
+        let device_reads = matches!(type_, VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES);
+        let data_flags = if device_reads { 0 } else { VRING_DESC_F_WRITE };
+        write_desc(mem, 0, REQ_HDR, 16, VRING_DESC_F_NEXT, 1);
+        write_desc(mem, 1, DATA_BUF, SECTOR_SIZE, data_flags | VRING_DESC_F_NEXT, 2);
+        write_desc(mem, 2, STATUS_BYTE, 1, VRING_DESC_F_WRITE, 0);
+
+        let avail_idx = mem.read_slice(AVAIL_RING as usize + 2, 2).map(|b| u16::from_le_bytes([b[0], b[1]])).unwrap();
+        mem.write_u16(AVAIL_RING as usize + 4 + (avail_idx % 16) as usize * 2, 0).unwrap();
+        mem.write_u16(AVAIL_RING as usize + 2, avail_idx.wrapping_add(1)).unwrap();
+    }
+
+    // Queue a single header/data/status request and run the queue.
+    fn submit(blk: &VirtioBlock, mem: &mut GuestMemory, type_: u32, sector: u64) -> u8 {
+        queue_request(blk, mem, type_, sector);
+        assert!(blk.process_queue(mem).unwrap());
+        mem.read_slice(STATUS_BYTE as usize, 1).unwrap()[0]
+    }
+
+    // Submit a DISCARD or WRITE_ZEROES request for (sector, count, flags) segments.
+    fn submit_ranges(blk: &VirtioBlock, mem: &mut GuestMemory, type_: u32, ranges: &[(u64, u32, u32)]) -> u8 {
+        queue_request(blk, mem, type_, 0);
+        let list: Vec<u8> = ranges.iter().flat_map(|&(sector, count, flags)| {
+            [&sector.to_le_bytes()[..], &count.to_le_bytes(), &flags.to_le_bytes()].concat()
+        }).collect();
+        mem.write_slice(DATA_BUF as usize, &list).unwrap();
+        write_desc(mem, 1, DATA_BUF, list.len() as u32, VRING_DESC_F_NEXT, 2);
+        assert!(blk.process_queue(mem).unwrap());
+        mem.read_slice(STATUS_BYTE as usize, 1).unwrap()[0]
+    }
+
+    fn temp_disk(name: &str, sectors: usize) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("axvm-test-{}-{}.img", name, std::process::id()));
+        std::fs::write(&path, vec![0xABu8; sectors * SECTOR_SIZE as usize]).unwrap();
+        path
+    }
+
+    fn write_reg(blk: &VirtioBlock, mem: &mut GuestMemory, offset: u64, val: u32) {
+        blk.write(offset, &val.to_le_bytes(), mem).unwrap();
+    }
+
+    #[test]
+    fn test_validate_rings() {
+        let ram = 0..0x10000;
+        assert!(validate_rings(DESC_TABLE, AVAIL_RING, USED_RING, 16, ram.clone()).is_ok());
+
+        let err = validate_rings(DESC_TABLE, AVAIL_RING, USED_RING, 0, ram.clone()).unwrap_err();
+        assert!(err.contains("zero"));
+
+        let err = validate_rings(DESC_TABLE + 8, AVAIL_RING, USED_RING, 16, ram.clone()).unwrap_err();
+        assert!(err.contains("descriptor table") && err.contains("aligned"));
+
+        let err = validate_rings(DESC_TABLE, AVAIL_RING, 0xFFF0, 16, ram.clone()).unwrap_err();
+        assert!(err.contains("used ring") && err.contains("exceeds"));
+
+        let err = validate_rings(DESC_TABLE, u64::MAX - 15, USED_RING, 16, ram).unwrap_err();
+        assert!(err.contains("available ring"));
+
+        // Rings below the start of RAM are as unusable as rings past its end
+        let err = validate_rings(DESC_TABLE, AVAIL_RING, USED_RING, 16, 0x1800..0x10000).unwrap_err();
+        assert!(err.contains("descriptor table") && err.contains("below"));
+    }
+
+    #[test]
+    fn test_driver_ok_checks_negotiated_features() {
+        let blk = VirtioBlock::new(None);
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+        let driver_ok = 1 | 2 | 8 | VIRTIO_STATUS_DRIVER_OK;
+        let read_status = |blk: &VirtioBlock| {
+            let mut data = [0u8; 4];
+            blk.read(VIRTIO_MMIO_STATUS, &mut data).unwrap();
+            u32::from_le_bytes(data)
+        };
+
+        // Legacy driver: VERSION_1 not accepted
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_BLK_F_BLK_SIZE as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_STATUS, driver_ok);
+        assert_eq!(read_status(&blk), driver_ok | VIRTIO_STATUS_FAILED);
+
+        // FLUSH is only offered in write-back mode
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_STATUS, 0);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_BLK_F_FLUSH as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_DRIVER_FEATURES, (VIRTIO_F_VERSION_1 >> 32) as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_STATUS, driver_ok);
+        assert_eq!(read_status(&blk), driver_ok | VIRTIO_STATUS_FAILED);
+
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_STATUS, 0);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 0);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_BLK_F_BLK_SIZE as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_STATUS, driver_ok);
+        assert_eq!(read_status(&blk), driver_ok);
+    }
+
+    #[test]
+    fn test_failed_status_counts_once_per_failure() {
+        let metrics = Arc::new(VmMetrics::new());
+        let blk = VirtioBlock::new(None).with_metrics(Arc::clone(&metrics));
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        // The driver gives up; rewriting the same status is not a new failure
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_STATUS, 1 | 2 | VIRTIO_STATUS_FAILED);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_STATUS, 1 | 2 | VIRTIO_STATUS_FAILED);
+        assert_eq!(metrics.errors(), 1);
+
+        // After a reset, negotiation failing on DRIVER_OK counts too
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_STATUS, 0);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_STATUS, 1 | 2 | 8 | VIRTIO_STATUS_DRIVER_OK);
+        assert_eq!(metrics.errors(), 2);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_STATUS, 0);
+        assert_eq!(metrics.errors(), 2);
+    }
+
+    #[test]
+    fn test_event_idx_suppresses_interrupts() {
+        assert!(vring_need_event(0, 1, 0));
+        assert!(!vring_need_event(5, 3, 1));
+        assert!(vring_need_event(0xFFFF, 1, 0xFFFE));
+
+        let path = temp_disk("event-idx", 4);
+        let blk = VirtioBlock::new(path.to_str());
+        *blk.driver_features.lock().unwrap() = VIRTIO_RING_F_EVENT_IDX;
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+        let used_event = AVAIL_RING as usize + 4 + 16 * 2;
+        let avail_event = USED_RING as usize + 4 + 16 * 8;
+
+        // used_event = 0: the first completion crosses it
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_IN, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(mem.read_slice(avail_event, 2).unwrap(), &1u16.to_le_bytes());
+
+        // Guest only wants to hear about used index 3 onwards
+        *blk.interrupt_status.lock().unwrap() = 0;
+        mem.write_u16(used_event, 2).unwrap();
+        queue_request(&blk, &mut mem, VIRTIO_BLK_T_IN, 0);
+        assert!(!blk.process_queue(&mut mem).unwrap());
+        assert_eq!(*blk.interrupt_status.lock().unwrap(), 0);
+        queue_request(&blk, &mut mem, VIRTIO_BLK_T_IN, 0);
+        assert!(blk.process_queue(&mut mem).unwrap());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_interrupt_level_held_until_ack() {
+        let path = temp_disk("level", 4);
+        let blk = VirtioBlock::new(path.to_str());
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        assert!(!blk.interrupt_level().unwrap());
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_IN, 0), VIRTIO_BLK_S_OK);
+        assert!(blk.interrupt_level().unwrap());
+        // Still asserted after a second completion, until the guest acknowledges it
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_IN, 1), VIRTIO_BLK_S_OK);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_INTERRUPT_ACK, 1);
+        assert!(!blk.interrupt_level().unwrap());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_irq_line_follows_interrupt_status() {
+        use crate::idle::IdleWaker;
+        use crate::irq::{IrqManager, IrqSource};
+        use crate::mmio::{MmioBus, MmioWrite};
+
+        let path = temp_disk("irq-line", 4);
+        let blk = Arc::new(VirtioBlock::new(path.to_str()));
+        let mut bus = MmioBus::new();
+        bus.register(0xFEB00000, 0x1000, 5, Arc::clone(&blk) as Arc<dyn MmioDevice>).unwrap();
+        let vm = kvm_ioctls::Kvm::new().unwrap().create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        let metrics = Arc::new(VmMetrics::new());
+        let irq = IrqManager::new(Arc::new(Mutex::new(vm)), Arc::new(IdleWaker::new()), Arc::clone(&metrics))
+            .with_source(5, IrqSource::Block);
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        // What run_vcpu does for each MMIO write
+        let write = |offset: u64, val: u32, mem: &mut GuestMemory| {
+            let outcome = bus.dispatch_write(0xFEB00000 + offset, &val.to_le_bytes(), mem).unwrap();
+            assert_eq!(outcome, MmioWrite::Handled(5));
+            irq.set_level(5, || bus.interrupt_level(5));
+        };
+
+        queue_request(&blk, &mut mem, VIRTIO_BLK_T_IN, 0);
+        write(VIRTIO_MMIO_QUEUE_NOTIFY, 0, &mut mem);
+        assert_eq!(metrics.blk_irqs(), 1);
+        // A completion before the ACK keeps the line high: no second edge
+        queue_request(&blk, &mut mem, VIRTIO_BLK_T_IN, 1);
+        write(VIRTIO_MMIO_QUEUE_NOTIFY, 0, &mut mem);
+        assert_eq!(metrics.blk_irqs(), 1);
+
+        // The ACK lowers it, so the next completion raises it again
+        write(VIRTIO_MMIO_INTERRUPT_ACK, 1, &mut mem);
+        assert!(!bus.interrupt_level(5).unwrap());
+        queue_request(&blk, &mut mem, VIRTIO_BLK_T_IN, 2);
+        write(VIRTIO_MMIO_QUEUE_NOTIFY, 0, &mut mem);
+        assert_eq!(metrics.blk_irqs(), 2);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_out_of_range_queue_not_ready() {
+        let blk = VirtioBlock::new(None);
+        let mut mem = GuestMemory::new(0x10000).unwrap();
+
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_NUM, 16);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_DESC_LOW, DESC_TABLE as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_AVAIL_LOW, AVAIL_RING as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_USED_HIGH, 1);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_READY, 1);
+        assert_eq!(*blk.queue_ready.lock().unwrap(), 0);
+
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_USED_HIGH, 0);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_USED_LOW, USED_RING as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_READY, 1);
+        assert_eq!(*blk.queue_ready.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_ring_address_fixed_while_ready() {
+        let blk = VirtioBlock::new(None);
+        let mut mem = GuestMemory::new(0x10000).unwrap();
+
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_NUM, 16);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_DESC_LOW, DESC_TABLE as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_AVAIL_LOW, AVAIL_RING as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_USED_LOW, USED_RING as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_READY, 1);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_AVAIL_HIGH, 0xFFFF_FFFF);
+        assert_eq!(*blk.queue_avail.lock().unwrap(), AVAIL_RING);
+
+        // A ring that ends past the top of the address space is treated as unreadable
+        *blk.queue_avail.lock().unwrap() = u64::MAX - 1;
+        assert!(!blk.process_queue(&mut mem).unwrap());
+    }
+
+    #[test]
+    fn test_queue_size_validation() {
+        assert_eq!(check_queue_size(256, QUEUE_NUM_MAX), Ok(256));
+        assert_eq!(check_queue_size(1, QUEUE_NUM_MAX), Ok(1));
+        assert!(check_queue_size(0, QUEUE_NUM_MAX).is_err());
+        assert!(check_queue_size(100, QUEUE_NUM_MAX).is_err());
+        assert!(check_queue_size(512, QUEUE_NUM_MAX).is_err());
+
+        let blk = VirtioBlock::new(None).with_queue_size(64);
+        let mut mem = GuestMemory::new(0x10000).unwrap();
+        let mut max = [0u8; 4];
+        blk.read(VIRTIO_MMIO_QUEUE_NUM_MAX, &mut max).unwrap();
+        assert_eq!(u32::from_le_bytes(max), 64);
+        assert_eq!(blk.config_value(BlkConfigField::SegMax), 62);
+
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_NUM, 32);
+        assert_eq!(*blk.queue_num.lock().unwrap(), 32);
+        // Rejected sizes keep the old one
+        for bad in [128, 48] {
+            write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_NUM, bad);
+            assert_eq!(*blk.queue_num.lock().unwrap(), 32);
+        }
+
+        // And a ready queue ignores even a valid size
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_DESC_LOW, DESC_TABLE as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_AVAIL_LOW, AVAIL_RING as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_USED_LOW, USED_RING as u32);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_READY, 1);
+        assert_eq!(*blk.queue_ready.lock().unwrap(), 1);
+        write_reg(&blk, &mut mem, VIRTIO_MMIO_QUEUE_NUM, 16);
+        assert_eq!(*blk.queue_num.lock().unwrap(), 32);
+    }
+
+    #[test]
+    fn test_partial_width_register_access() {
+        let blk = VirtioBlock::new(None);
+        let mut mem = GuestMemory::new(0x10000).unwrap();
+
+        for (i, byte) in DESC_TABLE.to_le_bytes()[..4].iter().enumerate() {
+            blk.write(VIRTIO_MMIO_QUEUE_DESC_LOW + i as u64, &[*byte], &mut mem).unwrap();
+        }
+        blk.write(VIRTIO_MMIO_QUEUE_NUM, &16u16.to_le_bytes(), &mut mem).unwrap();
+        blk.write(VIRTIO_MMIO_QUEUE_NUM + 2, &0u16.to_le_bytes(), &mut mem).unwrap();
+        assert_eq!(*blk.queue_desc.lock().unwrap(), DESC_TABLE);
+        assert_eq!(*blk.queue_num.lock().unwrap(), 16);
+
+        let mut half = [0u8; 2];
+        blk.read(VIRTIO_MMIO_MAGIC_VALUE + 2, &mut half).unwrap();
+        assert_eq!(&half, b"rt");
+        let mut byte = [0u8; 1];
+        blk.read(VIRTIO_MMIO_DEVICE_ID, &mut byte).unwrap();
+        assert_eq!(byte, [DEVICE_ID_BLOCK as u8]);
+        blk.read(VIRTIO_MMIO_CONFIG + 0x12, &mut byte).unwrap();
+        assert_eq!(byte, [GEOMETRY_HEADS]);
+    }
+
+    #[test]
+    fn test_config_space() {
+        let mut blk = VirtioBlock::new(None);
+        blk.disk_size = 100 * 1024 * 1024;
+
+        let read = |offset: u64, len: usize| {
+            let mut data = [0u8; 8];
+            blk.read(VIRTIO_MMIO_CONFIG + offset, &mut data[..len]).unwrap();
+            u64::from_le_bytes(data)
+        };
+        assert_eq!(read(0x00, 8), 204800);
+        assert_eq!(read(0x08, 4), BLK_SIZE_MAX as u64);
+        assert_eq!(read(0x0C, 4), QUEUE_NUM_MAX as u64 - 2);
+        assert_eq!(read(0x10, 2), 204800 / (16 * 63));
+        assert_eq!(read(0x12, 1), 16);
+        assert_eq!(read(0x13, 1), 63);
+        assert_eq!(read(0x14, 4), 512);
+        assert_eq!(read(0x18, 8), 0);
+    }
+
+    #[test]
+    fn test_config_layout_matches_struct() {
+        use std::mem::{offset_of, size_of};
+
+        // struct virtio_blk_config from the virtio spec, up to write zeroes
+        #[repr(C, packed)]
+        struct Config {
+            capacity: u64,
+            size_max: u32,
+            seg_max: u32,
+            cylinders: u16,
+            heads: u8,
+            sectors: u8,
+            blk_size: u32,
+            physical_block_exp: u8,
+            alignment_offset: u8,
+            min_io_size: u16,
+            opt_io_size: u32,
+            writeback: u8,
+            _unused0: u8,
+            num_queues: u16,
+            max_discard_sectors: u32,
+            max_discard_seg: u32,
+            discard_sector_alignment: u32,
+            max_write_zeroes_sectors: u32,
+            max_write_zeroes_seg: u32,
+            write_zeroes_may_unmap: u8,
+            _unused1: [u8; 3],
+        }
+        let expected = [
+            (offset_of!(Config, capacity), 8),
+            (offset_of!(Config, size_max), 4),
+            (offset_of!(Config, seg_max), 4),
+            (offset_of!(Config, cylinders), 2),
+            (offset_of!(Config, heads), 1),
+            (offset_of!(Config, sectors), 1),
+            (offset_of!(Config, blk_size), 4),
+            (offset_of!(Config, physical_block_exp), 1),
+            (offset_of!(Config, alignment_offset), 1),
+            (offset_of!(Config, min_io_size), 2),
+            (offset_of!(Config, opt_io_size), 4),
+            (offset_of!(Config, writeback), 1),
+            (offset_of!(Config, num_queues), 2),
+            (offset_of!(Config, max_discard_sectors), 4),
+            (offset_of!(Config, max_discard_seg), 4),
+            (offset_of!(Config, discard_sector_alignment), 4),
+            (offset_of!(Config, max_write_zeroes_sectors), 4),
+            (offset_of!(Config, max_write_zeroes_seg), 4),
+            (offset_of!(Config, write_zeroes_may_unmap), 1),
+        ];
+        let layout: Vec<_> = BLK_CONFIG_LAYOUT.iter().map(|&(_, offset, width, _)| (offset, width)).collect();
+        assert_eq!(layout, expected);
+        assert_eq!(size_of::<Config>(), BLK_CONFIG_LEN);
+
+        // Every advertised field is served non-zero; nothing else is
+        let mut blk = VirtioBlock::new(None);
+        blk.disk_size = 100 * 1024 * 1024;
+        let config = blk.config_space();
+        for (field, offset, width, feature) in BLK_CONFIG_LAYOUT {
+            let served = config[offset..offset + width].iter().any(|&b| b != 0);
+            let offered = feature == 0 || blk.features() & feature != 0;
+            assert_eq!(served, offered, "{:?}", field);
+        }
+    }
+
+    #[test]
+    fn test_read_ok() {
+        let path = temp_disk("read-ok", 4);
+        let blk = VirtioBlock::new(path.to_str());
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_IN, 1), VIRTIO_BLK_S_OK);
+        assert!(mem.read_slice(DATA_BUF as usize, SECTOR_SIZE as usize).unwrap().iter().all(|&b| b == 0xAB));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_multi_segment_write() {
+        let path = temp_disk("multi-seg", 8);
+        let blk = VirtioBlock::new(path.to_str());
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+        queue_request(&blk, &mut mem, VIRTIO_BLK_T_OUT, 1);
+
+        // Header, three data segments of 512, 1024 and 512 bytes, status
+        let segments = [(DATA_BUF, 512, 0x11), (DATA_BUF + 0x1000, 1024, 0x22), (DATA_BUF + 0x800, 512, 0x33)];
+        for (i, &(addr, len, fill)) in segments.iter().enumerate() {
+            mem.write_slice(addr as usize, &vec![fill; len as usize]).unwrap();
+            write_desc(&mut mem, 1 + i as u64, addr, len, VRING_DESC_F_NEXT, 2 + i as u16);
+        }
+        write_desc(&mut mem, 4, STATUS_BYTE, 1, VRING_DESC_F_WRITE, 0);
+
+        assert!(blk.process_queue(&mut mem).unwrap());
+        assert_eq!(mem.read_slice(STATUS_BYTE as usize, 1).unwrap()[0], VIRTIO_BLK_S_OK);
+        let disk = std::fs::read(&path).unwrap();
+        let sector = |n: usize| &disk[n * SECTOR_SIZE as usize..(n + 1) * SECTOR_SIZE as usize];
+        assert!(sector(0).iter().chain(sector(5)).all(|&b| b == 0xAB));
+        assert!(sector(1).iter().all(|&b| b == 0x11));
+        assert!(sector(2).iter().chain(sector(3)).all(|&b| b == 0x22));
+        assert!(sector(4).iter().all(|&b| b == 0x33));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_oversized_request_reports_ioerr() {
+        // Big enough that only the limits can fail the requests
+        let path = temp_disk("oversized", (BLK_SIZE_MAX / SECTOR_SIZE) as usize + 1);
+        let blk = VirtioBlock::new(path.to_str()).with_queue_size(16);
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        // One segment past size_max
+        queue_request(&blk, &mut mem, VIRTIO_BLK_T_IN, 0);
+        write_desc(&mut mem, 1, DATA_BUF, BLK_SIZE_MAX + SECTOR_SIZE, VRING_DESC_F_WRITE | VRING_DESC_F_NEXT, 2);
+        write_desc(&mut mem, 2, STATUS_BYTE, 1, VRING_DESC_F_WRITE, 0);
+        assert!(blk.process_queue(&mut mem).unwrap());
+        assert_eq!(mem.read_slice(STATUS_BYTE as usize, 1).unwrap()[0], VIRTIO_BLK_S_IOERR);
+
+        // More segments than seg_max
+        let segments = blk.seg_max() as u16 + 1;
+        queue_request(&blk, &mut mem, VIRTIO_BLK_T_IN, 0);
+        for i in 1..=segments {
+            write_desc(&mut mem, i as u64, DATA_BUF, SECTOR_SIZE, VRING_DESC_F_WRITE | VRING_DESC_F_NEXT, i + 1);
+        }
+        write_desc(&mut mem, segments as u64 + 1, STATUS_BYTE, 1, VRING_DESC_F_WRITE, 0);
+        assert!(blk.process_queue(&mut mem).unwrap());
+        assert_eq!(mem.read_slice(STATUS_BYTE as usize, 1).unwrap()[0], VIRTIO_BLK_S_IOERR);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_get_id() {
+        let blk = VirtioBlock::new(None).with_serial("AXVM-BLK-0042");
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_GET_ID, 0), VIRTIO_BLK_S_OK);
+        let id = mem.read_slice(DATA_BUF as usize, VIRTIO_BLK_ID_BYTES).unwrap();
+        assert_eq!(&id[..13], b"AXVM-BLK-0042");
+        assert!(id[13..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_write_error_reports_ioerr() {
+        let path = temp_disk("write-ro", 4);
+        let blk = VirtioBlock::new(None);
+        *blk.disk.lock().unwrap() = Some(File::open(&path).unwrap());
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_OUT, 0), VIRTIO_BLK_S_IOERR);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_writeback_read_after_write() {
+        let path = temp_disk("writeback", 4);
+        let blk = VirtioBlock::new(path.to_str()).with_cache(DiskCache::Writeback, 1024 * 1024);
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+        assert_ne!(blk.features() & VIRTIO_BLK_F_FLUSH, 0);
+
+        mem.write_slice(DATA_BUF as usize, &[0x5A; SECTOR_SIZE as usize]).unwrap();
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_OUT, 2), VIRTIO_BLK_S_OK);
+        assert_eq!(std::fs::read(&path).unwrap()[2 * SECTOR_SIZE as usize], 0xAB);
+
+        // The read must see the cached write, not the stale sector on disk
+        mem.write_slice(DATA_BUF as usize, &[0; SECTOR_SIZE as usize]).unwrap();
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_IN, 2), VIRTIO_BLK_S_OK);
+        assert_eq!(mem.read_slice(DATA_BUF as usize, 1).unwrap()[0], 0x5A);
+
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_FLUSH, 0), VIRTIO_BLK_S_OK);
+        let disk = std::fs::read(&path).unwrap();
+        assert!(disk[2 * SECTOR_SIZE as usize..3 * SECTOR_SIZE as usize].iter().all(|&b| b == 0x5A));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_out_of_range_sector_reports_ioerr() {
+        let path = temp_disk("out-of-range", 4);
+        let blk = VirtioBlock::new(path.to_str()).with_cache(DiskCache::Writeback, 1024 * 1024);
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_IN, 4), VIRTIO_BLK_S_IOERR);
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_IN, u64::MAX), VIRTIO_BLK_S_IOERR);
+        // Refused before the cache, so the flush has nothing to grow the image with
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_OUT, 4), VIRTIO_BLK_S_IOERR);
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_FLUSH, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * SECTOR_SIZE as u64);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_sync_writes_back_the_cache() {
+        let path = temp_disk("sync", 4);
+        let blk = VirtioBlock::new(path.to_str()).with_cache(DiskCache::Writeback, 1024 * 1024);
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        mem.write_slice(DATA_BUF as usize, &[0x5A; SECTOR_SIZE as usize]).unwrap();
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_OUT, 1), VIRTIO_BLK_S_OK);
+        blk.sync().unwrap();
+        assert!(blk.cache.lock().unwrap().as_ref().unwrap().is_empty());
+        assert_eq!(std::fs::read(&path).unwrap()[SECTOR_SIZE as usize], 0x5A);
+        VirtioBlock::new(None).sync().unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_discard_and_write_zeroes() {
+        let path = temp_disk("discard", 8);
+        let blk = VirtioBlock::new(path.to_str()).with_cache(DiskCache::Writeback, 1024 * 1024);
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+        assert_ne!(blk.features() & VIRTIO_BLK_F_DISCARD, 0);
+
+        // A cached write must not resurface once its sector is zeroed
+        mem.write_slice(DATA_BUF as usize, &[0x5A; SECTOR_SIZE as usize]).unwrap();
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_OUT, 2), VIRTIO_BLK_S_OK);
+
+        let unmap = VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP;
+        assert_eq!(submit_ranges(&blk, &mut mem, VIRTIO_BLK_T_WRITE_ZEROES, &[(1, 2, 0), (6, 1, unmap)]), VIRTIO_BLK_S_OK);
+        assert_eq!(submit_ranges(&blk, &mut mem, VIRTIO_BLK_T_DISCARD, &[(4, 1, 0)]), VIRTIO_BLK_S_OK);
+        assert_eq!(submit_ranges(&blk, &mut mem, VIRTIO_BLK_T_DISCARD, &[(0, 1, unmap)]), VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(submit_ranges(&blk, &mut mem, VIRTIO_BLK_T_WRITE_ZEROES, &[(7, 2, 0)]), VIRTIO_BLK_S_IOERR);
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_FLUSH, 0), VIRTIO_BLK_S_OK);
+
+        let disk = std::fs::read(&path).unwrap();
+        let sector = |n: usize| &disk[n * SECTOR_SIZE as usize..(n + 1) * SECTOR_SIZE as usize];
+        for n in [1, 2, 6] {
+            assert!(sector(n).iter().all(|&b| b == 0), "sector {} not zeroed", n);
+        }
+        for n in [0, 3, 5, 7] {
+            assert!(sector(n).iter().all(|&b| b == 0xAB), "sector {} changed", n);
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_direct_partial_block_write() {
+        // Falls back to buffered I/O where the filesystem lacks O_DIRECT
+        let path = temp_disk("direct", 2 * DIRECT_IO_ALIGN / SECTOR_SIZE as usize);
+        let blk = VirtioBlock::open(path.to_str(), true);
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        mem.write_slice(DATA_BUF as usize, &[0x11; SECTOR_SIZE as usize]).unwrap();
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_OUT, 9), VIRTIO_BLK_S_OK);
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_IN, 9), VIRTIO_BLK_S_OK);
+        assert_eq!(mem.read_slice(DATA_BUF as usize, 1).unwrap()[0], 0x11);
+
+        let disk = std::fs::read(&path).unwrap();
+        let sector = |n: usize| &disk[n * SECTOR_SIZE as usize..(n + 1) * SECTOR_SIZE as usize];
+        assert!(sector(9).iter().all(|&b| b == 0x11));
+        assert!(sector(8).iter().chain(sector(10)).all(|&b| b == 0xAB));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_short_read_reports_ioerr() {
+        let path = temp_disk("short-read", 1);
+        let blk = VirtioBlock::new(path.to_str());
+        let mut mem = GuestMemory::new(2 * 1024 * 1024).unwrap();
+
+        assert_eq!(submit(&blk, &mut mem, VIRTIO_BLK_T_IN, 1), VIRTIO_BLK_S_IOERR);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,7 +1,16 @@
 // src/virtio_net.rs
 use crate::tap::TapInterface;
-use std::sync::Mutex;
+use crate::usernet::UserNet;
+use crate::memory::{guest_slice, guest_slice_mut, GuestMemory};
+use crate::error::{AxvmError, AxvmResult, LockExt};
+use crate::metrics::VmMetrics;
+use crate::mmio::{self, MmioDevice, RegisterLatch};
+use crate::virtio::{check_queue_size, latch_status, vring_need_event, QUEUE_NUM_MAX, VIRTIO_RING_F_EVENT_IDX};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::{Arc, Mutex};
 use std::mem::size_of;
+use std::ops::Range;
 
 // Constantes de Registradores MMIO (Spec v2)
 const MMIO_MAGIC_VALUE: u64 = 0x000;
@@ -16,6 +25,7 @@ const MMIO_QUEUE_SEL: u64 = 0x030;
 const MMIO_QUEUE_NUM_MAX: u64 = 0x034;
 const MMIO_QUEUE_NUM: u64 = 0x038;
 const MMIO_QUEUE_READY: u64 = 0x044;
+const MMIO_QUEUE_NOTIFY: u64 = 0x050;
 const MMIO_INTERRUPT_STATUS: u64 = 0x060;
 const MMIO_INTERRUPT_ACK: u64 = 0x064;
 const MMIO_STATUS: u64 = 0x070;
@@ -29,16 +39,54 @@ const MMIO_CONFIG_SPACE: u64 = 0x100;
 
 // VirtIO Net Feature Bits
 const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_NET_F_CTRL_VQ: u64 = 1 << 17;
+const VIRTIO_NET_F_CTRL_RX: u64 = 1 << 18;
+const VIRTIO_NET_F_MQ: u64 = 1 << 22;
 const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+// No CSUM/GUEST_CSUM or GSO: frames cross the TAP fully checksummed
+const DEVICE_FEATURES: u64 = VIRTIO_NET_F_MAC | VIRTIO_NET_F_MRG_RXBUF | VIRTIO_NET_F_STATUS
+    | VIRTIO_NET_F_CTRL_VQ | VIRTIO_NET_F_CTRL_RX | VIRTIO_NET_F_MQ | VIRTIO_RING_F_EVENT_IDX | VIRTIO_F_VERSION_1;
+
+// virtio_net_hdr flags / gso_type
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+
+// Config space: mac[6], status (u16), max_virtqueue_pairs (u16)
+const NET_CONFIG_LEN: usize = 10;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+// Interrupt status bit telling the driver to re-read config space
+const VIRTIO_MMIO_INT_CONFIG: u32 = 1 << 1;
+
+// Queue indices: RX and TX of pair n at 2n and 2n + 1, then control, which
+// sits right after the first pair unless MQ is negotiated
+const MAX_QUEUE_PAIRS: u16 = 2;
+const NUM_QUEUES: usize = 2 * MAX_QUEUE_PAIRS as usize + 1;
+
+const VRING_DESC_F_NEXT: u16 = 1;
+const VRING_DESC_F_WRITE: u16 = 2;
+// Avail ring flag: the driver does not want used-buffer interrupts
+const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+// Control virtqueue commands (virtio spec 5.1.6.5)
+const VIRTIO_NET_CTRL_RX: u8 = 0;
+const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+const VIRTIO_NET_CTRL_MAC: u8 = 1;
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const VIRTIO_NET_OK: u8 = 0;
+const VIRTIO_NET_ERR: u8 = 1;
 
 // VirtIO Ring Buffer Structures
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
-struct VirtqDesc {
-    addr: u64,
-    len: u32,
-    flags: u16,
-    next: u16,
+pub(crate) struct VirtqDesc {
+    pub(crate) addr: u64,
+    pub(crate) len: u32,
+    pub(crate) flags: u16,
+    pub(crate) next: u16,
 }
 
 #[repr(C, packed)]
@@ -61,6 +109,39 @@ struct VirtioNetHdr {
     num_buffers: u16,
 }
 
+impl VirtioNetHdr {
+    fn to_bytes(self) -> [u8; size_of::<VirtioNetHdr>()] {
+        unsafe { std::mem::transmute(self) }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut raw = [0u8; size_of::<VirtioNetHdr>()];
+        raw.copy_from_slice(&bytes[..size_of::<VirtioNetHdr>()]);
+        unsafe { std::mem::transmute(raw) }
+    }
+}
+
+/// Finish a partially checksummed frame (`VIRTIO_NET_HDR_F_NEEDS_CSUM`): the
+/// guest left the pseudo-header sum at `csum_start + csum_offset`, and the
+/// Internet checksum of everything from `csum_start` on goes there.
+fn complete_checksum(frame: &mut [u8], csum_start: usize, csum_offset: usize) -> bool {
+    let field = csum_start + csum_offset;
+    if field + 2 > frame.len() {
+        return false;
+    }
+    let mut sum: u32 = frame[csum_start..].chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    frame[field..field + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    true
+}
+
+// Largest frame accepted from the TAP when RX buffers can be merged
+const MAX_MRG_RX_FRAME: usize = 65536;
+
 #[derive(Clone, Copy, Debug)]
 pub struct VirtQueue {
     pub desc_addr: u64,
@@ -69,10 +150,16 @@ pub struct VirtQueue {
     pub queue_size: u16,
     pub ready: bool,
     pub last_avail_idx: u16,
+    /// VIRTIO_RING_F_EVENT_IDX was negotiated for this queue
+    pub event_idx: bool,
+    // Used index at the last interrupt decision
+    signalled_used: u16,
+    // Start of guest RAM when the queue was enabled; lower buffers are refused
+    ram_base: u64,
 }
 
 impl VirtQueue {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         VirtQueue {
             desc_addr: 0,
             avail_addr: 0,
@@ -80,78 +167,228 @@ impl VirtQueue {
             queue_size: 0,
             ready: false,
             last_avail_idx: 0,
+            event_idx: false,
+            signalled_used: 0,
+            ram_base: 0,
         }
     }
     
-    fn available_idx(&self, mem: &[u8]) -> u16 {
-        let idx_addr = self.avail_addr + 2;
-        if idx_addr as usize + 2 > mem.len() {
-            return 0;
+    /// Apply a QUEUE_NUM write. A ready queue keeps its size, and so does
+    /// any queue given an invalid one; a queue never sized stays at zero
+    /// and cannot be made ready.
+    pub fn set_size(&mut self, val: u32, max: u16) -> Result<(), String> {
+        if self.ready {
+            return Err(format!("queue size {} written while the queue is ready", val));
         }
-        let b = &mem[idx_addr as usize..idx_addr as usize + 2];
-        u16::from_le_bytes([b[0], b[1]])
+        self.queue_size = check_queue_size(val, max)?;
+        Ok(())
     }
     
-    fn get_avail_desc_idx(&self, mem: &[u8]) -> Option<u16> {
-        let guest_idx = self.available_idx(mem);
-        
-        if self.last_avail_idx == guest_idx {
-            return None;
+    /// Apply a QUEUE_READY write; the queue stays disabled if its rings
+    /// fall outside the guest RAM range `ram` or are misaligned.
+    pub(crate) fn set_ready(&mut self, val: u32, ram: Range<usize>) -> Result<(), String> {
+        self.ready = false;
+        if val & 1 == 0 {
+            return Ok(());
         }
-        
-        let ring_offset = 4 + (self.last_avail_idx % self.queue_size) as u64 * 2;
-        let addr = self.avail_addr + ring_offset;
-        
-        if addr as usize + 2 > mem.len() {
-            return None;
+        self.ram_base = ram.start as u64;
+        crate::virtio::validate_rings(self.desc_addr, self.avail_addr, self.used_addr, self.queue_size, ram)?;
+        self.ready = true;
+        Ok(())
+    }
+
+    /// Ready with a usable size; ring indices are taken modulo the size.
+    pub(crate) fn is_live(&self) -> bool {
+        self.ready && self.queue_size != 0
+    }
+
+    /// Update the low or high half of one ring address. The rings were
+    /// validated when the queue was enabled, so they are fixed while ready.
+    pub(crate) fn set_ring_addr(&mut self, ring: fn(&mut Self) -> &mut u64, high: bool, val: u32) -> Result<(), String> {
+        if self.ready {
+            return Err("ring address written while the queue is ready".to_string());
         }
-        
-        let b = &mem[addr as usize..addr as usize + 2];
-        let desc_idx = u16::from_le_bytes([b[0], b[1]]);
-        
-        Some(desc_idx)
+        let addr = ring(self);
+        *addr = if high {
+            (*addr & 0x00000000FFFFFFFF) | ((val as u64) << 32)
+        } else {
+            (*addr & 0xFFFFFFFF00000000) | (val as u64)
+        };
+        Ok(())
+    }
+
+    fn read_u16(mem: &[u8], base: u64, offset: u64) -> Option<u16> {
+        let b = guest_slice(mem, base.checked_add(offset)?, 2)?;
+        Some(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn available_idx(&self, mem: &[u8]) -> u16 {
+        Self::read_u16(mem, self.avail_addr, 2).unwrap_or(0)
     }
     
-    fn read_desc(&self, mem: &[u8], idx: u16) -> Option<VirtqDesc> {
-        let offset = self.desc_addr + (idx as u64 * size_of::<VirtqDesc>() as u64);
+    pub(crate) fn get_avail_desc_idx(&self, mem: &[u8]) -> Option<u16> {
+        let guest_idx = self.available_idx(mem);
         
-        if offset as usize + size_of::<VirtqDesc>() > mem.len() {
+        if self.last_avail_idx == guest_idx {
             return None;
         }
         
-        let b = &mem[offset as usize..offset as usize + size_of::<VirtqDesc>()];
-        Some(unsafe { std::ptr::read(b.as_ptr() as *const VirtqDesc) })
+        self.avail_desc_at(mem, self.last_avail_idx)
     }
     
-    fn add_used(&mut self, mem: &mut [u8], desc_idx: u16, len: u32) {
-        let used_elem_offset = 4 + (self.last_avail_idx % self.queue_size) as u64 * size_of::<VirtqUsedElem>() as u64;
-        let addr = self.used_addr + used_elem_offset;
-        
-        if addr as usize + size_of::<VirtqUsedElem>() > mem.len() {
+    /// Number of buffers the guest has made available but we have not consumed.
+    fn pending_avail(&self, mem: &[u8]) -> u16 {
+        self.available_idx(mem).wrapping_sub(self.last_avail_idx)
+    }
+    
+    fn avail_desc_at(&self, mem: &[u8], ring_idx: u16) -> Option<u16> {
+        let slot = ring_idx.checked_rem(self.queue_size)?;
+        Self::read_u16(mem, self.avail_addr, 4 + slot as u64 * 2)
+    }
+    
+    pub(crate) fn read_desc(&self, mem: &[u8], idx: u16) -> Option<VirtqDesc> {
+        let offset = self.desc_addr.checked_add(idx as u64 * size_of::<VirtqDesc>() as u64)?;
+        let b = guest_slice(mem, offset, size_of::<VirtqDesc>())?;
+        let desc = unsafe { std::ptr::read_unaligned(b.as_ptr() as *const VirtqDesc) };
+        // The device view starts at guest physical 0, so RAM's lower bound is checked here
+        Some(desc).filter(|desc| desc.addr >= self.ram_base)
+    }
+    
+    pub(crate) fn add_used(&mut self, mem: &mut [u8], desc_idx: u16, len: u32) {
+        self.push_used(mem, desc_idx, len);
+        self.publish_used(mem);
+    }
+    
+    /// Write a used element without making it visible to the guest yet.
+    fn push_used(&mut self, mem: &mut [u8], desc_idx: u16, len: u32) {
+        let Some(slot) = self.last_avail_idx.checked_rem(self.queue_size) else { return };
+        let offset = 4 + slot as u64 * size_of::<VirtqUsedElem>() as u64;
+        let Some(b) = self.used_addr.checked_add(offset)
+            .and_then(|addr| guest_slice_mut(mem, addr, size_of::<VirtqUsedElem>()))
+        else {
             return;
-        }
-        
-        let elem = VirtqUsedElem { id: desc_idx as u32, len };
+        };
         
-        unsafe {
-            let ptr = mem.as_mut_ptr().add(addr as usize) as *mut VirtqUsedElem;
-            *ptr = elem;
-        }
+        b[..4].copy_from_slice(&(desc_idx as u32).to_le_bytes());
+        b[4..].copy_from_slice(&len.to_le_bytes());
         
         self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
-        
-        let idx_addr = self.used_addr + 2;
-        if idx_addr as usize + 2 <= mem.len() {
-            unsafe {
-                let idx_ptr = mem.as_mut_ptr().add(idx_addr as usize) as *mut u16;
-                *idx_ptr = self.last_avail_idx;
+    }
+    
+    fn publish_used(&mut self, mem: &mut [u8]) {
+        if let Some(b) = self.used_addr.checked_add(2).and_then(|addr| guest_slice_mut(mem, addr, 2)) {
+            b.copy_from_slice(&self.last_avail_idx.to_le_bytes());
+        }
+        if self.event_idx {
+            // avail_event: ask for a notification once the guest passes what we consumed
+            let offset = 4 + self.queue_size as u64 * size_of::<VirtqUsedElem>() as u64;
+            if let Some(b) = self.used_addr.checked_add(offset).and_then(|addr| guest_slice_mut(mem, addr, 2)) {
+                b.copy_from_slice(&self.last_avail_idx.to_le_bytes());
             }
         }
     }
+    
+    /// Whether the guest wants an interrupt for the used entries published
+    /// since the last call: per used_event with EVENT_IDX, otherwise unless
+    /// the avail ring flags ask for no interrupts.
+    pub(crate) fn needs_interrupt(&mut self, mem: &[u8]) -> bool {
+        let old = std::mem::replace(&mut self.signalled_used, self.last_avail_idx);
+        if !self.event_idx {
+            return match Self::read_u16(mem, self.avail_addr, 0) {
+                Some(flags) => flags & VRING_AVAIL_F_NO_INTERRUPT == 0,
+                None => true,
+            };
+        }
+        match Self::read_u16(mem, self.avail_addr, 4 + self.queue_size as u64 * 2) {
+            Some(used_event) => vring_need_event(used_event, self.last_avail_idx, old),
+            None => true,
+        }
+    }
+}
+
+// Frames the loopback backend holds before dropping new ones
+const LOOPBACK_QUEUE_LEN: usize = 256;
+
+/// `--net loopback`: every frame the guest sends comes straight back on RX.
+pub struct LoopbackBackend {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl LoopbackBackend {
+    pub fn new() -> Self {
+        Self { frames: VecDeque::new() }
+    }
+}
+
+impl Default for LoopbackBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where guest frames go: a host TAP device, the built-in user-mode stack,
+/// or back to the guest itself.
+pub trait NetBackend {
+    /// Next frame for the guest, or `WouldBlock` when there is none.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
+
+    /// Send one frame from the guest.
+    fn write(&mut self, frame: &[u8]) -> std::io::Result<usize>;
+
+    /// Name for the logs.
+    fn kind(&self) -> &'static str;
+}
+
+impl NetBackend for TapInterface {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        TapInterface::read(self, buf)
+    }
+
+    fn write(&mut self, frame: &[u8]) -> std::io::Result<usize> {
+        TapInterface::write(self, frame)
+    }
+
+    fn kind(&self) -> &'static str {
+        "TAP"
+    }
+}
+
+impl NetBackend for UserNet {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        UserNet::read(self, buf)
+    }
+
+    fn write(&mut self, frame: &[u8]) -> std::io::Result<usize> {
+        UserNet::write(self, frame)
+    }
+
+    fn kind(&self) -> &'static str {
+        "user networking"
+    }
+}
+
+impl NetBackend for LoopbackBackend {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let frame = self.frames.pop_front().ok_or_else(|| std::io::Error::from(std::io::ErrorKind::WouldBlock))?;
+        let n = frame.len().min(buf.len());
+        buf[..n].copy_from_slice(&frame[..n]);
+        Ok(n)
+    }
+
+    fn write(&mut self, frame: &[u8]) -> std::io::Result<usize> {
+        if self.frames.len() < LOOPBACK_QUEUE_LEN {
+            self.frames.push_back(frame.to_vec());
+        }
+        Ok(frame.len())
+    }
+
+    fn kind(&self) -> &'static str {
+        "loopback"
+    }
 }
 
 pub struct VirtioNet {
-    tap: Mutex<Option<TapInterface>>,
+    backend: Mutex<Option<Box<dyn NetBackend + Send>>>,
     mac: [u8; 6],
     
     status: Mutex<u32>,
@@ -160,352 +397,937 @@ pub struct VirtioNet {
     driver_features: Mutex<u64>,
     queue_sel: Mutex<u32>,
     
-    queues: Mutex<[VirtQueue; 2]>,
+    queues: Mutex<[VirtQueue; NUM_QUEUES]>,
+    // Queue pairs the driver enabled with VQ_PAIRS_SET; RX only uses these
+    active_pairs: Mutex<u16>,
+    // Pair whose RX queue gets the next frame
+    next_rx_pair: Mutex<u16>,
     interrupt_status: Mutex<u32>,
+    registers: RegisterLatch,
+    
+    // Scratch space for frames read from the backend
+    rx_buf: Mutex<Vec<u8>>,
+    
+    pcap: Option<Mutex<File>>,
+    queue_num_max: u16,
+    metrics: Option<Arc<VmMetrics>>,
 }
 
 impl VirtioNet {
-    pub fn new(tap: Option<TapInterface>) -> Self {
-        if tap.is_some() {
-            println!(">>> [Net] VirtIO-Net device initialized with TAP");
-            tracing::info!("VirtIO-Net device initialized with TAP interface");
+    pub fn new(backend: Option<Box<dyn NetBackend + Send>>) -> Self {
+        if let Some(ref backend) = backend {
+            status!("Net", "VirtIO-Net device initialized with {}", backend.kind());
         } else {
-            println!(">>> [Net] VirtIO-Net device initialized WITHOUT TAP (link down)");
-            tracing::warn!("VirtIO-Net device initialized without TAP interface");
+            status!(warn, "Net", "VirtIO-Net device initialized WITHOUT TAP (link down)");
         }
         
         VirtioNet {
-            tap: Mutex::new(tap),
+            backend: Mutex::new(backend),
             mac: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56],
             status: Mutex::new(0),
             driver_features_sel: Mutex::new(0),
             device_features_sel: Mutex::new(0),
             driver_features: Mutex::new(0),
             queue_sel: Mutex::new(0),
-            queues: Mutex::new([VirtQueue::new(), VirtQueue::new()]),
+            queues: Mutex::new([VirtQueue::new(); NUM_QUEUES]),
+            active_pairs: Mutex::new(1),
+            next_rx_pair: Mutex::new(0),
             interrupt_status: Mutex::new(0),
+            registers: RegisterLatch::new(),
+            rx_buf: Mutex::new(vec![0u8; MAX_MRG_RX_FRAME]),
+            pcap: None,
+            queue_num_max: QUEUE_NUM_MAX,
+            metrics: None,
+        }
+    }
+    
+    pub fn with_mac(mut self, mac: [u8; 6]) -> Self {
+        self.mac = mac;
+        self
+    }
+    
+    /// Advertise `max` instead of QUEUE_NUM_MAX as the largest queue
+    pub fn with_queue_size(mut self, max: u16) -> Self {
+        self.queue_num_max = max;
+        self
+    }
+    
+    /// Count a FAILED device status as an error in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<VmMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+    
+    /// Record every TX/RX frame into `file`, which must already hold a pcap header.
+    pub fn with_pcap(mut self, file: File) -> Self {
+        self.pcap = Some(Mutex::new(file));
+        self
+    }
+    
+    fn capture(&self, frame: &[u8]) {
+        if let Some(ref pcap) = self.pcap {
+            let result = pcap.lock_or_err().map_err(|e| e.to_string())
+                .and_then(|mut file| crate::pcap::write_record(&mut *file, frame).map_err(|e| e.to_string()));
+            if let Err(e) = result {
+                tracing::warn!(error = %e, "Failed to write pcap record");
+            }
         }
     }
 
-    pub fn read(&self, offset: u64, data: &mut [u8]) {
-        let val: u64 = match offset {
+    fn reset(&self) -> AxvmResult<()> {
+        *self.status.lock_or_err()? = 0;
+        let mut queues = self.queues.lock_or_err()?;
+        *queues = [VirtQueue::new(); NUM_QUEUES];
+        *self.active_pairs.lock_or_err()? = 1;
+        *self.next_rx_pair.lock_or_err()? = 0;
+        *self.queue_sel.lock_or_err()? = 0;
+        *self.interrupt_status.lock_or_err()? = 0;
+        tracing::info!("VirtIO-Net device reset");
+        status!("Net", "Device RESET");
+        Ok(())
+    }
+    
+    fn set_ring_addr(&self, ring: fn(&mut VirtQueue) -> &mut u64, high: bool, val: u32) -> AxvmResult<()> {
+        let sel = *self.queue_sel.lock_or_err()? as usize;
+        if sel < NUM_QUEUES {
+            if let Err(e) = self.queues.lock_or_err()?[sel].set_ring_addr(ring, high, val) {
+                tracing::warn!(queue = sel, error = %e, "VirtIO-Net: ignoring ring address");
+            }
+        }
+        Ok(())
+    }
+    
+    fn ctrl_queue(&self) -> AxvmResult<usize> {
+        if *self.driver_features.lock_or_err()? & VIRTIO_NET_F_MQ != 0 {
+            Ok(2 * MAX_QUEUE_PAIRS as usize)
+        } else {
+            Ok(2)
+        }
+    }
+    
+    /// Move frames from the backend into RX buffers until the backend has
+    /// nothing more to read or the guest runs out of buffers; one interrupt
+    /// covers the whole batch. Frames are spread round-robin over the RX
+    /// queues of the active pairs that have buffers posted.
+    pub fn process_rx(&self, mem: &mut [u8]) -> AxvmResult<bool> {
+        let mut backend = self.backend.lock_or_err()?;
+        let Some(backend) = backend.as_mut() else {
+            return Ok(false);
+        };
+        
+        let active_pairs = *self.active_pairs.lock_or_err()?;
+        let mut next_pair = self.next_rx_pair.lock_or_err()?;
+        let mut queues = self.queues.lock_or_err()?;
+        let mergeable = *self.driver_features.lock_or_err()? & VIRTIO_NET_F_MRG_RXBUF != 0;
+        let mut packet_buf = self.rx_buf.lock_or_err()?;
+        let mut delivered = [0usize; MAX_QUEUE_PAIRS as usize];
+        
+        while let Some(pair) = (0..active_pairs).map(|i| (*next_pair + i) % active_pairs)
+            .find(|&p| queues[2 * p as usize].is_live() && queues[2 * p as usize].pending_avail(mem) > 0)
+        {
+            let queue = &mut queues[2 * pair as usize];
+            
+            let n = match backend.read(&mut packet_buf[..]) {
+                Ok(n) if n > 0 => n,
+                _ => break,
+            };
+            let packet = &packet_buf[..n];
+            
+            if mergeable {
+                if !deliver_mergeable(queue, mem, packet) {
+                    continue;
+                }
+            } else {
+                let Some(desc_idx) = queue.get_avail_desc_idx(mem) else { break };
+                let Some(desc) = queue.read_desc(mem, desc_idx) else { break };
+                let (addr, desc_len) = (desc.addr, desc.len); // Copy to avoid packed field reference
+                let hdr_len = size_of::<VirtioNetHdr>();
+                
+                if (n + hdr_len) as u32 > desc_len {
+                    tracing::warn!(packet_size = n, buffer_size = desc_len, "Packet too big for buffer");
+                    continue;
+                }
+                
+                let Some(dest) = guest_slice_mut(mem, addr, hdr_len + n) else {
+                    tracing::error!("Buffer address out of bounds");
+                    break;
+                };
+                dest[..hdr_len].copy_from_slice(&VirtioNetHdr::default().to_bytes());
+                dest[hdr_len..].copy_from_slice(packet);
+                queue.add_used(mem, desc_idx, (n + hdr_len) as u32);
+            }
+            self.capture(packet);
+            delivered[pair as usize] += 1;
+            *next_pair = (pair + 1) % active_pairs;
+            tracing::debug!(bytes = n, pair = pair, mergeable = mergeable, "RX packet processed");
+        }
+        
+        let mut interrupt = false;
+        for (pair, &count) in delivered.iter().enumerate() {
+            if count > 0 && queues[2 * pair].needs_interrupt(mem) {
+                interrupt = true;
+            }
+        }
+        if !interrupt {
+            return Ok(false);
+        }
+        *self.interrupt_status.lock_or_err()? |= 1;
+        Ok(true)
+    }
+    
+    /// Acknowledge control commands. RX-mode and MAC filter requests are
+    /// accepted without filtering anything: the TAP already sees all traffic.
+    fn process_ctrl(&self, mem: &mut [u8]) -> AxvmResult<bool> {
+        let ctrl_queue = self.ctrl_queue()?;
+        let mut queues = self.queues.lock_or_err()?;
+        let queue = &mut queues[ctrl_queue];
+        
+        if !queue.is_live() {
+            return Ok(false);
+        }
+        
+        let mut work_done = false;
+        while let Some(head) = queue.get_avail_desc_idx(mem) {
+            let mut command = Vec::new();
+            let mut ack_addr = None;
+            let mut idx = head;
+            for _ in 0..queue.queue_size {
+                let Some(desc) = queue.read_desc(mem, idx) else { break };
+                let (addr, len, flags) = (desc.addr, desc.len as usize, desc.flags);
+                if let Some(buf) = guest_slice(mem, addr, len) {
+                    if flags & VRING_DESC_F_WRITE != 0 {
+                        ack_addr = Some(addr);
+                    } else {
+                        command.extend_from_slice(buf);
+                    }
+                }
+                if flags & VRING_DESC_F_NEXT == 0 {
+                    break;
+                }
+                idx = desc.next;
+            }
+            
+            let status = match (command.first(), command.get(1)) {
+                (Some(&VIRTIO_NET_CTRL_RX), Some(&cmd @ (VIRTIO_NET_CTRL_RX_PROMISC | VIRTIO_NET_CTRL_RX_ALLMULTI))) => {
+                    let on = command.get(2).copied().unwrap_or(0) != 0;
+                    tracing::debug!(cmd = cmd, on = on, "VirtIO-Net RX mode command");
+                    VIRTIO_NET_OK
+                },
+                (Some(&VIRTIO_NET_CTRL_MAC), Some(&cmd)) => {
+                    tracing::debug!(cmd = cmd, "VirtIO-Net MAC command");
+                    VIRTIO_NET_OK
+                },
+                (Some(&VIRTIO_NET_CTRL_MQ), Some(&VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET)) => {
+                    match command.get(2..4).map(|b| u16::from_le_bytes([b[0], b[1]])) {
+                        Some(pairs @ 1..=MAX_QUEUE_PAIRS) => {
+                            status!("Net", pairs = pairs, "Using {} queue pair(s)", pairs);
+                            *self.active_pairs.lock_or_err()? = pairs;
+                            *self.next_rx_pair.lock_or_err()? = 0;
+                            VIRTIO_NET_OK
+                        },
+                        pairs => {
+                            tracing::warn!(pairs = ?pairs, "VirtIO-Net: rejecting queue pair count");
+                            VIRTIO_NET_ERR
+                        }
+                    }
+                },
+                (class, cmd) => {
+                    tracing::debug!(class = ?class, cmd = ?cmd, "Unsupported VirtIO-Net control command");
+                    VIRTIO_NET_ERR
+                }
+            };
+            
+            let written = match ack_addr.and_then(|addr| guest_slice_mut(mem, addr, 1)) {
+                Some(ack) => {
+                    ack[0] = status;
+                    1
+                },
+                None => 0,
+            };
+            queue.add_used(mem, head, written);
+            work_done = true;
+        }
+        
+        if work_done && queue.needs_interrupt(mem) {
+            *self.interrupt_status.lock_or_err()? |= 1;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+    
+    pub fn has_backend(&self) -> AxvmResult<bool> {
+        Ok(self.backend.lock_or_err()?.is_some())
+    }
+    
+    /// Attach `tap` as the backend of a device that has none, bring the link
+    /// up and raise a config-change interrupt so the driver notices.
+    pub fn attach_tap(&self, tap: TapInterface) -> AxvmResult<()> {
+        let mut current = self.backend.lock_or_err()?;
+        if let Some(ref backend) = *current {
+            return Err(AxvmError::InvalidState(format!("VirtIO-Net already has {} attached", backend.kind())));
+        }
+        *current = Some(Box::new(tap));
+        drop(current);
+        *self.interrupt_status.lock_or_err()? |= VIRTIO_MMIO_INT_CONFIG;
+        Ok(())
+    }
+    
+    fn config_space(&self) -> AxvmResult<[u8; NET_CONFIG_LEN]> {
+        let link = if self.has_backend()? { VIRTIO_NET_S_LINK_UP } else { 0 };
+        let mut config = [0u8; NET_CONFIG_LEN];
+        config[..6].copy_from_slice(&self.mac);
+        config[6..8].copy_from_slice(&link.to_le_bytes());
+        config[8..].copy_from_slice(&MAX_QUEUE_PAIRS.to_le_bytes());
+        Ok(config)
+    }
+    
+    pub fn should_interrupt(&self) -> AxvmResult<bool> {
+        Ok(*self.interrupt_status.lock_or_err()? != 0)
+    }
+    
+    /// Send what the driver queued on every ready TX queue. A driver may
+    /// still drain TX queues of pairs it just disabled, so all are served.
+    pub fn process_tx(&self, mem: &mut [u8]) -> AxvmResult<bool> {
+        let mut backend = self.backend.lock_or_err()?;
+        let Some(backend) = backend.as_mut() else {
+            return Ok(false);
+        };
+        
+        let mut queues = self.queues.lock_or_err()?;
+        let mut work_done = false;
+        let mut interrupt = false;
+        for pair in 0..MAX_QUEUE_PAIRS as usize {
+            let queue = &mut queues[2 * pair + 1];
+            if !queue.is_live() {
+                continue;
+            }
+            let (sent, used_added) = self.transmit(backend.as_mut(), queue, mem);
+            work_done |= sent;
+            interrupt |= used_added && queue.needs_interrupt(mem);
+        }
+        
+        if interrupt {
+            *self.interrupt_status.lock_or_err()? |= 1;
+        }
+        Ok(work_done)
+    }
+    
+    /// Drain one TX queue into `backend`: whether any frame was sent, and
+    /// whether any buffer was returned to the driver.
+    fn transmit(&self, backend: &mut dyn NetBackend, queue: &mut VirtQueue, mem: &mut [u8]) -> (bool, bool) {
+        let mut work_done = false;
+        let mut used_added = false;
+        
+        while let Some(desc_idx) = queue.get_avail_desc_idx(mem) {
+            if let Some(desc) = queue.read_desc(mem, desc_idx) {
+                let desc_len = desc.len as usize;
+                let hdr_len = size_of::<VirtioNetHdr>();
+                let buf = guest_slice(mem, desc.addr, desc_len).filter(|_| desc_len > hdr_len);
+                
+                if let Some(buf) = buf {
+                    let hdr = VirtioNetHdr::from_bytes(&buf[..hdr_len]);
+                    let packet_slice = &buf[hdr_len..];
+                    
+                    // No offloads are offered, but a driver that sets NEEDS_CSUM anyway
+                    // must not put a half-finished checksum on the wire
+                    let mut completed = None;
+                    if hdr.gso_type != VIRTIO_NET_HDR_GSO_NONE {
+                        tracing::warn!(gso_type = hdr.gso_type, "Dropping TX GSO frame: GSO was not negotiated");
+                        queue.add_used(mem, desc_idx, 0);
+                        used_added = true;
+                        continue;
+                    }
+                    if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                        let (csum_start, csum_offset) = (hdr.csum_start as usize, hdr.csum_offset as usize);
+                        let mut frame = packet_slice.to_vec();
+                        if !complete_checksum(&mut frame, csum_start, csum_offset) {
+                            tracing::warn!(csum_start = csum_start, csum_offset = csum_offset,
+                                "TX checksum offsets outside the frame");
+                        }
+                        completed = Some(frame);
+                    }
+                    let packet_slice = completed.as_deref().unwrap_or(packet_slice);
+                    self.capture(packet_slice);
+                    
+                    match backend.write(packet_slice) {
+                        Ok(n) => {
+                            tracing::debug!(bytes = n, "TX packet sent");
+                            work_done = true;
+                        },
+                        Err(e) => {
+                            tracing::warn!(error = %e, "Failed to hand TX frame to the backend");
+                        }
+                    }
+                }
+                
+                queue.add_used(mem, desc_idx, 0);
+                used_added = true;
+            } else {
+                break;
+            }
+        }
+        (work_done, used_added)
+    }
+}
+
+/// Spread `packet` (prefixed by a virtio-net header) over as many available
+/// RX buffers as needed. Nothing is consumed unless the whole frame fits.
+fn deliver_mergeable(queue: &mut VirtQueue, mem: &mut [u8], packet: &[u8]) -> bool {
+    let hdr_len = size_of::<VirtioNetHdr>();
+    let total = hdr_len + packet.len();
+    
+    let mut buffers = Vec::new();
+    let mut capacity = 0;
+    for i in 0..queue.pending_avail(mem) {
+        if capacity >= total {
+            break;
+        }
+        let Some(desc_idx) = queue.avail_desc_at(mem, queue.last_avail_idx.wrapping_add(i)) else { break };
+        let Some(desc) = queue.read_desc(mem, desc_idx) else { break };
+        let (addr, len) = (desc.addr, desc.len as usize);
+        if guest_slice(mem, addr, len).is_none() {
+            tracing::error!("Buffer address out of bounds");
+            return false;
+        }
+        buffers.push((desc_idx, addr, len));
+        capacity += len;
+    }
+    
+    if capacity < total || buffers[0].2 < hdr_len {
+        tracing::warn!(packet_size = packet.len(), available = capacity, "Not enough RX buffers, dropping packet");
+        return false;
+    }
+    
+    let hdr = VirtioNetHdr { num_buffers: buffers.len() as u16, ..Default::default() };
+    let mut frame = Vec::with_capacity(total);
+    frame.extend_from_slice(&hdr.to_bytes());
+    frame.extend_from_slice(packet);
+    
+    let mut offset = 0;
+    for (desc_idx, addr, len) in buffers {
+        let chunk = len.min(total - offset);
+        // In bounds: every buffer was checked above and chunk <= len
+        guest_slice_mut(mem, addr, chunk).unwrap().copy_from_slice(&frame[offset..offset + chunk]);
+        queue.push_used(mem, desc_idx, chunk as u32);
+        offset += chunk;
+    }
+    queue.publish_used(mem);
+    true
+}
+
+impl MmioDevice for VirtioNet {
+    fn read(&self, offset: u64, data: &mut [u8]) -> AxvmResult<()> {
+        mmio::read_registers(offset, data, |reg| Ok(match reg {
             MMIO_MAGIC_VALUE => 0x74726976,
             MMIO_VERSION => 2,
             MMIO_DEVICE_ID => 1,
             MMIO_VENDOR_ID => 0x1AF4,
             
             MMIO_DEVICE_FEATURES => {
-                let sel = *self.device_features_sel.lock().unwrap();
+                let sel = *self.device_features_sel.lock_or_err()?;
                 if sel == 0 {
-                    (VIRTIO_NET_F_MAC | (VIRTIO_F_VERSION_1 & 0xFFFFFFFF)) as u64
+                    DEVICE_FEATURES as u32
                 } else if sel == 1 {
-                    (VIRTIO_F_VERSION_1 >> 32) as u64
+                    (DEVICE_FEATURES >> 32) as u32
                 } else {
                     0
                 }
             },
             
-            MMIO_QUEUE_NUM_MAX => 256,
+            MMIO_QUEUE_NUM_MAX => self.queue_num_max as u32,
             
             MMIO_QUEUE_READY => {
-                let sel = *self.queue_sel.lock().unwrap();
-                let queues = self.queues.lock().unwrap();
-                if (sel as usize) < 2 {
-                    queues[sel as usize].ready as u64
+                let sel = *self.queue_sel.lock_or_err()?;
+                let queues = self.queues.lock_or_err()?;
+                if (sel as usize) < NUM_QUEUES {
+                    queues[sel as usize].ready as u32
                 } else {
                     0
                 }
             },
             
-            MMIO_INTERRUPT_STATUS => *self.interrupt_status.lock().unwrap() as u64,
-            MMIO_STATUS => *self.status.lock().unwrap() as u64,
+            MMIO_INTERRUPT_STATUS => *self.interrupt_status.lock_or_err()?,
+            MMIO_STATUS => *self.status.lock_or_err()?,
             
-            off if off >= MMIO_CONFIG_SPACE && off < MMIO_CONFIG_SPACE + 6 => {
-                let idx = (off - MMIO_CONFIG_SPACE) as usize;
-                let mut val: u64 = 0;
-                for i in 0..data.len().min(6 - idx) {
-                    val |= (self.mac[idx + i] as u64) << (i * 8);
+            reg if reg >= MMIO_CONFIG_SPACE => {
+                let start = (reg - MMIO_CONFIG_SPACE) as usize;
+                let config = self.config_space()?;
+                let mut word = [0u8; 4];
+                for (i, byte) in word.iter_mut().enumerate() {
+                    *byte = config.get(start + i).copied().unwrap_or(0);
                 }
-                val
+                u32::from_le_bytes(word)
             },
             
             _ => 0,
-        };
-
-        let bytes = val.to_le_bytes();
-        let len = data.len().min(8);
-        data[..len].copy_from_slice(&bytes[..len]);
+        }))
     }
 
-    pub fn write(&self, offset: u64, data: &[u8]) -> Result<bool, String> {
-        let val = match data.len() {
-            1 => data[0] as u32,
-            2 => u16::from_le_bytes([data[0], data[1]]) as u32,
-            4 => u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
-            _ => return Err(format!("Invalid write size: {}", data.len())),
-        };
-
-        match offset {
-            MMIO_DEVICE_FEATURES_SEL => {
-                *self.device_features_sel.lock().unwrap() = val;
-            },
+    fn write(&self, offset: u64, data: &[u8], mem: &mut GuestMemory) -> AxvmResult<()> {
+        self.registers.write(offset, data, |reg, val| {
+            match reg {
+                MMIO_DEVICE_FEATURES_SEL => {
+                    *self.device_features_sel.lock_or_err()? = val;
+                },
             
-            MMIO_DRIVER_FEATURES_SEL => {
-                *self.driver_features_sel.lock().unwrap() = val;
-            },
+                MMIO_DRIVER_FEATURES_SEL => {
+                    *self.driver_features_sel.lock_or_err()? = val;
+                },
             
-            MMIO_DRIVER_FEATURES => {
-                let sel = *self.driver_features_sel.lock().unwrap();
-                let mut features = self.driver_features.lock().unwrap();
-                if sel == 0 {
-                    *features = (*features & 0xFFFFFFFF00000000) | (val as u64);
-                } else {
-                    *features = (*features & 0x00000000FFFFFFFF) | ((val as u64) << 32);
-                }
-                tracing::debug!(features = *features, "Driver features negotiated");
-            },
+                MMIO_DRIVER_FEATURES => {
+                    let sel = *self.driver_features_sel.lock_or_err()?;
+                    let mut features = self.driver_features.lock_or_err()?;
+                    if sel == 0 {
+                        *features = (*features & 0xFFFFFFFF00000000) | (val as u64);
+                    } else {
+                        *features = (*features & 0x00000000FFFFFFFF) | ((val as u64) << 32);
+                    }
+                    tracing::debug!(features = *features, "Driver features negotiated");
+                },
             
-            MMIO_QUEUE_SEL => {
-                *self.queue_sel.lock().unwrap() = val;
-            },
+                MMIO_QUEUE_SEL => {
+                    *self.queue_sel.lock_or_err()? = val;
+                },
             
-            MMIO_QUEUE_NUM => {
-                let sel = *self.queue_sel.lock().unwrap();
-                if (sel as usize) < 2 {
-                    self.queues.lock().unwrap()[sel as usize].queue_size = val as u16;
-                }
-            },
+                MMIO_QUEUE_NUM => {
+                    let sel = *self.queue_sel.lock_or_err()?;
+                    if (sel as usize) < NUM_QUEUES {
+                        if let Err(e) = self.queues.lock_or_err()?[sel as usize].set_size(val, self.queue_num_max) {
+                            tracing::warn!(queue = sel, error = %e, "VirtIO-Net: rejecting queue size");
+                        }
+                    }
+                },
             
-            MMIO_QUEUE_READY => {
-                let sel = *self.queue_sel.lock().unwrap();
-                if (sel as usize) < 2 {
-                    let mut queues = self.queues.lock().unwrap();
-                    queues[sel as usize].ready = (val & 1) == 1;
-                    
-                    if val == 1 {
-                        let q = &queues[sel as usize];
-                        println!(">>> [Net] Queue {} Configured: size={}, desc=0x{:x}, avail=0x{:x}, used=0x{:x}",
-                            sel, q.queue_size, q.desc_addr, q.avail_addr, q.used_addr);
-                        tracing::info!(
-                            queue = sel,
-                            size = q.queue_size,
-                            desc = format!("0x{:x}", q.desc_addr),
-                            avail = format!("0x{:x}", q.avail_addr),
-                            used = format!("0x{:x}", q.used_addr),
-                            "VirtIO-Net queue configured"
-                        );
+                MMIO_QUEUE_READY => {
+                    let sel = *self.queue_sel.lock_or_err()?;
+                    if (sel as usize) < NUM_QUEUES {
+                        let event_idx = *self.driver_features.lock_or_err()? & VIRTIO_RING_F_EVENT_IDX != 0;
+                        let mut queues = self.queues.lock_or_err()?;
+                        let q = &mut queues[sel as usize];
+                        q.event_idx = event_idx;
+                        if let Err(e) = q.set_ready(val, mem.ram()) {
+                            tracing::warn!(queue = sel, error = %e, "VirtIO-Net: refusing to enable queue");
+                        } else if q.ready {
+                            status!("Net", queue = sel, size = q.queue_size,
+                                "Queue {} Configured: size={}, desc=0x{:x}, avail=0x{:x}, used=0x{:x}",
+                                sel, q.queue_size, q.desc_addr, q.avail_addr, q.used_addr);
+                        }
                     }
-                }
-            },
+                },
             
-            MMIO_QUEUE_DESC_LOW => {
-                let sel = *self.queue_sel.lock().unwrap();
-                if (sel as usize) < 2 {
-                    let mut queues = self.queues.lock().unwrap();
-                    let addr = &mut queues[sel as usize].desc_addr;
-                    *addr = (*addr & 0xFFFFFFFF00000000) | (val as u64);
-                }
-            },
+                MMIO_QUEUE_DESC_LOW => self.set_ring_addr(|q| &mut q.desc_addr, false, val)?,
             
-            MMIO_QUEUE_DESC_HIGH => {
-                let sel = *self.queue_sel.lock().unwrap();
-                if (sel as usize) < 2 {
-                    let mut queues = self.queues.lock().unwrap();
-                    let addr = &mut queues[sel as usize].desc_addr;
-                    *addr = (*addr & 0x00000000FFFFFFFF) | ((val as u64) << 32);
-                }
-            },
+                MMIO_QUEUE_DESC_HIGH => self.set_ring_addr(|q| &mut q.desc_addr, true, val)?,
             
-            MMIO_QUEUE_AVAIL_LOW => {
-                let sel = *self.queue_sel.lock().unwrap();
-                if (sel as usize) < 2 {
-                    let mut queues = self.queues.lock().unwrap();
-                    let addr = &mut queues[sel as usize].avail_addr;
-                    *addr = (*addr & 0xFFFFFFFF00000000) | (val as u64);
-                }
-            },
+                MMIO_QUEUE_AVAIL_LOW => self.set_ring_addr(|q| &mut q.avail_addr, false, val)?,
             
-            MMIO_QUEUE_AVAIL_HIGH => {
-                let sel = *self.queue_sel.lock().unwrap();
-                if (sel as usize) < 2 {
-                    let mut queues = self.queues.lock().unwrap();
-                    let addr = &mut queues[sel as usize].avail_addr;
-                    *addr = (*addr & 0x00000000FFFFFFFF) | ((val as u64) << 32);
-                }
-            },
+                MMIO_QUEUE_AVAIL_HIGH => self.set_ring_addr(|q| &mut q.avail_addr, true, val)?,
             
-            MMIO_QUEUE_USED_LOW => {
-                let sel = *self.queue_sel.lock().unwrap();
-                if (sel as usize) < 2 {
-                    let mut queues = self.queues.lock().unwrap();
-                    let addr = &mut queues[sel as usize].used_addr;
-                    *addr = (*addr & 0xFFFFFFFF00000000) | (val as u64);
-                }
-            },
+                MMIO_QUEUE_USED_LOW => self.set_ring_addr(|q| &mut q.used_addr, false, val)?,
             
-            MMIO_QUEUE_USED_HIGH => {
-                let sel = *self.queue_sel.lock().unwrap();
-                if (sel as usize) < 2 {
-                    let mut queues = self.queues.lock().unwrap();
-                    let addr = &mut queues[sel as usize].used_addr;
-                    *addr = (*addr & 0x00000000FFFFFFFF) | ((val as u64) << 32);
-                }
-            },
+                MMIO_QUEUE_USED_HIGH => self.set_ring_addr(|q| &mut q.used_addr, true, val)?,
             
-            MMIO_STATUS => {
-                *self.status.lock().unwrap() = val;
-                tracing::debug!(status = val, "VirtIO-Net status updated");
+                MMIO_STATUS => {
+                    let accepted = *self.driver_features.lock_or_err()?;
+                    let mut status = self.status.lock_or_err()?;
+                    *status = latch_status("VirtIO-Net", *status, val, DEVICE_FEATURES, accepted, self.metrics.as_deref());
+                    drop(status);
+                    tracing::debug!(status = val, "VirtIO-Net status updated");
                 
-                if val == 0 {
-                    self.reset();
-                }
-            },
+                    if val == 0 {
+                        self.reset()?;
+                    }
+                },
             
-            MMIO_INTERRUPT_ACK => {
-                let mut int_status = self.interrupt_status.lock().unwrap();
-                *int_status &= !val;
-            },
+                MMIO_QUEUE_NOTIFY if val as usize == self.ctrl_queue()? => {
+                    self.process_ctrl(mem.as_mut_slice())?;
+                },
+            
+                MMIO_INTERRUPT_ACK => {
+                    let mut int_status = self.interrupt_status.lock_or_err()?;
+                    *int_status &= !val;
+                },
             
-            _ => {
-                tracing::debug!(offset = offset, val = val, "Unknown VirtIO-Net write");
+                _ => {
+                    tracing::debug!(offset = reg, val = val, "Unknown VirtIO-Net write");
+                }
             }
-        }
+            Ok(())
+        })
+    }
 
-        Ok(false)
+    fn interrupt_level(&self) -> AxvmResult<bool> {
+        self.should_interrupt()
     }
-    
-    fn reset(&self) {
-        *self.status.lock().unwrap() = 0;
-        let mut queues = self.queues.lock().unwrap();
-        queues[0] = VirtQueue::new();
-        queues[1] = VirtQueue::new();
-        *self.queue_sel.lock().unwrap() = 0;
-        tracing::info!("VirtIO-Net device reset");
-        println!(">>> [Net] Device RESET");
+}
+
+impl Default for VirtioNet {
+    fn default() -> Self {
+        Self::new(None)
     }
-    
-    pub fn process_rx(&self, mem: &mut [u8]) -> bool {
-        let mut tap_guard = self.tap.lock().unwrap();
-        if tap_guard.is_none() {
-            return false;
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESC: u64 = 0x1000;
+    const AVAIL: u64 = 0x2000;
+    const USED: u64 = 0x3000;
+
+    fn rx_queue(mem: &mut [u8], buffers: &[(u64, u32)]) -> VirtQueue {
+        rx_queue_at(mem, 0, buffers)
+    }
+
+    // Rings moved up by `offset`, so several queues can share one memory
+    fn rx_queue_at(mem: &mut [u8], offset: u64, buffers: &[(u64, u32)]) -> VirtQueue {
+        let mut queue = VirtQueue::new();
+        queue.desc_addr = DESC + offset;
+        queue.avail_addr = AVAIL + offset;
+        queue.used_addr = USED + offset;
+        queue.queue_size = 16;
+        queue.ready = true;
+
+        for (i, &(addr, len)) in buffers.iter().enumerate() {
+            let d = (queue.desc_addr + i as u64 * 16) as usize;
+            mem[d..d + 8].copy_from_slice(&addr.to_le_bytes());
+            mem[d + 8..d + 12].copy_from_slice(&len.to_le_bytes());
+            let a = (queue.avail_addr + 4 + i as u64 * 2) as usize;
+            mem[a..a + 2].copy_from_slice(&(i as u16).to_le_bytes());
         }
-        
-        let mut queues = self.queues.lock().unwrap();
-        let queue = &mut queues[0]; // RX Queue
-        
-        if !queue.ready {
-            return false;
+        let a = (queue.avail_addr + 2) as usize;
+        mem[a..a + 2].copy_from_slice(&(buffers.len() as u16).to_le_bytes());
+        queue
+    }
+
+    fn used_idx(mem: &[u8]) -> u16 {
+        used_idx_at(mem, 0)
+    }
+
+    fn used_idx_at(mem: &[u8], offset: u64) -> u16 {
+        let u = (USED + offset) as usize;
+        u16::from_le_bytes([mem[u + 2], mem[u + 3]])
+    }
+
+    #[test]
+    fn test_set_ready_rejects_bad_rings() {
+        let mut mem = vec![0u8; 0x4000];
+        let mut queue = rx_queue(&mut mem, &[]);
+        queue.ready = false;
+
+        queue.used_addr = 0x3FF8;
+        assert!(queue.set_ready(1, 0..mem.len()).is_err());
+        assert!(!queue.ready);
+
+        queue.used_addr = USED + 4;
+        assert!(queue.set_ready(1, 0..mem.len()).unwrap_err().contains("aligned"));
+
+        queue.used_addr = USED;
+        queue.set_ready(1, 0..mem.len()).unwrap();
+        assert!(queue.ready);
+        queue.set_ready(0, 0..mem.len()).unwrap();
+        assert!(!queue.ready);
+    }
+
+    #[test]
+    fn test_ram_base_bounds_rings_and_buffers() {
+        let mut mem = GuestMemory::new_at(0x8000, 0x20000).unwrap();
+        let ram = mem.ram();
+
+        // Every ring of the default layout sits below the start of RAM
+        let mut queue = rx_queue(mem.as_mut_slice(), &[]);
+        queue.ready = false;
+        assert!(queue.set_ready(1, ram.clone()).unwrap_err().contains("below"));
+
+        let mut queue = rx_queue_at(mem.as_mut_slice(), 0x10000, &[(0x1000, 64), (0x18000, 64)]);
+        queue.ready = false;
+        queue.set_ready(1, ram).unwrap();
+        let mem = mem.as_mut_slice();
+        assert!(queue.read_desc(mem, 0).is_none());
+        assert_eq!(queue.read_desc(mem, 1).map(|desc| desc.addr), Some(0x18000));
+    }
+
+    #[test]
+    fn test_complete_checksum() {
+        // 4-byte prefix, then a UDP-like body with the checksum field at offset 6
+        let mut frame = vec![0xEE, 0xEE, 0xEE, 0xEE, 0x12, 0x34, 0x00, 0x50, 0x00, 0x0A, 0x00, 0x00, 0xAB];
+        assert!(complete_checksum(&mut frame, 4, 6));
+
+        let mut sum: u32 = frame[4..].chunks(2)
+            .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+            .sum();
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
         }
-        
-        if let Some(desc_idx) = queue.get_avail_desc_idx(mem) {
-            if let Some(desc) = queue.read_desc(mem, desc_idx) {
-                let addr = desc.addr as usize;
-                let desc_len = desc.len; // Copy to avoid packed field reference
-                let mut packet_buf = [0u8; 1514];
-                
-                if let Some(tap) = tap_guard.as_mut() {
-                    match tap.read(&mut packet_buf) {
-                        Ok(n) if n > 0 => {
-                            let hdr = VirtioNetHdr::default();
-                            let hdr_len = size_of::<VirtioNetHdr>();
-                            
-                            if (n + hdr_len) as u32 > desc_len {
-                                tracing::warn!(packet_size = n, buffer_size = desc_len, "Packet too big for buffer");
-                                return false;
-                            }
-                            
-                            if addr + hdr_len + n > mem.len() {
-                                tracing::error!("Buffer address out of bounds");
-                                return false;
-                            }
-                            
-                            unsafe {
-                                let dest_ptr = mem.as_mut_ptr().add(addr);
-                                std::ptr::copy_nonoverlapping(
-                                    &hdr as *const _ as *const u8,
-                                    dest_ptr,
-                                    hdr_len
-                                );
-                                std::ptr::copy_nonoverlapping(
-                                    packet_buf.as_ptr(),
-                                    dest_ptr.add(hdr_len),
-                                    n
-                                );
-                            }
-                            
-                            queue.add_used(mem, desc_idx, (n + hdr_len) as u32);
-                            
-                            let mut int_status = self.interrupt_status.lock().unwrap();
-                            *int_status |= 1;
-                            
-                            tracing::debug!(bytes = n, "RX packet processed");
-                            return true;
-                        },
-                        _ => {}
-                    }
-                }
-            }
+        assert_eq!(sum, 0xFFFF);
+        assert_eq!(&frame[..4], &[0xEE; 4]);
+
+        assert!(!complete_checksum(&mut frame, 4, 8));
+    }
+
+    #[test]
+    fn test_config_reports_link_down_without_tap() {
+        let net = VirtioNet::new(None).with_mac([2, 0, 0, 0xAA, 0xBB, 0xCC]);
+        let mut data = [0u8; 4];
+        net.read(MMIO_DEVICE_FEATURES, &mut data).unwrap();
+        assert_ne!(u32::from_le_bytes(data) as u64 & VIRTIO_NET_F_STATUS, 0);
+
+        let mut mac = [0u8; 4];
+        net.read(MMIO_CONFIG_SPACE + 2, &mut mac).unwrap();
+        assert_eq!(mac, [0, 0xAA, 0xBB, 0xCC]);
+        let mut status = [0xFFu8; 2];
+        net.read(MMIO_CONFIG_SPACE + 6, &mut status).unwrap();
+        assert_eq!(u16::from_le_bytes(status), 0);
+    }
+
+    #[test]
+    fn test_partial_width_register_access() {
+        let net = VirtioNet::new(None);
+        let mut mem = GuestMemory::new(0x10000).unwrap();
+
+        for (i, byte) in 0x12345000u32.to_le_bytes().into_iter().enumerate() {
+            net.write(MMIO_QUEUE_DESC_LOW + i as u64, &[byte], &mut mem).unwrap();
         }
-        
-        false
+        net.write(MMIO_QUEUE_DESC_HIGH, &0x0001u16.to_le_bytes(), &mut mem).unwrap();
+        net.write(MMIO_QUEUE_DESC_HIGH + 2, &0x0002u16.to_le_bytes(), &mut mem).unwrap();
+        assert_eq!(net.queues.lock().unwrap()[0].desc_addr, 0x0002_0001_1234_5000);
+
+        let mut byte = [0u8; 1];
+        net.read(MMIO_MAGIC_VALUE + 1, &mut byte).unwrap();
+        assert_eq!(byte, [b'i']);
+        let mut half = [0u8; 2];
+        net.read(MMIO_VENDOR_ID + 2, &mut half).unwrap();
+        assert_eq!(half, [0, 0]);
+        net.read(MMIO_VENDOR_ID, &mut half).unwrap();
+        assert_eq!(u16::from_le_bytes(half), 0x1AF4);
     }
-    
-    pub fn should_interrupt(&self) -> bool {
-        *self.interrupt_status.lock().unwrap() != 0
+
+    #[test]
+    fn test_mergeable_rx_spans_buffers() {
+        let mut mem = vec![0u8; 0x10000];
+        let mut queue = rx_queue(&mut mem, &[(0x4000, 64), (0x5000, 64), (0x6000, 64), (0x7000, 64)]);
+        let packet: Vec<u8> = (0..150u8).collect();
+
+        assert!(deliver_mergeable(&mut queue, &mut mem, &packet));
+        assert_eq!(used_idx(&mem), 3);
+        assert_eq!(u16::from_le_bytes([mem[0x4000 + 10], mem[0x4000 + 11]]), 3);
+
+        let hdr_len = size_of::<VirtioNetHdr>();
+        let mut received = mem[0x4000 + hdr_len..0x4040].to_vec();
+        received.extend_from_slice(&mem[0x5000..0x5040]);
+        received.extend_from_slice(&mem[0x6000..0x6000 + (150 + hdr_len - 128)]);
+        assert_eq!(received, packet);
+
+        let last = (USED + 4 + 2 * 8) as usize;
+        assert_eq!(u32::from_le_bytes(mem[last + 4..last + 8].try_into().unwrap()), (150 + hdr_len - 128) as u32);
     }
-    
-    pub fn process_tx(&self, mem: &mut [u8]) -> bool {
-        let mut tap_guard = self.tap.lock().unwrap();
-        if tap_guard.is_none() {
-            return false;
+
+    #[test]
+    fn test_rx_delivers_a_batch_with_one_interrupt() {
+        let mut user = UserNet::new([2, 0, 0, 0, 0, 1], &[]).unwrap();
+        // Two ARP requests for the gateway: two replies waiting
+        let mut request = vec![0xFF; 6];
+        request.extend_from_slice(&[2, 0, 0, 0, 0, 1, 0x08, 0x06, 0, 1, 8, 0, 6, 4, 0, 1, 2, 0, 0, 0, 0, 1]);
+        request.extend_from_slice(&[10, 0, 2, 15, 0, 0, 0, 0, 0, 0, 10, 0, 2, 2]);
+        user.write(&request).unwrap();
+        user.write(&request).unwrap();
+
+        let net = VirtioNet::new(Some(Box::new(user)));
+        let mut mem = vec![0u8; 0x10000];
+        net.queues.lock().unwrap()[0] = rx_queue(&mut mem, &[(0x4000, 128), (0x5000, 128), (0x6000, 128)]);
+
+        assert!(net.process_rx(&mut mem).unwrap());
+        assert_eq!(used_idx(&mem), 2);
+        assert!(!net.process_rx(&mut mem).unwrap());
+    }
+
+    #[test]
+    fn test_loopback_returns_tx_frames_on_rx() {
+        let net = VirtioNet::new(Some(Box::new(LoopbackBackend::new())));
+        let mut mem = vec![0u8; 0x10000];
+        let hdr_len = size_of::<VirtioNetHdr>();
+        let frame: Vec<u8> = (0..60).collect();
+        mem[0x4000 + hdr_len..0x4000 + hdr_len + frame.len()].copy_from_slice(&frame);
+        net.queues.lock().unwrap()[1] = rx_queue(&mut mem, &[(0x4000, (hdr_len + frame.len()) as u32)]);
+        net.process_tx(&mut mem).unwrap();
+        assert_eq!(used_idx(&mem), 1);
+
+        // Reuse the same ring area for RX
+        mem[USED as usize..USED as usize + 4].fill(0);
+        net.queues.lock().unwrap()[0] = rx_queue(&mut mem, &[(0x5000, 128)]);
+        assert!(net.process_rx(&mut mem).unwrap());
+        assert_eq!(used_idx(&mem), 1);
+        assert_eq!(&mem[0x5000 + hdr_len..0x5000 + hdr_len + frame.len()], &frame[..]);
+        assert!(!net.process_rx(&mut mem).unwrap());
+    }
+
+    #[test]
+    fn test_ctrl_queue_acks_commands() {
+        let net = VirtioNet::new(None);
+        let mut mem = vec![0u8; 0x10000];
+        {
+            let mut queues = net.queues.lock().unwrap();
+            queues[net.ctrl_queue().unwrap()] = rx_queue(&mut mem, &[(0x4000, 2), (0x4010, 1), (0x4020, 1), (0x5000, 2), (0x5020, 1)]);
         }
-        
-        let mut queues = self.queues.lock().unwrap();
-        let queue = &mut queues[1]; // TX Queue
-        
-        if !queue.ready {
-            return false;
+        // Chain 0 -> 1 -> 2: CTRL_RX/PROMISC, on, ack
+        let set_desc = |mem: &mut [u8], i: usize, flags: u16, next: u16| {
+            let d = DESC as usize + i * 16;
+            mem[d + 12..d + 14].copy_from_slice(&flags.to_le_bytes());
+            mem[d + 14..d + 16].copy_from_slice(&next.to_le_bytes());
+        };
+        set_desc(&mut mem, 0, VRING_DESC_F_NEXT, 1);
+        set_desc(&mut mem, 1, VRING_DESC_F_NEXT, 2);
+        set_desc(&mut mem, 2, VRING_DESC_F_WRITE, 0);
+        mem[0x4000..0x4002].copy_from_slice(&[VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC]);
+        mem[0x4010] = 1;
+        mem[0x4020] = 0xFF;
+        // Chain 3 -> 4: unknown class 0x7F, ack
+        set_desc(&mut mem, 3, VRING_DESC_F_NEXT, 4);
+        set_desc(&mut mem, 4, VRING_DESC_F_WRITE, 0);
+        mem[0x5000..0x5002].copy_from_slice(&[0x7F, 0]);
+        mem[0x5020] = 0xFF;
+        // Publish only the two chain heads
+        let a = AVAIL as usize;
+        mem[a + 4..a + 6].copy_from_slice(&0u16.to_le_bytes());
+        mem[a + 6..a + 8].copy_from_slice(&3u16.to_le_bytes());
+        mem[a + 2..a + 4].copy_from_slice(&2u16.to_le_bytes());
+
+        assert!(net.process_ctrl(&mut mem).unwrap());
+        assert_eq!(mem[0x4020], VIRTIO_NET_OK);
+        assert_eq!(mem[0x5020], VIRTIO_NET_ERR);
+        assert_eq!(used_idx(&mem), 2);
+        assert!(net.should_interrupt().unwrap());
+    }
+
+    #[test]
+    fn test_mq_spreads_rx_over_active_pairs() {
+        let mut loopback = LoopbackBackend::new();
+        for i in 0..4u8 {
+            loopback.write(&[i; 60]).unwrap();
         }
-        
-        let mut work_done = false;
-        
-        while let Some(desc_idx) = queue.get_avail_desc_idx(mem) {
-            if let Some(desc) = queue.read_desc(mem, desc_idx) {
-                let addr = desc.addr as usize;
-                let desc_len = desc.len as usize;
-                let hdr_len = size_of::<VirtioNetHdr>();
-                
-                if desc_len > hdr_len && addr + desc_len <= mem.len() {
-                    let packet_slice = &mem[addr + hdr_len..addr + desc_len];
-                    
-                    if let Some(tap) = tap_guard.as_mut() {
-                        match tap.write(packet_slice) {
-                            Ok(n) => {
-                                tracing::debug!(bytes = n, "TX packet sent");
-                                work_done = true;
-                            },
-                            Err(e) => {
-                                tracing::warn!(error = %e, "Failed to write to TAP");
-                            }
-                        }
-                    }
-                }
-                
-                queue.add_used(mem, desc_idx, 0);
-                
-                let mut int_status = self.interrupt_status.lock().unwrap();
-                *int_status |= 1;
-            } else {
-                break;
-            }
+        let net = VirtioNet::new(Some(Box::new(loopback)));
+        let mut mem = vec![0u8; 0x20000];
+        let mut config = [0u8; 2];
+        net.read(MMIO_CONFIG_SPACE + 8, &mut config).unwrap();
+        assert_eq!(u16::from_le_bytes(config), MAX_QUEUE_PAIRS);
+
+        // With MQ the control queue moves behind the last pair
+        *net.driver_features.lock().unwrap() = VIRTIO_NET_F_MQ | VIRTIO_NET_F_CTRL_VQ;
+        assert_eq!(net.ctrl_queue().unwrap(), 4);
+        {
+            let mut queues = net.queues.lock().unwrap();
+            queues[0] = rx_queue_at(&mut mem, 0, &[(0x10000, 128), (0x10100, 128)]);
+            queues[2] = rx_queue_at(&mut mem, 0x4000, &[(0x11000, 128), (0x11100, 128)]);
+            queues[4] = rx_queue_at(&mut mem, 0x8000, &[(0x12000, 4), (0x12010, 1)]);
         }
-        
-        work_done
+        // One pair until the driver asks for more
+        assert!(net.process_rx(&mut mem).unwrap());
+        assert_eq!((used_idx_at(&mem, 0), used_idx_at(&mem, 0x4000)), (2, 0));
+
+        // VQ_PAIRS_SET 2, then an ack buffer
+        let d = (DESC + 0x8000) as usize;
+        mem[d + 12..d + 14].copy_from_slice(&VRING_DESC_F_NEXT.to_le_bytes());
+        mem[d + 14..d + 16].copy_from_slice(&1u16.to_le_bytes());
+        mem[d + 16 + 12..d + 16 + 14].copy_from_slice(&VRING_DESC_F_WRITE.to_le_bytes());
+        let a = (AVAIL + 0x8000) as usize;
+        mem[a + 2..a + 4].copy_from_slice(&1u16.to_le_bytes());
+        mem[0x12000..0x12004].copy_from_slice(&[VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, 2, 0]);
+        mem[0x12010] = 0xFF;
+        assert!(net.process_ctrl(&mut mem).unwrap());
+        assert_eq!(mem[0x12010], VIRTIO_NET_OK);
+        assert_eq!(*net.active_pairs.lock().unwrap(), 2);
+
+        // Queue 0 is out of buffers, so both remaining frames land on pair 1
+        assert!(net.process_rx(&mut mem).unwrap());
+        assert_eq!(used_idx_at(&mem, 0x4000), 2);
+        let hdr_len = size_of::<VirtioNetHdr>();
+        assert_eq!(mem[0x11000 + hdr_len], 2);
+        assert_eq!(mem[0x11100 + hdr_len], 3);
+
+        net.write(MMIO_STATUS, &0u32.to_le_bytes(), &mut GuestMemory::new(0x10000).unwrap()).unwrap();
+        assert_eq!(*net.active_pairs.lock().unwrap(), 1);
     }
-}
 
-impl Default for VirtioNet {
-    fn default() -> Self {
-        Self::new(None)
+    #[test]
+    fn test_no_interrupt_flag_suppresses_interrupts() {
+        let mut mem = vec![0u8; 0x10000];
+        let mut queue = rx_queue(&mut mem, &[(0x4000, 64), (0x5000, 64)]);
+
+        mem[AVAIL as usize..AVAIL as usize + 2].copy_from_slice(&VRING_AVAIL_F_NO_INTERRUPT.to_le_bytes());
+        queue.add_used(&mut mem, 0, 64);
+        assert!(!queue.needs_interrupt(&mem));
+
+        mem[AVAIL as usize..AVAIL as usize + 2].copy_from_slice(&0u16.to_le_bytes());
+        queue.add_used(&mut mem, 1, 64);
+        assert!(queue.needs_interrupt(&mem));
+    }
+
+    #[test]
+    fn test_mergeable_rx_without_room_consumes_nothing() {
+        let mut mem = vec![0u8; 0x10000];
+        let mut queue = rx_queue(&mut mem, &[(0x4000, 64), (0x5000, 64)]);
+
+        assert!(!deliver_mergeable(&mut queue, &mut mem, &[0xAB; 200]));
+        assert_eq!(used_idx(&mem), 0);
+        assert_eq!(queue.last_avail_idx, 0);
+    }
+
+    #[test]
+    fn test_queue_size_is_fixed_once_ready() {
+        let net = VirtioNet::new(Some(Box::new(LoopbackBackend::new())));
+        let mut mem = GuestMemory::new(0x10000).unwrap();
+        let write = |net: &VirtioNet, mem: &mut GuestMemory, reg: u64, val: u32| {
+            net.write(reg, &val.to_le_bytes(), mem).unwrap();
+        };
+        for queue in 0..2 {
+            write(&net, &mut mem, MMIO_QUEUE_SEL, queue);
+            write(&net, &mut mem, MMIO_QUEUE_NUM, 3);
+            assert_eq!(net.queues.lock().unwrap()[queue as usize].queue_size, 0);
+            write(&net, &mut mem, MMIO_QUEUE_NUM, 16);
+            write(&net, &mut mem, MMIO_QUEUE_NUM, 3);
+            write(&net, &mut mem, MMIO_QUEUE_DESC_LOW, DESC as u32 + queue * 0x4000);
+            write(&net, &mut mem, MMIO_QUEUE_AVAIL_LOW, AVAIL as u32 + queue * 0x4000);
+            write(&net, &mut mem, MMIO_QUEUE_USED_LOW, USED as u32 + queue * 0x4000);
+            write(&net, &mut mem, MMIO_QUEUE_READY, 1);
+            write(&net, &mut mem, MMIO_QUEUE_NUM, 0);
+            let q = net.queues.lock().unwrap()[queue as usize];
+            assert!(q.ready);
+            assert_eq!(q.queue_size, 16);
+        }
+
+        // The guest publishes a TX buffer; a zero-sized queue is skipped
+        let a = (AVAIL + 0x4000) as usize;
+        mem.as_mut_slice()[a + 2..a + 4].copy_from_slice(&1u16.to_le_bytes());
+        net.queues.lock().unwrap()[1].queue_size = 0;
+        write(&net, &mut mem, MMIO_QUEUE_NOTIFY, 1);
+        assert!(!net.process_tx(mem.as_mut_slice()).unwrap());
+        assert!(!net.process_rx(mem.as_mut_slice()).unwrap());
+    }
+
+    #[test]
+    fn test_ring_accessors_survive_wrapping_addresses() {
+        let net = VirtioNet::new(None);
+        let mut mem = GuestMemory::new(0x10000).unwrap();
+        net.queues.lock().unwrap()[0] = rx_queue(mem.as_mut_slice(), &[(0x4000, 64)]);
+        net.write(MMIO_QUEUE_DESC_HIGH, &0xFFFF_FFFFu32.to_le_bytes(), &mut mem).unwrap();
+        net.write(MMIO_QUEUE_USED_LOW, &0u32.to_le_bytes(), &mut mem).unwrap();
+        let queue = net.queues.lock().unwrap()[0];
+        assert_eq!((queue.desc_addr, queue.used_addr), (DESC, USED));
+
+        let mem = mem.as_mut_slice();
+        let mut queue = rx_queue(mem, &[(0x4000, 64)]);
+        queue.desc_addr = u64::MAX - 8;
+        queue.used_addr = u64::MAX - 2;
+        assert!(queue.read_desc(mem, 0).is_none());
+        queue.add_used(mem, 0, 64);
+        assert_eq!(used_idx(mem), 0);
+        queue.avail_addr = u64::MAX - 1;
+        assert_eq!(queue.get_avail_desc_idx(mem), None);
+        assert!(queue.needs_interrupt(mem));
     }
 }
+
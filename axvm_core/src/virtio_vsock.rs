@@ -0,0 +1,617 @@
+// src/virtio_vsock.rs
+use crate::memory::{guest_slice, guest_slice_mut, GuestMemory};
+use crate::error::{AxvmResult, LockExt};
+use crate::metrics::VmMetrics;
+use crate::mmio::{self, MmioDevice, RegisterLatch};
+use crate::virtio::{
+    VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_VERSION, VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_VENDOR_ID,
+    VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL, VIRTIO_MMIO_DRIVER_FEATURES,
+    VIRTIO_MMIO_DRIVER_FEATURES_SEL, VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_NUM_MAX,
+    VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_READY, VIRTIO_MMIO_QUEUE_NOTIFY,
+    VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_STATUS,
+    VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_DESC_HIGH, VIRTIO_MMIO_QUEUE_AVAIL_LOW,
+    VIRTIO_MMIO_QUEUE_AVAIL_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW, VIRTIO_MMIO_QUEUE_USED_HIGH,
+    VIRTIO_MMIO_CONFIG, latch_status, QUEUE_NUM_MAX,
+};
+use crate::virtio_net::VirtQueue;
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+
+const DEVICE_ID_VSOCK: u32 = 19;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Well-known CID of the host side of the socket
+pub const VSOCK_HOST_CID: u64 = 2;
+
+// Queue indices (virtio spec 5.10.2)
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const NUM_QUEUES: usize = 3;
+
+const VRING_DESC_F_NEXT: u16 = 1;
+const VRING_DESC_F_WRITE: u16 = 2;
+
+const VSOCK_TYPE_STREAM: u16 = 1;
+
+const VSOCK_OP_REQUEST: u16 = 1;
+const VSOCK_OP_RESPONSE: u16 = 2;
+const VSOCK_OP_RST: u16 = 3;
+const VSOCK_OP_SHUTDOWN: u16 = 4;
+const VSOCK_OP_RW: u16 = 5;
+const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+// Receive buffer space advertised to the guest for every connection
+const VSOCK_BUF_ALLOC: u32 = 256 * 1024;
+
+// Upper bound for a single TX packet payload
+const MAX_PKT_PAYLOAD: usize = 64 * 1024;
+
+// Replies held for the guest's RX buffers; once full, TX is left unprocessed
+const MAX_PENDING_RX: usize = 64;
+
+// Packet header (must precede every packet)
+#[repr(C, packed)]
+#[derive(Default, Debug, Clone, Copy)]
+struct VsockHdr {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl VsockHdr {
+    fn from_bytes(b: &[u8]) -> Option<Self> {
+        if b.len() < size_of::<VsockHdr>() {
+            return None;
+        }
+        Some(unsafe { std::ptr::read_unaligned(b.as_ptr() as *const VsockHdr) })
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self as *const _ as *const u8, size_of::<VsockHdr>()) }
+    }
+
+    // Header for a reply travelling in the opposite direction
+    fn reply(&self, op: u16, len: u32, fwd_cnt: u32) -> Self {
+        VsockHdr {
+            src_cid: self.dst_cid,
+            dst_cid: self.src_cid,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            len,
+            type_: VSOCK_TYPE_STREAM,
+            op,
+            flags: 0,
+            buf_alloc: VSOCK_BUF_ALLOC,
+            fwd_cnt,
+        }
+    }
+}
+
+/// VirtIO socket device with a loopback backend.
+///
+/// Connections the guest opens towards the host CID are accepted and every
+/// byte written to them is echoed back on the same stream.
+pub struct VirtioVsock {
+    guest_cid: u64,
+
+    status: Mutex<u32>,
+    device_features_sel: Mutex<u32>,
+    driver_features_sel: Mutex<u32>,
+    driver_features: Mutex<u64>,
+    queue_sel: Mutex<u32>,
+
+    queues: Mutex<[VirtQueue; NUM_QUEUES]>,
+    interrupt_status: Mutex<u32>,
+    registers: RegisterLatch,
+
+    // Packets waiting for an RX buffer from the guest
+    pending_rx: Mutex<VecDeque<Vec<u8>>>,
+    // Bytes consumed per connection, keyed by (guest port, host port)
+    connections: Mutex<HashMap<(u32, u32), u32>>,
+    queue_num_max: u16,
+    metrics: Option<Arc<VmMetrics>>,
+}
+
+impl VirtioVsock {
+    pub fn new(guest_cid: u64) -> Self {
+        status!("Vsock", guest_cid = guest_cid, "VirtIO-Vsock device initialized (guest CID {})", guest_cid);
+
+        VirtioVsock {
+            guest_cid,
+            status: Mutex::new(0),
+            device_features_sel: Mutex::new(0),
+            driver_features_sel: Mutex::new(0),
+            driver_features: Mutex::new(0),
+            queue_sel: Mutex::new(0),
+            queues: Mutex::new([VirtQueue::new(), VirtQueue::new(), VirtQueue::new()]),
+            interrupt_status: Mutex::new(0),
+            registers: RegisterLatch::new(),
+            pending_rx: Mutex::new(VecDeque::new()),
+            connections: Mutex::new(HashMap::new()),
+            queue_num_max: QUEUE_NUM_MAX,
+            metrics: None,
+        }
+    }
+
+    /// Advertise `max` instead of QUEUE_NUM_MAX as the largest queue
+    pub fn with_queue_size(mut self, max: u16) -> Self {
+        self.queue_num_max = max;
+        self
+    }
+
+    /// Count a FAILED device status as an error in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<VmMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    fn with_selected_queue<F: FnOnce(&mut VirtQueue)>(&self, f: F) -> AxvmResult<()> {
+        let sel = *self.queue_sel.lock_or_err()? as usize;
+        if sel < NUM_QUEUES {
+            f(&mut self.queues.lock_or_err()?[sel]);
+        }
+        Ok(())
+    }
+
+    fn set_ring_addr(&self, ring: fn(&mut VirtQueue) -> &mut u64, high: bool, val: u32) -> AxvmResult<()> {
+        self.with_selected_queue(|q| {
+            if let Err(e) = q.set_ring_addr(ring, high, val) {
+                tracing::warn!(error = %e, "VirtIO-Vsock: ignoring ring address");
+            }
+        })
+    }
+
+    fn reset(&self) -> AxvmResult<()> {
+        *self.queues.lock_or_err()? = [VirtQueue::new(), VirtQueue::new(), VirtQueue::new()];
+        *self.queue_sel.lock_or_err()? = 0;
+        self.pending_rx.lock_or_err()?.clear();
+        self.connections.lock_or_err()?.clear();
+        *self.interrupt_status.lock_or_err()? = 0;
+        tracing::info!("VirtIO-Vsock device reset");
+        Ok(())
+    }
+
+    // Drain TX, then hand any queued replies to the guest. TX stalls while
+    // the replies pile up, so it is retried whenever RX makes room.
+    fn process_queues(&self, mem: &mut [u8]) -> AxvmResult<bool> {
+        let mut work_done = false;
+        loop {
+            let tx_work = self.process_tx(mem)?;
+            let rx_work = self.process_rx(mem)?;
+            work_done |= tx_work || rx_work;
+            if !rx_work {
+                break;
+            }
+        }
+
+        if work_done {
+            *self.interrupt_status.lock_or_err()? |= 1;
+        }
+        Ok(work_done)
+    }
+
+    fn process_tx(&self, mem: &mut [u8]) -> AxvmResult<bool> {
+        let mut queues = self.queues.lock_or_err()?;
+        let queue = &mut queues[TX_QUEUE];
+        if !queue.is_live() {
+            return Ok(false);
+        }
+
+        let mut work_done = false;
+        // Every packet queues at most one reply
+        while self.pending_rx.lock_or_err()?.len() < MAX_PENDING_RX {
+            let Some(head) = queue.get_avail_desc_idx(mem) else { break };
+            match read_chain(queue, mem, head) {
+                Some(packet) => if let Some(hdr) = VsockHdr::from_bytes(&packet) {
+                    let payload_len = (hdr.len as usize).min(packet.len() - size_of::<VsockHdr>());
+                    let payload = &packet[size_of::<VsockHdr>()..size_of::<VsockHdr>() + payload_len];
+                    self.handle_packet(&hdr, payload)?;
+                },
+                None => tracing::warn!(head = head, "VirtIO-Vsock: dropping TX chain outside guest RAM"),
+            }
+            queue.add_used(mem, head, 0);
+            work_done = true;
+        }
+        Ok(work_done)
+    }
+
+    fn process_rx(&self, mem: &mut [u8]) -> AxvmResult<bool> {
+        let mut queues = self.queues.lock_or_err()?;
+        let queue = &mut queues[RX_QUEUE];
+        if !queue.is_live() {
+            return Ok(false);
+        }
+
+        let mut pending = self.pending_rx.lock_or_err()?;
+        let mut work_done = false;
+        while let Some(packet) = pending.front() {
+            let head = match queue.get_avail_desc_idx(mem) {
+                Some(h) => h,
+                None => break,
+            };
+            let written = write_chain(queue, mem, head, packet).unwrap_or_else(|| {
+                tracing::warn!(head = head, "VirtIO-Vsock: dropping RX packet, buffer outside guest RAM");
+                0
+            });
+            queue.add_used(mem, head, written);
+            pending.pop_front();
+            work_done = true;
+        }
+        Ok(work_done)
+    }
+
+    fn handle_packet(&self, hdr: &VsockHdr, payload: &[u8]) -> AxvmResult<()> {
+        let op = hdr.op;
+        let dst_cid = hdr.dst_cid;
+        let key = (hdr.src_port, hdr.dst_port);
+
+        if hdr.type_ != VSOCK_TYPE_STREAM || dst_cid != VSOCK_HOST_CID {
+            if op != VSOCK_OP_RST {
+                self.queue_reply(hdr.reply(VSOCK_OP_RST, 0, 0), &[])?;
+            }
+            return Ok(());
+        }
+
+        let mut connections = self.connections.lock_or_err()?;
+        match op {
+            VSOCK_OP_REQUEST => {
+                connections.insert(key, 0);
+                tracing::debug!(src_port = key.0, dst_port = key.1, "Vsock connection accepted");
+                self.queue_reply(hdr.reply(VSOCK_OP_RESPONSE, 0, 0), &[])?;
+            },
+            VSOCK_OP_RW => match connections.get_mut(&key) {
+                Some(fwd_cnt) => {
+                    *fwd_cnt = fwd_cnt.wrapping_add(payload.len() as u32);
+                    self.queue_reply(hdr.reply(VSOCK_OP_RW, payload.len() as u32, *fwd_cnt), payload)?;
+                },
+                None => self.queue_reply(hdr.reply(VSOCK_OP_RST, 0, 0), &[])?,
+            },
+            VSOCK_OP_CREDIT_REQUEST => {
+                let fwd_cnt = connections.get(&key).copied().unwrap_or(0);
+                self.queue_reply(hdr.reply(VSOCK_OP_CREDIT_UPDATE, 0, fwd_cnt), &[])?;
+            },
+            VSOCK_OP_SHUTDOWN => {
+                connections.remove(&key);
+                self.queue_reply(hdr.reply(VSOCK_OP_RST, 0, 0), &[])?;
+            },
+            VSOCK_OP_RST => {
+                connections.remove(&key);
+            },
+            VSOCK_OP_CREDIT_UPDATE => {},
+            _ => {
+                tracing::debug!(op = op, "Unknown vsock op");
+            }
+        }
+        Ok(())
+    }
+
+    fn queue_reply(&self, hdr: VsockHdr, payload: &[u8]) -> AxvmResult<()> {
+        let mut packet = Vec::with_capacity(size_of::<VsockHdr>() + payload.len());
+        packet.extend_from_slice(hdr.as_bytes());
+        packet.extend_from_slice(payload);
+        self.pending_rx.lock_or_err()?.push_back(packet);
+        Ok(())
+    }
+}
+
+impl MmioDevice for VirtioVsock {
+    fn read(&self, offset: u64, data: &mut [u8]) -> AxvmResult<()> {
+        mmio::read_registers(offset, data, |reg| Ok(match reg {
+            VIRTIO_MMIO_MAGIC_VALUE => 0x74726976,
+            VIRTIO_MMIO_VERSION => 2,
+            VIRTIO_MMIO_DEVICE_ID => DEVICE_ID_VSOCK,
+            VIRTIO_MMIO_VENDOR_ID => 0x1AF4,
+            VIRTIO_MMIO_DEVICE_FEATURES => match *self.device_features_sel.lock_or_err()? {
+                1 => (VIRTIO_F_VERSION_1 >> 32) as u32,
+                _ => 0,
+            },
+            VIRTIO_MMIO_QUEUE_NUM_MAX => self.queue_num_max as u32,
+            VIRTIO_MMIO_QUEUE_READY => {
+                let sel = *self.queue_sel.lock_or_err()? as usize;
+                if sel < NUM_QUEUES {
+                    self.queues.lock_or_err()?[sel].ready as u32
+                } else {
+                    0
+                }
+            },
+            VIRTIO_MMIO_INTERRUPT_STATUS => *self.interrupt_status.lock_or_err()?,
+            VIRTIO_MMIO_STATUS => *self.status.lock_or_err()?,
+            // guest_cid (le64)
+            VIRTIO_MMIO_CONFIG => (self.guest_cid & 0xFFFFFFFF) as u32,
+            0x104 => (self.guest_cid >> 32) as u32,
+            _ => 0,
+        }))
+    }
+
+    fn write(&self, offset: u64, data: &[u8], mem: &mut GuestMemory) -> AxvmResult<()> {
+        self.registers.write(offset, data, |reg, val| {
+            match reg {
+                VIRTIO_MMIO_DEVICE_FEATURES_SEL => *self.device_features_sel.lock_or_err()? = val,
+                VIRTIO_MMIO_DRIVER_FEATURES_SEL => *self.driver_features_sel.lock_or_err()? = val,
+                VIRTIO_MMIO_DRIVER_FEATURES => {
+                    let sel = *self.driver_features_sel.lock_or_err()?;
+                    let mut features = self.driver_features.lock_or_err()?;
+                    if sel == 0 {
+                        *features = (*features & 0xFFFFFFFF00000000) | (val as u64);
+                    } else {
+                        *features = (*features & 0x00000000FFFFFFFF) | ((val as u64) << 32);
+                    }
+                },
+                VIRTIO_MMIO_QUEUE_SEL => *self.queue_sel.lock_or_err()? = val,
+                VIRTIO_MMIO_QUEUE_NUM => self.with_selected_queue(|q| {
+                    if let Err(e) = q.set_size(val, self.queue_num_max) {
+                        tracing::warn!(error = %e, "VirtIO-Vsock: rejecting queue size");
+                    }
+                })?,
+                VIRTIO_MMIO_QUEUE_READY => {
+                    let ram = mem.ram();
+                    self.with_selected_queue(|q| {
+                        if let Err(e) = q.set_ready(val, ram) {
+                            tracing::warn!(error = %e, "VirtIO-Vsock: refusing to enable queue");
+                        }
+                    })?
+                },
+                VIRTIO_MMIO_QUEUE_DESC_LOW => self.set_ring_addr(|q| &mut q.desc_addr, false, val)?,
+                VIRTIO_MMIO_QUEUE_DESC_HIGH => self.set_ring_addr(|q| &mut q.desc_addr, true, val)?,
+                VIRTIO_MMIO_QUEUE_AVAIL_LOW => self.set_ring_addr(|q| &mut q.avail_addr, false, val)?,
+                VIRTIO_MMIO_QUEUE_AVAIL_HIGH => self.set_ring_addr(|q| &mut q.avail_addr, true, val)?,
+                VIRTIO_MMIO_QUEUE_USED_LOW => self.set_ring_addr(|q| &mut q.used_addr, false, val)?,
+                VIRTIO_MMIO_QUEUE_USED_HIGH => self.set_ring_addr(|q| &mut q.used_addr, true, val)?,
+                VIRTIO_MMIO_QUEUE_NOTIFY => {
+                    self.process_queues(mem.as_mut_slice())?;
+                },
+                VIRTIO_MMIO_INTERRUPT_ACK => *self.interrupt_status.lock_or_err()? &= !val,
+                VIRTIO_MMIO_STATUS => {
+                    let accepted = *self.driver_features.lock_or_err()?;
+                    let mut status = self.status.lock_or_err()?;
+                    *status = latch_status("VirtIO-Vsock", *status, val, VIRTIO_F_VERSION_1, accepted, self.metrics.as_deref());
+                    drop(status);
+                    if val == 0 {
+                        self.reset()?;
+                    }
+                },
+                _ => {
+                    tracing::debug!(offset = reg, val = val, "Unknown VirtIO-Vsock write");
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn interrupt_level(&self) -> AxvmResult<bool> {
+        Ok(*self.interrupt_status.lock_or_err()? != 0)
+    }
+}
+
+// Gather the device-readable part of a descriptor chain; `None` if any
+// readable descriptor falls outside guest RAM.
+fn read_chain(queue: &VirtQueue, mem: &[u8], head: u16) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut idx = head;
+
+    for _ in 0..queue.queue_size {
+        let desc = match queue.read_desc(mem, idx) {
+            Some(d) => d,
+            None => break,
+        };
+        let (addr, len, flags) = (desc.addr, desc.len as usize, desc.flags);
+
+        if flags & VRING_DESC_F_WRITE == 0 {
+            let buf = guest_slice(mem, addr, len)?;
+            let take = len.min(size_of::<VsockHdr>() + MAX_PKT_PAYLOAD - out.len());
+            out.extend_from_slice(&buf[..take]);
+        }
+
+        if flags & VRING_DESC_F_NEXT == 0 {
+            break;
+        }
+        idx = desc.next;
+    }
+    Some(out)
+}
+
+// Scatter `data` over the device-writable part of a descriptor chain;
+// `None` if a buffer it needs falls outside guest RAM.
+fn write_chain(queue: &VirtQueue, mem: &mut [u8], head: u16, data: &[u8]) -> Option<u32> {
+    let mut written = 0usize;
+    let mut idx = head;
+
+    for _ in 0..queue.queue_size {
+        if written == data.len() {
+            break;
+        }
+        let desc = match queue.read_desc(mem, idx) {
+            Some(d) => d,
+            None => break,
+        };
+        let (addr, len, flags) = (desc.addr, desc.len as usize, desc.flags);
+
+        if flags & VRING_DESC_F_WRITE != 0 {
+            let n = len.min(data.len() - written);
+            guest_slice_mut(mem, addr, n)?.copy_from_slice(&data[written..written + n]);
+            written += n;
+        }
+
+        if flags & VRING_DESC_F_NEXT == 0 {
+            break;
+        }
+        idx = desc.next;
+    }
+    Some(written as u32)
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_CID: u64 = 3;
+    const QUEUE_SIZE: u16 = 256;
+    const TX_RINGS: u64 = 0x10000;
+    const RX_RINGS: u64 = 0x20000;
+
+    // Descriptor table, avail and used ring 4 KiB apart
+    fn queue_at(base: u64) -> VirtQueue {
+        let mut queue = VirtQueue::new();
+        queue.desc_addr = base;
+        queue.avail_addr = base + 0x1000;
+        queue.used_addr = base + 0x2000;
+        queue.queue_size = QUEUE_SIZE;
+        queue.ready = true;
+        queue
+    }
+
+    fn device() -> VirtioVsock {
+        let vsock = VirtioVsock::new(GUEST_CID);
+        {
+            let mut queues = vsock.queues.lock().unwrap();
+            queues[TX_QUEUE] = queue_at(TX_RINGS);
+            queues[RX_QUEUE] = queue_at(RX_RINGS);
+        }
+        vsock
+    }
+
+    // Fill descriptor `desc` and make it available as a single-descriptor chain
+    fn post(mem: &mut [u8], rings: u64, desc: u16, addr: u64, len: u32, flags: u16) {
+        let d = (rings + desc as u64 * 16) as usize;
+        mem[d..d + 8].copy_from_slice(&addr.to_le_bytes());
+        mem[d + 8..d + 12].copy_from_slice(&len.to_le_bytes());
+        mem[d + 12..d + 14].copy_from_slice(&flags.to_le_bytes());
+        let a = (rings + 0x1000) as usize;
+        let idx = u16::from_le_bytes([mem[a + 2], mem[a + 3]]);
+        let slot = a + 4 + (idx % QUEUE_SIZE) as usize * 2;
+        mem[slot..slot + 2].copy_from_slice(&desc.to_le_bytes());
+        mem[a + 2..a + 4].copy_from_slice(&idx.wrapping_add(1).to_le_bytes());
+    }
+
+    fn used_idx(mem: &[u8], rings: u64) -> u16 {
+        let u = (rings + 0x2000) as usize;
+        u16::from_le_bytes([mem[u + 2], mem[u + 3]])
+    }
+
+    fn packet(op: u16, src_port: u32, payload: &[u8]) -> Vec<u8> {
+        let hdr = VsockHdr {
+            src_cid: GUEST_CID,
+            dst_cid: VSOCK_HOST_CID,
+            src_port,
+            dst_port: 5000,
+            len: payload.len() as u32,
+            type_: VSOCK_TYPE_STREAM,
+            op,
+            ..Default::default()
+        };
+        let mut bytes = hdr.as_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    // Send `bytes` from the guest and return the one reply it produces
+    fn send(vsock: &VirtioVsock, mem: &mut [u8], bytes: &[u8]) -> (VsockHdr, Vec<u8>) {
+        mem[0x30000..0x30000 + bytes.len()].copy_from_slice(bytes);
+        post(mem, TX_RINGS, 0, 0x30000, bytes.len() as u32, 0);
+        mem[0x31000..0x32000].fill(0);
+        post(mem, RX_RINGS, 0, 0x31000, 0x1000, VRING_DESC_F_WRITE);
+        assert!(vsock.process_queues(mem).unwrap());
+        let reply = &mem[0x31000..0x32000];
+        let hdr = VsockHdr::from_bytes(reply).unwrap();
+        let hdr_len = size_of::<VsockHdr>();
+        (hdr, reply[hdr_len..hdr_len + hdr.len as usize].to_vec())
+    }
+
+    #[test]
+    fn test_header_parsing_and_reply() {
+        assert_eq!(size_of::<VsockHdr>(), 44);
+        assert!(VsockHdr::from_bytes(&[0u8; 43]).is_none());
+
+        let bytes = packet(VSOCK_OP_REQUEST, 1000, b"");
+        let hdr = VsockHdr::from_bytes(&bytes).unwrap();
+        let (src_cid, op, src_port) = (hdr.src_cid, hdr.op, hdr.src_port);
+        assert_eq!((src_cid, op, src_port), (GUEST_CID, VSOCK_OP_REQUEST, 1000));
+
+        let reply = hdr.reply(VSOCK_OP_RESPONSE, 7, 9);
+        let (src_cid, dst_cid, src_port, dst_port) = (reply.src_cid, reply.dst_cid, reply.src_port, reply.dst_port);
+        assert_eq!((src_cid, dst_cid, src_port, dst_port), (VSOCK_HOST_CID, GUEST_CID, 5000, 1000));
+        let (len, fwd_cnt, buf_alloc) = (reply.len, reply.fwd_cnt, reply.buf_alloc);
+        assert_eq!((len, fwd_cnt, buf_alloc), (7, 9, VSOCK_BUF_ALLOC));
+    }
+
+    #[test]
+    fn test_connect_echo_and_reset() {
+        let vsock = device();
+        let mut mem = vec![0u8; 0x40000];
+
+        let (hdr, _) = send(&vsock, &mut mem, &packet(VSOCK_OP_REQUEST, 1000, b""));
+        let (op, dst_port) = (hdr.op, hdr.dst_port);
+        assert_eq!((op, dst_port), (VSOCK_OP_RESPONSE, 1000));
+
+        let (hdr, payload) = send(&vsock, &mut mem, &packet(VSOCK_OP_RW, 1000, b"ping"));
+        let (op, fwd_cnt) = (hdr.op, hdr.fwd_cnt);
+        assert_eq!((op, fwd_cnt, payload.as_slice()), (VSOCK_OP_RW, 4, &b"ping"[..]));
+
+        // Data on a port that never connected is refused
+        let (hdr, _) = send(&vsock, &mut mem, &packet(VSOCK_OP_RW, 1001, b"x"));
+        assert_eq!({ hdr.op }, VSOCK_OP_RST);
+
+        let (hdr, _) = send(&vsock, &mut mem, &packet(VSOCK_OP_SHUTDOWN, 1000, b""));
+        assert_eq!({ hdr.op }, VSOCK_OP_RST);
+        assert!(vsock.connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_out_of_range_descriptors_are_dropped() {
+        let vsock = device();
+        let mut mem = vec![0u8; 0x40000];
+
+        post(&mut mem, TX_RINGS, 0, u64::MAX - 8, 64, 0);
+        assert!(vsock.process_queues(&mut mem).unwrap());
+        assert_eq!(used_idx(&mem, TX_RINGS), 1);
+        assert!(vsock.pending_rx.lock().unwrap().is_empty());
+
+        // A reply aimed at an RX buffer past the end of RAM is discarded
+        let request = packet(VSOCK_OP_REQUEST, 1000, b"");
+        mem[0x30000..0x30000 + request.len()].copy_from_slice(&request);
+        post(&mut mem, TX_RINGS, 0, 0x30000, request.len() as u32, 0);
+        post(&mut mem, RX_RINGS, 0, 0x3FFF0, 0x100, VRING_DESC_F_WRITE);
+        assert!(vsock.process_queues(&mut mem).unwrap());
+        assert_eq!(used_idx(&mem, RX_RINGS), 1);
+        let u = (RX_RINGS + 0x2000 + 4) as usize;
+        assert_eq!(u32::from_le_bytes(mem[u + 4..u + 8].try_into().unwrap()), 0);
+        assert!(vsock.pending_rx.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tx_stalls_while_replies_are_pending() {
+        let vsock = device();
+        let mut mem = vec![0u8; 0x40000];
+        let request = packet(VSOCK_OP_CREDIT_REQUEST, 1000, b"");
+        mem[0x30000..0x30000 + request.len()].copy_from_slice(&request);
+        for _ in 0..MAX_PENDING_RX + 6 {
+            post(&mut mem, TX_RINGS, 0, 0x30000, request.len() as u32, 0);
+        }
+
+        // No RX buffers: TX stops once the reply queue is full
+        assert!(vsock.process_queues(&mut mem).unwrap());
+        assert_eq!(used_idx(&mem, TX_RINGS) as usize, MAX_PENDING_RX);
+        assert_eq!(vsock.pending_rx.lock().unwrap().len(), MAX_PENDING_RX);
+
+        for i in 0..8 {
+            post(&mut mem, RX_RINGS, i, 0x31000 + i as u64 * 0x100, 0x100, VRING_DESC_F_WRITE);
+        }
+        assert!(vsock.process_queues(&mut mem).unwrap());
+        assert_eq!(used_idx(&mem, RX_RINGS), 8);
+        assert_eq!(used_idx(&mem, TX_RINGS) as usize, MAX_PENDING_RX + 6);
+        assert_eq!(vsock.pending_rx.lock().unwrap().len(), MAX_PENDING_RX - 2);
+    }
+}
@@ -0,0 +1,260 @@
+// src/watchdog.rs
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::WatchdogAction;
+use crate::error::{AxvmResult, LockExt};
+use crate::metrics::VmMetrics;
+use crate::serial::SerialPorts;
+use crate::{PauseHandle, StopHandle};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Snapshot of the counters the watchdog treats as guest progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Progress {
+    serial_bytes: u64,
+    vcpu_exits: u64,
+}
+
+impl Progress {
+    fn sample(serial: &SerialPorts, metrics: &VmMetrics) -> Self {
+        Self { serial_bytes: serial.tx_bytes(), vcpu_exits: metrics.vcpu_exits() }
+    }
+}
+
+/// Stop the VM if the guest neither writes to a serial port nor exits to
+/// the hypervisor for `timeout`.
+///
+/// VM exits are taken from the metrics counters, so with `--no-metrics`
+/// only serial output counts as progress, and time spent paused does not
+/// count towards `timeout`. Sets `timed_out` before stopping.
+pub fn spawn_boot_watchdog(
+    timeout: Duration,
+    serial: Arc<SerialPorts>,
+    metrics: Arc<VmMetrics>,
+    stop: StopHandle,
+    pause: PauseHandle,
+    timed_out: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last = Progress::sample(&serial, &metrics);
+        let mut last_change = Instant::now();
+
+        while !stop.is_stopped() {
+            thread::sleep(POLL_INTERVAL);
+
+            let now = Progress::sample(&serial, &metrics);
+            if now != last || pause.is_paused() {
+                last = now;
+                last_change = Instant::now();
+                continue;
+            }
+            if last_change.elapsed() >= timeout {
+                status!(error, "Watchdog", timeout = ?timeout, serial_bytes = now.serial_bytes, vcpu_exits = now.vcpu_exits,
+                    "No serial output or VM exits for {:?}, stopping VM", timeout);
+                metrics.record_timeout();
+                timed_out.store(true, Ordering::SeqCst);
+                stop.stop();
+                break;
+            }
+        }
+    })
+}
+
+/// Writing any value disables the IB700 watchdog
+pub const IB700_STOP_PORT: u16 = 0x441;
+/// Writing a timeout index (low nibble) arms or kicks it
+pub const IB700_START_PORT: u16 = 0x443;
+
+/// iBase IB700 hardware watchdog, the ISA board Linux drives with
+/// `ib700wdt`. Index n of a start write means 30 - 2n seconds.
+pub struct Ib700 {
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl Ib700 {
+    pub fn new() -> Self {
+        Self { deadline: Mutex::new(None) }
+    }
+
+    pub fn handles(port: u16) -> bool {
+        port == IB700_STOP_PORT || port == IB700_START_PORT
+    }
+
+    pub fn write(&self, port: u16, data: &[u8]) -> AxvmResult<()> {
+        let mut deadline = self.deadline.lock_or_err()?;
+        match (port, data.first()) {
+            (IB700_START_PORT, Some(&index)) => {
+                let timeout = Duration::from_secs(30 - 2 * (index & 0x0F) as u64);
+                if deadline.is_none() {
+                    tracing::debug!(timeout = ?timeout, "Guest watchdog armed");
+                }
+                *deadline = Some(Instant::now() + timeout);
+            },
+            (IB700_STOP_PORT, _) if deadline.take().is_some() => {
+                tracing::debug!("Guest watchdog disabled");
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Disarm the watchdog if it expired by `now`; until the guest arms it
+    /// again it does not fire twice.
+    fn take_expired(&self, now: Instant) -> AxvmResult<bool> {
+        let mut deadline = self.deadline.lock_or_err()?;
+        if deadline.is_some_and(|d| now >= d) {
+            *deadline = None;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn postpone(&self, by: Duration) -> AxvmResult<()> {
+        if let Some(ref mut deadline) = *self.deadline.lock_or_err()? {
+            *deadline += by;
+        }
+        Ok(())
+    }
+}
+
+/// Run `action` whenever the guest lets `device` expire. Time spent paused
+/// does not count, since the guest cannot kick the watchdog meanwhile.
+pub fn spawn_guest_watchdog(
+    device: Arc<Ib700>,
+    action: WatchdogAction,
+    metrics: Arc<VmMetrics>,
+    stop: StopHandle,
+    pause: PauseHandle,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while !stop.is_stopped() {
+            thread::sleep(POLL_INTERVAL);
+            let expired = if pause.is_paused() {
+                device.postpone(POLL_INTERVAL).map(|_| false)
+            } else {
+                device.take_expired(Instant::now())
+            };
+            match expired {
+                Ok(true) => {},
+                Ok(false) => continue,
+                Err(e) => {
+                    status!(error, "Watchdog", error = %e, "Guest watchdog failed: {}", e);
+                    metrics.record_error();
+                    break;
+                }
+            }
+
+            metrics.record_timeout();
+            match action {
+                // Guest reboots are not emulated: like an i8042 reset, this ends the VM
+                WatchdogAction::Reset => status!(error, "Watchdog", "RESET (guest watchdog expired)"),
+                WatchdogAction::Poweroff => status!(error, "Watchdog", "Guest watchdog expired, powering off"),
+                WatchdogAction::None => {
+                    status!(warn, "Watchdog", "Guest watchdog expired, no action taken");
+                    continue;
+                },
+            }
+            stop.stop();
+            break;
+        }
+    })
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::idle::IdleWaker;
+    use crate::pause::PauseGate;
+    use crate::serial::{SerialConsole, SerialOutput, COM1_BASE, COM1_IRQ};
+    use crate::state::VmState;
+
+    fn handles() -> (StopHandle, PauseHandle) {
+        let waker = Arc::new(IdleWaker::new());
+        let gate = Arc::new(PauseGate::new(Arc::clone(&waker)));
+        let stop = StopHandle { should_stop: Arc::new(AtomicBool::new(false)), waker, pause: Arc::clone(&gate) };
+        (stop, PauseHandle { gate, state: Arc::new(Mutex::new(VmState::Running)) })
+    }
+
+    #[test]
+    fn test_boot_watchdog_fires_once_progress_stops() {
+        let serial = Arc::new(SerialPorts::new(vec![SerialConsole::new(COM1_BASE, COM1_IRQ, SerialOutput::Stdout)]));
+        let metrics = Arc::new(VmMetrics::new());
+        let (stop, pause) = handles();
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let timeout = Duration::from_millis(300);
+        let watchdog = spawn_boot_watchdog(timeout, serial, Arc::clone(&metrics), stop.clone(), pause, Arc::clone(&timed_out));
+
+        // VM exits keep it quiet for several timeouts
+        let start = Instant::now();
+        while start.elapsed() < 3 * timeout {
+            metrics.record_io_exit();
+            thread::sleep(POLL_INTERVAL / 2);
+        }
+        assert!(!stop.is_stopped());
+
+        let idle = Instant::now();
+        watchdog.join().unwrap();
+        // Progress is sampled once per poll, so the last change may predate `idle`
+        assert!(idle.elapsed() >= timeout - POLL_INTERVAL);
+        assert!(stop.is_stopped() && timed_out.load(Ordering::SeqCst));
+        assert_eq!(metrics.timeout_events(), 1);
+    }
+
+    #[test]
+    fn test_ib700_arm_kick_and_stop() {
+        let wdt = Ib700::new();
+        let now = Instant::now();
+        assert!(!wdt.take_expired(now + Duration::from_secs(60)).unwrap());
+
+        // Index 0 is 30 seconds; a kick restarts the countdown
+        wdt.write(IB700_START_PORT, &[0]).unwrap();
+        assert!(!wdt.take_expired(Instant::now() + Duration::from_secs(29)).unwrap());
+        wdt.write(IB700_START_PORT, &[0x10]).unwrap();
+        assert!(wdt.take_expired(Instant::now() + Duration::from_secs(31)).unwrap());
+        assert!(!wdt.take_expired(Instant::now() + Duration::from_secs(31)).unwrap());
+
+        // Index 15 is 0 seconds: expired at once
+        wdt.write(IB700_START_PORT, &[15]).unwrap();
+        assert!(wdt.take_expired(Instant::now()).unwrap());
+
+        wdt.write(IB700_START_PORT, &[14]).unwrap();
+        wdt.postpone(Duration::from_secs(10)).unwrap();
+        assert!(!wdt.take_expired(Instant::now() + Duration::from_secs(3)).unwrap());
+        wdt.write(IB700_STOP_PORT, &[0]).unwrap();
+        assert!(!wdt.take_expired(Instant::now() + Duration::from_secs(60)).unwrap());
+    }
+
+    #[test]
+    fn test_expiry_actions() {
+        for action in [WatchdogAction::Reset, WatchdogAction::Poweroff, WatchdogAction::None] {
+            let wdt = Arc::new(Ib700::new());
+            wdt.write(IB700_START_PORT, &[15]).unwrap();
+            let metrics = Arc::new(VmMetrics::new());
+            let (stop, pause) = handles();
+            let watchdog = spawn_guest_watchdog(Arc::clone(&wdt), action, Arc::clone(&metrics), stop.clone(), pause);
+
+            if action != WatchdogAction::None {
+                watchdog.join().unwrap();
+                assert!(stop.is_stopped());
+            } else {
+                let start = Instant::now();
+                while metrics.timeout_events() == 0 && start.elapsed() < Duration::from_secs(5) {
+                    thread::sleep(POLL_INTERVAL);
+                }
+                // Expired, counted, and still running
+                assert!(!stop.is_stopped());
+                stop.stop();
+                watchdog.join().unwrap();
+            }
+            assert_eq!(metrics.timeout_events(), 1, "{:?}", action);
+        }
+    }
+}
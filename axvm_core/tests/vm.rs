@@ -0,0 +1,238 @@
+// tests/vm.rs
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use axvm_core::config::{SerialTarget, VmConfig, WatchdogAction};
+use axvm_core::error::AxvmError;
+use axvm_core::state::VmState;
+use axvm_core::Vm;
+
+/// Smallest file the loader accepts as a bzImage: a boot protocol 2.15
+/// setup header followed by a few sectors of "kernel code".
+fn fake_bzimage(name: &str) -> PathBuf {
+    let mut image = vec![0u8; 13 * 512];
+    image[0x1F1] = 4;
+    image[0x202..0x206].copy_from_slice(b"HdrS");
+    image[0x206..0x208].copy_from_slice(&0x020Fu16.to_le_bytes());
+    image[0x238..0x23C].copy_from_slice(&2047u32.to_le_bytes());
+
+    let path = std::env::temp_dir().join(format!("axvm-{}-{}", name, std::process::id()));
+    fs::write(&path, image).unwrap();
+    path
+}
+
+const SELFTEST_BANNER: &str = "AXVM-SELFTEST-OK";
+const SELFTEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Long-mode flat binary that writes `SELFTEST_BANNER` to COM1 and halts.
+fn selftest_payload() -> Vec<u8> {
+    let mut code = vec![
+        0x66, 0xBA, 0xF8, 0x03,                   // mov dx, 0x3F8
+        0x48, 0x8D, 0x35, 0x0C, 0x00, 0x00, 0x00, // lea rsi, [rip + msg]
+        0xAC,                                     // next: lodsb
+        0x84, 0xC0,                               // test al, al
+        0x74, 0x03,                               // jz done
+        0xEE,                                     // out dx, al
+        0xEB, 0xF8,                               // jmp next
+        0xFA,                                     // done: cli
+        0xF4,                                     // halt: hlt
+        0xEB, 0xFD,                               // jmp halt
+    ];
+    code.extend_from_slice(SELFTEST_BANNER.as_bytes());
+    code.extend_from_slice(b"\n\0");
+    code
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("axvm-{}-{}", name, std::process::id()))
+}
+
+#[test]
+fn raw_payload_boots_and_prints_to_serial() {
+    if fs::OpenOptions::new().read(true).write(true).open("/dev/kvm").is_err() {
+        eprintln!("skipping: /dev/kvm is not available");
+        return;
+    }
+
+    let payload = temp_path("selftest.bin");
+    let log = temp_path("selftest.log");
+    fs::write(&payload, selftest_payload()).unwrap();
+    let config = VmConfig {
+        memory: 128,
+        raw: Some(payload.clone()),
+        serial: SerialTarget::File(log.clone()),
+        quiet: true,
+        // The halted vCPU sits in KVM_RUN until it is kicked
+        shutdown_timeout: 1,
+        ..Default::default()
+    };
+
+    let mut vm = Vm::new(config).unwrap();
+    assert_eq!(vm.state(), VmState::Configured);
+    assert!(matches!(vm.pause(), Err(AxvmError::InvalidState(_))));
+    let stop = vm.stop_handle();
+    let watcher = {
+        let log = log.clone();
+        thread::spawn(move || {
+            let start = Instant::now();
+            let seen = loop {
+                let output = fs::read_to_string(&log).unwrap_or_default();
+                if output.contains(SELFTEST_BANNER) {
+                    break true;
+                }
+                if start.elapsed() > SELFTEST_TIMEOUT {
+                    break false;
+                }
+                thread::sleep(Duration::from_millis(20));
+            };
+            stop.stop();
+            seen
+        })
+    };
+
+    let result = vm.run();
+    let seen = watcher.join().unwrap();
+    let output = fs::read_to_string(&log).unwrap_or_default();
+    fs::remove_file(payload).unwrap();
+    let _ = fs::remove_file(log);
+
+    result.unwrap();
+    assert!(seen, "guest did not print {:?} within {:?}; serial output: {:?}",
+        SELFTEST_BANNER, SELFTEST_TIMEOUT, output);
+
+    assert_eq!(vm.state(), VmState::Stopped);
+    assert!(matches!(vm.run(), Err(AxvmError::InvalidState(_))));
+}
+
+#[test]
+fn guest_watchdog_fires_when_not_kicked() {
+    if fs::OpenOptions::new().read(true).write(true).open("/dev/kvm").is_err() {
+        eprintln!("skipping: /dev/kvm is not available");
+        return;
+    }
+
+    // Arms the IB700 with its shortest (0 s) timeout and never kicks it
+    let payload = temp_path("watchdog.bin");
+    fs::write(&payload, [
+        0x66, 0xBA, 0x43, 0x04, // mov dx, 0x443
+        0xB0, 0x0F,             // mov al, 15
+        0xEE,                   // out dx, al
+        0xEB, 0xFE,             // jmp $
+    ]).unwrap();
+    let config = VmConfig {
+        memory: 128,
+        raw: Some(payload.clone()),
+        watchdog: true,
+        watchdog_action: WatchdogAction::Poweroff,
+        quiet: true,
+        shutdown_timeout: 1,
+        ..Default::default()
+    };
+
+    let mut vm = Vm::new(config).unwrap();
+    let start = Instant::now();
+    let result = vm.run();
+    fs::remove_file(payload).unwrap();
+
+    result.unwrap();
+    assert!(start.elapsed() < SELFTEST_TIMEOUT, "watchdog took {:?} to stop the VM", start.elapsed());
+    assert_eq!(vm.metrics().timeout_events(), 1);
+    assert_eq!(vm.state(), VmState::Stopped);
+}
+
+#[test]
+fn dry_run_loads_guest_without_kvm() {
+    let kernel = fake_bzimage("dry-run");
+    let config = VmConfig {
+        kernel: kernel.clone(),
+        dry_run: true,
+        ..Default::default()
+    };
+
+    let result = axvm_core::dry_run(&config);
+    fs::remove_file(kernel).unwrap();
+    result.unwrap();
+}
+
+#[test]
+fn new_rejects_invalid_config() {
+    let config = VmConfig {
+        kernel: PathBuf::from("/nonexistent/bzImage"),
+        ..Default::default()
+    };
+
+    match Vm::new(config) {
+        Err(AxvmError::InvalidConfiguration(msg)) => assert!(msg.contains("Kernel image not found")),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("VM created from a missing kernel"),
+    }
+}
+
+#[test]
+fn count_instructions_single_steps_the_guest() {
+    if fs::OpenOptions::new().read(true).write(true).open("/dev/kvm").is_err() {
+        eprintln!("skipping: /dev/kvm is not available");
+        return;
+    }
+
+    // Four instructions, the last of which resets the VM through the i8042
+    let payload = temp_path("count.bin");
+    fs::write(&payload, [
+        0x90,       // nop
+        0x90,       // nop
+        0xB0, 0xFE, // mov al, 0xFE
+        0xE6, 0x64, // out 0x64, al
+    ]).unwrap();
+    let config = VmConfig {
+        memory: 128,
+        raw: Some(payload.clone()),
+        count_instructions: true,
+        quiet: true,
+        shutdown_timeout: 1,
+        ..Default::default()
+    };
+
+    let mut vm = Vm::new(config).unwrap();
+    let result = vm.run();
+    fs::remove_file(payload).unwrap();
+
+    result.unwrap();
+    // The out exits to the hypervisor before its single-step trap
+    assert_eq!(vm.metrics().total_instructions(), 3);
+    assert_eq!(vm.state(), VmState::Stopped);
+}
+
+#[test]
+fn internal_error_stops_the_vm() {
+    if fs::OpenOptions::new().read(true).write(true).open("/dev/kvm").is_err() {
+        eprintln!("skipping: /dev/kvm is not available");
+        return;
+    }
+
+    // Fetching code from identity-mapped memory past the end of RAM is an
+    // emulation failure, which KVM reports as an internal error
+    let payload = temp_path("internal-error.bin");
+    fs::write(&payload, [
+        0x48, 0xB8, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, // mov rax, 0x20000000
+        0xFF, 0xE0,                                                 // jmp rax
+    ]).unwrap();
+    let config = VmConfig {
+        memory: 128,
+        raw: Some(payload.clone()),
+        quiet: true,
+        shutdown_timeout: 1,
+        ..Default::default()
+    };
+
+    let mut vm = Vm::new(config).unwrap();
+    let start = Instant::now();
+    let result = vm.run();
+    fs::remove_file(payload).unwrap();
+
+    result.unwrap();
+    assert!(start.elapsed() < SELFTEST_TIMEOUT, "VM took {:?} to stop", start.elapsed());
+    assert_eq!(vm.metrics().hardware_failures(), 1);
+    assert_eq!(vm.state(), VmState::Stopped);
+}